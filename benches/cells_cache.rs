@@ -0,0 +1,69 @@
+use std::sync::Arc;
+use std::time::{Duration, Instant};
+
+use criterion::{Criterion, criterion_group, criterion_main, Throughput};
+use ton_types::{BuilderData, Cell, IBitstring, Result};
+
+use ton_node_storage::dynamic_boc_db::DynamicBocDb;
+use ton_node_storage::types::CellId;
+
+const CHILDREN_COUNT: u32 = 4;
+const THREADS: [usize; 3] = [1, 4, 8];
+
+fn build_tree() -> Result<Cell> {
+    let mut root = BuilderData::new();
+    root.append_u32(u32::max_value())?;
+    for i in 0..CHILDREN_COUNT {
+        let mut child = BuilderData::new();
+        child.append_u32(i)?;
+        root.append_reference_cell(child.into());
+    }
+
+    Ok(root.into())
+}
+
+/// Loads the tree `iterations` times from each of several threads, dropping loaded cells right away
+fn load_concurrently(db: &Arc<DynamicBocDb>, root_id: &CellId, threads: usize, iterations: u64) -> Duration {
+    let started = Instant::now();
+    let handles = (0..threads)
+        .map(|_| {
+            let db = Arc::clone(db);
+            let root_id = root_id.clone();
+            std::thread::spawn(move || -> Result<()> {
+                for _ in 0..iterations {
+                    let root = db.load_dynamic_boc(&root_id)?;
+                    for i in 0..root.references_count() {
+                        root.reference(i)?;
+                    }
+                }
+                Ok(())
+            })
+        })
+        .collect::<Vec<_>>();
+    for handle in handles {
+        handle.join().expect("Thread panicked").expect("Tree loading failed");
+    }
+
+    started.elapsed()
+}
+
+/// Concurrent loads of the tree whose cells are dropped right after loading, so the loads hit
+/// the cells cache only while another thread holds the cell
+fn cells_cache(c: &mut Criterion) {
+    let db = Arc::new(DynamicBocDb::in_memory());
+    let root = build_tree().expect("Tree building failed");
+    let root_id = CellId::new(root.repr_hash());
+    db.save_as_dynamic_boc(root).expect("Tree saving failed");
+
+    let mut group = c.benchmark_group("cells_cache");
+    for threads in THREADS.iter() {
+        group.throughput(Throughput::Elements(*threads as u64 * (CHILDREN_COUNT as u64 + 1)));
+        group.bench_function(format!("{}_threads", threads), |b| b.iter_custom(|iterations| {
+            load_concurrently(&db, &root_id, *threads, iterations)
+        }));
+    }
+    group.finish();
+}
+
+criterion_group!(benches, cells_cache);
+criterion_main!(benches);
@@ -0,0 +1,292 @@
+use std::path::PathBuf;
+use std::sync::Arc;
+use std::sync::atomic::{AtomicU64, Ordering};
+
+use criterion::{BatchSize, Criterion, criterion_group, criterion_main, Throughput};
+use rand::Rng;
+use rand::seq::SliceRandom;
+use serde_derive::Deserialize;
+use ton_block::{AccountIdPrefixFull, BlockIdExt, ShardIdent};
+use ton_types::{BuilderData, Cell, IBitstring, Result, UInt256};
+
+use ton_node_storage::archives::archive_fs::LocalFs;
+use ton_node_storage::archives::package::{FileBudget, Package};
+use ton_node_storage::archives::package_entry::PackageEntry;
+use ton_node_storage::block_index_db::BlockIndexDb;
+use ton_node_storage::dynamic_boc_db::DynamicBocDb;
+use ton_node_storage::types::{BlockHandle, BlockMeta, CellId, Lt, UnixTime};
+
+const SCENARIOS_ENV: &str = "STORAGE_BENCH_SCENARIOS";
+const DEFAULT_SCENARIOS: &str = concat!(env!("CARGO_MANIFEST_DIR"), "/benches/scenarios.toml");
+
+const MASTERCHAIN_PREFIX: u64 = 0x8000_0000_0000_0000;
+const GEN_UTIME_BASE: u32 = 1_600_000_000;
+const LT_STEP: u64 = 1_000_000;
+
+#[derive(Debug, Default, Deserialize)]
+struct Scenarios {
+    #[serde(default)]
+    cell_writes: Vec<CellWritesScenario>,
+    #[serde(default)]
+    cell_loads: Vec<CellLoadsScenario>,
+    #[serde(default)]
+    lt_lookups: Vec<LtLookupsScenario>,
+    #[serde(default)]
+    archive: Vec<ArchiveScenario>,
+}
+
+#[derive(Debug, Deserialize)]
+struct CellWritesScenario {
+    name: String,
+    depth: u32,
+    fan_out: u32,
+    #[serde(default)]
+    on_disk: bool,
+}
+
+#[derive(Debug, Deserialize)]
+struct CellLoadsScenario {
+    name: String,
+    depth: u32,
+    fan_out: u32,
+    hit_rate: f64,
+}
+
+#[derive(Debug, Deserialize)]
+struct LtLookupsScenario {
+    name: String,
+    blocks: u32,
+    #[serde(default)]
+    on_disk: bool,
+}
+
+#[derive(Debug, Deserialize)]
+struct ArchiveScenario {
+    name: String,
+    entries: usize,
+    entry_size: usize,
+}
+
+fn load_scenarios() -> Scenarios {
+    let path = std::env::var(SCENARIOS_ENV).unwrap_or_else(|_| DEFAULT_SCENARIOS.to_string());
+    let text = std::fs::read_to_string(&path)
+        .unwrap_or_else(|err| panic!("Can't read scenarios {}: {}", path, err));
+    toml::from_str(&text)
+        .unwrap_or_else(|err| panic!("Can't parse scenarios {}: {}", path, err))
+}
+
+/// Directory removed when the benchmark is done
+struct TempDir(PathBuf);
+
+impl TempDir {
+    fn new(name: &str) -> Self {
+        let path = std::env::temp_dir().join(format!("storage_bench_{}_{}", name, std::process::id()));
+        let _ = std::fs::remove_dir_all(&path);
+        std::fs::create_dir_all(&path).expect("Can't create temporary directory");
+        Self(path)
+    }
+}
+
+impl Drop for TempDir {
+    fn drop(&mut self) {
+        let _ = std::fs::remove_dir_all(&self.0);
+    }
+}
+
+fn new_runtime() -> tokio::runtime::Runtime {
+    tokio::runtime::Builder::new()
+        .build()
+        .expect("Can't create tokio runtime")
+}
+
+/// Builds the tree of unique cells: every cell stores the next value of the counter
+fn build_tree(depth: u32, fan_out: u32, counter: &AtomicU64) -> Result<Cell> {
+    let mut builder = BuilderData::new();
+    builder.append_u64(counter.fetch_add(1, Ordering::Relaxed))?;
+    if depth > 1 {
+        for _ in 0..fan_out {
+            builder.append_reference_cell(build_tree(depth - 1, fan_out, counter)?);
+        }
+    }
+
+    Ok(builder.into())
+}
+
+fn tree_size(depth: u32, fan_out: u32) -> u64 {
+    let mut size = 0;
+    let mut level = 1;
+    let mut i = 0;
+    while i < depth {
+        size += level;
+        level *= fan_out as u64;
+        i += 1;
+    }
+
+    size
+}
+
+fn collect_ids(cell: &Cell, ids: &mut Vec<CellId>) -> Result<()> {
+    ids.push(CellId::new(cell.repr_hash()));
+    for i in 0..cell.references_count() {
+        collect_ids(&cell.reference(i)?, ids)?;
+    }
+
+    Ok(())
+}
+
+fn cell_writes(c: &mut Criterion, scenarios: &[CellWritesScenario]) {
+    let mut group = c.benchmark_group("cell_writes");
+    for scenario in scenarios {
+        let temp_dir = TempDir::new(&format!("cell_writes_{}", scenario.name));
+        let db = Arc::new(if scenario.on_disk {
+            DynamicBocDb::with_path(&temp_dir.0)
+        } else {
+            DynamicBocDb::in_memory()
+        });
+        let counter = AtomicU64::new(0);
+        group.throughput(Throughput::Elements(tree_size(scenario.depth, scenario.fan_out)));
+        group.bench_function(&scenario.name, |b| b.iter_batched(
+            || build_tree(scenario.depth, scenario.fan_out, &counter).expect("Can't build tree"),
+            |tree| db.save_as_dynamic_boc(tree).expect("Can't save tree"),
+            BatchSize::LargeInput,
+        ));
+    }
+    group.finish();
+}
+
+fn cell_loads(c: &mut Criterion, scenarios: &[CellLoadsScenario]) {
+    let mut group = c.benchmark_group("cell_loads");
+    group.throughput(Throughput::Elements(1));
+    for scenario in scenarios {
+        let temp_dir = TempDir::new(&format!("cell_loads_{}", scenario.name));
+        let db = Arc::new(DynamicBocDb::with_path(&temp_dir.0));
+        let tree = build_tree(scenario.depth, scenario.fan_out, &AtomicU64::new(0)).expect("Can't build tree");
+        let mut ids = Vec::new();
+        collect_ids(&tree, &mut ids).expect("Can't collect cell ids");
+        db.save_as_dynamic_boc(tree).expect("Can't save tree");
+
+        // Cells kept loaded stay in the cache, the others are evicted when dropped
+        let mut rng = rand::thread_rng();
+        ids.shuffle(&mut rng);
+        let hot_count = (ids.len() as f64 * scenario.hit_rate.max(0.0).min(1.0)) as usize;
+        let hot = ids[..hot_count].iter()
+            .map(|id| db.load_dynamic_boc(id))
+            .collect::<Result<Vec<_>>>()
+            .expect("Can't load cells");
+
+        group.bench_function(&scenario.name, |b| b.iter(|| {
+            let id = ids.choose(&mut rng).expect("Tree is empty");
+            db.load_dynamic_boc(id).expect("Can't load cell")
+        }));
+        drop(hot);
+    }
+    group.finish();
+}
+
+fn fill_block_index(index: &BlockIndexDb, blocks: u32) -> Result<()> {
+    let block_handle_cache = Arc::new(lockfree::map::Map::new());
+    for seq_no in 1..=blocks {
+        let id = BlockIdExt {
+            shard_id: ShardIdent::masterchain(),
+            seq_no,
+            root_hash: UInt256::from(&[(seq_no % 251) as u8; 32][..]),
+            file_hash: UInt256::default(),
+        };
+        let meta = BlockMeta::with_data(0, GEN_UTIME_BASE + seq_no, seq_no as u64 * LT_STEP, seq_no, true);
+        index.add_handle(&BlockHandle::with_values(id, meta, Arc::clone(&block_handle_cache)))?;
+    }
+
+    Ok(())
+}
+
+fn lt_lookups(c: &mut Criterion, scenarios: &[LtLookupsScenario]) {
+    let mut group = c.benchmark_group("lt_lookups");
+    group.throughput(Throughput::Elements(1));
+    let account_id = AccountIdPrefixFull { workchain_id: -1, prefix: MASTERCHAIN_PREFIX };
+    for scenario in scenarios {
+        let temp_dir = TempDir::new(&format!("lt_lookups_{}", scenario.name));
+        let index = if scenario.on_disk {
+            BlockIndexDb::with_paths(temp_dir.0.join("lt_desc_db"), temp_dir.0.join("lt_db"))
+        } else {
+            BlockIndexDb::in_memory()
+        };
+        fill_block_index(&index, scenario.blocks).expect("Can't fill block index");
+
+        let mut rng = rand::thread_rng();
+        group.bench_function(format!("{}/seq_no", scenario.name), |b| b.iter(|| {
+            let seq_no = rng.gen_range(1, scenario.blocks + 1);
+            index.get_block_by_seq_no(&account_id, seq_no).expect("Can't find block")
+        }));
+        group.bench_function(format!("{}/lt", scenario.name), |b| b.iter(|| {
+            let lt = rng.gen_range(LT_STEP, scenario.blocks as u64 * LT_STEP);
+            index.get_block_by_lt(&account_id, Lt::new(lt)).expect("Can't find block")
+        }));
+        group.bench_function(format!("{}/utime", scenario.name), |b| b.iter(|| {
+            let utime = rng.gen_range(GEN_UTIME_BASE + 1, GEN_UTIME_BASE + scenario.blocks);
+            index.get_block_by_ut(&account_id, UnixTime::new(utime)).expect("Can't find block")
+        }));
+    }
+    group.finish();
+}
+
+fn archive(c: &mut Criterion, scenarios: &[ArchiveScenario]) {
+    let mut group = c.benchmark_group("archive");
+    let mut runtime = new_runtime();
+    for scenario in scenarios {
+        let temp_dir = TempDir::new(&format!("archive_{}", scenario.name));
+        let entries = (0..scenario.entries)
+            .map(|i| PackageEntry::with_data(format!("entry_{}", i), vec![(i % 251) as u8; scenario.entry_size]))
+            .collect::<Vec<_>>();
+        let file_budget = Arc::new(FileBudget::new(16));
+        let open = |name: &str| Package::open(
+            Arc::new(temp_dir.0.join(name)),
+            false,
+            true,
+            Arc::clone(&file_budget),
+            Arc::new(LocalFs),
+        );
+
+        group.throughput(Throughput::Bytes((scenario.entries * scenario.entry_size) as u64));
+        let mut iteration = 0;
+        group.bench_function(format!("{}/append", scenario.name), |b| b.iter(|| {
+            iteration += 1;
+            runtime.block_on(async {
+                let package = open(&format!("append_{}.pack", iteration)).await?;
+                package.append_entries(&entries, |_index, _offset, _size| Ok(())).await?;
+                package.close().await;
+                Ok::<_, failure::Error>(())
+            }).expect("Can't append entries")
+        }));
+
+        let mut offsets = Vec::with_capacity(entries.len());
+        let package = runtime.block_on(async {
+            let package = open("read.pack").await?;
+            package.append_entries(&entries, |_index, offset, _size| {
+                offsets.push(offset);
+                Ok(())
+            }).await?;
+            Ok::<_, failure::Error>(package)
+        }).expect("Can't write package");
+        group.bench_function(format!("{}/read", scenario.name), |b| b.iter(|| {
+            runtime.block_on(async {
+                for offset in offsets.iter() {
+                    package.read_entry(*offset).await?;
+                }
+                Ok::<_, failure::Error>(())
+            }).expect("Can't read entries")
+        }));
+        runtime.block_on(package.close());
+    }
+    group.finish();
+}
+
+fn storage_throughput(c: &mut Criterion) {
+    let scenarios = load_scenarios();
+    cell_writes(c, &scenarios.cell_writes);
+    cell_loads(c, &scenarios.cell_loads);
+    lt_lookups(c, &scenarios.lt_lookups);
+    archive(c, &scenarios.archive);
+}
+
+criterion_group!(benches, storage_throughput);
+criterion_main!(benches);
@@ -0,0 +1,75 @@
+use ton_block::{BlockIdExt, ShardIdent, UnixTime32};
+use ton_types::{fail, Result};
+
+use crate::db::traits::KvcWriteable;
+use crate::db_impl_serializable;
+use crate::traits::Serializable;
+use crate::types::{ApplyCheckpoint, ShardIdentKey};
+
+db_impl_serializable!(ApplyCheckpointDb, KvcWriteable, ShardIdentKey, ApplyCheckpoint);
+
+impl ApplyCheckpointDb {
+    /// Gets the checkpoint of the shard
+    pub fn get_checkpoint(&self, shard: &ShardIdent) -> Result<Option<ApplyCheckpoint>> {
+        self.try_get_value(&ShardIdentKey::new(shard)?)
+    }
+
+    /// Moves the checkpoint of the block's shard to the block. The checkpoint never goes back;
+    /// advancing to the block already checkpointed is a no-op. Returns the resulting checkpoint.
+    pub fn advance(&self, block_id_ext: &BlockIdExt) -> Result<ApplyCheckpoint> {
+        let key = ShardIdentKey::new(block_id_ext.shard())?;
+        let apply_seq_no = match self.try_get_value(&key)? {
+            Some(checkpoint) if checkpoint.block_id_ext() == block_id_ext => return Ok(checkpoint),
+            Some(checkpoint) if checkpoint.block_id_ext().seq_no() >= block_id_ext.seq_no() => fail!(
+                "Apply checkpoint can't go back from {} to {}",
+                checkpoint.block_id_ext(),
+                block_id_ext
+            ),
+            Some(checkpoint) => checkpoint.apply_seq_no() + 1,
+            None => 0,
+        };
+
+        let checkpoint = ApplyCheckpoint::with_values(block_id_ext.clone(), apply_seq_no, UnixTime32::now().0);
+        self.put_value(&key, &checkpoint)?;
+        log::trace!(target: "storage", "Apply checkpoint advanced to {} (#{})", block_id_ext, apply_seq_no);
+
+        Ok(checkpoint)
+    }
+
+    /// Sets the checkpoint of the shard to given block regardless of the current one (intended for
+    /// recovery), or removes it if the block is None
+    pub fn reset(&self, shard: &ShardIdent, block_id_ext: Option<&BlockIdExt>) -> Result<Option<ApplyCheckpoint>> {
+        let key = ShardIdentKey::new(shard)?;
+        let block_id_ext = match block_id_ext {
+            Some(block_id_ext) => block_id_ext,
+            None => {
+                self.delete(&key)?;
+                log::warn!(target: "storage", "Apply checkpoint of {} removed", shard);
+                return Ok(None);
+            }
+        };
+        if block_id_ext.shard() != shard {
+            fail!("Block {} doesn't belong to shard {}", block_id_ext, shard)
+        }
+
+        let apply_seq_no = self.try_get_value(&key)?
+            .map(|checkpoint| checkpoint.apply_seq_no() + 1)
+            .unwrap_or_default();
+        let checkpoint = ApplyCheckpoint::with_values(block_id_ext.clone(), apply_seq_no, UnixTime32::now().0);
+        self.put_value(&key, &checkpoint)?;
+        log::warn!(target: "storage", "Apply checkpoint of {} reset to {}", shard, block_id_ext);
+
+        Ok(Some(checkpoint))
+    }
+
+    /// Lists the checkpoints of all the shards
+    pub fn list(&self) -> Result<Vec<ApplyCheckpoint>> {
+        let mut result = Vec::new();
+        self.for_each(&mut |_key, value| {
+            result.push(ApplyCheckpoint::from_slice(value)?);
+            Ok(true)
+        })?;
+
+        Ok(result)
+    }
+}
@@ -0,0 +1,170 @@
+//! File system abstraction for the archive packages.
+//!
+//! Packages are accessed only through `ArchiveFs`, so they may be kept outside of the local disk.
+//! The indexes of the archives (RocksDB) and the unapplied files always stay on the local disk.
+//!
+//! An S3-like object storage backend would be implemented in a separate module enabled by a
+//! feature flag (e.g. `archive-s3`), mapping the operations as follows:
+//! - `open` checks the object with HEAD (creating an empty object if requested); the handle
+//!   caches the object size;
+//! - `read_at` issues ranged GET requests; `into_reader` streams the body of a plain GET;
+//! - `append` buffers data in the handle and uploads it as parts of a multipart upload, which is
+//!   completed when the package is finalized or the handle is dropped; object stores can't append
+//!   in place, so finalized packages are the natural unit to keep remotely;
+//! - `truncate` and `rename` rewrite the object by server-side copy (and delete the source);
+//! - `sync` completes the pending upload, `sync_dir` is a no-op (objects have no directories);
+//! - `list` maps to listing by the key prefix.
+
+use std::fmt::Debug;
+use std::io::{ErrorKind, SeekFrom};
+use std::path::{Path, PathBuf};
+
+use async_trait::async_trait;
+use tokio::fs::{File, OpenOptions};
+use tokio::io::{AsyncRead, AsyncReadExt, AsyncWriteExt};
+use ton_types::{fail, Result};
+
+/// Handle of the package file opened with `ArchiveFs`
+#[async_trait]
+pub trait ArchiveFile: Debug + Send + Sync {
+    /// Current size of the file
+    async fn size(&mut self) -> Result<u64>;
+
+    /// Reads data at the offset into the buffer; returns count of bytes read, which is less than
+    /// the buffer's size only at the end of the file
+    async fn read_at(&mut self, offset: u64, buf: &mut [u8]) -> Result<usize>;
+
+    /// Reads exactly the buffer's size of data at the offset
+    async fn read_exact_at(&mut self, offset: u64, buf: &mut [u8]) -> Result<()> {
+        let read = self.read_at(offset, buf).await?;
+        if read != buf.len() {
+            fail!("Unexpected end of file: {} bytes read at offset {}, {} expected", read, offset, buf.len())
+        }
+
+        Ok(())
+    }
+
+    /// Appends data to the end of the file; the data are visible to other handles on return
+    async fn append(&mut self, data: &[u8]) -> Result<()>;
+
+    /// Cuts the file to the size
+    async fn truncate(&mut self, size: u64) -> Result<()>;
+
+    /// Makes the data written so far durable
+    async fn sync(&mut self) -> Result<()>;
+
+    /// Converts the handle into the sequential reader positioned at the beginning of the file
+    async fn into_reader(self: Box<Self>) -> Result<Box<dyn AsyncRead + Send + Unpin>>;
+}
+
+/// Storage of the archive package files
+#[async_trait]
+pub trait ArchiveFs: Debug + Send + Sync {
+    /// Opens the file; if `create` is set, the missing file is created empty
+    async fn open(&self, path: &Path, read_only: bool, create: bool) -> Result<Box<dyn ArchiveFile>>;
+
+    /// Replaces the file at `to` with the file at `from`
+    async fn rename(&self, from: &Path, to: &Path) -> Result<()>;
+
+    /// Removes the file; removing the missing file is not an error
+    async fn remove(&self, path: &Path) -> Result<()>;
+
+    /// Makes the creation, renaming and removal of the files of the directory durable
+    async fn sync_dir(&self, dir: &Path) -> Result<()>;
+
+    /// Lists the files located in the directory (not recursively)
+    async fn list(&self, dir: &Path) -> Result<Vec<PathBuf>>;
+}
+
+/// Archive file system on the local disk
+#[derive(Debug, Default)]
+pub struct LocalFs;
+
+#[async_trait]
+impl ArchiveFs for LocalFs {
+    async fn open(&self, path: &Path, read_only: bool, create: bool) -> Result<Box<dyn ArchiveFile>> {
+        let file = OpenOptions::new()
+            .read(true)
+            .write(!read_only || create)
+            .create(create)
+            .open(path).await?;
+
+        Ok(Box::new(LocalFile { file }))
+    }
+
+    async fn rename(&self, from: &Path, to: &Path) -> Result<()> {
+        Ok(tokio::fs::rename(from, to).await?)
+    }
+
+    async fn remove(&self, path: &Path) -> Result<()> {
+        match tokio::fs::remove_file(path).await {
+            Err(err) if err.kind() != ErrorKind::NotFound => Err(err.into()),
+            _ => Ok(()),
+        }
+    }
+
+    async fn sync_dir(&self, dir: &Path) -> Result<()> {
+        Ok(File::open(dir).await?.sync_all().await?)
+    }
+
+    async fn list(&self, dir: &Path) -> Result<Vec<PathBuf>> {
+        let mut result = Vec::new();
+        let mut read_dir = tokio::fs::read_dir(dir).await?;
+        while let Some(dir_entry) = read_dir.next_entry().await? {
+            if dir_entry.file_type().await?.is_file() {
+                result.push(dir_entry.path());
+            }
+        }
+        result.sort();
+
+        Ok(result)
+    }
+}
+
+#[derive(Debug)]
+struct LocalFile {
+    file: File,
+}
+
+#[async_trait]
+impl ArchiveFile for LocalFile {
+    async fn size(&mut self) -> Result<u64> {
+        Ok(self.file.metadata().await?.len())
+    }
+
+    async fn read_at(&mut self, offset: u64, buf: &mut [u8]) -> Result<usize> {
+        self.file.seek(SeekFrom::Start(offset)).await?;
+        let mut total = 0;
+        while total < buf.len() {
+            let read = self.file.read(&mut buf[total..]).await?;
+            if read == 0 {
+                break;
+            }
+            total += read;
+        }
+
+        Ok(total)
+    }
+
+    async fn append(&mut self, data: &[u8]) -> Result<()> {
+        self.file.seek(SeekFrom::End(0)).await?;
+        self.file.write_all(data).await?;
+        self.file.flush().await?;
+
+        Ok(())
+    }
+
+    async fn truncate(&mut self, size: u64) -> Result<()> {
+        Ok(self.file.set_len(size).await?)
+    }
+
+    async fn sync(&mut self) -> Result<()> {
+        Ok(self.file.sync_all().await?)
+    }
+
+    async fn into_reader(mut self: Box<Self>) -> Result<Box<dyn AsyncRead + Send + Unpin>> {
+        self.file.seek(SeekFrom::Start(0)).await?;
+
+        Ok(Box::new(self.file))
+    }
+}
@@ -1,46 +1,204 @@
 use std::borrow::Borrow;
 use std::hash::Hash;
 use std::io::ErrorKind;
-use std::path::PathBuf;
+use std::ops::RangeInclusive;
+use std::path::{Path, PathBuf};
 use std::sync::Arc;
+use std::sync::atomic::{AtomicBool, Ordering};
+use std::time::{Duration, Instant, UNIX_EPOCH};
 
 use tokio::fs::OpenOptions;
 use tokio::io::AsyncWriteExt;
 use ton_api::ton::PublicKey;
-use ton_block::BlockIdExt;
-use ton_types::{error, Result, UInt256};
+use ton_block::{BlockIdExt, ShardIdent};
+use ton_types::{error, fail, Result, UInt256};
 
-use crate::archives::archive_slice::ArchiveSlice;
+use crate::archives::archive_fs::{ArchiveFs, LocalFs};
+use crate::archives::block_data_locator::{DataProvenance, LocatedData};
+use crate::archives::file_hash_index_db::{FileHashIndexDb, FileHashIndexEntry};
 use crate::archives::file_maps::{FileDescription, FileMaps};
 use crate::archives::get_mc_seq_no;
-use crate::archives::package_entry_id::{GetFileNameShort, PackageEntryId};
-use crate::archives::package_id::PackageId;
-use crate::types::BlockHandle;
+use crate::archives::package::{DEFAULT_PACKAGE_FILE_BUDGET, FileBudget};
+use crate::archives::package_entry::PackageEntry;
+use crate::archives::package_entry_id::{block_id_short_hash, GetFileName, GetFileNameShort, PackageEntryId};
+use crate::archives::package_entry_meta::PackageEntryMeta;
+use crate::archives::package_id::{PackageId, PackageType};
+use crate::archives::package_manifest::{file_digest, PackageManifest, PackageManifestEntry};
+use crate::archives::pruned_archives::PrunedArchives;
+use crate::archives::read_ahead_cache::ReadAheadConfig;
+use crate::archives::slice_rotator::{SliceRotator, target_package};
+use crate::archives::storage_pools::StoragePoolsConfig;
+use crate::archives::unapplied_gc::{parse_filename_short, UnappliedFileInfo, UnappliedFilter, UnappliedGcConfig};
+use crate::background_tasks::BackgroundTasks;
+use crate::cancellation::{CancellationToken, is_cancelled};
+use crate::clock::{Clock, system_clock};
+use crate::block_handle_db::seq_no_gaps;
+use crate::error::StorageError;
+use crate::events::{StorageEvent, StorageEventBus};
+use crate::key_block_db::KeyBlockDb;
+use crate::path_safety::{fan_out_path, SafeFileNames};
+use crate::pruning_coordinator::PruningCoordinator;
+use crate::retention_profile::RetentionConfig;
+use crate::slow_op_recorder::SlowOpRecorder;
+use crate::storage_config::ArchivesConfig;
+use crate::types::{BlockHandle, check_same_content, McSeqNo, WriteMode};
 
 
 pub const ARCHIVE_SIZE: usize = 20_000;
 pub const KEY_ARCHIVE_SIZE: usize = 200_000;
 pub const SLICE_SIZE: u32 = 100;
 
+/// Levels of the fan-out directories of the unapplied files
+const UNAPPLIED_FAN_OUT_DEPTH: usize = 2;
+
+/// Description of a package file stored in the archive
+#[derive(Debug, Clone)]
+pub struct ArchivePackageInfo {
+    pub archive_id: u32,
+    pub package_type: PackageType,
+    pub path: PathBuf,
+    pub size: u64,
+    pub finalized: bool,
+}
+
 pub struct ArchiveManager {
     db_root_path: Arc<PathBuf>,
     unapplied_dir: Arc<PathBuf>,
     file_maps: FileMaps,
+    file_budget: Arc<FileBudget>,
+    fs: Arc<dyn ArchiveFs>,
+    write_once: AtomicBool,
+    file_names: SafeFileNames,
+    pruned_archives: PrunedArchives,
+    file_hash_index: FileHashIndexDb,
+    slice_rotator: SliceRotator,
+    storage_pools: Arc<StoragePoolsConfig>,
+    unapplied_gc: UnappliedGcConfig,
+    event_bus: Arc<StorageEventBus>,
+    slow_op_recorder: Option<Arc<SlowOpRecorder>>,
+    clock: Arc<dyn Clock>,
 }
 
 impl ArchiveManager {
     pub async fn with_data(
         db_root_path: Arc<PathBuf>,
     ) -> Result<Self> {
-        let file_maps = FileMaps::new(&db_root_path).await?;
+        Self::with_read_ahead_config(db_root_path, ReadAheadConfig::default()).await
+    }
+
+    pub async fn with_read_ahead_config(
+        db_root_path: Arc<PathBuf>,
+        read_ahead_config: ReadAheadConfig,
+    ) -> Result<Self> {
+        Self::with_config(db_root_path, read_ahead_config, DEFAULT_PACKAGE_FILE_BUDGET).await
+    }
+
+    /// Constructs the manager configured by the archives section of the storage configuration
+    pub async fn with_archives_config(db_root_path: Arc<PathBuf>, config: &ArchivesConfig) -> Result<Self> {
+        let mut manager = Self::with_config(db_root_path, config.read_ahead.clone(), config.max_open_files).await?;
+        manager.set_write_once(config.write_once);
+        manager.set_storage_pools(config.storage_pools.clone());
+        manager.set_unapplied_gc_config(config.unapplied_gc.clone());
+
+        Ok(manager)
+    }
+
+    /// Constructs the manager keeping up to `max_open_files` package reader handles open
+    pub async fn with_config(
+        db_root_path: Arc<PathBuf>,
+        read_ahead_config: ReadAheadConfig,
+        max_open_files: usize,
+    ) -> Result<Self> {
+        Self::with_fs(db_root_path, read_ahead_config, max_open_files, Arc::new(LocalFs)).await
+    }
+
+    /// Constructs the manager keeping the package files in given file system
+    pub async fn with_fs(
+        db_root_path: Arc<PathBuf>,
+        read_ahead_config: ReadAheadConfig,
+        max_open_files: usize,
+        fs: Arc<dyn ArchiveFs>,
+    ) -> Result<Self> {
+        let file_budget = Arc::new(FileBudget::new(max_open_files));
+        let file_maps = FileMaps::new(&db_root_path, &read_ahead_config, &file_budget, &fs).await?;
         let unapplied_dir = Arc::new(db_root_path.join("archive").join("unapplied"));
+        let file_names = SafeFileNames::with_path(db_root_path.join("archive").join("file_names_db"));
+        let pruned_archives = PrunedArchives::with_path(db_root_path.join("archive").join("pruned_archives_db"))?;
+        let file_hash_index = FileHashIndexDb::with_path(db_root_path.join("archive").join("file_hash_index_db"));
+        let slice_rotator = SliceRotator::new(
+            Arc::clone(&db_root_path),
+            read_ahead_config,
+            Arc::clone(&file_budget),
+            Arc::clone(&fs),
+        );
         tokio::fs::create_dir_all(&*unapplied_dir).await?;
 
-        Ok(Self {
+        let manager = Self {
             db_root_path,
             unapplied_dir,
             file_maps,
-        })
+            file_budget,
+            fs,
+            write_once: AtomicBool::new(false),
+            file_names,
+            pruned_archives,
+            file_hash_index,
+            slice_rotator,
+            storage_pools: Arc::new(StoragePoolsConfig::default()),
+            unapplied_gc: UnappliedGcConfig::default(),
+            event_bus: Arc::new(StorageEventBus::new()),
+            slow_op_recorder: None,
+            clock: system_clock(),
+        };
+        manager.backfill_file_hash_index().await?;
+
+        Ok(manager)
+    }
+
+    /// Budget of the package file handles shared by all the archives
+    pub const fn file_budget(&self) -> &Arc<FileBudget> {
+        &self.file_budget
+    }
+
+    /// File system the package files are kept in
+    pub const fn fs(&self) -> &Arc<dyn ArchiveFs> {
+        &self.fs
+    }
+
+    /// Sets the bus BlockArchived and PackageFinalized events are emitted to
+    pub fn set_event_bus(&mut self, event_bus: Arc<StorageEventBus>) {
+        self.event_bus = event_bus;
+    }
+
+    /// Lets the manager prepare the next archive in the background before the writers reach it
+    pub fn set_background_tasks(&mut self, background_tasks: Arc<BackgroundTasks>) {
+        self.slice_rotator.set_background_tasks(background_tasks);
+    }
+
+    /// Sets the storage pools the package files are placed into. New archives are created in their
+    /// pools; existing archives are moved by `relocate_packages`.
+    pub fn set_storage_pools(&mut self, storage_pools: StoragePoolsConfig) {
+        self.storage_pools = Arc::new(storage_pools);
+        self.slice_rotator.set_storage_pools(Arc::clone(&self.storage_pools));
+    }
+
+    pub const fn storage_pools(&self) -> &Arc<StoragePoolsConfig> {
+        &self.storage_pools
+    }
+
+    /// Sets TTL and the dry-run mode of the unapplied files GC
+    pub fn set_unapplied_gc_config(&mut self, config: UnappliedGcConfig) {
+        self.unapplied_gc = config;
+    }
+
+    /// Sets the clock TTL of the unapplied files is checked against
+    pub fn set_clock(&mut self, clock: Arc<dyn Clock>) {
+        self.clock = clock;
+    }
+
+    /// Sets the recorder slow reading and archiving of the entries are reported to
+    pub fn set_slow_op_recorder(&mut self, slow_op_recorder: Arc<SlowOpRecorder>) {
+        self.slow_op_recorder = Some(slow_op_recorder);
     }
 
     pub const fn db_root_path(&self) -> &Arc<PathBuf> {
@@ -51,7 +209,58 @@ impl ArchiveManager {
         &self.unapplied_dir
     }
 
+    pub fn write_once(&self) -> bool {
+        self.write_once.load(Ordering::Relaxed)
+    }
+
+    /// Enables integrity mode: adding the file which already exists with different content fails
+    /// with StorageError::ContentMismatch instead of being silently overwritten (or ignored)
+    pub fn set_write_once(&self, write_once: bool) {
+        self.write_once.store(write_once, Ordering::Relaxed);
+    }
+
     pub async fn add_file<B, U256, PK>(&self, entry_id: &PackageEntryId<B, U256, PK>, data: Vec<u8>) -> Result<()>
+    where
+        B: Borrow<BlockIdExt> + Hash,
+        U256: Borrow<UInt256> + Hash,
+        PK: Borrow<PublicKey> + Hash
+    {
+        if self.write_once() {
+            if let Ok((_filename, existing)) = self.read_temp_file(entry_id).await {
+                return check_same_content(entry_id, &existing, &data);
+            }
+        }
+
+        self.write_temp_file(entry_id, data).await
+    }
+
+    /// Writes the file replacing existing content both in unapplied files and in the archive.
+    /// Intended for repair tools only.
+    pub async fn overwrite_file<B, U256, PK>(
+        &self,
+        handle: &BlockHandle,
+        entry_id: &PackageEntryId<B, U256, PK>,
+        data: Vec<u8>
+    ) -> Result<()>
+    where
+        B: Borrow<BlockIdExt> + Hash,
+        U256: Borrow<UInt256> + Hash,
+        PK: Borrow<PublicKey> + Hash
+    {
+        log::warn!(target: "storage", "Overwriting file: {}", entry_id);
+
+        if handle.moved_to_archive() {
+            let package_id = self.get_package_id(get_mc_seq_no(handle)).await?;
+            if let Some(ref fd) = self.get_file_desc(package_id).await? {
+                return fd.archive_slice()
+                    .add_file(Some(handle), entry_id, data, WriteMode::Overwrite, self.clock.now()).await;
+            }
+        }
+
+        self.write_temp_file(entry_id, data).await
+    }
+
+    async fn write_temp_file<B, U256, PK>(&self, entry_id: &PackageEntryId<B, U256, PK>, data: Vec<u8>) -> Result<()>
     where
         B: Borrow<BlockIdExt> + Hash,
         U256: Borrow<UInt256> + Hash,
@@ -59,7 +268,10 @@ impl ArchiveManager {
     {
         log::debug!(target: "storage", "Saving unapplied file: {}", entry_id);
 
-        let filename = self.unapplied_dir.join(entry_id.filename_short());
+        let filename = self.temp_file_path(entry_id).await?;
+        if let Some(dir) = filename.parent() {
+            tokio::fs::create_dir_all(dir).await?;
+        }
         let mut file = OpenOptions::new()
             .write(true)
             .create(true)
@@ -83,19 +295,153 @@ impl ArchiveManager {
     {
         handle.temp_lock().read().await;
 
+        let started = Instant::now();
         if handle.moved_to_archive() {
-            let package_id = self.get_package_id(get_mc_seq_no(handle)).await?;
-            if let Some(ref fd) = self.get_file_desc(package_id, false).await? {
-                return Ok(fd.archive_slice()
+            let mc_seq_no = get_mc_seq_no(handle);
+            // Key blocks are kept in the pruned archives
+            if let Some(range) = self.pruned_archives.find(mc_seq_no).filter(|_| !is_kept_key_block(handle)) {
+                Err(StorageError::Pruned(format!("block {} (archive of mc blocks {:?})", handle.id(), range)))?
+            }
+            let package_id = self.get_package_id(mc_seq_no).await?;
+            if let Some(ref fd) = self.get_file_desc(package_id).await? {
+                let data = fd.archive_slice()
                     .get_file(Some(handle), entry_id).await?
-                    .take_data());
+                    .take_data();
+                self.check_slow_op("archive_get_file", entry_id, started);
+                return Ok(data);
             }
         }
 
-        self.read_temp_file(entry_id).await
-            .map(|(_filename, data)| data)
+        match self.read_temp_file(entry_id).await {
+            Ok((_filename, data)) => Ok(data),
+            Err(_) if handle.pruned() => Err(StorageError::Pruned(format!("block {}", handle.id())))?,
+            Err(err) => Err(err),
+        }
     }
 
+    /// Gets the block data by the file hash of the block (e.g. for the peers requesting blocks
+    /// without the full id). Ok(None) is returned for unknown hashes and for the blocks of deleted
+    /// archives.
+    pub async fn get_file_by_hash(&self, file_hash: &UInt256) -> Result<Option<Vec<u8>>> {
+        let (fd, entry) = match self.locate_by_hash(file_hash).await? {
+            Some(location) => location,
+            None => return Ok(None),
+        };
+
+        Ok(fd.archive_slice()
+            .get_entry_by_name(entry.filename()).await?
+            .map(|entry| entry.take_data()))
+    }
+
+    /// Checks whether the block data with given file hash are stored in the archives (see
+    /// `get_file_by_hash`)
+    pub async fn has_file_with_hash(&self, file_hash: &UInt256) -> Result<bool> {
+        Ok(self.locate_by_hash(file_hash).await?.is_some())
+    }
+
+    async fn locate_by_hash(&self, file_hash: &UInt256) -> Result<Option<(Arc<FileDescription>, FileHashIndexEntry)>> {
+        let entry = match self.file_hash_index.try_get_value(file_hash)? {
+            Some(entry) => entry,
+            None => return Ok(None),
+        };
+        match self.file_maps.files().get(entry.archive_id()).await {
+            Some(fd) if !fd.deleted() => Ok(Some((fd, entry))),
+            _ => Ok(None),
+        }
+    }
+
+    /// Indexes by the file hash the block entries of the archives created before the index existed
+    /// (their packages are read, so the first run may take long). Archives are marked once indexed,
+    /// so the next runs skip them. Returns count of the entries indexed.
+    pub async fn backfill_file_hash_index(&self) -> Result<usize> {
+        let mut indexed = 0;
+        for fd in self.file_maps.files().entries().await {
+            let archive_slice = fd.archive_slice();
+            if fd.deleted() || archive_slice.file_hashes_indexed()? {
+                continue;
+            }
+            log::info!(target: "storage", "Indexing file hashes of archive {}", fd.id().id());
+            for filename in archive_slice.entry_filenames().await? {
+                if let Ok(entry_id @ PackageEntryId::Block(_)) = PackageEntryId::<BlockIdExt, UInt256, PublicKey>::from_filename(&filename) {
+                    self.index_file_hash(fd.id(), &entry_id)?;
+                    indexed += 1;
+                }
+            }
+            archive_slice.set_file_hashes_indexed()?;
+        }
+
+        Ok(indexed)
+    }
+
+    /// Removes the file hash index entries pointing to the archives which are no longer in the file
+    /// map (deleted ones, including the ones left by the runs interrupted before the removal).
+    /// The whole index is scanned. Returns count of the entries removed.
+    fn unindex_deleted_archives(&self) -> Result<usize> {
+        let files = self.file_maps.files();
+        let mut stale_keys = Vec::new();
+        self.file_hash_index.for_each(&mut |key, value| {
+            let entry: FileHashIndexEntry = serde_cbor::from_slice(value)?;
+            if !files.contains_key(entry.archive_id()) {
+                stale_keys.push(UInt256::from(key));
+            }
+            Ok(true)
+        })?;
+        for key in stale_keys.iter() {
+            self.file_hash_index.delete(key)?;
+        }
+
+        Ok(stale_keys.len())
+    }
+
+    /// Registry of the ranges of the archives deleted by pruning
+    pub const fn pruned_archives(&self) -> &PrunedArchives {
+        &self.pruned_archives
+    }
+
+    /// Looks for the entry in the archive (if the block is moved to archive) and in the unapplied
+    /// directory. Returns Ok(None) if the entry is found nowhere.
+    pub async fn locate_file<B, U256, PK>(
+        &self,
+        handle: &BlockHandle,
+        entry_id: &PackageEntryId<B, U256, PK>
+    ) -> Result<Option<LocatedData>>
+    where
+        B: Borrow<BlockIdExt> + Hash,
+        U256: Borrow<UInt256> + Hash,
+        PK: Borrow<PublicKey> + Hash
+    {
+        handle.temp_lock().read().await;
+
+        if handle.moved_to_archive() {
+            let package_id = self.get_package_id(get_mc_seq_no(handle)).await?;
+            if let Some(ref fd) = self.get_file_desc(package_id).await? {
+                if let Some((entry, package_info)) = fd.archive_slice()
+                    .get_file_with_package(Some(handle), entry_id).await?
+                {
+                    return Ok(Some(LocatedData {
+                        data: entry.take_data(),
+                        provenance: DataProvenance::Package {
+                            path: package_info.package().path().to_path_buf(),
+                            finalized: package_info.package().is_finalized(),
+                        },
+                    }));
+                }
+            }
+        }
+
+        let filename = self.temp_file_path(entry_id).await?;
+        match tokio::fs::read(&filename).await {
+            Ok(data) => Ok(Some(LocatedData { data, provenance: DataProvenance::Unapplied(filename) })),
+            Err(err) if err.kind() == ErrorKind::NotFound => Ok(None),
+            Err(err) => fail!("Error reading file: {:?}, {}", filename, err),
+        }
+    }
+
+    #[cfg_attr(feature = "tracing", tracing::instrument(
+        target = "storage",
+        skip(self, handle, on_success),
+        fields(block_id = %handle.id())
+    ))]
     pub async fn move_to_archive(
         &self,
         handle: &BlockHandle,
@@ -105,6 +451,7 @@ impl ArchiveManager {
             return Ok(());
         }
 
+        let started = Instant::now();
         let proof_inited = handle.proof_inited();
         let prooflink_inited = handle.proof_link_inited();
         let data_inited = handle.data_inited();
@@ -120,20 +467,36 @@ impl ArchiveManager {
             );
         }
 
-        let proof_filename = if proof_inited {
-            Some(self.move_file_to_archive(handle, &PackageEntryId::<&BlockIdExt, &UInt256, &PublicKey>::Proof(handle.id())).await?)
-        } else if prooflink_inited {
-            Some(self.move_file_to_archive(handle, &PackageEntryId::<&BlockIdExt, &UInt256, &PublicKey>::ProofLink(handle.id())).await?)
-        } else {
-            None
-        };
-        let block_filename = if data_inited {
-            Some(self.move_file_to_archive(handle, &PackageEntryId::<&BlockIdExt, &UInt256, &PublicKey>::Block(handle.id())).await?)
-        } else {
-            None
-        };
+        let archived: Result<_> = async {
+            // Proofs saved by `NodeStorage::save_block` are kept in BlockInfoDb, so only the proof
+            // files written into the unapplied directory are moved
+            let proof = PackageEntryId::<&BlockIdExt, &UInt256, &PublicKey>::Proof(handle.id());
+            let prooflink = PackageEntryId::<&BlockIdExt, &UInt256, &PublicKey>::ProofLink(handle.id());
+            let proof_filename = if proof_inited && self.has_temp_file(&proof).await? {
+                Some(self.move_file_to_archive(handle, &proof).await?)
+            } else if prooflink_inited && self.has_temp_file(&prooflink).await? {
+                Some(self.move_file_to_archive(handle, &prooflink).await?)
+            } else {
+                None
+            };
+            let block_filename = if data_inited {
+                Some(self.move_file_to_archive(handle, &PackageEntryId::<&BlockIdExt, &UInt256, &PublicKey>::Block(handle.id())).await?)
+            } else {
+                None
+            };
+
+            on_success()?;
 
-        on_success()?;
+            Ok((proof_filename, block_filename))
+        }.await;
+        // The failed move is retried from scratch (entries already added are overwritten)
+        let (proof_filename, block_filename) = match archived {
+            Ok(filenames) => filenames,
+            Err(err) => {
+                handle.reset_moving_to_archive();
+                return Err(err);
+            }
+        };
 
         {
             handle.temp_lock().write().await;
@@ -145,22 +508,655 @@ impl ArchiveManager {
             }
         }
 
+        self.event_bus.emit(StorageEvent::BlockArchived { block_id: handle.id().clone() });
+        self.check_slow_op("move_to_archive", handle.id(), started);
+
+        Ok(())
+    }
+
+    /// Moves masterchain block together with its shard blocks into the archive. Entries destined for
+    /// the same package are appended at once, and `on_success` is called once for the whole set.
+    /// Blocks which are already being moved are skipped.
+    #[cfg_attr(feature = "tracing", tracing::instrument(
+        target = "storage",
+        skip(self, mc_handle, shard_handles, on_success),
+        fields(block_id = %mc_handle.id(), shard_blocks = shard_handles.len())
+    ))]
+    pub async fn move_set_to_archive(
+        &self,
+        mc_handle: &BlockHandle,
+        shard_handles: &[&BlockHandle],
+        on_success: impl FnOnce() -> Result<()>,
+    ) -> Result<()> {
+        let mut handles = Vec::with_capacity(shard_handles.len() + 1);
+        for handle in std::iter::once(mc_handle).chain(shard_handles.iter().copied()) {
+            if !handle.start_moving_to_archive() {
+                handles.push(handle);
+            }
+        }
+
+        let archived: Result<_> = async {
+            // Entries are grouped by the masterchain seq_no (which selects the package and the slice)
+            let mut groups: Vec<((u32, bool), Vec<_>)> = Vec::new();
+            let mut temp_files = Vec::new();
+            for handle in handles.iter() {
+                let mut entry_ids = Vec::with_capacity(2);
+                if handle.proof_inited() {
+                    entry_ids.push(PackageEntryId::<&BlockIdExt, &UInt256, &PublicKey>::Proof(handle.id()));
+                } else if handle.proof_link_inited() {
+                    entry_ids.push(PackageEntryId::<&BlockIdExt, &UInt256, &PublicKey>::ProofLink(handle.id()));
+                }
+                if handle.data_inited() {
+                    entry_ids.push(PackageEntryId::<&BlockIdExt, &UInt256, &PublicKey>::Block(handle.id()));
+                }
+                if entry_ids.len() < 2 {
+                    log::error!(
+                        target: "storage",
+                        "Block {} is not moved to archive completely: data = {}, proof = {}, prooflink = {}",
+                        handle.id(),
+                        handle.data_inited(),
+                        handle.proof_inited(),
+                        handle.proof_link_inited()
+                    );
+                }
+
+                let group_key = (get_mc_seq_no(handle), handle.is_key_block()?);
+                let index = match groups.iter().position(|(key, _entries)| key == &group_key) {
+                    Some(index) => index,
+                    None => {
+                        groups.push((group_key, Vec::new()));
+                        groups.len() - 1
+                    }
+                };
+                for entry_id in entry_ids {
+                    log::debug!(target: "storage", "Moving entry to archive: {}", entry_id.filename_short());
+                    let (filename, data) = {
+                        handle.temp_lock().read().await;
+                        self.read_temp_file(&entry_id).await?
+                    };
+                    temp_files.push(filename);
+                    groups[index].1.push((entry_id, data));
+                }
+            }
+
+            for ((mc_seq_no, is_key), entries) in groups {
+                let (package_id, fd) = self.get_or_create_file_desc(mc_seq_no, is_key).await?;
+                let block_ids: Vec<&BlockIdExt> = entries.iter()
+                    .filter_map(|(entry_id, _data)| match entry_id {
+                        PackageEntryId::Block(block_id) => Some(*block_id),
+                        _ => None,
+                    })
+                    .collect();
+                fd.archive_slice().add_files_with_mc_seq_no(mc_seq_no, entries, self.archive_write_mode(), self.clock.now()).await?;
+                for block_id in block_ids {
+                    self.index_file_hash(&package_id, &PackageEntryId::<_, &UInt256, &PublicKey>::Block(block_id))?;
+                }
+            }
+
+            on_success()?;
+
+            Ok(temp_files)
+        }.await;
+        // The failed moves are retried from scratch (entries already added are overwritten)
+        let temp_files = match archived {
+            Ok(temp_files) => temp_files,
+            Err(err) => {
+                for handle in handles.iter() {
+                    handle.reset_moving_to_archive();
+                }
+                return Err(err);
+            }
+        };
+
+        for handle in handles.iter() {
+            handle.temp_lock().write().await;
+        }
+        for filename in temp_files {
+            tokio::fs::remove_file(filename).await?;
+        }
+        for handle in handles {
+            self.event_bus.emit(StorageEvent::BlockArchived { block_id: handle.id().clone() });
+        }
+
+        Ok(())
+    }
+
+    /// Writes trailers to all the packages of the archive containing given masterchain seq_no.
+    /// Must be called when no more entries are going to be added to the archive.
+    pub async fn finalize_archive(&self, mc_seq_no: u32) -> Result<()> {
+        let package_id = self.get_package_id(mc_seq_no).await?;
+        let fd = self.get_file_desc(package_id).await?
+            .ok_or_else(|| error!("Archive for mc_seq_no {} is not found", mc_seq_no))?;
+
+        for (path, trailer) in fd.archive_slice().finalize_packages().await? {
+            self.event_bus.emit(StorageEvent::PackageFinalized {
+                path: path.to_path_buf(),
+                entry_count: trailer.entry_count(),
+                payload_size: trailer.payload_size(),
+            });
+        }
+
         Ok(())
     }
 
-    pub async fn get_archive_id(&self, mc_seq_no: u32) -> Option<u64> {
-        if let Some(fd) = self.file_maps.files().get_closest(mc_seq_no).await {
-            fd.archive_slice().get_archive_id(mc_seq_no).await
+    /// Iterates over all packages of all (not deleted) archives, running predicate for each one.
+    /// Iteration stops when the predicate returns false.
+    pub async fn iterate_archives(
+        &self,
+        mut predicate: impl FnMut(&PackageId, &ArchivePackageInfo) -> Result<bool>
+    ) -> Result<bool> {
+        for fd in self.file_maps.files().entries().await {
+            if fd.deleted() {
+                continue;
+            }
+            let archive_slice = fd.archive_slice();
+            for (package_id, path, size) in archive_slice.packages().await {
+                let info = ArchivePackageInfo {
+                    archive_id: fd.id().id(),
+                    package_type: package_id.package_type(),
+                    path: path.as_ref().clone(),
+                    size,
+                    finalized: archive_slice.finalized(),
+                };
+                if !predicate(&package_id, &info)? {
+                    return Ok(false);
+                }
+            }
+        }
+
+        Ok(true)
+    }
+
+    /// Finds seq_no ranges of the shard's blocks within `range` which have no block data entry in
+    /// the archives (holes left by prunes or crashes), so the blocks can be downloaded again.
+    /// The entries are looked up in the file hash index (which lists the block data entries of all
+    /// the archives), the package files are not read.
+    pub async fn find_gaps(&self, shard: &ShardIdent, range: RangeInclusive<u32>) -> Result<Vec<RangeInclusive<u32>>> {
+        let files = self.file_maps.files();
+        let mut present = Vec::new();
+        self.file_hash_index.for_each(&mut |_key, value| {
+            let entry: FileHashIndexEntry = serde_cbor::from_slice(value)?;
+            if !files.contains_key(entry.archive_id()) {
+                return Ok(true);
+            }
+            if let Ok(PackageEntryId::Block(id)) = PackageEntryId::<BlockIdExt, UInt256, PublicKey>::from_filename(entry.filename()) {
+                if id.shard() == shard && range.contains(&id.seq_no()) {
+                    present.push(id.seq_no());
+                }
+            }
+            Ok(true)
+        })?;
+
+        Ok(seq_no_gaps(range, present))
+    }
+
+    /// Total payload size of all packages of all (not deleted) archives
+    pub async fn total_archive_bytes(&self) -> Result<u64> {
+        let mut total = 0;
+        self.iterate_archives(|_package_id, info| {
+            total += info.size;
+            Ok(true)
+        }).await?;
+
+        Ok(total)
+    }
+
+    /// Id of the package holding the blocks of given masterchain seq_no. The lookup is made in the
+    /// in-memory index of the packages (the persistent file maps are the source of truth it is built
+    /// from on startup and kept in sync with), so it takes O(log n) and never awaits.
+    pub fn package_for_seqno(&self, mc_seq_no: McSeqNo) -> Option<PackageId> {
+        self.file_maps.files().get_closest_key(mc_seq_no.value())
+            .map(PackageId::for_block)
+    }
+
+    pub async fn get_archive_id(&self, mc_seq_no: McSeqNo) -> Option<u64> {
+        if let Some(fd) = self.file_maps.files().get_closest(mc_seq_no.value()).await {
+            fd.archive_slice().get_archive_id(mc_seq_no.value()).await
         } else {
             None
         }
     }
 
+    #[cfg_attr(feature = "tracing", tracing::instrument(
+        target = "storage",
+        skip(self),
+        fields(bytes = tracing::field::Empty)
+    ))]
     pub async fn get_archive_slice(&self, archive_id: u64, offset: u64, limit: u32) -> Result<Vec<u8>> {
-        let fd = self.get_file_desc(PackageId::for_block(archive_id as u32), false).await?
+        let fd = self.get_file_desc(PackageId::for_block(archive_id as u32)).await?
+            .ok_or_else(|| error!("Archive not found"))?;
+
+        let slice = fd.archive_slice().get_slice(archive_id, offset, limit).await?;
+
+        #[cfg(feature = "tracing")]
+        tracing::Span::current().record("bytes", &slice.len());
+
+        Ok(slice)
+    }
+
+    /// Gets the entry of the archive (with id as returned by `get_archive_id`) by the entry filename,
+    /// without block handles. Returns Ok(None) if there is no such archive or entry.
+    pub async fn get_entry(&self, archive_id: u64, filename: &str) -> Result<Option<PackageEntry>> {
+        match self.get_file_desc(PackageId::for_block(archive_id as u32)).await? {
+            Some(fd) => fd.archive_slice().get_entry_by_name(filename).await,
+            None => Ok(None),
+        }
+    }
+
+    /// Lists the packages of the archive (with id as returned by `get_archive_id`) with their
+    /// metadata, without reading the package files. Returns Ok(None) if there is no such archive.
+    pub async fn get_packages_meta(&self, archive_id: u64) -> Result<Option<Vec<(PackageId, Option<PackageEntryMeta>)>>> {
+        match self.get_file_desc(PackageId::for_block(archive_id as u32)).await? {
+            Some(fd) => Ok(Some(fd.archive_slice().list_packages_meta().await?)),
+            None => Ok(None),
+        }
+    }
+
+    /// Exports the package with given archive id (as returned by `get_archive_id`) into standalone
+    /// finalized package file at `dest_path`. If `manifest_path` is given, the manifest with the list
+    /// of entries and their checksums is written there as JSON. Returns the manifest. If the export
+    /// is cancelled, the partially written package file is removed.
+    pub async fn export_package(
+        &self,
+        archive_id: u64,
+        dest_path: impl AsRef<Path>,
+        manifest_path: Option<&Path>,
+        cancellation: &CancellationToken,
+    ) -> Result<PackageManifest> {
+        let fd = self.get_file_desc(PackageId::for_block(archive_id as u32)).await?
             .ok_or_else(|| error!("Archive not found"))?;
+        let package_info = fd.archive_slice().get_package(archive_id).await?;
+
+        let dest_path = dest_path.as_ref();
+        if tokio::fs::metadata(dest_path).await.is_ok() {
+            fail!("Export destination {:?} already exists", dest_path)
+        }
+        log::info!(target: "storage", "Exporting package {:?} to {:?}", package_info.package().path(), dest_path);
+
+        let mut entries = Vec::new();
+        let trailer = package_info.package().export_to(Arc::new(dest_path.to_path_buf()), |entry| {
+            cancellation.check()?;
+            entries.push(PackageManifestEntry::from_entry(entry));
+            Ok(())
+        }).await;
+        let trailer = match trailer {
+            Err(err) if is_cancelled(&err) => {
+                log::info!(target: "storage", "Package export to {:?} is cancelled", dest_path);
+                tokio::fs::remove_file(dest_path).await?;
+                return Err(err);
+            }
+            trailer => trailer?,
+        };
+        let (size, digest) = file_digest(dest_path).await?;
+
+        let manifest = PackageManifest {
+            archive_id,
+            package: dest_path.file_name()
+                .map(|filename| filename.to_string_lossy().to_string())
+                .unwrap_or_default(),
+            size,
+            sha256: hex::encode(digest),
+            entries,
+        };
+        if let Some(manifest_path) = manifest_path {
+            manifest.write_to_file(manifest_path).await?;
+        }
+        log::info!(
+            target: "storage",
+            "Package exported to {:?}: {} entries, {} bytes",
+            dest_path,
+            trailer.entry_count(),
+            size
+        );
+
+        Ok(manifest)
+    }
+
+    /// Lists the unapplied (not yet archived) files matching the filter, in no particular order.
+    /// Filenames keep only a hash of the block id, so the files are described by the parsed short
+    /// filename; use `get_unapplied` to fetch the file of the known entry.
+    pub async fn list_unapplied(&self, filter: &UnappliedFilter) -> Result<Vec<UnappliedFileInfo>> {
+        let mut result = Vec::new();
+        for (path, metadata) in self.list_temp_files().await? {
+            if let Some(info) = self.describe_temp_file(path, &metadata)? {
+                if filter.matches(&info) {
+                    result.push(info);
+                }
+            }
+        }
+
+        Ok(result)
+    }
+
+    /// Reads the unapplied file of the entry; Ok(None) if there is no such file (e.g. the entry is
+    /// already moved to the archive)
+    pub async fn get_unapplied<B, U256, PK>(&self, entry_id: &PackageEntryId<B, U256, PK>) -> Result<Option<Vec<u8>>>
+    where
+        B: Borrow<BlockIdExt> + Hash,
+        U256: Borrow<UInt256> + Hash,
+        PK: Borrow<PublicKey> + Hash
+    {
+        let path = self.temp_file_path(entry_id).await?;
+        match tokio::fs::read(&path).await {
+            Ok(data) => Ok(Some(data)),
+            Err(err) if err.kind() == ErrorKind::NotFound => Ok(None),
+            Err(err) => fail!("Error reading file: {:?}, {}", path, err),
+        }
+    }
+
+    /// Sweeps unapplied files older than the configured TTL whose blocks were superseded or already
+    /// archived. `applied_handle` must return handle of the applied block with given shard and
+    /// seq_no, if the one is known; files with no known applied block are retained.
+    /// Returns the list of deleted files (or files to be deleted in dry-run mode).
+    pub async fn gc_unapplied(
+        &self,
+        applied_handle: impl Fn(&ShardIdent, u32) -> Result<Option<Arc<BlockHandle>>>,
+    ) -> Result<Vec<UnappliedFileInfo>> {
+        let config = &self.unapplied_gc;
+        log::info!(target: "storage", "Unapplied files GC started (ttl = {}, dry run = {})", config.ttl, config.dry_run);
+
+        let now = UNIX_EPOCH + Duration::from_secs(self.clock.now() as u64);
+        let ttl = Duration::from_secs(config.ttl as u64);
+        let mut result = Vec::new();
+        for (path, metadata) in self.list_temp_files().await? {
+            let modified = metadata.modified()?;
+            if now.duration_since(modified).unwrap_or_default() < ttl {
+                continue;
+            }
+
+            let info = match self.describe_temp_file(path, &metadata)? {
+                Some(info) => info,
+                None => continue,
+            };
+
+            let superseded = match applied_handle(&info.shard, info.seq_no)? {
+                Some(handle) if handle.applied() =>
+                    block_id_short_hash(handle.id()) != info.block_id_hash || handle.moved_to_archive(),
+                _ => false,
+            };
+            if !superseded {
+                continue;
+            }
+
+            if config.dry_run {
+                log::info!(target: "storage", "Unapplied file to delete: {:?}", info.path);
+            } else {
+                log::debug!(target: "storage", "Deleting unapplied file: {:?}", info.path);
+                tokio::fs::remove_file(&info.path).await?;
+                if let Some(safe_filename) = info.path.file_name() {
+                    self.file_names.forget(&safe_filename.to_string_lossy())?;
+                }
+            }
+            result.push(info);
+        }
 
-        fd.archive_slice().get_slice(archive_id, offset, limit).await
+        log::info!(target: "storage", "Unapplied files GC finished, {} files swept", result.len());
+
+        Ok(result)
+    }
+
+    /// Prunes archives entirely lying below the horizon of the pruning coordinator, unless the
+    /// retention configuration keeps the archives. Archives without key blocks are deleted; in the
+    /// archives containing key blocks (they are stored in the common packages) only the entries of
+    /// the key blocks are kept, others are made unreachable and dropped from the package files by
+    /// the next `compact_packages`. Either way the archive's range is recorded as pruned. The last
+    /// archive is always retained, as are the archives being read at the moment (they are deleted
+    /// by one of the next runs). Returns ids of the pruned archives.
+    pub async fn gc_archives(
+        &self,
+        pruning_coordinator: &PruningCoordinator,
+        retention: &RetentionConfig,
+        key_block_db: &KeyBlockDb,
+    ) -> Result<Vec<u32>> {
+        if !retention.prunes_archives() {
+            log::debug!(target: "storage", "Archives GC is disabled by retention profile {:?}", retention.profile);
+            return Ok(Vec::new());
+        }
+        let horizon = pruning_coordinator.min_retained_mc_seqno();
+        log::info!(target: "storage", "Archives GC started (horizon = {})", horizon);
+
+        let file_map = self.file_maps.files();
+        let entries = file_map.entries().await;
+        let mut candidates = Vec::new();
+        for pair in entries.windows(2) {
+            // Archive covers masterchain blocks up to the start of the next one
+            let (archive_id, next_archive_id) = (pair[0].id().id(), pair[1].id().id());
+            if !pruning_coordinator.allows(McSeqNo::new(next_archive_id.saturating_sub(1))) {
+                break;
+            }
+            // Archive reduced to the key blocks by one of the previous runs
+            if self.pruned_archives.find(archive_id).is_some() {
+                continue;
+            }
+            candidates.push((archive_id, next_archive_id));
+        }
+        drop(entries);
+
+        let mut deleted = Vec::new();
+        let mut pruned = Vec::with_capacity(candidates.len());
+        for (archive_id, next_archive_id) in candidates {
+            let key_block_ids = key_block_db.key_block_ids_in_range(archive_id, next_archive_id)?;
+            if !key_block_ids.is_empty() {
+                self.prune_to_key_blocks(archive_id, next_archive_id, &key_block_ids).await?;
+                pruned.push(archive_id);
+                continue;
+            }
+            match file_map.remove(archive_id).await? {
+                Some(archive_slice) => {
+                    log::debug!(target: "storage", "Deleting archive {}", archive_id);
+                    // The range is recorded first, so reads of the archive being deleted fail as pruned
+                    self.pruned_archives.add(archive_id..=next_archive_id - 1)?;
+                    archive_slice.destroy().await?;
+                    deleted.push(archive_id);
+                    pruned.push(archive_id);
+                },
+                None => log::warn!(target: "storage", "Archive {} is in use, deletion postponed", archive_id),
+            }
+        }
+
+        if !deleted.is_empty() {
+            let unindexed = self.unindex_deleted_archives()?;
+            log::debug!(target: "storage", "{} file hash index entries of deleted archives removed", unindexed);
+        }
+
+        log::info!(
+            target: "storage",
+            "Archives GC finished, {} archives deleted, {} reduced to key blocks",
+            deleted.len(),
+            pruned.len() - deleted.len()
+        );
+
+        Ok(pruned)
+    }
+
+    /// Drops all the entries of the archive except the ones of the key blocks and records the
+    /// archive's range as pruned. Reads of the key blocks bypass the pruned ranges.
+    async fn prune_to_key_blocks(&self, archive_id: u32, next_archive_id: u32, key_block_ids: &[BlockIdExt]) -> Result<()> {
+        let fd = match self.file_maps.files().get(archive_id).await {
+            Some(fd) => fd,
+            None => return Ok(()),
+        };
+        log::debug!(target: "storage", "Reducing archive {} to {} key blocks", archive_id, key_block_ids.len());
+
+        let dropped = fd.archive_slice().drop_entries(|filename| {
+            let block_id = match PackageEntryId::<BlockIdExt, UInt256, PublicKey>::from_filename(filename) {
+                Ok(PackageEntryId::Block(block_id))
+                | Ok(PackageEntryId::Proof(block_id))
+                | Ok(PackageEntryId::ProofLink(block_id))
+                | Ok(PackageEntryId::Signatures(block_id))
+                | Ok(PackageEntryId::BlockInfo(block_id)) => block_id,
+                // States and unknown entries are left as is
+                _ => return true,
+            };
+            key_block_ids.contains(&block_id)
+        }).await?;
+        for filename in dropped.iter() {
+            if let Ok(PackageEntryId::Block(block_id)) = PackageEntryId::<BlockIdExt, UInt256, PublicKey>::from_filename(filename) {
+                self.file_hash_index.delete(&block_id.file_hash)?;
+            }
+        }
+        // The range is recorded last: the run interrupted before is repeated by the next GC
+        self.pruned_archives.add(archive_id..=next_archive_id - 1)?;
+        log::debug!(target: "storage", "{} entries of archive {} dropped", dropped.len(), archive_id);
+
+        Ok(())
+    }
+
+    /// Rewrites the packages of all (not deleted) archives dropping the entries which are no longer
+    /// referenced. Intended for offline maintenance: must not run concurrently with reading or
+    /// writing the archives. Returns total sizes of the packages before and after the compaction.
+    pub async fn compact_packages(&self) -> Result<(u64, u64)> {
+        log::info!(target: "storage", "Packages compaction started");
+
+        let mut size_before = 0;
+        let mut size_after = 0;
+        for fd in self.file_maps.files().entries().await {
+            if fd.deleted() {
+                continue;
+            }
+            let (before, after) = fd.archive_slice().compact_packages().await?;
+            size_before += before;
+            size_after += after;
+        }
+
+        log::info!(target: "storage", "Packages compaction finished: {} -> {} bytes", size_before, size_after);
+
+        Ok((size_before, size_after))
+    }
+
+    /// Moves the package files of the archives kept outside of their storage pools into the pools
+    /// (or back under the database root). Only archives with all the packages finalized are moved.
+    /// The files are copied, the new location is persisted, and then the former files are removed,
+    /// so an interrupted relocation leaves the archive at one of the locations. Returns count of
+    /// the archives relocated.
+    pub async fn relocate_packages(&self, cancellation: &CancellationToken) -> Result<usize> {
+        let file_map = self.file_maps.files();
+        let entries = file_map.entries().await;
+        let newest_archive_id = match entries.last() {
+            Some(fd) => fd.id().id(),
+            None => return Ok(0),
+        };
+
+        let mut relocated = 0;
+        for fd in entries {
+            if fd.deleted() {
+                continue;
+            }
+            cancellation.check()?;
+            let archive_slice = fd.archive_slice();
+            let target = self.storage_pools.root_for(fd.id().id(), newest_archive_id, &self.db_root_path);
+            if *archive_slice.packages_root() == target {
+                continue;
+            }
+
+            let former_paths = match archive_slice.relocate(Arc::new(target.clone())).await? {
+                Some(former_paths) => former_paths,
+                None => continue,
+            };
+            file_map.update_location(fd.id().id()).await?;
+            for path in former_paths {
+                self.fs.remove(&path).await?;
+            }
+            log::info!(target: "storage", "Packages of archive {} are relocated to {:?}", fd.id().id(), target);
+            relocated += 1;
+        }
+
+        Ok(relocated)
+    }
+
+    /// Removes empty directories left in the packages directory by deleted archives. Returns the
+    /// paths of the removed directories.
+    pub async fn remove_empty_package_dirs(&self) -> Result<Vec<PathBuf>> {
+        let root = self.db_root_path.join("archive").join("packages");
+        if tokio::fs::metadata(&root).await.is_err() {
+            return Ok(Vec::new());
+        }
+
+        // Directories are collected top-down and checked bottom-up, so nested empty ones go first
+        let mut dirs = Vec::new();
+        let mut pending = vec![root.clone()];
+        while let Some(dir) = pending.pop() {
+            let mut read_dir = tokio::fs::read_dir(&dir).await?;
+            while let Some(dir_entry) = read_dir.next_entry().await? {
+                if dir_entry.file_type().await?.is_dir() {
+                    pending.push(dir_entry.path());
+                }
+            }
+            dirs.push(dir);
+        }
+
+        let mut removed = Vec::new();
+        for dir in dirs.into_iter().rev() {
+            if dir == root {
+                continue;
+            }
+            if tokio::fs::read_dir(&dir).await?.next_entry().await?.is_none() {
+                log::debug!(target: "storage", "Removing empty directory {:?}", dir);
+                tokio::fs::remove_dir(&dir).await?;
+                removed.push(dir);
+            }
+        }
+
+        Ok(removed)
+    }
+
+    /// Adds the file of the block: into the archive if the block is already moved there, otherwise
+    /// into the unapplied files
+    pub(crate) async fn add_block_file<B, U256, PK>(
+        &self,
+        handle: &BlockHandle,
+        entry_id: &PackageEntryId<B, U256, PK>,
+        data: Vec<u8>,
+    ) -> Result<()>
+    where
+        B: Borrow<BlockIdExt> + Hash,
+        U256: Borrow<UInt256> + Hash,
+        PK: Borrow<PublicKey> + Hash
+    {
+        if handle.moved_to_archive() {
+            self.add_file_to_archive(get_mc_seq_no(handle), handle.is_key_block()?, entry_id, data).await?;
+            return Ok(());
+        }
+
+        self.add_file(entry_id, data).await
+    }
+
+    /// Adds entry into the archive package corresponding to given masterchain seq_no. Entries of
+    /// key blocks open the archive of their own, so they may be added without the preceding blocks.
+    /// Returns false if the existing entry was kept.
+    pub(crate) async fn add_file_to_archive<B, U256, PK>(
+        &self,
+        mc_seq_no: u32,
+        is_key: bool,
+        entry_id: &PackageEntryId<B, U256, PK>,
+        data: Vec<u8>,
+    ) -> Result<bool>
+    where
+        B: Borrow<BlockIdExt> + Hash,
+        U256: Borrow<UInt256> + Hash,
+        PK: Borrow<PublicKey> + Hash
+    {
+        let (package_id, fd) = self.get_or_create_file_desc(mc_seq_no, is_key).await?;
+        let written = fd.archive_slice()
+            .add_file_with_mc_seq_no(mc_seq_no, entry_id, data, self.archive_write_mode(), self.clock.now()).await?;
+        self.index_file_hash(&package_id, entry_id)?;
+
+        Ok(written)
+    }
+
+    /// Indexes the block data entry added to the package by the file hash of the block
+    fn index_file_hash<B, U256, PK>(&self, package_id: &PackageId, entry_id: &PackageEntryId<B, U256, PK>) -> Result<()>
+    where
+        B: Borrow<BlockIdExt> + Hash,
+        U256: Borrow<UInt256> + Hash,
+        PK: Borrow<PublicKey> + Hash
+    {
+        if let PackageEntryId::Block(block_id) = entry_id {
+            let entry = FileHashIndexEntry::with_values(package_id.id(), entry_id.filename());
+            self.file_hash_index.put_value(&block_id.borrow().file_hash, entry)?;
+        }
+
+        Ok(())
     }
 
     async fn move_file_to_archive<B, U256, PK>(&self, handle: &BlockHandle, entry_id: &PackageEntryId<B, U256, PK>) -> Result<PathBuf>
@@ -180,7 +1176,7 @@ impl ArchiveManager {
         let mc_seq_no = get_mc_seq_no(handle);
 
         let is_key = handle.is_key_block()?;
-        let package_id = self.get_package_id_force(mc_seq_no, is_key).await;
+        let (package_id, fd) = self.get_or_create_file_desc(mc_seq_no, is_key).await?;
         log::debug!(target: "storage", "PackageId for ({},{},{}) (mc_seq_no = {}, key block = {:?}) is {:?}, path: {:?}",
             handle.id().shard().workchain_id(),
             handle.id().shard().shard_prefix_as_str_with_tag(),
@@ -188,24 +1184,130 @@ impl ArchiveManager {
             mc_seq_no,
             is_key,
             package_id,
-            package_id.full_path(self.db_root_path.as_ref(), "pack"),
+            package_id.full_path(fd.archive_slice().packages_root().as_ref(), "pack"),
         );
 
-        let fd = self.get_file_desc(package_id,true).await?
-            .ok_or_else(|| error!("Expected some value"))?;
-
-        fd.archive_slice().add_file(Some(handle), entry_id, data).await?;
+        fd.archive_slice().add_file(Some(handle), entry_id, data, self.archive_write_mode(), self.clock.now()).await?;
+        self.index_file_hash(&package_id, entry_id)?;
 
         Ok(filename)
     }
 
+    fn archive_write_mode(&self) -> WriteMode {
+        if self.write_once() {
+            WriteMode::WriteOnce
+        } else {
+            WriteMode::KeepExisting
+        }
+    }
+
+    /// Returns path of the unapplied file. Files are spread over the two-level fan-out directories;
+    /// the file found in the legacy flat layout is moved into its fan-out directory on access.
+    async fn temp_file_path<B, U256, PK>(&self, entry_id: &PackageEntryId<B, U256, PK>) -> Result<PathBuf>
+    where
+        B: Borrow<BlockIdExt> + Hash,
+        U256: Borrow<UInt256> + Hash,
+        PK: Borrow<PublicKey> + Hash
+    {
+        let safe_filename = self.file_names.safe_filename(&entry_id.filename_short())?;
+        let path = self.unapplied_dir.join(fan_out_path(&safe_filename));
+        let legacy_path = self.unapplied_dir.join(&safe_filename);
+        match tokio::fs::metadata(&legacy_path).await {
+            Ok(metadata) if metadata.is_file() => {
+                if tokio::fs::metadata(&path).await.is_ok() {
+                    // The file was rewritten in the fan-out layout, so the legacy one is stale
+                    match tokio::fs::remove_file(&legacy_path).await {
+                        Err(err) if err.kind() != ErrorKind::NotFound => Err(err)?,
+                        _ => (),
+                    }
+                } else {
+                    if let Some(dir) = path.parent() {
+                        tokio::fs::create_dir_all(dir).await?;
+                    }
+                    match tokio::fs::rename(&legacy_path, &path).await {
+                        // Concurrent access might have migrated the file already
+                        Err(err) if err.kind() != ErrorKind::NotFound => fail!(
+                            "Can't migrate unapplied file {:?} into {:?}: {}", legacy_path, path, err
+                        ),
+                        _ => log::debug!(target: "storage", "Unapplied file {} migrated into fan-out layout", safe_filename),
+                    }
+                }
+            }
+            _ => (),
+        }
+
+        Ok(path)
+    }
+
+    /// Lists the unapplied files of both fan-out and legacy flat layouts
+    async fn list_temp_files(&self) -> Result<Vec<(PathBuf, std::fs::Metadata)>> {
+        let mut result = Vec::new();
+        let mut dirs = vec![(self.unapplied_dir.to_path_buf(), 0)];
+        while let Some((dir, depth)) = dirs.pop() {
+            let mut read_dir = tokio::fs::read_dir(&dir).await?;
+            while let Some(dir_entry) = read_dir.next_entry().await? {
+                let metadata = dir_entry.metadata().await?;
+                if metadata.is_file() {
+                    result.push((dir_entry.path(), metadata));
+                } else if metadata.is_dir() && depth < UNAPPLIED_FAN_OUT_DEPTH {
+                    dirs.push((dir_entry.path(), depth + 1));
+                }
+            }
+        }
+
+        Ok(result)
+    }
+
+    /// Describes the unapplied file parsing its original (short) filename. Returns None (with a
+    /// warning logged) for the files with unrecognized names.
+    fn describe_temp_file(&self, path: PathBuf, metadata: &std::fs::Metadata) -> Result<Option<UnappliedFileInfo>> {
+        let safe_filename = match path.file_name() {
+            Some(safe_filename) => safe_filename.to_string_lossy().to_string(),
+            None => return Ok(None),
+        };
+        let filename = match self.file_names.original_filename(&safe_filename) {
+            Ok(filename) => filename,
+            Err(err) => {
+                log::warn!(target: "storage", "Skipping unapplied file {}: {}", safe_filename, err);
+                return Ok(None);
+            }
+        };
+        let (entry_type, shard, seq_no, block_id_hash) = match parse_filename_short(&filename) {
+            Ok(parsed) => parsed,
+            Err(err) => {
+                log::warn!(target: "storage", "Skipping unapplied file {}: {}", filename, err);
+                return Ok(None);
+            }
+        };
+
+        Ok(Some(UnappliedFileInfo {
+            path,
+            size: metadata.len(),
+            modified: metadata.modified()?,
+            entry_type,
+            shard,
+            seq_no,
+            block_id_hash,
+        }))
+    }
+
+    /// Checks whether the file of the entry is in the unapplied directory
+    async fn has_temp_file<B, U256, PK>(&self, entry_id: &PackageEntryId<B, U256, PK>) -> Result<bool>
+    where
+        B: Borrow<BlockIdExt> + Hash,
+        U256: Borrow<UInt256> + Hash,
+        PK: Borrow<PublicKey> + Hash
+    {
+        Ok(tokio::fs::metadata(self.temp_file_path(entry_id).await?).await.is_ok())
+    }
+
     async fn read_temp_file<B, U256, PK>(&self, entry_id: &PackageEntryId<B, U256, PK>) -> Result<(PathBuf, Vec<u8>)>
     where
         B: Borrow<BlockIdExt> + Hash,
         U256: Borrow<UInt256> + Hash,
         PK: Borrow<PublicKey> + Hash
     {
-        let temp_filename = self.unapplied_dir.join(entry_id.filename_short());
+        let temp_filename = self.temp_file_path(entry_id).await?;
         let data = tokio::fs::read(&temp_filename).await
             .map_err(|error| {
                 if error.kind() == ErrorKind::NotFound {
@@ -218,8 +1320,13 @@ impl ArchiveManager {
         Ok((temp_filename, data))
     }
 
-    async fn get_file_desc(&self, id: PackageId, force: bool) -> Result<Option<Arc<FileDescription>>> {
-        // TODO: Rewrite logics in order to handle multithreaded adding of packages
+    fn check_slow_op(&self, op: &str, key: impl std::fmt::Display, started: Instant) {
+        if let Some(ref slow_op_recorder) = self.slow_op_recorder {
+            slow_op_recorder.check(op, key, started);
+        }
+    }
+
+    async fn get_file_desc(&self, id: PackageId) -> Result<Option<Arc<FileDescription>>> {
         if let Some(fd) = self.file_maps.get(id.package_type())
             .get(id.id()).await
         {
@@ -230,63 +1337,58 @@ impl ArchiveManager {
             return Ok(Some(fd));
         }
 
-        if force {
-            Ok(Some(self.add_file_desc(id).await?))
-        } else {
-            Ok(None)
-        }
+        Ok(None)
     }
 
-    async fn add_file_desc(&self, id: PackageId) -> Result<Arc<FileDescription>> {
-        // TODO: Rewrite logics in order to handle multithreaded adding of packages
-        let file_map = self.file_maps.get(id.package_type());
-        assert!(file_map.get(id.id()).await.is_none());
-
-        let dir = self.db_root_path.join(id.path());
-        tokio::fs::create_dir_all(&dir).await?;
-
-        let archive_slice = Arc::new(
-            ArchiveSlice::with_data(
-                Arc::clone(&self.db_root_path),
-                id.id(),
-                id.package_type(),
-                false,
-            ).await?
-        );
-
-        let fd = Arc::new(FileDescription::with_data(
-            id.clone(),
-            archive_slice,
-            false
-        ));
+    /// Chooses the package for the entries of the masterchain block and returns it, creating the
+    /// archive if needed. The rotation lock is taken only if the archive is missing: the choice and
+    /// the creation are made under it, so writers racing near the archive boundary (or with the key
+    /// block opening its archive) agree on the package, and the archive is created once.
+    async fn get_or_create_file_desc(&self, mc_seq_no: u32, is_key: bool) -> Result<(PackageId, Arc<FileDescription>)> {
+        let package_id = target_package(mc_seq_no, is_key, self.package_for_seqno(McSeqNo::new(mc_seq_no)));
+        if let Some(fd) = self.get_file_desc(package_id.clone()).await? {
+            // Preparing of the next archive is only an optimization, so it isn't waited for
+            if let Some(mut state) = self.slice_rotator.try_lock() {
+                let files = self.file_maps.files();
+                self.slice_rotator.prepare_next(&mut state, mc_seq_no, |archive_id| files.contains_key(archive_id));
+            }
+            return Ok((package_id, fd));
+        }
 
-        file_map.put(id.id(), Arc::clone(&fd)).await?;
+        let mut state = self.slice_rotator.lock().await;
+        let package_id = target_package(mc_seq_no, is_key, self.package_for_seqno(McSeqNo::new(mc_seq_no)));
+        let fd = match self.get_file_desc(package_id.clone()).await? {
+            Some(fd) => fd,
+            None => {
+                let file_map = self.file_maps.get(package_id.package_type());
+                if file_map.get(package_id.id()).await.is_some() {
+                    fail!("Archive {} is being deleted", package_id.id())
+                }
+                let archive_slice = self.slice_rotator.create_slice(&mut state, &package_id).await?;
+                let fd = Arc::new(FileDescription::with_data(package_id.clone(), archive_slice, false));
+                file_map.put(package_id.id(), Arc::clone(&fd)).await?;
+                // The archive may be re-created in place of the pruned one (e.g. with re-downloaded blocks)
+                self.pruned_archives.remove(package_id.id())?;
+                fd
+            }
+        };
+        let files = self.file_maps.files();
+        self.slice_rotator.prepare_next(&mut state, mc_seq_no, |archive_id| files.contains_key(archive_id));
 
-        Ok(fd)
+        Ok((package_id, fd))
     }
 
     async fn get_package_id(&self, seq_no: u32) -> Result<PackageId> {
-        Ok(self.file_maps.files().get_closest(seq_no).await
+        self.package_for_seqno(McSeqNo::new(seq_no))
             .ok_or_else(|| {
                 log::error!(target: "storage", "Package not found for seq_no: {}", seq_no);
                 error!("Package not found for seq_no: {}", seq_no)
-            })?
-            .id()
-            .clone())
+            })
     }
 
-    async fn get_package_id_force(&self, mc_seq_no: u32, is_key: bool) -> PackageId {
-        if is_key {
-            PackageId::for_block(mc_seq_no)
-        } else {
-            let mut package_id = PackageId::for_block(mc_seq_no - (mc_seq_no % ARCHIVE_SIZE as u32));
-            if let Some(fd) = self.file_maps.files().get_closest(mc_seq_no).await {
-                let found_package_id = fd.id();
-                if package_id < *found_package_id {
-                    package_id = found_package_id.clone();
-                }
-            }
-            package_id
-        }
-    }
+}
+
+/// Checks whether the block is a masterchain key block, whose entries are kept by archives GC
+fn is_kept_key_block(handle: &BlockHandle) -> bool {
+    handle.id().shard().is_masterchain() && handle.is_key_block().unwrap_or(false)
 }
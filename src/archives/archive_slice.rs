@@ -1,30 +1,32 @@
 use std::borrow::Borrow;
+use std::collections::{HashMap, HashSet};
 use std::hash::Hash;
-use std::io::SeekFrom;
 use std::path::PathBuf;
-use std::sync::Arc;
+use std::sync::{Arc, RwLock as SyncRwLock};
 
-use tokio::fs::File;
-use tokio::io::AsyncReadExt;
 use tokio::sync::RwLock;
 use ton_api::ton::PublicKey;
 use ton_block::BlockIdExt;
 use ton_types::{error, fail, Result, UInt256};
 
 use crate::archives::archive_manager::SLICE_SIZE;
+use crate::archives::archive_fs::ArchiveFs;
 use crate::archives::get_mc_seq_no_opt;
-use crate::archives::package::Package;
-use crate::archives::package_entry::PackageEntry;
+use crate::archives::package::{FileBudget, Package};
+use crate::archives::package_entry::{PackageEntry, PKG_ENTRY_HEADER_SIZE};
 use crate::archives::package_entry_id::{GetFileName, PackageEntryId};
 use crate::archives::package_entry_meta::PackageEntryMeta;
 use crate::archives::package_entry_meta_db::PackageEntryMetaDb;
 use crate::archives::package_id::{PackageId, PackageType};
 use crate::archives::package_info::PackageInfo;
-use crate::archives::package_offsets_db::PackageOffsetsDb;
+use crate::archives::package_offsets_db::{PackageOffsetKey, PackageOffsetsDb, PACKAGE_OFFSETS_VERSION};
 use crate::archives::package_status_db::PackageStatusDb;
 use crate::archives::package_status_key::PackageStatusKey;
+use crate::archives::package_trailer::PackageTrailer;
+use crate::archives::read_ahead_cache::{ReadAheadCache, ReadAheadConfig};
+use crate::archives::storage_pools::copy_file;
 use crate::traits::Serializable;
-use crate::types::BlockHandle;
+use crate::types::{BlockHandle, check_same_content, WriteMode};
 
 
 const DEFAULT_PKG_VERSION: u32 = 1;
@@ -33,7 +35,8 @@ const DEFAULT_PKG_VERSION: u32 = 1;
 pub struct ArchiveSlice {
     archive_id: u32,
     packages: RwLock<Vec<Arc<PackageInfo>>>,
-    db_root_path: Arc<PathBuf>,
+    /// Root directory of the package files; the indexes are kept under the database root
+    packages_root: SyncRwLock<Arc<PathBuf>>,
     index_path: PathBuf,
     sliced_mode: bool,
     slice_size: u32,
@@ -42,14 +45,21 @@ pub struct ArchiveSlice {
     index_db: Arc<PackageEntryMetaDb>,
     offsets_db: Arc<PackageOffsetsDb>,
     package_status_db: Arc<PackageStatusDb>,
+    read_ahead_cache: ReadAheadCache,
+    file_budget: Arc<FileBudget>,
+    fs: Arc<dyn ArchiveFs>,
 }
 
 impl ArchiveSlice {
     pub async fn with_data(
         db_root_path: Arc<PathBuf>,
+        packages_root: Arc<PathBuf>,
         archive_id: u32,
         package_type: PackageType,
         finalized: bool,
+        read_ahead_config: ReadAheadConfig,
+        file_budget: Arc<FileBudget>,
+        fs: Arc<dyn ArchiveFs>,
     ) -> Result<Self> {
         let package_id = PackageId::with_values(archive_id, package_type);
         let index_path = package_id.full_path(db_root_path.as_ref(), "index");
@@ -61,7 +71,7 @@ impl ArchiveSlice {
         let mut archive_slice = Self {
             archive_id,
             packages: RwLock::new(Vec::new()),
-            db_root_path,
+            packages_root: SyncRwLock::new(packages_root),
             index_path,
             sliced_mode: false,
             slice_size: SLICE_SIZE,
@@ -70,6 +80,9 @@ impl ArchiveSlice {
             index_db: Arc::clone(&index_db),
             offsets_db,
             package_status_db: Arc::clone(&package_status_db),
+            read_ahead_cache: ReadAheadCache::new(read_ahead_config, Arc::clone(&fs)),
+            file_budget,
+            fs,
         };
 
         if let Some(sliced_mode) = package_status_db.try_get_value::<bool>(&PackageStatusKey::SlicedMode)? {
@@ -93,6 +106,11 @@ impl ArchiveSlice {
                 archive_slice.packages.write().await
                     .push(archive_slice.new_package(0, archive_id, size, 0).await?);
             }
+
+            let offsets_version = package_status_db.try_get_value::<u32>(&PackageStatusKey::OffsetsVersion)?;
+            if offsets_version.unwrap_or_default() < PACKAGE_OFFSETS_VERSION {
+                archive_slice.migrate_offsets().await?;
+            }
         } else {
             if package_type == PackageType::Blocks {
                 archive_slice.sliced_mode = true;
@@ -103,8 +121,10 @@ impl ArchiveSlice {
                     transaction.put(&PackageStatusKey::SlicedMode, true.to_vec()?.as_slice());
                     transaction.put(&PackageStatusKey::TotalSlices, 1u32.to_vec()?.as_slice());
                     transaction.put(&PackageStatusKey::SliceSize, archive_slice.slice_size.to_vec()?.as_slice());
+                    transaction.put(&PackageStatusKey::OffsetsVersion, PACKAGE_OFFSETS_VERSION.to_vec()?.as_slice());
+                    transaction.put(&PackageStatusKey::FileHashesIndexed, true.to_vec()?.as_slice());
 
-                    let meta = PackageEntryMeta::with_data(0, DEFAULT_PKG_VERSION);
+                    let meta = PackageEntryMeta::with_type(0, DEFAULT_PKG_VERSION, package_type);
                     index_db.put_value(&0.into(), &meta)?;
                     transaction.commit()?;
                 }
@@ -117,6 +137,8 @@ impl ArchiveSlice {
 
                     transaction.put(&PackageStatusKey::SlicedMode, false.to_vec()?.as_slice());
                     transaction.put(&PackageStatusKey::NonSlicedSize, 0u64.to_vec()?.as_slice());
+                    transaction.put(&PackageStatusKey::OffsetsVersion, PACKAGE_OFFSETS_VERSION.to_vec()?.as_slice());
+                    transaction.put(&PackageStatusKey::FileHashesIndexed, true.to_vec()?.as_slice());
 
                     transaction.commit()?;
                 }
@@ -129,12 +151,203 @@ impl ArchiveSlice {
         Ok(archive_slice)
     }
 
-    #[allow(dead_code)]
+    /// Rebuilds the offsets database of the slice from its packages, replacing the keys of the
+    /// previous version. Entries whose previous keys collided (so one of them was read instead of
+    /// another) are reported; collision of the new keys fails the migration.
+    async fn migrate_offsets(&self) -> Result<()> {
+        log::info!(target: "storage", "Migrating offsets of archive slice {}", self.archive_id);
+
+        let mut offsets = HashMap::new();
+        let mut legacy_keys = HashMap::new();
+        let mut legacy_collisions = 0;
+        let packages = self.packages.read().await.clone();
+        for package_info in packages.iter() {
+            let package = package_info.package();
+            let mut offset = 0;
+            while offset + (PKG_ENTRY_HEADER_SIZE as u64) < package.size() {
+                let entry = package.read_entry(offset).await?;
+                if PackageTrailer::is_trailer_entry(&entry) {
+                    break;
+                }
+                let entry_offset = offset;
+                offset += (PKG_ENTRY_HEADER_SIZE + entry.filename().len() + entry.data().len()) as u64;
+
+                let key = PackageOffsetKey::from_filename(entry.filename());
+                match offsets.get(&key) {
+                    Some((filename, _)) if filename != entry.filename() => fail!(
+                        "Offset key collision in archive slice {}: {} and {}",
+                        self.archive_id,
+                        filename,
+                        entry.filename()
+                    ),
+                    // Overwritten entry: the offset points to the last copy, as add_file does
+                    Some(_) | None => offsets.insert(key, (entry.filename().clone(), entry_offset)),
+                };
+
+                let entry_id = match PackageEntryId::from_filename(entry.filename()) {
+                    Ok(entry_id) => entry_id,
+                    Err(err) => {
+                        log::warn!(target: "storage", "Bad entry name {} in archive slice {}: {}", entry.filename(), self.archive_id, err);
+                        continue;
+                    }
+                };
+                let legacy_key = PackageOffsetKey::legacy_key(&entry_id);
+                match legacy_keys.get(&legacy_key) {
+                    Some(filename) if filename != entry.filename() => {
+                        legacy_collisions += 1;
+                        log::warn!(
+                            target: "storage",
+                            "Entries {} and {} of archive slice {} had colliding offset keys, one of them was unreachable",
+                            filename,
+                            entry.filename(),
+                            self.archive_id
+                        );
+                    }
+                    Some(_) => (),
+                    None => {
+                        legacy_keys.insert(legacy_key, entry.filename().clone());
+                    }
+                }
+            }
+        }
+
+        let mut old_keys = Vec::new();
+        self.offsets_db.for_each_key_only(&mut |key| {
+            old_keys.push(PackageOffsetKey::from_raw(key));
+            Ok(true)
+        })?;
+
+        let transaction = self.offsets_db.begin_transaction()?;
+        for key in old_keys.iter() {
+            transaction.delete(key);
+        }
+        for (key, (_filename, offset)) in offsets.iter() {
+            transaction.put(key, serde_cbor::to_vec(offset)?.as_slice());
+        }
+        transaction.commit()?;
+        self.package_status_db.put_value(&PackageStatusKey::OffsetsVersion, PACKAGE_OFFSETS_VERSION)?;
+
+        log::info!(
+            target: "storage",
+            "Offsets of archive slice {} migrated: {} entries, {} old keys replaced, {} collisions found",
+            self.archive_id,
+            offsets.len(),
+            old_keys.len(),
+            legacy_collisions
+        );
+
+        Ok(())
+    }
+
+    /// Whether the block entries of the slice are indexed by the file hash. The slices created
+    /// before the index existed are not, until they are backfilled.
+    pub fn file_hashes_indexed(&self) -> Result<bool> {
+        Ok(self.package_status_db.try_get_value::<bool>(&PackageStatusKey::FileHashesIndexed)?.unwrap_or(false))
+    }
+
+    pub fn set_file_hashes_indexed(&self) -> Result<()> {
+        self.package_status_db.put_value(&PackageStatusKey::FileHashesIndexed, true)
+    }
+
+    /// Reads the filenames of all the entries of the slice from its packages (an expensive
+    /// operation, intended for migrations)
+    pub async fn entry_filenames(&self) -> Result<Vec<String>> {
+        let mut filenames = Vec::new();
+        let packages = self.packages.read().await.clone();
+        for package_info in packages.iter() {
+            let package = package_info.package();
+            let mut offset = 0;
+            while offset + (PKG_ENTRY_HEADER_SIZE as u64) < package.size() {
+                let entry = package.read_entry(offset).await?;
+                if PackageTrailer::is_trailer_entry(&entry) {
+                    break;
+                }
+                offset += (PKG_ENTRY_HEADER_SIZE + entry.filename().len() + entry.data().len()) as u64;
+                filenames.push(entry.filename().clone());
+            }
+        }
+
+        Ok(filenames)
+    }
+
+    /// Makes the entries rejected by the predicate unreachable by deleting their offsets; their
+    /// data stay in the packages until `compact_packages` drops them. Returns the filenames of the
+    /// dropped entries.
+    pub async fn drop_entries(&self, mut keep: impl FnMut(&str) -> bool) -> Result<Vec<String>> {
+        let mut dropped = HashSet::new();
+        for filename in self.entry_filenames().await? {
+            if !keep(&filename) {
+                dropped.insert(filename);
+            }
+        }
+
+        let transaction = self.offsets_db.begin_transaction()?;
+        for filename in dropped.iter() {
+            transaction.delete(&PackageOffsetKey::from_filename(filename));
+        }
+        transaction.commit()?;
+        self.read_ahead_cache.clear().await;
+
+        Ok(dropped.into_iter().collect())
+    }
+
+    /// Rewrites the packages of the slice dropping the entries which are not referenced by the
+    /// offsets database (overwritten copies and the like), updates the offsets and compacts the
+    /// index databases of the slice. Must not run
+    /// concurrently with reading or writing the slice. Returns sizes of the packages before and
+    /// after the compaction.
+    pub async fn compact_packages(&self) -> Result<(u64, u64)> {
+        let mut size_before = 0;
+        let mut size_after = 0;
+        let packages = self.packages.read().await.clone();
+        for package_info in packages.iter() {
+            let package = package_info.package();
+            let compaction = package.compact(|offset, entry| {
+                let key = PackageOffsetKey::from_filename(entry.filename());
+                Ok(self.offsets_db.try_get_value(&key)? == Some(offset))
+            }).await?;
+            let compaction = match compaction {
+                Some(compaction) => compaction,
+                None => {
+                    size_before += package.size();
+                    size_after += package.size();
+                    continue;
+                }
+            };
+
+            let transaction = self.offsets_db.begin_transaction()?;
+            for (filename, offset) in compaction.entries.iter() {
+                transaction.put(&PackageOffsetKey::from_filename(filename), serde_cbor::to_vec(offset)?.as_slice());
+            }
+            transaction.commit()?;
+
+            let idx = self.meta_idx(package_info);
+            let headers_size = compaction.entries.iter()
+                .map(|(filename, _offset)| (PKG_ENTRY_HEADER_SIZE + filename.len()) as u64)
+                .sum::<u64>();
+            {
+                let mut meta = package_info.meta();
+                meta.on_compacted(compaction.size_after, compaction.entries.len(), compaction.payload_size - headers_size);
+                self.index_db.put_value(&idx.into(), &*meta)?;
+            }
+
+            size_before += compaction.size_before;
+            size_after += compaction.size_after;
+        }
+        self.read_ahead_cache.clear().await;
+
+        self.index_db.compact_range(None, None)?;
+        self.offsets_db.compact_range(None, None)?;
+        self.package_status_db.compact_range(None, None)?;
+
+        Ok((size_before, size_after))
+    }
+
     pub async fn destroy(mut self) -> Result<()> {
         for pi in self.packages.write().await.drain(..) {
             let path = Arc::clone(pi.package().path());
             drop(pi);
-            tokio::fs::remove_file(&*path).await?;
+            self.fs.remove(&*path).await?;
         }
 
         Arc::get_mut(&mut self.index_db)
@@ -152,6 +365,43 @@ impl ArchiveSlice {
         Ok(())
     }
 
+    pub const fn finalized(&self) -> bool {
+        self.finalized
+    }
+
+    /// Writes end-of-archive trailers to all the packages of the slice. Must be called when no more
+    /// entries are going to be added to the slice. Returns paths and trailers of the packages.
+    pub async fn finalize_packages(&self) -> Result<Vec<(Arc<PathBuf>, PackageTrailer)>> {
+        let mut result = Vec::new();
+        for pi in self.packages.read().await.iter() {
+            let trailer = pi.package().finalize().await?;
+            let idx = self.meta_idx(pi);
+            {
+                let mut meta = pi.meta();
+                meta.set_finalized(pi.package().size());
+                self.index_db.put_value(&idx.into(), &*meta)?;
+            }
+            log::info!(
+                target: "storage",
+                "Package {:?} finalized: {} entries, {} bytes",
+                pi.package_id().name(),
+                trailer.entry_count(),
+                trailer.payload_size()
+            );
+            result.push((Arc::clone(pi.package().path()), trailer));
+        }
+
+        Ok(result)
+    }
+
+    /// Returns package id, path and payload size of every package of the slice
+    pub async fn packages(&self) -> Vec<(PackageId, Arc<PathBuf>, u64)> {
+        self.packages.read().await
+            .iter()
+            .map(|pi| (pi.package_id().clone(), Arc::clone(pi.package().path()), pi.package().size()))
+            .collect()
+    }
+
     pub async fn get_archive_id(&self, mc_seq_no: u32) -> Option<u64> {
         if !self.sliced_mode {
             return Some(self.archive_id as u64);
@@ -169,36 +419,137 @@ impl ArchiveSlice {
         None
     }
 
-    pub async fn add_file<B, U256, PK>(&self, block_handle: Option<&BlockHandle>, entry_id: &PackageEntryId<B, U256, PK>, data: Vec<u8>) -> Result<()>
+    pub async fn add_file<B, U256, PK>(
+        &self,
+        block_handle: Option<&BlockHandle>,
+        entry_id: &PackageEntryId<B, U256, PK>,
+        data: Vec<u8>,
+        mode: WriteMode,
+        appended_at: u32,
+    ) -> Result<()>
+    where
+        B: Borrow<BlockIdExt> + Hash,
+        U256: Borrow<UInt256> + Hash,
+        PK: Borrow<PublicKey> + Hash
+    {
+        self.add_file_with_mc_seq_no(get_mc_seq_no_opt(block_handle), entry_id, data, mode, appended_at).await?;
+
+        Ok(())
+    }
+
+    /// Adds entry into the package. If the entry already exists, behavior is defined by the write mode;
+    /// overwritten entry is appended to the package and the offset is updated to point to the new copy.
+    /// `appended_at` is the unix time recorded in the package's metadata. Returns false if the
+    /// existing entry was kept.
+    pub async fn add_file_with_mc_seq_no<B, U256, PK>(
+        &self,
+        mc_seq_no: u32,
+        entry_id: &PackageEntryId<B, U256, PK>,
+        data: Vec<u8>,
+        mode: WriteMode,
+        appended_at: u32,
+    ) -> Result<bool>
     where
         B: Borrow<BlockIdExt> + Hash,
         U256: Borrow<UInt256> + Hash,
         PK: Borrow<PublicKey> + Hash
     {
         let offset_key = entry_id.into();
-        if self.offsets_db.contains(&offset_key)? {
-            return Ok(());
+        if let Some(offset) = self.offsets_db.try_get_value(&offset_key)? {
+            match mode {
+                WriteMode::KeepExisting => return Ok(false),
+                WriteMode::WriteOnce => {
+                    let existing = self.choose_package(mc_seq_no, false).await?
+                        .package()
+                        .read_entry(offset).await?;
+                    check_same_content(entry_id, existing.data(), &data)?;
+                    return Ok(false);
+                }
+                WriteMode::Overwrite =>
+                    log::warn!(target: "storage", "Overwriting archived entry {}", entry_id),
+            }
         }
 
-        let package_info = self.choose_package(get_mc_seq_no_opt(block_handle), true).await?;
+        let package_info = self.choose_package(mc_seq_no, true).await?;
 
         let entry = PackageEntry::with_data(entry_id.filename(), data);
 
-        let idx = if self.sliced_mode {
-            package_info.idx()
-        } else {
+        if !self.sliced_mode {
             assert_ne!(package_info.idx(), 0);
-            u32::max_value()
-        };
+        }
+        let idx = self.meta_idx(&package_info);
 
+        // Metadata is updated under the package's write lock, so concurrent appends don't race
         package_info.package().append_entry(&entry,
             |offset, size| {
-                let meta = PackageEntryMeta::with_data(size, package_info.version());
+                let mut meta = package_info.meta();
+                meta.on_appended(size, entry.data().len() as u64, appended_at);
                 log::debug!(target: "storage", "Writing package entry metadata for slice #{}: {:?}, offset: {}", idx, meta, offset);
-                self.index_db.put_value(&idx.into(), meta)?;
+                self.index_db.put_value(&idx.into(), &*meta)?;
                 self.offsets_db.put_value(&offset_key, offset)
             }
-        ).await
+        ).await?;
+
+        Ok(true)
+    }
+
+    /// Adds several entries into the package corresponding to given masterchain seq_no, appending
+    /// them under one write lock. Existing entries are handled according to the write mode.
+    pub async fn add_files_with_mc_seq_no<B, U256, PK>(
+        &self,
+        mc_seq_no: u32,
+        entries: Vec<(PackageEntryId<B, U256, PK>, Vec<u8>)>,
+        mode: WriteMode,
+        appended_at: u32,
+    ) -> Result<()>
+    where
+        B: Borrow<BlockIdExt> + Hash,
+        U256: Borrow<UInt256> + Hash,
+        PK: Borrow<PublicKey> + Hash
+    {
+        let mut offset_keys = Vec::with_capacity(entries.len());
+        let mut package_entries = Vec::with_capacity(entries.len());
+        for (entry_id, data) in entries {
+            let offset_key = (&entry_id).into();
+            if let Some(offset) = self.offsets_db.try_get_value(&offset_key)? {
+                match mode {
+                    WriteMode::KeepExisting => continue,
+                    WriteMode::WriteOnce => {
+                        let existing = self.choose_package(mc_seq_no, false).await?
+                            .package()
+                            .read_entry(offset).await?;
+                        check_same_content(&entry_id, existing.data(), &data)?;
+                        continue;
+                    }
+                    WriteMode::Overwrite =>
+                        log::warn!(target: "storage", "Overwriting archived entry {}", entry_id),
+                }
+            }
+            offset_keys.push(offset_key);
+            package_entries.push(PackageEntry::with_data(entry_id.filename(), data));
+        }
+        if package_entries.is_empty() {
+            return Ok(());
+        }
+
+        let package_info = self.choose_package(mc_seq_no, true).await?;
+
+        if !self.sliced_mode {
+            assert_ne!(package_info.idx(), 0);
+        }
+        let idx = self.meta_idx(&package_info);
+
+        package_info.package().append_entries(&package_entries,
+            |index, offset, size| {
+                let mut meta = package_info.meta();
+                meta.on_appended(size, package_entries[index].data().len() as u64, appended_at);
+                self.index_db.put_value(&idx.into(), &*meta)?;
+                self.offsets_db.put_value(&offset_keys[index], offset)
+            }
+        ).await?;
+        log::debug!(target: "storage", "{} entries written into slice #{}", package_entries.len(), idx);
+
+        Ok(())
     }
 
     pub async fn get_file<B, U256, PK>(
@@ -226,48 +577,195 @@ impl ArchiveSlice {
         package_info.package().read_entry(offset).await
     }
 
-    pub async fn get_slice(&self, archive_id: u64, offset: u64, limit: u32) -> Result<Vec<u8>> {
+    /// Gets the entry together with the package it is read from
+    pub async fn get_file_with_package<B, U256, PK>(
+        &self,
+        block_handle: Option<&BlockHandle>,
+        entry_id: &PackageEntryId<B, U256, PK>
+    ) -> Result<Option<(PackageEntry, Arc<PackageInfo>)>>
+    where
+        B: Borrow<BlockIdExt> + Hash,
+        U256: Borrow<UInt256> + Hash,
+        PK: Borrow<PublicKey> + Hash
+    {
+        let offset = match self.offsets_db.try_get_value(&entry_id.into())? {
+            Some(offset) => offset,
+            None => return Ok(None),
+        };
+        let package_info = self.choose_package(get_mc_seq_no_opt(block_handle), false).await?;
+        let entry = package_info.package().read_entry(offset).await?;
+
+        Ok(Some((entry, package_info)))
+    }
+
+    /// Gets the entry by its filename, resolving it via the offsets database alone (no block handle
+    /// is needed). Returns Ok(None) if the entry is not in the archive.
+    pub async fn get_entry_by_name(&self, filename: &str) -> Result<Option<PackageEntry>> {
+        let entry_id = PackageEntryId::from_filename(filename)?;
+        let offset = match self.offsets_db.try_get_value(&(&entry_id).into())? {
+            Some(offset) => offset,
+            None => return Ok(None),
+        };
+
+        // Offsets are kept per slice, so in sliced mode the package holding the entry is found
+        // by checking the entry's name at the offset
+        let canonical_filename = entry_id.filename();
+        let packages = self.packages.read().await.clone();
+        for package_info in packages.iter() {
+            match package_info.package().read_entry(offset).await {
+                Ok(entry) if entry.filename() == &canonical_filename => return Ok(Some(entry)),
+                Ok(_) => continue,
+                Err(err) => log::trace!(
+                    target: "storage",
+                    "Entry {} is not found in package {:?}: {}",
+                    filename,
+                    package_info.package().path(),
+                    err
+                ),
+            }
+        }
+
+        fail!("Entry {} is indexed in archive {}, but is not found in its packages", filename, self.archive_id)
+    }
+
+    /// Gets the package by archive id (as returned by `get_archive_id`)
+    pub async fn get_package(&self, archive_id: u64) -> Result<Arc<PackageInfo>> {
         if archive_id as u32 != self.archive_id {
             fail!("Bad archive ID (archive_id = {}, expected {})!", archive_id as u32, self.archive_id);
         }
 
         let package_id = (archive_id >> 32) as u32;
-        let package_info = self.choose_package(package_id, false).await?;
-        let mut file = File::open(&**package_info.package().path()).await?;
-        let mut buffer = vec![0; limit as usize];
-        file.seek(SeekFrom::Start(offset)).await?;
-        let mut buf_offset = 0;
-        let mut actual_read = 0;
-        loop {
-            let read = file.read(&mut buffer[buf_offset..]).await?;
-            if read == 0 {
-                break;
+        self.choose_package(package_id, false).await
+    }
+
+    /// Reads the raw bytes of the package for the syncing peers, up to the package's served size
+    /// (i.e. without the trailer of the finalized package)
+    pub async fn get_slice(&self, archive_id: u64, offset: u64, limit: u32) -> Result<Vec<u8>> {
+        let package_info = self.get_package(archive_id).await?;
+        let served_size = package_info.package().served_size();
+        if offset >= served_size {
+            return Ok(Vec::new());
+        }
+        let limit = std::cmp::min(limit as u64, served_size - offset) as u32;
+
+        self.read_ahead_cache.read(archive_id, package_info.package().path(), offset, limit).await
+    }
+
+    /// Returns package id and metadata of every package of the slice, read from the slice's index
+    /// only (the package files are not touched). Metadata of the packages without index record
+    /// (non-sliced packages written by older versions) is None.
+    pub async fn list_packages_meta(&self) -> Result<Vec<(PackageId, Option<PackageEntryMeta>)>> {
+        let packages = self.packages.read().await.clone();
+        let mut result = Vec::with_capacity(packages.len());
+        for package_info in packages.iter() {
+            let meta = self.index_db.try_get_value(&self.meta_idx(package_info).into())?;
+            result.push((package_info.package_id().clone(), meta));
+        }
+
+        Ok(result)
+    }
+
+    /// Root directory the package files of the slice are kept in
+    pub fn packages_root(&self) -> Arc<PathBuf> {
+        Arc::clone(&self.packages_root.read().expect("Poisoned RwLock"))
+    }
+
+    /// Copies the package files into given root directory and switches the slice to the copies.
+    /// Only the slices with all the packages finalized are relocated (None is returned otherwise),
+    /// so no entries are appended during the copy. The packages are copied without blocking the
+    /// readers, which are switched to the copies once they are complete. Returns paths of the former
+    /// package files, which are to be removed when the new location is persisted.
+    pub async fn relocate(&self, packages_root: Arc<PathBuf>) -> Result<Option<Vec<Arc<PathBuf>>>> {
+        let source = self.packages.read().await.clone();
+        if !source.iter().all(|pi| pi.package().is_finalized()) {
+            return Ok(None);
+        }
+
+        let mut relocated = Vec::with_capacity(source.len());
+        for pi in source.iter() {
+            let path = Arc::new(pi.package_id().full_path(packages_root.as_ref(), "pack"));
+            if let Some(dir) = path.parent() {
+                tokio::fs::create_dir_all(dir).await?;
+            }
+            let size = copy_file(&*self.fs, pi.package().path(), &path).await?;
+            log::debug!(target: "storage", "Package {:?} copied to {:?}, {} bytes", pi.package().path(), path, size);
+            let package = Package::open(path, false, false, Arc::clone(&self.file_budget), Arc::clone(&self.fs)).await?;
+            relocated.push(Arc::new(PackageInfo::with_data(
+                pi.package_id().clone(),
+                package,
+                pi.idx(),
+                pi.version(),
+                pi.meta().clone(),
+            )));
+        }
+
+        let mut packages = self.packages.write().await;
+        let unchanged = packages.len() == source.len()
+            && packages.iter().zip(source.iter()).all(|(current, copied)| Arc::ptr_eq(current, copied));
+        if !unchanged {
+            // The packages were replaced (e.g. compacted) during the copy, so the copies are stale
+            drop(packages);
+            log::warn!(target: "storage", "Packages of archive {} changed during relocation", self.archive_id);
+            for pi in relocated {
+                pi.package().close().await;
+                self.fs.remove(pi.package().path()).await?;
             }
-            actual_read += read;
-            buf_offset += read;
+            return Ok(None);
+        }
+
+        let former = std::mem::replace(&mut *packages, relocated);
+        *self.packages_root.write().expect("Poisoned RwLock") = packages_root;
+        drop(packages);
+        self.read_ahead_cache.clear().await;
+
+        let mut former_paths = Vec::with_capacity(former.len());
+        for pi in former {
+            pi.package().close().await;
+            former_paths.push(Arc::clone(pi.package().path()));
+        }
+
+        Ok(Some(former_paths))
+    }
+
+    /// Key of the package's metadata in the index
+    fn meta_idx(&self, package_info: &PackageInfo) -> u32 {
+        if self.sliced_mode {
+            package_info.idx()
+        } else {
+            u32::max_value()
         }
-        buffer.resize(actual_read, 0);
+    }
 
-        Ok(buffer)
+    fn package_meta(&self, idx: u32, version: u32) -> Result<PackageEntryMeta> {
+        Ok(self.index_db.try_get_value(&idx.into())?
+            .unwrap_or_else(|| PackageEntryMeta::with_type(0, version, self.package_type)))
     }
 
     async fn new_package(&self, idx: u32, seq_no: u32, size: u64, version: u32) -> Result<Arc<PackageInfo>> {
         log::debug!(target: "storage", "Adding package, seq_no: {}, size: {} bytes, version: {}", seq_no, size, version);
         let package_id = PackageId::with_values(seq_no, self.package_type);
-        let path = Arc::new(package_id.full_path(self.db_root_path.as_ref(), "pack"));
-
-        let package = Package::open(Arc::clone(&path), false, true).await
+        let path = Arc::new(package_id.full_path(self.packages_root().as_ref(), "pack"));
+
+        let package = Package::open(
+            Arc::clone(&path),
+            false,
+            true,
+            Arc::clone(&self.file_budget),
+            Arc::clone(&self.fs),
+        ).await
             .map_err(|err| error!("Failed to open or create archive \"{}\": {}", path.to_string_lossy(), err))?;
 
         if !self.finalized && version >= DEFAULT_PKG_VERSION {
             package.truncate(size).await?;
         }
 
+        let meta_idx = if self.sliced_mode { idx } else { u32::max_value() };
         let pi = Arc::new(PackageInfo::with_data(
             package_id,
             package,
             idx,
-            version
+            version,
+            self.package_meta(meta_idx, version)?,
         ));
 
         Ok(pi)
@@ -312,7 +810,7 @@ impl ArchiveSlice {
 
                 let pi = self.new_package(idx, mc_seq_no, 0, DEFAULT_PKG_VERSION).await?;
 
-                let index_entry = PackageEntryMeta::with_data(0, DEFAULT_PKG_VERSION);
+                let index_entry = PackageEntryMeta::with_type(0, DEFAULT_PKG_VERSION, self.package_type);
                 self.index_db.put_value(&idx.into(), &index_entry)?;
                 self.package_status_db.put_value(&PackageStatusKey::TotalSlices, idx + 1)?;
                 write_guard.push(Arc::clone(&pi));
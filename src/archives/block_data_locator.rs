@@ -0,0 +1,84 @@
+use std::borrow::Borrow;
+use std::hash::Hash;
+use std::path::PathBuf;
+use std::sync::Arc;
+
+use async_trait::async_trait;
+use ton_api::ton::PublicKey;
+use ton_block::BlockIdExt;
+use ton_types::{fail, Result, UInt256};
+
+use crate::archives::archive_manager::ArchiveManager;
+use crate::archives::package_entry_id::{GetFileName, PackageEntryId};
+use crate::types::BlockHandle;
+
+/// Where the located data was taken from
+#[derive(Debug, Clone, PartialEq)]
+pub enum DataProvenance {
+    /// File in the unapplied directory
+    Unapplied(PathBuf),
+    /// Entry of the archive package
+    Package {
+        path: PathBuf,
+        finalized: bool,
+    },
+    /// Data fetched by the remote fetcher
+    Remote,
+}
+
+/// Data of the package entry together with its provenance
+#[derive(Debug, Clone)]
+pub struct LocatedData {
+    pub data: Vec<u8>,
+    pub provenance: DataProvenance,
+}
+
+/// Fetcher of the entries missing in the local storage (e.g. downloading them from peers)
+#[async_trait]
+pub trait RemoteFetcher: Send + Sync {
+    /// Fetches the entry with given filename (as in packages); returns Ok(None) if it is not available
+    async fn fetch(&self, handle: &BlockHandle, entry_filename: &str) -> Result<Option<Vec<u8>>>;
+}
+
+/// Resolves package entries across the unapplied directory, archive packages and (optionally)
+/// the remote fetcher, so the caller doesn't need to know where the data is kept now
+pub struct BlockDataLocator<'a> {
+    archive_manager: &'a ArchiveManager,
+    remote_fetcher: Option<Arc<dyn RemoteFetcher>>,
+}
+
+impl<'a> BlockDataLocator<'a> {
+    pub fn new(archive_manager: &'a ArchiveManager) -> Self {
+        Self { archive_manager, remote_fetcher: None }
+    }
+
+    pub fn with_remote_fetcher(mut self, remote_fetcher: Arc<dyn RemoteFetcher>) -> Self {
+        self.remote_fetcher = Some(remote_fetcher);
+        self
+    }
+
+    /// Locates the entry; fails if the entry is found nowhere
+    pub async fn locate<B, U256, PK>(
+        &self,
+        handle: &BlockHandle,
+        entry_id: &PackageEntryId<B, U256, PK>
+    ) -> Result<LocatedData>
+    where
+        B: Borrow<BlockIdExt> + Hash,
+        U256: Borrow<UInt256> + Hash,
+        PK: Borrow<PublicKey> + Hash
+    {
+        if let Some(located) = self.archive_manager.locate_file(handle, entry_id).await? {
+            return Ok(located);
+        }
+
+        if let Some(ref remote_fetcher) = self.remote_fetcher {
+            log::debug!(target: "storage", "Fetching remote entry: {}", entry_id);
+            if let Some(data) = remote_fetcher.fetch(handle, &entry_id.filename()).await? {
+                return Ok(LocatedData { data, provenance: DataProvenance::Remote });
+            }
+        }
+
+        fail!("Entry {} of block {} is not found", entry_id, handle.id())
+    }
+}
@@ -0,0 +1,29 @@
+use serde_derive::{Deserialize, Serialize};
+use ton_types::UInt256;
+
+use crate::db::traits::KvcWriteable;
+use crate::db_impl_cbor;
+
+/// Location of the block data entry in the archives
+#[derive(Debug, Clone, PartialEq, Serialize, Deserialize)]
+pub struct FileHashIndexEntry {
+    archive_id: u32,
+    filename: String,
+}
+
+impl FileHashIndexEntry {
+    pub const fn with_values(archive_id: u32, filename: String) -> Self {
+        Self { archive_id, filename }
+    }
+
+    pub const fn archive_id(&self) -> u32 {
+        self.archive_id
+    }
+
+    pub fn filename(&self) -> &str {
+        &self.filename
+    }
+}
+
+// Block file hash -> location of the block data entry
+db_impl_cbor!(FileHashIndexDb, KvcWriteable, UInt256, FileHashIndexEntry);
@@ -1,13 +1,16 @@
 use std::path::{Path, PathBuf};
-use std::sync::Arc;
+use std::sync::{Arc, RwLock as SyncRwLock};
 
 use tokio::sync::RwLock;
 
-use ton_types::Result;
+use ton_types::{fail, Result};
 
+use crate::archives::archive_fs::ArchiveFs;
 use crate::archives::archive_slice::ArchiveSlice;
+use crate::archives::package::FileBudget;
 use crate::archives::package_id::{PackageId, PackageType};
 use crate::archives::package_index_db::{PackageIndexDb, PackageIndexEntry};
+use crate::archives::read_ahead_cache::ReadAheadConfig;
 
 #[derive(Debug)]
 pub struct FileDescription {
@@ -43,11 +46,21 @@ pub struct FileMapEntry {
 #[derive(Debug)]
 pub struct FileMap {
     storage: PackageIndexDb,
+    db_root_path: Arc<PathBuf>,
     elements: RwLock<Vec<FileMapEntry>>,
+    // Sorted keys of the elements, mirrored for the lookups on the hot path which must not await
+    keys: SyncRwLock<Vec<u32>>,
 }
 
 impl FileMap {
-    pub async fn new(db_root_path: &Arc<PathBuf>, path: impl AsRef<Path>, package_type: PackageType) -> Result<Self> {
+    pub async fn new(
+        db_root_path: &Arc<PathBuf>,
+        path: impl AsRef<Path>,
+        package_type: PackageType,
+        read_ahead_config: &ReadAheadConfig,
+        file_budget: &Arc<FileBudget>,
+        fs: &Arc<dyn ArchiveFs>,
+    ) -> Result<Self> {
         let storage = PackageIndexDb::with_path(path);
         let mut index_pairs = Vec::new();
 
@@ -61,11 +74,19 @@ impl FileMap {
 
         let mut elements = Vec::new();
         for (key, value) in index_pairs {
+            let packages_root = match value.packages_root() {
+                Some(packages_root) => Arc::new(packages_root.clone()),
+                None => Arc::clone(db_root_path),
+            };
             let archive_slice = Arc::new(ArchiveSlice::with_data(
                 Arc::clone(db_root_path),
+                packages_root,
                 key,
                 package_type,
-                value.finalized()
+                value.finalized(),
+                read_ahead_config.clone(),
+                Arc::clone(file_budget),
+                Arc::clone(fs),
             ).await?);
             let value = Arc::new(FileDescription::with_data(
                 PackageId::with_values(key, package_type),
@@ -75,24 +96,89 @@ impl FileMap {
             elements.push(FileMapEntry { key, value });
         }
 
+        let keys = elements.iter().map(|entry| entry.key).collect();
+
         Ok(Self {
             storage,
+            db_root_path: Arc::clone(db_root_path),
             elements: RwLock::new(elements),
+            keys: SyncRwLock::new(keys),
         })
     }
 
     pub async fn put(&self, package_id: u32, file_description: Arc<FileDescription>) -> Result<()> {
+        let index_entry = self.index_entry(&file_description);
         let entry = FileMapEntry { key: package_id, value: file_description };
         let mut guard = self.elements.write().await;
         match guard.binary_search_by(|entry| entry.key.cmp(&package_id)) {
             Ok(index) => guard[index] = entry,
-            Err(index) => guard.insert(index, entry),
+            Err(index) => {
+                guard.insert(index, entry);
+                self.keys.write().expect("Poisoned RwLock").insert(index, package_id);
+            }
         }
-        self.storage.put_value(&package_id.into(), PackageIndexEntry::new())?;
+        self.storage.put_value(&package_id.into(), index_entry)?;
+
+        Ok(())
+    }
+
+    /// Persists the current location of the package files of the element (by one write, so the
+    /// location is updated atomically)
+    pub async fn update_location(&self, package_id: u32) -> Result<()> {
+        let guard = self.elements.read().await;
+        let index = match guard.binary_search_by(|entry| entry.key.cmp(&package_id)) {
+            Ok(index) => index,
+            Err(_) => fail!("Archive {} is not found in the file map", package_id),
+        };
+        self.storage.put_value(&package_id.into(), self.index_entry(&guard[index].value))?;
 
         Ok(())
     }
 
+    fn index_entry(&self, file_description: &FileDescription) -> PackageIndexEntry {
+        let packages_root = file_description.archive_slice().packages_root();
+        PackageIndexEntry::new().with_packages_root(if packages_root == self.db_root_path {
+            None
+        } else {
+            Some(packages_root.as_ref().clone())
+        })
+    }
+
+    /// Removes the entry from the map and the index, if its file description is not used elsewhere,
+    /// and returns the archive slice to be destroyed. Entries in use are retained.
+    pub async fn remove(&self, package_id: u32) -> Result<Option<ArchiveSlice>> {
+        let mut guard = self.elements.write().await;
+        let index = match guard.binary_search_by(|entry| entry.key.cmp(&package_id)) {
+            Ok(index) => index,
+            Err(_) => return Ok(None),
+        };
+
+        let entry = guard.remove(index);
+        let file_description = match Arc::try_unwrap(entry.value) {
+            Ok(file_description) => file_description,
+            Err(value) => {
+                guard.insert(index, FileMapEntry { key: package_id, value });
+                return Ok(None);
+            }
+        };
+        match Arc::try_unwrap(file_description.archive_slice) {
+            Ok(archive_slice) => {
+                self.keys.write().expect("Poisoned RwLock").remove(index);
+                self.storage.delete(&package_id.into())?;
+                Ok(Some(archive_slice))
+            },
+            Err(archive_slice) => {
+                let value = Arc::new(FileDescription::with_data(
+                    file_description.id,
+                    archive_slice,
+                    file_description.deleted
+                ));
+                guard.insert(index, FileMapEntry { key: package_id, value });
+                Ok(None)
+            }
+        }
+    }
+
     pub async fn get(&self, package_id: u32) -> Option<Arc<FileDescription>> {
         let guard = self.elements.read().await;
         guard.binary_search_by(|entry| entry.key.cmp(&package_id))
@@ -100,6 +186,30 @@ impl FileMap {
             .ok()
     }
 
+    /// Returns snapshot of all file descriptions in the ascending order of package ids
+    pub async fn entries(&self) -> Vec<Arc<FileDescription>> {
+        self.elements.read().await
+            .iter()
+            .map(|entry| Arc::clone(&entry.value))
+            .collect()
+    }
+
+    /// Checks whether the element with given key exists without awaiting
+    pub fn contains_key(&self, package_id: u32) -> bool {
+        self.keys.read().expect("Poisoned RwLock").binary_search(&package_id).is_ok()
+    }
+
+    /// Key of the element `get_closest` returns, i.e. the greatest key not above given seq_no; looked
+    /// up by binary search in the in-memory index without awaiting
+    pub fn get_closest_key(&self, mc_seq_no: u32) -> Option<u32> {
+        let keys = self.keys.read().expect("Poisoned RwLock");
+        match keys.binary_search(&mc_seq_no) {
+            Ok(index) => Some(keys[index]),
+            Err(0) => None,
+            Err(index) => Some(keys[index - 1]),
+        }
+    }
+
     pub async fn get_closest(&self, mc_seq_no: u32) -> Option<Arc<FileDescription>> {
         let guard = self.elements.read().await;
         log::debug!(target: "storage", "Searching for file description (elements count = {})", guard.len());
@@ -122,10 +232,15 @@ pub struct FileMaps {
 }
 
 impl FileMaps {
-    pub async fn new(db_root_path: &Arc<PathBuf>) -> Result<Self> {
+    pub async fn new(
+        db_root_path: &Arc<PathBuf>,
+        read_ahead_config: &ReadAheadConfig,
+        file_budget: &Arc<FileBudget>,
+        fs: &Arc<dyn ArchiveFs>,
+    ) -> Result<Self> {
         let path = db_root_path.join("file_maps");
         Ok(Self {
-            files: FileMap::new(db_root_path, path.join("files"), PackageType::Blocks).await?,
+            files: FileMap::new(db_root_path, path.join("files"), PackageType::Blocks, read_ahead_config, file_budget, fs).await?,
             // key_files: FileMap::new(db_root_path, path.join("key_files"), PackageType::KeyBlocks).await?,
             // temp_files: FileMap::new(db_root_path, path.join("temp_files"), PackageType::Temp).await?,
         })
@@ -0,0 +1,152 @@
+use std::path::{Path, PathBuf};
+use std::str::FromStr;
+
+use lazy_static::lazy_static;
+use regex::Regex;
+
+use ton_api::ton::PublicKey;
+use ton_block::BlockIdExt;
+use ton_types::{Result, UInt256};
+
+use crate::archives::archive_manager::ArchiveManager;
+use crate::archives::package::read_package_from_file;
+use crate::archives::package_entry_id::PackageEntryId;
+use crate::cancellation::CancellationToken;
+
+/// Statistics of the legacy archives import
+#[derive(Debug, Clone, Default)]
+pub struct LegacyImportStats {
+    pub packages_imported: usize,
+    pub packages_skipped: usize,
+    pub entries_imported: usize,
+    /// Entries already stored in the archive (e.g. by the interrupted import)
+    pub entries_existing: usize,
+    pub entries_skipped: usize,
+}
+
+/// Package file of C++ node's archive
+#[derive(Debug, Clone, Copy, PartialEq, Eq, PartialOrd, Ord)]
+enum LegacyPackage {
+    /// Blocks package starting with given masterchain seq_no
+    Blocks(u32),
+    /// Key blocks package with given index
+    KeyBlocks(u32),
+}
+
+/// Parses the name of C++ node's package file. Blocks packages are named "archive.00100.pack" (or
+/// shard-split "archive.00100.0:8000000000000000.pack"), key blocks ones "key.archive.000000.pack".
+fn parse_package_name(filename: &str) -> Option<LegacyPackage> {
+    lazy_static! {
+        static ref BLOCKS_REGEX: Regex = Regex::new(r"^archive\.(\d+)(\.[^.]+)?\.pack$")
+            .expect("Failed to compile regular expression");
+        static ref KEY_BLOCKS_REGEX: Regex = Regex::new(r"^key\.archive\.(\d+)\.pack$")
+            .expect("Failed to compile regular expression");
+    }
+
+    if let Some(captures) = BLOCKS_REGEX.captures(filename) {
+        return u32::from_str(&captures[1]).ok().map(LegacyPackage::Blocks);
+    }
+    KEY_BLOCKS_REGEX.captures(filename)
+        .and_then(|captures| u32::from_str(&captures[1]).ok())
+        .map(LegacyPackage::KeyBlocks)
+}
+
+async fn find_packages(root: &Path) -> Result<Vec<(LegacyPackage, PathBuf)>> {
+    let mut result = Vec::new();
+    let mut dirs = vec![root.to_path_buf()];
+    while let Some(dir) = dirs.pop() {
+        let mut read_dir = tokio::fs::read_dir(&dir).await?;
+        while let Some(dir_entry) = read_dir.next_entry().await? {
+            let path = dir_entry.path();
+            if dir_entry.metadata().await?.is_dir() {
+                dirs.push(path);
+                continue;
+            }
+            let filename = dir_entry.file_name().to_string_lossy().to_string();
+            match parse_package_name(&filename) {
+                Some(package) => result.push((package, path)),
+                None if filename.ends_with(".pack") =>
+                    log::warn!(target: "storage", "Unsupported legacy package skipped: {:?}", path),
+                None => (),
+            }
+        }
+    }
+    // Blocks packages go first, so the key blocks they contain are not imported twice
+    result.sort();
+
+    Ok(result)
+}
+
+/// Imports blocks and key blocks packages of C++ node's archive located in `src_dir` into the
+/// archive. Package files are read sequentially and every entry is re-added into the corresponding
+/// package, so C++ node's index databases are not required. Entries of the key blocks packages are
+/// added as the ones of the key block's masterchain seq_no (key blocks pruned from the blocks
+/// packages by C++ node are kept in the key blocks packages). Import is idempotent: already
+/// imported entries are skipped, so the cancelled import may be resumed by the repeated one.
+pub async fn import_legacy_archives(
+    archive_manager: &ArchiveManager,
+    src_dir: impl AsRef<Path>,
+    mut progress: impl FnMut(&LegacyImportStats),
+    cancellation: &CancellationToken,
+) -> Result<LegacyImportStats> {
+    log::info!(target: "storage", "Importing legacy archives from {:?}", src_dir.as_ref());
+
+    let mut stats = LegacyImportStats::default();
+    for (package, path) in find_packages(src_dir.as_ref()).await? {
+        log::debug!(target: "storage", "Importing legacy package {:?} ({:?})", path, package);
+
+        let mut reader = match read_package_from_file(&path).await {
+            Ok(reader) => reader,
+            Err(err) => {
+                log::warn!(target: "storage", "Legacy package {:?} skipped: {}", path, err);
+                stats.packages_skipped += 1;
+                continue;
+            }
+        };
+
+        while let Some(entry) = reader.next().await? {
+            cancellation.check()?;
+            let entry_id = match PackageEntryId::from_filename(entry.filename()) {
+                Ok(entry_id) => entry_id,
+                Err(err) => {
+                    log::warn!(target: "storage", "Legacy package entry skipped: {}", err);
+                    stats.entries_skipped += 1;
+                    continue;
+                }
+            };
+            let mc_seq_no = match package {
+                LegacyPackage::Blocks(seq_no) => seq_no,
+                LegacyPackage::KeyBlocks(_) => match key_block_seq_no(&entry_id) {
+                    Some(seq_no) => seq_no,
+                    None => {
+                        log::warn!(target: "storage", "Unexpected entry {} of key blocks package skipped", entry.filename());
+                        stats.entries_skipped += 1;
+                        continue;
+                    }
+                },
+            };
+            if archive_manager.add_file_to_archive(mc_seq_no, false, &entry_id, entry.take_data()).await? {
+                stats.entries_imported += 1;
+            } else {
+                stats.entries_existing += 1;
+            }
+        }
+
+        stats.packages_imported += 1;
+        progress(&stats);
+    }
+
+    log::info!(target: "storage", "Legacy archives import finished: {:?}", stats);
+
+    Ok(stats)
+}
+
+/// Masterchain seq_no of the key block the entry of key blocks package belongs to
+fn key_block_seq_no(entry_id: &PackageEntryId<BlockIdExt, UInt256, PublicKey>) -> Option<u32> {
+    match entry_id {
+        PackageEntryId::Block(block_id)
+        | PackageEntryId::Proof(block_id)
+        | PackageEntryId::ProofLink(block_id) if block_id.shard().is_masterchain() => Some(block_id.seq_no()),
+        _ => None,
+    }
+}
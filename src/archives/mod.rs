@@ -1,11 +1,22 @@
 use crate::types::BlockHandle;
 
-mod package_index_db;
-
+pub mod archive_fs;
 pub mod archive_manager;
+pub mod block_data_locator;
+pub mod file_hash_index_db;
+pub mod legacy_import;
 pub mod package;
 pub mod package_entry_id;
+pub mod package_id;
+pub mod package_index_db;
+pub mod package_manifest;
+pub mod package_trailer;
+pub mod pruned_archives;
+pub mod read_ahead_cache;
+pub mod storage_pools;
 pub mod package_entry;
+pub mod package_entry_meta;
+pub mod unapplied_gc;
 
 mod package_status_db;
 mod package_status_key;
@@ -14,8 +25,7 @@ mod package_offsets_db;
 mod package_info;
 mod archive_slice;
 mod package_entry_meta_db;
-mod package_entry_meta;
-mod package_id;
+mod slice_rotator;
 
 fn get_mc_seq_no_opt(block_handle: Option<&BlockHandle>) -> u32 {
     if let Some(handle) = block_handle {
@@ -1,22 +1,84 @@
-use std::io::SeekFrom;
 use std::path::{Path, PathBuf};
 use std::sync::Arc;
-use std::sync::atomic::{AtomicU64, Ordering};
+use std::sync::atomic::{AtomicBool, AtomicU64, AtomicUsize, Ordering};
 
 use tokio::fs::{File, OpenOptions};
-use tokio::io::{AsyncReadExt, AsyncWriteExt, BufReader};
+use tokio::io::{AsyncReadExt, BufReader};
 use tokio::sync::Mutex;
 use ton_types::{error, fail, Result};
 
-use crate::archives::package_entry::{PackageEntry, PKG_ENTRY_HEADER_SIZE};
+use crate::archives::archive_fs::{ArchiveFile, ArchiveFs, LocalFs};
+use crate::archives::package_entry::{PackageEntry, PackageEntryHeader, PKG_ENTRY_HEADER_SIZE};
+use crate::archives::package_trailer::{PackageTrailer, PackageTrailerBuilder, PKG_TRAILER_ENTRY_SIZE};
+use crate::traits::Serializable;
 
 
+/// Default count of package reader handles kept open across all the packages
+pub const DEFAULT_PACKAGE_FILE_BUDGET: usize = 1024;
+
+/// Budget of file descriptors kept open by packages, shared across the archive. Both writer handles
+/// (they exist only for packages which are not finalized) and reader handles are kept open within
+/// the budget; the ones beyond it are closed after use.
+#[derive(Debug)]
+pub struct FileBudget {
+    limit: usize,
+    open: AtomicUsize,
+}
+
+impl FileBudget {
+    pub fn new(limit: usize) -> Self {
+        Self { limit, open: AtomicUsize::new(0) }
+    }
+
+    pub const fn limit(&self) -> usize {
+        self.limit
+    }
+
+    /// Count of package file handles kept open at the moment
+    pub fn open_files(&self) -> usize {
+        self.open.load(Ordering::Relaxed)
+    }
+
+    fn try_acquire(&self) -> bool {
+        self.open.fetch_update(Ordering::SeqCst, Ordering::SeqCst, |open| {
+            if open < self.limit {
+                Some(open + 1)
+            } else {
+                None
+            }
+        }).is_ok()
+    }
+
+    fn release(&self) {
+        self.open.fetch_sub(1, Ordering::SeqCst);
+    }
+}
+
+/// Result of the package compaction
+#[derive(Debug, Clone)]
+pub struct PackageCompaction {
+    /// Filenames and new offsets of the entries kept
+    pub entries: Vec<(String, u64)>,
+    /// Count of the entries removed
+    pub removed: usize,
+    /// Size of the entries kept (excluding the trailer)
+    pub payload_size: u64,
+    /// Sizes of the package (including the trailer) before and after the compaction
+    pub size_before: u64,
+    pub size_after: u64,
+}
+
 #[derive(Debug)]
 pub struct Package {
     path: Arc<PathBuf>,
     read_only: bool,
     size: AtomicU64,
-    write_mutex: Mutex<()>
+    finalized: AtomicBool,
+    // Persistent writer handle entries are appended with; the mutex serializes writes
+    writer: Mutex<Option<Box<dyn ArchiveFile>>>,
+    readers: std::sync::Mutex<Vec<Box<dyn ArchiveFile>>>,
+    file_budget: Arc<FileBudget>,
+    fs: Arc<dyn ArchiveFs>,
 }
 
 pub(crate) const PKG_HEADER_SIZE: usize = 4;
@@ -27,6 +89,11 @@ async fn read_header<R: AsyncReadExt + Unpin>(reader: &mut R) -> Result<()> {
     if reader.read_exact(&mut buf).await? != PKG_HEADER_SIZE {
         fail!("Package file read failed")
     }
+
+    check_header(buf)
+}
+
+fn check_header(buf: [u8; PKG_HEADER_SIZE]) -> Result<()> {
     if u32::from_le_bytes(buf) != PKG_HEADER_MAGIC {
         fail!("Package file header mismatch")
     }
@@ -35,27 +102,49 @@ async fn read_header<R: AsyncReadExt + Unpin>(reader: &mut R) -> Result<()> {
 }
 
 impl Package {
-    pub async fn open(path: Arc<PathBuf>, read_only: bool, create: bool) -> Result<Self> {
-        let mut file = Self::open_file_ext(read_only, create, &*path).await?;
-        let mut size = file.metadata().await?.len();
+    pub async fn open(
+        path: Arc<PathBuf>,
+        read_only: bool,
+        create: bool,
+        file_budget: Arc<FileBudget>,
+        fs: Arc<dyn ArchiveFs>,
+    ) -> Result<Self> {
+        let mut file = fs.open(&*path, read_only, create).await?;
+        let mut size = file.size().await?;
 
-        file.seek(SeekFrom::Start(0)).await?;
         if size < PKG_HEADER_SIZE as u64 {
             if !create {
                 fail!("Package file is too short")
             }
-            file.write(&PKG_HEADER_MAGIC.to_le_bytes()).await?;
+            if size != 0 {
+                file.truncate(0).await?;
+            }
+            file.append(&PKG_HEADER_MAGIC.to_le_bytes()).await?;
             size = PKG_HEADER_SIZE as u64;
         } else {
-            read_header(&mut file).await?;
+            let mut buf = [0; PKG_HEADER_SIZE];
+            file.read_exact_at(0, &mut buf).await?;
+            check_header(buf)?;
         }
+        let finalized = Self::read_trailer(&mut *file, size).await?.is_some();
+
+        // The handle used for opening becomes the writer of the package entries are appended to
+        let writer = if !read_only && !finalized && file_budget.try_acquire() {
+            Some(file)
+        } else {
+            None
+        };
 
         Ok(
             Self {
                 path,
-                read_only, size:
-                AtomicU64::new(size),
-                write_mutex: Mutex::new(()),
+                read_only,
+                size: AtomicU64::new(size),
+                finalized: AtomicBool::new(finalized),
+                writer: Mutex::new(writer),
+                readers: std::sync::Mutex::new(Vec::new()),
+                file_budget,
+                fs,
             }
         )
     }
@@ -64,19 +153,198 @@ impl Package {
         self.size.load(Ordering::SeqCst) - PKG_HEADER_SIZE as u64
     }
 
+    /// Size of the package file served to the syncing peers: the header and the entries. The trailer
+    /// of the finalized package is left out, since other nodes don't know its entry.
+    pub fn served_size(&self) -> u64 {
+        let size = self.size.load(Ordering::SeqCst);
+        if self.is_finalized() {
+            size - PKG_TRAILER_ENTRY_SIZE
+        } else {
+            size
+        }
+    }
+
+    /// Returns true if the package ends with the trailer, i.e. no more entries will be appended
+    pub fn is_finalized(&self) -> bool {
+        self.finalized.load(Ordering::SeqCst)
+    }
+
+    /// Reads the trailer of the package, if the package is finalized
+    pub async fn trailer(&self) -> Result<Option<PackageTrailer>> {
+        if !self.is_finalized() {
+            return Ok(None);
+        }
+        let (mut file, pooled) = self.take_reader().await?;
+        let result = Self::read_trailer(&mut *file, self.size.load(Ordering::SeqCst)).await;
+        self.put_reader(file, pooled, result.is_ok());
+
+        result
+    }
+
+    /// Verifies all the entries of the package and appends the trailer. Returns the trailer written
+    /// (or already existing one if the package is finalized).
+    pub async fn finalize(&self) -> Result<PackageTrailer> {
+        let mut writer = self.writer.lock().await;
+        if let Some(trailer) = self.trailer().await? {
+            return Ok(trailer);
+        }
+
+        let mut reader = read_package_from(self.open_file().await?.into_reader().await?).await?;
+        while reader.next().await?.is_some() {}
+        if reader.trailer_builder.payload_size() != self.size() {
+            fail!(
+                "Package {:?} size mismatch: {} bytes of entries read, {} expected",
+                self.path,
+                reader.trailer_builder.payload_size(),
+                self.size()
+            )
+        }
+        let trailer = reader.trailer_builder.build();
+
+        let trailer_entry = trailer.to_entry()?;
+        let trailer_size = self.write_entries(&mut writer, std::iter::once(&trailer_entry), |_size| Ok(())).await?;
+        self.size.fetch_add(trailer_size, Ordering::SeqCst);
+        self.finalized.store(true, Ordering::SeqCst);
+        // Nothing is appended to the finalized package, so the writer is not needed anymore
+        self.close_writer(&mut writer);
+        log::debug!(target: "storage", "Package {:?} finalized: {:?}", self.path, trailer);
+
+        Ok(trailer)
+    }
+
+    /// Copies all the entries of the package into the new finalized package at given path on the
+    /// local disk, calling `on_entry` for each entry copied. Appending is blocked during the copy,
+    /// so the snapshot is consistent. Returns the trailer of the copy.
+    pub async fn export_to(
+        &self,
+        dest_path: Arc<PathBuf>,
+        mut on_entry: impl FnMut(&PackageEntry) -> Result<()>,
+    ) -> Result<PackageTrailer> {
+        let _writer = self.writer.lock().await;
+
+        let dest = Package::open(dest_path, false, true, Arc::clone(&self.file_budget), Arc::new(LocalFs)).await?;
+        if dest.size() != 0 {
+            fail!("Destination package {:?} is not empty", dest.path)
+        }
+
+        let mut reader = read_package_from(self.open_file().await?.into_reader().await?).await?;
+        while let Some(entry) = reader.next().await? {
+            on_entry(&entry)?;
+            dest.append_entry(&entry, |_offset, _size| Ok(())).await?;
+        }
+
+        dest.finalize().await
+    }
+
+    /// Rewrites the package keeping only the entries `is_live` returns true for (it is called with the
+    /// offset and the entry). The copy is written next to the package and replaces it atomically;
+    /// the finalized package stays finalized. Returns None if all the entries are live (the package
+    /// is left intact). Readers holding handles to the replaced file are not affected, but offsets of
+    /// the entries change, so the package must not be read until the offsets are updated.
+    pub async fn compact(
+        &self,
+        mut is_live: impl FnMut(u64, &PackageEntry) -> Result<bool>,
+    ) -> Result<Option<PackageCompaction>> {
+        if self.read_only {
+            fail!("Package {:?} is opened read-only and can't be compacted", self.path)
+        }
+        let mut writer = self.writer.lock().await;
+
+        let size_before = self.size();
+        let temp_path = Arc::new(self.path.with_extension("compacting"));
+        self.fs.remove(&*temp_path).await?;
+        let temp = Package::open(
+            Arc::clone(&temp_path),
+            false,
+            true,
+            Arc::clone(&self.file_budget),
+            Arc::clone(&self.fs),
+        ).await?;
+
+        let mut entries = Vec::new();
+        let mut removed = 0;
+        let mut offset = 0;
+        let mut reader = read_package_from(self.open_file().await?.into_reader().await?).await?;
+        while let Some(entry) = reader.next().await? {
+            let entry_offset = offset;
+            offset += (PKG_ENTRY_HEADER_SIZE + entry.filename().len() + entry.data().len()) as u64;
+            if !is_live(entry_offset, &entry)? {
+                removed += 1;
+                continue;
+            }
+            temp.append_entry(&entry, |new_offset, _size| {
+                entries.push((entry.filename().clone(), new_offset));
+                Ok(())
+            }).await?;
+        }
+
+        if removed == 0 {
+            drop(temp);
+            self.fs.remove(&*temp_path).await?;
+            return Ok(None);
+        }
+        let payload_size = temp.size();
+        if self.is_finalized() {
+            temp.finalize().await?;
+        }
+        let size_after = temp.size();
+        let file_size = temp.size.load(Ordering::SeqCst);
+        temp.close().await;
+        drop(temp);
+
+        // Handles of the replaced file must not be reused
+        self.close_writer(&mut writer);
+        let readers = std::mem::take(&mut *self.readers.lock().expect("Poisoned Mutex"));
+        for _ in readers {
+            self.file_budget.release();
+        }
+        self.fs.rename(&*temp_path, &*self.path).await?;
+        self.size.store(file_size, Ordering::SeqCst);
+        log::info!(
+            target: "storage",
+            "Package {:?} compacted: {} entries removed, {} -> {} bytes",
+            self.path,
+            removed,
+            size_before,
+            size_after
+        );
+
+        Ok(Some(PackageCompaction { entries, removed, payload_size, size_before, size_after }))
+    }
+
+    async fn read_trailer(file: &mut dyn ArchiveFile, size: u64) -> Result<Option<PackageTrailer>> {
+        if size < PKG_HEADER_SIZE as u64 + PKG_TRAILER_ENTRY_SIZE {
+            return Ok(None);
+        }
+        let mut buf = vec![0; PKG_TRAILER_ENTRY_SIZE as usize];
+        file.read_exact_at(size - PKG_TRAILER_ENTRY_SIZE, &mut buf).await?;
+        let entry = match PackageEntry::read_from(&mut &buf[..]).await {
+            Ok(Some(entry)) if PackageTrailer::is_trailer_entry(&entry) => entry,
+            _ => return Ok(None),
+        };
+
+        Ok(PackageTrailer::from_entry(&entry).ok())
+    }
+
     pub const fn path(&self) -> &Arc<PathBuf> {
         &self.path
     }
 
+    /// Truncates the package to given size of the entries. The size of the finalized package includes
+    /// the trailer, so the package truncated to it stays finalized.
     pub async fn truncate(&self, size: u64) -> Result<()> {
         let new_size = PKG_HEADER_SIZE as u64 + size;
         log::debug!(target: "storage", "Truncating package, new size: {} bytes", new_size);
-        self.size.store(new_size, Ordering::SeqCst);
 
-        {
-            let mut file = self.open_file().await?;
-            let _write_guard = self.write_mutex.lock().await;
-            file.set_len(new_size).await?;
+        let mut writer = self.writer.lock().await;
+        self.size.store(new_size, Ordering::SeqCst);
+        let mut temporary = None;
+        let file = self.writer_file(&mut writer, &mut temporary).await?;
+        file.truncate(new_size).await?;
+        let finalized = Self::read_trailer(&mut **file, new_size).await?.is_some();
+        self.finalized.store(finalized, Ordering::SeqCst);
+        if finalized {
+            self.close_writer(&mut writer);
         }
 
         Ok(())
@@ -87,10 +355,25 @@ impl Package {
             fail!("Unexpected end of file while reading archives entry with offset: {}", offset)
         }
 
-        let mut file = self.open_file().await?;
-        file.seek(SeekFrom::Start(PKG_HEADER_SIZE as u64 + offset)).await?;
+        let (mut file, pooled) = self.take_reader().await?;
+        let result = Self::read_entry_from(&mut *file, offset).await;
+        self.put_reader(file, pooled, result.is_ok());
+
+        result
+    }
 
-        PackageEntry::read_from(&mut file).await?
+    async fn read_entry_from(file: &mut dyn ArchiveFile, offset: u64) -> Result<PackageEntry> {
+        let offset = PKG_HEADER_SIZE as u64 + offset;
+        let mut header = [0; PKG_ENTRY_HEADER_SIZE];
+        file.read_exact_at(offset, &mut header).await
+            .map_err(|err| error!("Package::read_entry: {}", err))?;
+        let entry_size = PackageEntryHeader::from_slice(&header)?.calc_entry_size();
+
+        let mut buf = vec![0; entry_size as usize];
+        file.read_exact_at(offset, &mut buf).await
+            .map_err(|err| error!("Package::read_entry: {}", err))?;
+
+        PackageEntry::read_from(&mut &buf[..]).await?
             .ok_or_else(|| error!("Package::read_entry: Unexpected end of file"))
     }
 
@@ -99,41 +382,186 @@ impl Package {
         entry: &PackageEntry,
         after_append: impl FnOnce(u64, u64) -> Result<()>
     ) -> Result<()> {
-        assert!(entry.filename().as_bytes().len() <= u16::max_value() as usize);
-        assert!(entry.data().len() <= u32::max_value() as usize);
+        let mut after_append = Some(after_append);
+        self.append_entries(std::slice::from_ref(entry), |_index, offset, size| {
+            match after_append.take() {
+                Some(after_append) => after_append(offset, size),
+                None => Ok(()),
+            }
+        }).await
+    }
 
-        let mut file = self.open_file().await?;
-        {
-            let _write_guard = self.write_mutex.lock().await;
-            file.seek(SeekFrom::End(0)).await?;
+    /// Appends several entries under one write lock; `after_append` is called with the index of the
+    /// entry and its offsets after each one is written
+    pub async fn append_entries(
+        &self,
+        entries: &[PackageEntry],
+        mut after_append: impl FnMut(usize, u64, u64) -> Result<()>
+    ) -> Result<()> {
+        for entry in entries {
+            assert!(entry.filename().as_bytes().len() <= u16::max_value() as usize);
+            assert!(entry.data().len() <= u32::max_value() as usize);
+        }
+
+        let mut writer = self.writer.lock().await;
+        if self.is_finalized() {
+            fail!("Package {:?} is finalized, {} entries can't be appended", self.path, entries.len())
+        }
+        let mut index = 0;
+        self.write_entries(&mut writer, entries.iter(), |entry_size| {
             let entry_offset = self.size();
-            let entry_size = entry.write_to(&mut file).await?;
             self.size.fetch_add(entry_size, Ordering::SeqCst);
+            index += 1;
+            after_append(index - 1, entry_offset, entry_offset + entry_size)
+        }).await?;
+
+        Ok(())
+    }
 
-            after_append(entry_offset, entry_offset + entry_size)
+    /// Closes all the file handles of the package; the handles are reopened on demand
+    pub async fn close(&self) {
+        self.close_writer(&mut *self.writer.lock().await);
+        let readers = std::mem::take(&mut *self.readers.lock().expect("Poisoned Mutex"));
+        for _ in readers {
+            self.file_budget.release();
         }
     }
 
-    async fn open_file_ext(read_only: bool, create: bool, path: impl AsRef<Path>) -> Result<File> {
-        Ok(OpenOptions::new()
-            .read(true)
-            .write(!read_only || create)
-            .create(create)
-            .open(&path).await?)
+    /// Writes entries with the writer, calling `on_written` with the size of each entry written.
+    /// Returns total size written. On failure the writer is closed, so it is repositioned when reopened.
+    async fn write_entries<'a>(
+        &self,
+        writer: &mut Option<Box<dyn ArchiveFile>>,
+        entries: impl Iterator<Item = &'a PackageEntry>,
+        on_written: impl FnMut(u64) -> Result<()>,
+    ) -> Result<u64> {
+        let mut temporary = None;
+        let file = self.writer_file(writer, &mut temporary).await?;
+        let result = Self::write_entries_to(&mut **file, entries, on_written).await;
+        if result.is_err() {
+            self.close_writer(writer);
+        }
+
+        result
+    }
+
+    async fn write_entries_to<'a>(
+        file: &mut dyn ArchiveFile,
+        entries: impl Iterator<Item = &'a PackageEntry>,
+        mut on_written: impl FnMut(u64) -> Result<()>,
+    ) -> Result<u64> {
+        let mut total_size = 0;
+        let mut buf = Vec::new();
+        for entry in entries {
+            buf.clear();
+            let entry_size = entry.write_to(&mut buf).await?;
+            // Appended entry is visible to readers using other handles
+            file.append(&buf).await?;
+            total_size += entry_size;
+            on_written(entry_size)?;
+        }
+
+        Ok(total_size)
     }
 
-    async fn open_file(&self) -> Result<File> {
-        Self::open_file_ext(self.read_only, false, &*self.path).await
+    /// Returns the writer handle, opening it if needed. The handle beyond the budget is put into
+    /// `temporary`, so it is closed after use.
+    async fn writer_file<'a>(
+        &self,
+        writer: &'a mut Option<Box<dyn ArchiveFile>>,
+        temporary: &'a mut Option<Box<dyn ArchiveFile>>,
+    ) -> Result<&'a mut Box<dyn ArchiveFile>> {
+        if writer.is_none() {
+            let file = self.open_file().await?;
+            if !self.file_budget.try_acquire() {
+                return Ok(temporary.get_or_insert(file));
+            }
+            *writer = Some(file);
+        }
+
+        Ok(writer.as_mut().expect("Writer is opened"))
+    }
+
+    fn close_writer(&self, writer: &mut Option<Box<dyn ArchiveFile>>) {
+        if writer.take().is_some() {
+            self.file_budget.release();
+        }
+    }
+
+    /// Takes reader handle from the pool or opens the new one; returns the handle and whether it
+    /// is accounted in the budget
+    async fn take_reader(&self) -> Result<(Box<dyn ArchiveFile>, bool)> {
+        if let Some(file) = self.readers.lock().expect("Poisoned Mutex").pop() {
+            return Ok((file, true));
+        }
+        let file = self.fs.open(&*self.path, true, false).await?;
+
+        Ok((file, self.file_budget.try_acquire()))
+    }
+
+    /// Returns reader handle into the pool; handles beyond the budget and the ones used by failed
+    /// reads are closed
+    fn put_reader(&self, file: Box<dyn ArchiveFile>, pooled: bool, reusable: bool) {
+        match (pooled, reusable) {
+            (true, true) => self.readers.lock().expect("Poisoned Mutex").push(file),
+            (true, false) => self.file_budget.release(),
+            (false, _) => (),
+        }
+    }
+
+    async fn open_file(&self) -> Result<Box<dyn ArchiveFile>> {
+        self.fs.open(&*self.path, self.read_only, false).await
+    }
+}
+
+impl Drop for Package {
+    fn drop(&mut self) {
+        let writer = self.writer.try_lock()
+            .map(|mut writer| writer.take().is_some())
+            .unwrap_or(false);
+        let readers = self.readers.lock()
+            .map(|readers| readers.len())
+            .unwrap_or(0);
+        for _ in 0..(writer as usize + readers) {
+            self.file_budget.release();
+        }
     }
 }
 
 pub struct PackageReader<R: AsyncReadExt + Unpin> {
     reader: BufReader<R>,
+    trailer_builder: PackageTrailerBuilder,
+    trailer: Option<PackageTrailer>,
 }
 
 impl<R: AsyncReadExt + Unpin> PackageReader<R> {
+    /// Reads next entry of the package. The trailer (if present) is verified against the entries
+    /// read and is not returned as an entry.
     pub async fn next(&mut self) -> Result<Option<PackageEntry>> {
-        PackageEntry::read_from(&mut self.reader).await
+        let entry = match PackageEntry::read_from(&mut self.reader).await? {
+            Some(entry) => entry,
+            None => return Ok(None),
+        };
+        if self.trailer.is_some() {
+            fail!("Unexpected package entry {} after the trailer", entry.filename())
+        }
+        if PackageTrailer::is_trailer_entry(&entry) {
+            let trailer = PackageTrailer::from_entry(&entry)?;
+            std::mem::take(&mut self.trailer_builder).verify(&trailer)?;
+            self.trailer = Some(trailer);
+            if let Some(entry) = PackageEntry::read_from(&mut self.reader).await? {
+                fail!("Unexpected package entry {} after the trailer", entry.filename())
+            }
+            return Ok(None);
+        }
+        self.trailer_builder.add_entry(&entry)?;
+
+        Ok(Some(entry))
+    }
+
+    /// Trailer of the package; available after all the entries have been read
+    pub fn trailer(&self) -> Option<&PackageTrailer> {
+        self.trailer.as_ref()
     }
 }
 
@@ -151,5 +579,5 @@ pub async fn read_package_from<R: AsyncReadExt + Unpin>(reader: R) -> Result<Pac
     let mut reader = BufReader::with_capacity(1 << 19, reader);
     read_header(&mut reader).await?;
 
-    Ok(PackageReader::<R> { reader })
+    Ok(PackageReader::<R> { reader, trailer_builder: PackageTrailerBuilder::default(), trailer: None })
 }
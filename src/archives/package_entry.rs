@@ -3,6 +3,7 @@ use std::io::{Read, Write};
 use tokio::io::{AsyncReadExt, AsyncWriteExt, ErrorKind};
 use ton_types::{ByteOrderRead, fail, Result};
 
+use crate::schema::{FieldSchema, FieldType, RecordSchema, RecordSchemas};
 use crate::traits::Serializable;
 
 pub(crate) const PKG_ENTRY_HEADER_SIZE: usize = 8;
@@ -26,6 +27,22 @@ impl PackageEntryHeader {
     }
 }
 
+impl RecordSchemas for PackageEntryHeader {
+    const SCHEMAS: &'static [RecordSchema] = &[
+        RecordSchema {
+            name: "PackageEntryHeader",
+            version: 0,
+            description: "Header of the package entry, followed by the filename and the data",
+            fields: &[
+                FieldSchema::new("magic", FieldType::Magic(&[0x8B, 0x1E]), "Entry magic 0x1E8B"),
+                FieldSchema::new("filename_size", FieldType::U16, "Length of the filename"),
+                FieldSchema::new("data_size", FieldType::U32, "Length of the data"),
+            ],
+            matches: |data| data.len() == PKG_ENTRY_HEADER_SIZE,
+        },
+    ];
+}
+
 impl Serializable for PackageEntryHeader {
     fn serialize<W: Write>(&self, writer: &mut W) -> Result<()> {
         writer.write_all(&PKG_ENTRY_HEADER_MAGIC.to_le_bytes())?;
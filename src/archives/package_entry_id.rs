@@ -31,6 +31,8 @@ where
 }
 
 impl PackageEntryId<BlockIdExt, UInt256, PublicKey> {
+    /// Parses entry filename produced by GetFileName (including the ones written by C++ node).
+    /// Candidate's source is restored as Ed25519 key, since the filename doesn't keep the key type.
     pub fn from_filename(filename: &str) -> Result<Self> {
         if filename == PackageEntryId::<BlockIdExt, UInt256, PublicKey>::Empty.filename_prefix() {
             return Ok(PackageEntryId::Empty);
@@ -38,96 +40,117 @@ impl PackageEntryId<BlockIdExt, UInt256, PublicKey> {
 
         let dummy = BlockIdExt::default();
 
-        if let Some(mut block_ids) = Self::parse_block_ids(
-            filename,
-            PackageEntryId::Block(&dummy),
-            1
-        )? {
-            return Ok(PackageEntryId::Block(block_ids.remove(0)));
-        }
-
-        if let Some(mut block_ids) = Self::parse_block_ids(
-            filename,
-            PackageEntryId::ZeroState(&dummy),
-            1
-        )? {
-            return Ok(PackageEntryId::ZeroState(block_ids.remove(0)));
-        }
-
-        if let Some(mut block_ids) = Self::parse_block_ids(
-            filename,
-            PackageEntryId::Proof(&dummy),
-            1
-        )? {
-            return Ok(PackageEntryId::Proof(block_ids.remove(0)));
-        }
-
-        if let Some(mut block_ids) = Self::parse_block_ids(
-            filename,
-            PackageEntryId::ProofLink(&dummy),
-            1)?
-        {
-            return Ok(PackageEntryId::ProofLink(block_ids.remove(0)));
-        }
-
-        if let Some(mut block_ids) = Self::parse_block_ids(
-            filename,
-            PackageEntryId::Signatures(&dummy),
-            1
-        )? {
-            return Ok(PackageEntryId::Signatures(block_ids.remove(0)));
+        let single_block_entries: [(PackageEntryId<&BlockIdExt, UInt256, PublicKey>, fn(BlockIdExt) -> Self); 6] = [
+            (PackageEntryId::Block(&dummy), PackageEntryId::Block),
+            (PackageEntryId::ZeroState(&dummy), PackageEntryId::ZeroState),
+            (PackageEntryId::Proof(&dummy), PackageEntryId::Proof),
+            (PackageEntryId::ProofLink(&dummy), PackageEntryId::ProofLink),
+            (PackageEntryId::Signatures(&dummy), PackageEntryId::Signatures),
+            (PackageEntryId::BlockInfo(&dummy), PackageEntryId::BlockInfo),
+        ];
+        for (dummy_entry, constructor) in single_block_entries.iter() {
+            if let Some((mut block_ids, rest)) = Self::parse_block_ids(filename, dummy_entry, 1)? {
+                Self::check_parsed(filename, rest)?;
+                return Ok(constructor(block_ids.remove(0)));
+            }
         }
 
-        if let Some(mut block_ids) = Self::parse_block_ids(
+        if let Some((mut block_ids, rest)) = Self::parse_block_ids(
             filename,
-            PackageEntryId::BlockInfo(&dummy),
-            1
-        )? {
-            return Ok(PackageEntryId::BlockInfo(block_ids.remove(0)));
-        }
-
-        if let Some(mut block_ids) = Self::parse_block_ids(
-            filename,
-            PackageEntryId::PersistentState {
+            &PackageEntryId::PersistentState {
                 mc_block_id: &dummy,
                 block_id: &dummy,
             },
             2
         )? {
+            Self::check_parsed(filename, rest)?;
             return Ok(PackageEntryId::PersistentState {
                 mc_block_id: block_ids.remove(0),
                 block_id: block_ids.remove(0),
             });
         }
 
-        if filename.starts_with(
-            PackageEntryId::<&BlockIdExt, UInt256, PublicKey>::Candidate {
+        if let Some((mut block_ids, rest)) = Self::parse_block_ids(
+            filename,
+            &PackageEntryId::<&BlockIdExt, UInt256, PublicKey>::Candidate {
                 block_id: &dummy,
                 collated_data_hash: UInt256::default(),
                 source: PublicKey::default()
-            }.filename_prefix()
-        ) {
-            fail!("Unsupported from_filename() for PackageEntryId::Candidate");
+            },
+            1
+        )? {
+            let (collated_data_hash, source) = parse_candidate_suffix(filename, rest)?;
+            return Ok(PackageEntryId::Candidate {
+                block_id: block_ids.remove(0),
+                collated_data_hash,
+                source,
+            });
         }
 
         fail!("Cannot parse filename: {}", filename)
     }
 
-    fn parse_block_ids(filename: &str, dummy: PackageEntryId<&BlockIdExt, UInt256, PublicKey>, count: usize) -> Result<Option<Vec<BlockIdExt>>> {
+    /// Parses given count of block ids separated by "_" after the entry prefix. Returns the block ids
+    /// and the rest of the filename, or None if the filename has another prefix.
+    fn parse_block_ids<'a>(
+        filename: &'a str,
+        dummy: &PackageEntryId<&BlockIdExt, UInt256, PublicKey>,
+        count: usize
+    ) -> Result<Option<(Vec<BlockIdExt>, &'a str)>> {
         let prefix = dummy.filename_prefix();
-        if !filename.starts_with(&(prefix.to_string() + "_")) {
+        if !filename.starts_with(prefix) || !filename[prefix.len()..].starts_with('_') {
             return Ok(None);
         }
 
-        let mut result = Vec::new();
-        let mut pos = prefix.len() + 1;
+        let mut result = Vec::with_capacity(count);
+        let mut rest = &filename[prefix.len()..];
         for _ in 0..count {
-            let (block_id, len) = parse_block_id(&filename[pos..filename.len()])?;
+            if !rest.starts_with('_') {
+                fail!("Incorrect filename format: {}", filename);
+            }
+            let (block_id, len) = parse_block_id(&rest[1..])?;
             result.push(block_id);
-            pos += len + 1;
+            rest = &rest[len + 1..];
+        }
+
+        Ok(Some((result, rest)))
+    }
+
+    fn check_parsed(filename: &str, rest: &str) -> Result<()> {
+        if !rest.is_empty() {
+            fail!("Unexpected trailing characters in filename: {}", filename);
         }
 
-        Ok(Some(result))
+        Ok(())
+    }
+}
+
+/// Parses "_{collated data hash}_{base64 source key}" suffix of candidate's filename
+fn parse_candidate_suffix(filename: &str, rest: &str) -> Result<(UInt256, PublicKey)> {
+    let mut parts = rest.splitn(3, '_');
+    let (collated_data_hash, source) = match (parts.next(), parts.next(), parts.next()) {
+        (Some(""), Some(hash), Some(source)) => (hash, source),
+        _ => fail!("Incorrect candidate filename format: {}", filename),
+    };
+
+    let collated_data_hash = UInt256::from_str(collated_data_hash)?;
+    let key = base64::decode(source)
+        .map_err(|err| error!("Incorrect candidate source in filename {}: {}", filename, err))?;
+    if key.len() != 32 {
+        fail!("Incorrect candidate source key length in filename {}: {}", filename, key.len());
+    }
+    let mut key_bytes = [0; 32];
+    key_bytes.copy_from_slice(&key);
+    let source = PublicKey::Pub_Ed25519(ton_api::ton::pub_::publickey::Ed25519 {
+        key: ton_api::ton::int256(key_bytes)
+    });
+
+    Ok((collated_data_hash, source))
+}
+
+impl FromFileName for PackageEntryId<BlockIdExt, UInt256, PublicKey> {
+    fn from_filename(filename: &str) -> Result<Self> {
+        PackageEntryId::from_filename(filename)
     }
 }
 
@@ -174,7 +197,7 @@ impl GetFileName for BlockIdExt {
 
 fn parse_block_id(filename: &str) -> Result<(BlockIdExt, usize)> {
     lazy_static! {
-            static ref REGEX: Regex = Regex::new(r"^\((-?\d+),([0-9a-f]{16}),(\d+)\):([0-9A-F]{64}):([0-9A-F]{64})")
+            static ref REGEX: Regex = Regex::new(r"^\((-?\d+),([0-9a-fA-F]{16}),(\d+)\):([0-9a-fA-F]{64}):([0-9a-fA-F]{64})")
                 .expect("Failed to compile regular expression");
         }
 
@@ -257,15 +280,19 @@ pub trait GetFileNameShort {
     fn filename_short(&self) -> String;
 }
 
+pub(crate) fn block_id_short_hash(block_id: &BlockIdExt) -> u64 {
+    let mut hasher = DefaultHasher::new();
+    block_id.hash(&mut hasher);
+    hasher.finish()
+}
+
 impl GetFileNameShort for BlockIdExt {
     fn filename_short(&self) -> String {
-        let mut hasher = DefaultHasher::new();
-        self.hash(&mut hasher);
         format!("{wc_id}_{shard_id:X}_{seq_no}_{hash:X}",
                 wc_id = self.shard().workchain_id(),
                 shard_id = self.shard().shard_prefix_with_tag(),
                 seq_no = self.seq_no(),
-                hash = hasher.finish(),
+                hash = block_id_short_hash(self),
         )
     }
 }
@@ -316,3 +343,99 @@ where
         f.write_str(self.filename().as_str())
     }
 }
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    type EntryId = PackageEntryId<BlockIdExt, UInt256, PublicKey>;
+
+    fn block_id(workchain_id: i32, shard_prefix_tagged: u64, seq_no: u32) -> BlockIdExt {
+        BlockIdExt {
+            shard_id: ShardIdent::with_tagged_prefix(workchain_id, shard_prefix_tagged).unwrap(),
+            seq_no,
+            root_hash: UInt256::from([0x1a; 32]),
+            file_hash: UInt256::from([0xf2; 32]),
+        }
+    }
+
+    fn assert_round_trip(entry_id: EntryId) {
+        let filename = entry_id.filename();
+        assert_eq!(EntryId::from_filename(&filename).unwrap(), entry_id, "{}", filename);
+    }
+
+    #[test]
+    fn single_block_entries_round_trip() {
+        let mc_block_id = block_id(-1, 0x8000_0000_0000_0000, 100);
+        let shard_block_id = block_id(0, 0x4000_0000_0000_0000, 12345);
+        for id in [mc_block_id, shard_block_id].iter() {
+            assert_round_trip(PackageEntryId::Block(id.clone()));
+            assert_round_trip(PackageEntryId::ZeroState(id.clone()));
+            assert_round_trip(PackageEntryId::Proof(id.clone()));
+            assert_round_trip(PackageEntryId::ProofLink(id.clone()));
+            assert_round_trip(PackageEntryId::Signatures(id.clone()));
+            assert_round_trip(PackageEntryId::BlockInfo(id.clone()));
+        }
+        assert_round_trip(PackageEntryId::Empty);
+    }
+
+    #[test]
+    fn persistent_state_keeps_key_block_pairing() {
+        let mc_block_id = block_id(-1, 0x8000_0000_0000_0000, 100);
+        let shard_block_id = block_id(0, 0x8000_0000_0000_0000, 90);
+        let filename = EntryId::PersistentState {
+            mc_block_id: mc_block_id.clone(),
+            block_id: shard_block_id.clone(),
+        }.filename();
+
+        match EntryId::from_filename(&filename).unwrap() {
+            PackageEntryId::PersistentState { mc_block_id: parsed_mc_block_id, block_id: parsed_block_id } => {
+                assert_eq!(parsed_mc_block_id, mc_block_id);
+                assert_eq!(parsed_block_id, shard_block_id);
+            }
+            entry_id => panic!("Unexpected entry {:?}", entry_id),
+        }
+    }
+
+    #[test]
+    fn candidate_round_trip() {
+        let source = PublicKey::Pub_Ed25519(ton_api::ton::pub_::publickey::Ed25519 {
+            key: ton_api::ton::int256([7; 32])
+        });
+        assert_round_trip(PackageEntryId::Candidate {
+            block_id: block_id(0, 0x8000_0000_0000_0000, 5),
+            collated_data_hash: UInt256::from([3; 32]),
+            source,
+        });
+    }
+
+    #[test]
+    fn legacy_lowercase_hashes_are_parsed() {
+        let id = block_id(-1, 0x8000_0000_0000_0000, 7);
+        let filename = format!(
+            "proof_(-1,8000000000000000,7):{}:{}",
+            hex::encode(id.root_hash().as_slice()),
+            hex::encode(id.file_hash().as_slice()),
+        );
+
+        assert_eq!(EntryId::from_filename(&filename).unwrap(), PackageEntryId::Proof(id));
+    }
+
+    #[test]
+    fn prooflink_is_not_parsed_as_proof() {
+        let id = block_id(0, 0x8000_0000_0000_0000, 7);
+        let filename = EntryId::ProofLink(id.clone()).filename();
+
+        assert_eq!(EntryId::from_filename(&filename).unwrap(), PackageEntryId::ProofLink(id));
+    }
+
+    #[test]
+    fn malformed_filenames_are_rejected() {
+        let filename = EntryId::Block(block_id(0, 0x8000_0000_0000_0000, 7)).filename();
+
+        assert!(EntryId::from_filename(&format!("{}_tail", filename)).is_err());
+        assert!(EntryId::from_filename(&filename.replacen("block", "unknown", 1)).is_err());
+        assert!(EntryId::from_filename(&filename[..filename.len() - 1]).is_err());
+        assert!(EntryId::from_filename("block").is_err());
+    }
+}
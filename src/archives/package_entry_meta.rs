@@ -1,16 +1,46 @@
 use serde_derive::{Deserialize, Serialize};
 
-#[derive(Debug, Serialize, Deserialize)]
+use crate::archives::package_id::PackageType;
+
+/// Metadata of the package of the archive slice. Fields appended later are absent in the records
+/// written by older versions (and take default values then); fields unknown to the reader are
+/// skipped, so the record may be extended further.
+#[derive(Debug, Clone, Serialize, Deserialize)]
 pub struct PackageEntryMeta {
     entry_size: u64,
     version: u32,
+    #[serde(default)]
+    appended_at: u32,
+    #[serde(default)]
+    payload_size: u64,
+    #[serde(default)]
+    entry_count: u32,
+    #[serde(default)]
+    entry_type: Option<PackageType>,
+    #[serde(default)]
+    finalized: bool,
 }
 
 impl PackageEntryMeta {
     pub const fn with_data(entry_size: u64, version: u32) -> Self {
-        Self { entry_size, version }
+        Self {
+            entry_size,
+            version,
+            appended_at: 0,
+            payload_size: 0,
+            entry_count: 0,
+            entry_type: None,
+            finalized: false,
+        }
+    }
+
+    pub fn with_type(entry_size: u64, version: u32, entry_type: PackageType) -> Self {
+        let mut meta = Self::with_data(entry_size, version);
+        meta.entry_type = Some(entry_type);
+        meta
     }
 
+    /// Size of the package's entries (excluding the package header)
     pub const fn entry_size(&self) -> u64 {
         self.entry_size
     }
@@ -18,4 +48,52 @@ impl PackageEntryMeta {
     pub const fn version(&self) -> u32 {
         self.version
     }
+
+    /// Unix time of the last append to the package; zero if nothing is appended since the field was
+    /// introduced
+    pub const fn appended_at(&self) -> u32 {
+        self.appended_at
+    }
+
+    /// Total size of the data of the appended entries, including overwritten copies
+    pub const fn payload_size(&self) -> u64 {
+        self.payload_size
+    }
+
+    /// Count of the appended entries, including overwritten copies
+    pub const fn entry_count(&self) -> u32 {
+        self.entry_count
+    }
+
+    /// Type of the package; None in the records written by older versions
+    pub const fn entry_type(&self) -> Option<PackageType> {
+        self.entry_type
+    }
+
+    pub const fn finalized(&self) -> bool {
+        self.finalized
+    }
+
+    /// Accounts the entry appended to the package; `entry_size` is the new size of the package
+    pub fn on_appended(&mut self, entry_size: u64, payload_size: u64, appended_at: u32) {
+        self.entry_size = entry_size;
+        self.payload_size += payload_size;
+        self.entry_count += 1;
+        self.appended_at = appended_at;
+    }
+
+    /// Accounts the compaction of the package, which leaves given count of entries of given payload
+    /// size; `entry_size` is the new size of the package (including the trailer, if any)
+    pub fn on_compacted(&mut self, entry_size: u64, entry_count: usize, payload_size: u64) {
+        self.entry_size = entry_size;
+        self.entry_count = entry_count as u32;
+        self.payload_size = payload_size;
+    }
+
+    /// Marks the package finalized; `entry_size` is the size of the package including the trailer,
+    /// so the trailer is kept when the package is reopened
+    pub fn set_finalized(&mut self, entry_size: u64) {
+        self.entry_size = entry_size;
+        self.finalized = true;
+    }
 }
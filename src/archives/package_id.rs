@@ -63,11 +63,14 @@ impl PackageId {
         self.id == u32::max_value()
     }
 
+    /// Relative directory of the package, built of components in order to be platform-independent
     pub fn path(&self) -> PathBuf {
         match self.package_type {
-            PackageType::Temp => "files/packages/".into(),
-            PackageType::KeyBlocks => format!("archive/packages/key{id:03}/", id = self.id / 1_000_000).into(),
-            PackageType::Blocks => format!("archive/packages/arch{id:04}/", id = self.id / 100_000).into(),
+            PackageType::Temp => ["files", "packages"].iter().collect(),
+            PackageType::KeyBlocks =>
+                ["archive", "packages", format!("key{id:03}", id = self.id / 1_000_000).as_str()].iter().collect(),
+            PackageType::Blocks =>
+                ["archive", "packages", format!("arch{id:04}", id = self.id / 100_000).as_str()].iter().collect(),
         }
     }
 
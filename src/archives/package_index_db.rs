@@ -1,4 +1,5 @@
 use std::convert::TryInto;
+use std::path::PathBuf;
 
 use serde_derive::{Deserialize, Serialize};
 
@@ -11,6 +12,9 @@ use crate::db_impl_cbor;
 pub struct PackageIndexEntry {
     deleted: bool,
     finalized: bool,
+    /// Root directory of the package files, if they are kept outside of the database root
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    packages_root: Option<PathBuf>,
 }
 
 impl PackageIndexEntry {
@@ -19,7 +23,16 @@ impl PackageIndexEntry {
     }
 
     pub const fn with_data(deleted: bool, finalized: bool) -> Self {
-        Self { deleted, finalized }
+        Self { deleted, finalized, packages_root: None }
+    }
+
+    pub fn with_packages_root(mut self, packages_root: Option<PathBuf>) -> Self {
+        self.packages_root = packages_root;
+        self
+    }
+
+    pub fn packages_root(&self) -> Option<&PathBuf> {
+        self.packages_root.as_ref()
     }
 
     pub const fn deleted(&self) -> bool {
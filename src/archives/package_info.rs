@@ -1,4 +1,7 @@
+use std::sync::{Mutex, MutexGuard};
+
 use crate::archives::package::Package;
+use crate::archives::package_entry_meta::PackageEntryMeta;
 use crate::archives::package_id::PackageId;
 
 #[derive(Debug)]
@@ -7,14 +10,15 @@ pub struct PackageInfo {
     package: Package,
     idx: u32,
     version: u32,
+    // Copy of the package's metadata stored in the slice's index, updated together with it
+    meta: Mutex<PackageEntryMeta>,
 }
 
 impl PackageInfo {
-    pub const fn with_data(package_id: PackageId, package: Package, idx: u32, version: u32) -> Self {
-        Self { package_id, package, idx, version }
+    pub const fn with_data(package_id: PackageId, package: Package, idx: u32, version: u32, meta: PackageEntryMeta) -> Self {
+        Self { package_id, package, idx, version, meta: Mutex::new(meta) }
     }
 
-    #[allow(dead_code)]
     pub const fn package_id(&self) -> &PackageId {
         &self.package_id
     }
@@ -30,4 +34,9 @@ impl PackageInfo {
     pub const fn version(&self) -> u32 {
         self.version
     }
+
+    /// Metadata of the package; the index must be updated while the guard is held
+    pub fn meta(&self) -> MutexGuard<'_, PackageEntryMeta> {
+        self.meta.lock().expect("Poisoned Mutex")
+    }
 }
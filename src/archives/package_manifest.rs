@@ -0,0 +1,74 @@
+use std::path::Path;
+
+use serde_derive::{Deserialize, Serialize};
+use sha2::{Digest, Sha256};
+use tokio::io::AsyncReadExt;
+
+use ton_types::Result;
+
+use crate::archives::package_entry::PackageEntry;
+
+/// Description of the package entry in the manifest
+#[derive(Debug, Clone, PartialEq, Serialize, Deserialize)]
+pub struct PackageManifestEntry {
+    pub filename: String,
+    pub size: u64,
+    /// Hex-encoded SHA-256 of the entry data
+    pub sha256: String,
+}
+
+impl PackageManifestEntry {
+    pub fn from_entry(entry: &PackageEntry) -> Self {
+        Self {
+            filename: entry.filename().clone(),
+            size: entry.data().len() as u64,
+            sha256: hex::encode(Sha256::digest(entry.data())),
+        }
+    }
+}
+
+/// Manifest accompanying exported package, intended for out-of-band distribution
+#[derive(Debug, Clone, PartialEq, Serialize, Deserialize)]
+pub struct PackageManifest {
+    pub archive_id: u64,
+    /// Name of the package file
+    pub package: String,
+    /// Size of the package file in bytes
+    pub size: u64,
+    /// Hex-encoded SHA-256 of the whole package file
+    pub sha256: String,
+    pub entries: Vec<PackageManifestEntry>,
+}
+
+impl PackageManifest {
+    pub async fn write_to_file(&self, path: impl AsRef<Path>) -> Result<()> {
+        tokio::fs::write(path, serde_json::to_vec_pretty(self)?).await?;
+
+        Ok(())
+    }
+
+    pub async fn read_from_file(path: impl AsRef<Path>) -> Result<Self> {
+        Ok(serde_json::from_slice(&tokio::fs::read(path).await?)?)
+    }
+}
+
+/// Calculates size and SHA-256 of the file, reading it by chunks
+pub(crate) async fn file_digest(path: impl AsRef<Path>) -> Result<(u64, [u8; 32])> {
+    let mut file = tokio::fs::File::open(path).await?;
+    let mut hasher = Sha256::new();
+    let mut buf = vec![0; 1 << 20];
+    let mut size = 0;
+    loop {
+        let read = file.read(&mut buf).await?;
+        if read == 0 {
+            break;
+        }
+        hasher.input(&buf[..read]);
+        size += read as u64;
+    }
+
+    let mut digest = [0; 32];
+    digest.copy_from_slice(hasher.result().as_slice());
+
+    Ok((size, digest))
+}
@@ -2,20 +2,46 @@ use std::borrow::Borrow;
 use std::collections::hash_map::DefaultHasher;
 use std::hash::{Hash, Hasher};
 
+use sha2::{Digest, Sha256};
 use ton_api::ton::PublicKey;
 use ton_block::BlockIdExt;
 use ton_types::UInt256;
 
-use crate::archives::package_entry_id::PackageEntryId;
-use crate::db::traits::{DbKey, KvcWriteable};
+use crate::archives::package_entry_id::{GetFileName, PackageEntryId};
+use crate::db::traits::{DbKey, KvcTransactional};
 use crate::db_impl_cbor;
 
+/// Version of the offsets database keys: SHA-256 of the entry filename. Version 0 (no version
+/// stored) used 8-byte DefaultHasher hash of the entry id, which is subject to collisions.
+pub const PACKAGE_OFFSETS_VERSION: u32 = 1;
+
+/// Key of the entry offset: SHA-256 of the entry filename
+#[derive(Debug, Clone, PartialEq, Eq, Hash)]
 pub struct PackageOffsetKey {
-    entry_id_hash: [u8; 8],
+    key: Vec<u8>,
 }
 
 impl PackageOffsetKey {
     pub fn from_entry_type<B, U256, PK>(entry_id: &PackageEntryId<B, U256, PK>) -> Self
+    where
+        B: Borrow<BlockIdExt> + Hash,
+        U256: Borrow<UInt256> + Hash,
+        PK: Borrow<PublicKey> + Hash
+    {
+        Self::from_filename(&entry_id.filename())
+    }
+
+    pub fn from_filename(filename: &str) -> Self {
+        Self { key: Sha256::digest(filename.as_bytes()).to_vec() }
+    }
+
+    /// Key as stored in the database, of any version (used to remove keys of the previous version)
+    pub(crate) fn from_raw(key: &[u8]) -> Self {
+        Self { key: key.to_vec() }
+    }
+
+    /// Key of version 0, used only for detection of the collisions during migration
+    pub(crate) fn legacy_key<B, U256, PK>(entry_id: &PackageEntryId<B, U256, PK>) -> [u8; 8]
     where
         B: Borrow<BlockIdExt> + Hash,
         U256: Borrow<UInt256> + Hash,
@@ -24,7 +50,7 @@ impl PackageOffsetKey {
         let mut hasher = DefaultHasher::new();
         entry_id.hash(&mut hasher);
 
-        Self { entry_id_hash: hasher.finish().to_le_bytes() }
+        hasher.finish().to_le_bytes()
     }
 }
 
@@ -45,8 +71,8 @@ impl DbKey for PackageOffsetKey {
     }
 
     fn key(&self) -> &[u8] {
-        &self.entry_id_hash
+        &self.key
     }
 }
 
-db_impl_cbor!(PackageOffsetsDb, KvcWriteable, PackageOffsetKey, u64);
+db_impl_cbor!(PackageOffsetsDb, KvcTransactional, PackageOffsetKey, u64);
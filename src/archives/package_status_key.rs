@@ -8,6 +8,8 @@ pub enum PackageStatusKey {
     SliceSize,
     NonSlicedSize,
     TotalSlices,
+    OffsetsVersion,
+    FileHashesIndexed,
 }
 
 impl DbKey for PackageStatusKey {
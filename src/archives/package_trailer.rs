@@ -0,0 +1,134 @@
+use std::io::{Read, Write};
+
+use sha2::{Digest, Sha256};
+use ton_types::{ByteOrderRead, fail, Result};
+
+use crate::archives::package_entry::{PackageEntry, PackageEntryHeader, PKG_ENTRY_HEADER_SIZE};
+use crate::traits::Serializable;
+
+/// Reserved filename of the package entry holding the trailer
+pub const PKG_TRAILER_FILENAME: &str = "package.trailer";
+const PKG_TRAILER_MAGIC: u32 = 0x7A11_E9D5;
+const PKG_TRAILER_DATA_SIZE: usize = 4 + 4 + 8 + 32;
+/// Full size of the trailer entry (header, filename and data)
+pub(crate) const PKG_TRAILER_ENTRY_SIZE: u64 =
+    (PKG_ENTRY_HEADER_SIZE + PKG_TRAILER_FILENAME.len() + PKG_TRAILER_DATA_SIZE) as u64;
+
+/// End-of-archive record, appended as the last entry of finalized package
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub struct PackageTrailer {
+    entry_count: u32,
+    payload_size: u64,
+    checksum: [u8; 32],
+}
+
+impl PackageTrailer {
+    pub const fn with_data(entry_count: u32, payload_size: u64, checksum: [u8; 32]) -> Self {
+        Self { entry_count, payload_size, checksum }
+    }
+
+    /// Count of entries preceding the trailer
+    pub const fn entry_count(&self) -> u32 {
+        self.entry_count
+    }
+
+    /// Total size of the entries preceding the trailer, including their headers
+    pub const fn payload_size(&self) -> u64 {
+        self.payload_size
+    }
+
+    /// SHA-256 of the entries preceding the trailer
+    pub const fn checksum(&self) -> &[u8; 32] {
+        &self.checksum
+    }
+
+    pub(crate) fn is_trailer_entry(entry: &PackageEntry) -> bool {
+        entry.filename() == PKG_TRAILER_FILENAME
+    }
+
+    pub(crate) fn from_entry(entry: &PackageEntry) -> Result<Self> {
+        if !Self::is_trailer_entry(entry) {
+            fail!("Package entry {} is not a trailer", entry.filename())
+        }
+        Self::from_slice(entry.data())
+    }
+
+    pub(crate) fn to_entry(&self) -> Result<PackageEntry> {
+        Ok(PackageEntry::with_data(PKG_TRAILER_FILENAME.to_string(), self.to_vec()?))
+    }
+}
+
+impl Serializable for PackageTrailer {
+    fn serialize<W: Write>(&self, writer: &mut W) -> Result<()> {
+        writer.write_all(&PKG_TRAILER_MAGIC.to_le_bytes())?;
+        writer.write_all(&self.entry_count.to_le_bytes())?;
+        writer.write_all(&self.payload_size.to_le_bytes())?;
+        writer.write_all(&self.checksum)?;
+
+        Ok(())
+    }
+
+    fn deserialize<R: Read>(reader: &mut R) -> Result<Self> where Self: Sized {
+        let magic = reader.read_le_u32()?;
+        if magic != PKG_TRAILER_MAGIC {
+            fail!("Bad package trailer magic: 0x{:X}", magic)
+        }
+
+        let entry_count = reader.read_le_u32()?;
+        let payload_size = reader.read_le_u64()?;
+        let mut checksum = [0; 32];
+        reader.read_exact(&mut checksum)?;
+
+        Ok(Self::with_data(entry_count, payload_size, checksum))
+    }
+}
+
+/// Accumulates trailer values over the sequence of package entries
+#[derive(Default)]
+pub(crate) struct PackageTrailerBuilder {
+    entry_count: u32,
+    payload_size: u64,
+    hasher: Sha256,
+}
+
+impl PackageTrailerBuilder {
+    pub fn add_entry(&mut self, entry: &PackageEntry) -> Result<()> {
+        let header = PackageEntryHeader::with_data(
+            entry.filename().as_bytes().len() as u16,
+            entry.data().len() as u32
+        );
+        self.hasher.input(&header.to_vec()?);
+        self.hasher.input(entry.filename().as_bytes());
+        self.hasher.input(entry.data());
+        self.entry_count += 1;
+        self.payload_size += header.calc_entry_size();
+
+        Ok(())
+    }
+
+    pub const fn payload_size(&self) -> u64 {
+        self.payload_size
+    }
+
+    pub fn build(self) -> PackageTrailer {
+        let mut checksum = [0; 32];
+        checksum.copy_from_slice(self.hasher.result().as_slice());
+        PackageTrailer::with_data(self.entry_count, self.payload_size, checksum)
+    }
+
+    /// Checks that the trailer matches the entries passed so far
+    pub fn verify(self, trailer: &PackageTrailer) -> Result<()> {
+        let expected = self.build();
+        if &expected != trailer {
+            fail!(
+                "Package trailer mismatch: stored {} entries ({} bytes), read {} entries ({} bytes)",
+                trailer.entry_count(),
+                trailer.payload_size(),
+                expected.entry_count(),
+                expected.payload_size()
+            )
+        }
+
+        Ok(())
+    }
+}
@@ -0,0 +1,80 @@
+use std::ops::RangeInclusive;
+use std::path::Path;
+use std::sync::RwLock;
+
+use ton_types::Result;
+
+use crate::db::traits::{KvcWriteable, U32Key};
+use crate::db_impl_cbor;
+
+// Start of the pruned range -> end of the range (inclusive)
+db_impl_cbor!(PrunedArchivesDb, KvcWriteable, U32Key, u32);
+
+/// Registry of the masterchain seq_no ranges covered by the deleted archives, so reads of the
+/// pruned blocks are told apart from reads of the blocks which never were stored
+#[derive(Debug)]
+pub struct PrunedArchives {
+    db: PrunedArchivesDb,
+    // Sorted by start, non-overlapping
+    ranges: RwLock<Vec<RangeInclusive<u32>>>,
+}
+
+impl PrunedArchives {
+    pub fn with_path(path: impl AsRef<Path>) -> Result<Self> {
+        let db = PrunedArchivesDb::with_path(path);
+        let mut ranges = Vec::new();
+        db.for_each(&mut |key, value| {
+            let mut start = [0; 4];
+            start.copy_from_slice(key);
+            ranges.push(u32::from_le_bytes(start)..=serde_cbor::from_slice::<u32>(value)?);
+            Ok(true)
+        })?;
+        ranges.sort_by_key(|range| *range.start());
+
+        Ok(Self { db, ranges: RwLock::new(ranges) })
+    }
+
+    /// Records the range of the archive being deleted
+    pub fn add(&self, range: RangeInclusive<u32>) -> Result<()> {
+        self.db.put_value(&U32Key::with_value(*range.start()), range.end())?;
+        let mut ranges = self.ranges.write().expect("Poisoned RwLock");
+        let index = match ranges.binary_search_by_key(range.start(), |range| *range.start()) {
+            Ok(index) => {
+                ranges.remove(index);
+                index
+            }
+            Err(index) => index,
+        };
+        ranges.insert(index, range);
+
+        Ok(())
+    }
+
+    /// Forgets the range starting at given seq_no, e.g. when the archive is created again
+    pub fn remove(&self, start: u32) -> Result<()> {
+        let mut ranges = self.ranges.write().expect("Poisoned RwLock");
+        if let Ok(index) = ranges.binary_search_by_key(&start, |range| *range.start()) {
+            self.db.delete(&U32Key::with_value(start))?;
+            ranges.remove(index);
+        }
+
+        Ok(())
+    }
+
+    /// Pruned range containing given masterchain seq_no, if any
+    pub fn find(&self, mc_seq_no: u32) -> Option<RangeInclusive<u32>> {
+        let ranges = self.ranges.read().expect("Poisoned RwLock");
+        let index = match ranges.binary_search_by_key(&mc_seq_no, |range| *range.start()) {
+            Ok(index) => index,
+            Err(0) => return None,
+            Err(index) => index - 1,
+        };
+
+        Some(ranges[index].clone()).filter(|range| range.contains(&mc_seq_no))
+    }
+
+    /// All the pruned ranges in the ascending order
+    pub fn ranges(&self) -> Vec<RangeInclusive<u32>> {
+        self.ranges.read().expect("Poisoned RwLock").clone()
+    }
+}
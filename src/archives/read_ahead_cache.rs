@@ -0,0 +1,106 @@
+use std::collections::VecDeque;
+use std::path::Path;
+use std::sync::Arc;
+
+use serde_derive::{Deserialize, Serialize};
+use tokio::sync::Mutex;
+use ton_types::Result;
+
+use crate::archives::archive_fs::{ArchiveFile, ArchiveFs};
+
+/// Configuration of the read-ahead cache of archive slices
+#[derive(Debug, Clone, Deserialize, Serialize)]
+#[serde(default)]
+pub struct ReadAheadConfig {
+    /// Size of the file region read at once. Requests not exceeding this size are served from the buffer.
+    pub buffer_size: usize,
+    /// Maximal count of packages (files) cached per slice; zero disables caching
+    pub max_files: usize,
+}
+
+impl Default for ReadAheadConfig {
+    fn default() -> Self {
+        Self { buffer_size: 4 << 20, max_files: 2 }
+    }
+}
+
+#[derive(Debug)]
+struct CachedRegion {
+    archive_id: u64,
+    file: Box<dyn ArchiveFile>,
+    offset: u64,
+    data: Vec<u8>,
+}
+
+impl CachedRegion {
+    fn covers(&self, offset: u64, limit: u32) -> bool {
+        offset >= self.offset && offset + limit as u64 <= self.offset + self.data.len() as u64
+    }
+
+    fn slice(&self, offset: u64, limit: u32) -> Vec<u8> {
+        let start = std::cmp::min((offset - self.offset) as usize, self.data.len());
+        let end = std::cmp::min(start + limit as usize, self.data.len());
+        self.data[start..end].to_vec()
+    }
+}
+
+/// Keeps opened files and the last read regions of the packages, so consecutive chunk requests
+/// of syncing peers don't reopen and reread the files. Entries are evicted in the LRU order.
+#[derive(Debug)]
+pub(crate) struct ReadAheadCache {
+    config: ReadAheadConfig,
+    regions: Mutex<VecDeque<CachedRegion>>,
+    fs: Arc<dyn ArchiveFs>,
+}
+
+impl ReadAheadCache {
+    pub fn new(config: ReadAheadConfig, fs: Arc<dyn ArchiveFs>) -> Self {
+        Self { config, regions: Mutex::new(VecDeque::new()), fs }
+    }
+
+    /// Reads up to `limit` bytes at `offset` of the package file with given archive_id
+    pub async fn read(&self, archive_id: u64, path: &Path, offset: u64, limit: u32) -> Result<Vec<u8>> {
+        if self.config.max_files == 0 {
+            let mut file = self.fs.open(path, true, false).await?;
+            return read_region(&mut *file, offset, limit as usize).await;
+        }
+
+        let mut regions = self.regions.lock().await;
+        let mut region = match regions.iter().position(|region| region.archive_id == archive_id) {
+            Some(index) => regions.remove(index).expect("Index must be valid"),
+            None => CachedRegion {
+                archive_id,
+                file: self.fs.open(path, true, false).await?,
+                offset: 0,
+                data: Vec::new(),
+            },
+        };
+
+        if region.covers(offset, limit) {
+            log::trace!(target: "storage", "Read-ahead cache hit: archive_id = {}, offset = {}", archive_id, offset);
+        } else {
+            let size = std::cmp::max(limit as usize, self.config.buffer_size);
+            region.data = read_region(&mut *region.file, offset, size).await?;
+            region.offset = offset;
+        }
+
+        let result = region.slice(offset, limit);
+        regions.push_front(region);
+        regions.truncate(self.config.max_files);
+
+        Ok(result)
+    }
+
+    /// Drops all the cached regions and files (e.g. after the packages are rewritten)
+    pub async fn clear(&self) {
+        self.regions.lock().await.clear();
+    }
+}
+
+async fn read_region(file: &mut dyn ArchiveFile, offset: u64, size: usize) -> Result<Vec<u8>> {
+    let mut buffer = vec![0; size];
+    let actual_read = file.read_at(offset, &mut buffer).await?;
+    buffer.truncate(actual_read);
+
+    Ok(buffer)
+}
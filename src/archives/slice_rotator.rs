@@ -0,0 +1,206 @@
+use std::path::PathBuf;
+use std::sync::Arc;
+
+use tokio::sync::{Mutex, MutexGuard};
+use ton_types::{error, Result};
+
+use crate::archives::archive_fs::ArchiveFs;
+use crate::archives::archive_manager::ARCHIVE_SIZE;
+use crate::archives::archive_slice::ArchiveSlice;
+use crate::archives::package::FileBudget;
+use crate::archives::package_id::PackageId;
+use crate::archives::read_ahead_cache::ReadAheadConfig;
+use crate::archives::storage_pools::StoragePoolsConfig;
+use crate::background_tasks::BackgroundTasks;
+
+/// The next archive is prepared when the block this close to its first one is written
+const PREPARE_MARGIN: u32 = (ARCHIVE_SIZE / 20) as u32;
+
+/// Chooses the package the entries of the masterchain block go to. Key blocks open the archive of
+/// their own; other blocks go to the archive of their `ARCHIVE_SIZE` boundary, or to the latest
+/// archive opened by a key block after it.
+pub fn target_package(mc_seq_no: u32, is_key: bool, closest: Option<PackageId>) -> PackageId {
+    if is_key {
+        return PackageId::for_block(mc_seq_no);
+    }
+
+    let package_id = PackageId::for_block(mc_seq_no - (mc_seq_no % ARCHIVE_SIZE as u32));
+    match closest {
+        Some(closest) if package_id < closest => closest,
+        _ => package_id,
+    }
+}
+
+/// Creates archive slices with the manager's configuration
+#[derive(Clone)]
+struct SliceFactory {
+    db_root_path: Arc<PathBuf>,
+    read_ahead_config: ReadAheadConfig,
+    file_budget: Arc<FileBudget>,
+    fs: Arc<dyn ArchiveFs>,
+    storage_pools: Arc<StoragePoolsConfig>,
+}
+
+impl SliceFactory {
+    async fn create(&self, package_id: &PackageId) -> Result<Arc<ArchiveSlice>> {
+        tokio::fs::create_dir_all(self.db_root_path.join(package_id.path())).await?;
+        // The new archive is the newest one, so only the pools of the id ranges may take it
+        let packages_root = match self.storage_pools.pool_for(package_id.id(), package_id.id()) {
+            Some(pool) => {
+                tokio::fs::create_dir_all(pool.root.join(package_id.path())).await?;
+                Arc::new(pool.root.clone())
+            }
+            None => Arc::clone(&self.db_root_path),
+        };
+
+        Ok(Arc::new(ArchiveSlice::with_data(
+            Arc::clone(&self.db_root_path),
+            packages_root,
+            package_id.id(),
+            package_id.package_type(),
+            false,
+            self.read_ahead_config.clone(),
+            Arc::clone(&self.file_budget),
+            Arc::clone(&self.fs),
+        ).await?))
+    }
+}
+
+/// State guarded by the rotation lock
+#[derive(Default)]
+pub struct RotationState {
+    /// Next archive requested to be prepared in the background
+    requested: Option<PackageId>,
+    /// Next archive prepared in the background, not registered yet
+    prepared: Option<(PackageId, Arc<ArchiveSlice>)>,
+}
+
+/// Serializes the choice of the package for the written entries with the creation of new archives,
+/// so concurrent writers near the boundary agree on the target package and the archive is never
+/// created twice. The next archive is created in advance in the background (if the background
+/// tasks are set), so writers crossing the boundary don't wait for it.
+pub struct SliceRotator {
+    state: Arc<Mutex<RotationState>>,
+    factory: SliceFactory,
+    background_tasks: Option<Arc<BackgroundTasks>>,
+}
+
+impl SliceRotator {
+    pub fn new(
+        db_root_path: Arc<PathBuf>,
+        read_ahead_config: ReadAheadConfig,
+        file_budget: Arc<FileBudget>,
+        fs: Arc<dyn ArchiveFs>,
+    ) -> Self {
+        Self {
+            state: Arc::new(Mutex::new(RotationState::default())),
+            factory: SliceFactory {
+                db_root_path,
+                read_ahead_config,
+                file_budget,
+                fs,
+                storage_pools: Arc::new(StoragePoolsConfig::default()),
+            },
+            background_tasks: None,
+        }
+    }
+
+    pub fn set_background_tasks(&mut self, background_tasks: Arc<BackgroundTasks>) {
+        self.background_tasks = Some(background_tasks);
+    }
+
+    /// Sets the storage pools new archives are placed into
+    pub fn set_storage_pools(&mut self, storage_pools: Arc<StoragePoolsConfig>) {
+        self.factory.storage_pools = storage_pools;
+    }
+
+    /// Acquires the rotation lock; the package must be chosen and created while it is held
+    pub async fn lock(&self) -> MutexGuard<'_, RotationState> {
+        self.state.lock().await
+    }
+
+    /// Acquires the rotation lock if it is free (e.g. not held by the background preparation)
+    pub fn try_lock(&self) -> Option<MutexGuard<'_, RotationState>> {
+        self.state.try_lock().ok()
+    }
+
+    /// Returns the slice for the new archive: the prepared one, if it matches, or the new one
+    pub async fn create_slice(&self, state: &mut RotationState, package_id: &PackageId) -> Result<Arc<ArchiveSlice>> {
+        if state.requested.as_ref() == Some(package_id) {
+            state.requested = None;
+        }
+        match state.prepared.take() {
+            Some((prepared_id, slice)) if &prepared_id == package_id => {
+                log::debug!(target: "storage", "Prepared archive slice {} is taken", package_id.id());
+                Ok(slice)
+            }
+            // Writers went past the prepared archive, so it is never going to be taken
+            Some((prepared_id, slice)) if &prepared_id < package_id => {
+                discard_slice(&prepared_id, slice).await;
+                self.factory.create(package_id).await
+            }
+            prepared => {
+                state.prepared = prepared;
+                self.factory.create(package_id).await
+            }
+        }
+    }
+
+    /// Requests the archive following the one of the written block to be prepared in the
+    /// background, if the block is close to the boundary. `exists` tells whether the archive
+    /// with given id is registered already.
+    pub fn prepare_next(&self, state: &mut RotationState, mc_seq_no: u32, exists: impl Fn(u32) -> bool) {
+        let background_tasks = match self.background_tasks {
+            Some(ref background_tasks) if background_tasks.has_spawner() => background_tasks,
+            _ => return,
+        };
+        let next = mc_seq_no - (mc_seq_no % ARCHIVE_SIZE as u32) + ARCHIVE_SIZE as u32;
+        if next - mc_seq_no > PREPARE_MARGIN || exists(next) {
+            return;
+        }
+        let package_id = PackageId::for_block(next);
+        let already_requested = state.requested.as_ref() == Some(&package_id)
+            || state.prepared.as_ref().map(|(prepared_id, _slice)| prepared_id) == Some(&package_id);
+        if already_requested {
+            return;
+        }
+
+        let shared_state = Arc::clone(&self.state);
+        let factory = self.factory.clone();
+        let requested_id = package_id.clone();
+        let spawned = background_tasks.spawn("archive_slice_prepare", move |_shutdown| async move {
+            let package_id = requested_id;
+            let mut state = shared_state.lock().await;
+            // The archive may have been created by the writer crossing the boundary meanwhile
+            if state.requested.as_ref() != Some(&package_id) {
+                return;
+            }
+            state.requested = None;
+            match factory.create(&package_id).await {
+                Ok(slice) => {
+                    log::debug!(target: "storage", "Archive slice {} is prepared", package_id.id());
+                    if let Some((replaced_id, replaced)) = state.prepared.replace((package_id, slice)) {
+                        discard_slice(&replaced_id, replaced).await;
+                    }
+                }
+                Err(err) => log::warn!(target: "storage", "Can't prepare archive slice {}: {}", package_id.id(), err),
+            }
+        });
+        match spawned {
+            Ok(()) => state.requested = Some(package_id),
+            Err(err) => log::warn!(target: "storage", "Can't prepare archive slice {}: {}", next, err),
+        }
+    }
+}
+
+/// Removes the files of the prepared slice which is not going to be registered
+async fn discard_slice(package_id: &PackageId, slice: Arc<ArchiveSlice>) {
+    log::debug!(target: "storage", "Unused prepared archive slice {} is discarded", package_id.id());
+    let result = match Arc::try_unwrap(slice) {
+        Ok(slice) => slice.destroy().await,
+        Err(_) => Err(error!("the slice is still referenced")),
+    };
+    if let Err(err) = result {
+        log::warn!(target: "storage", "Can't discard prepared archive slice {}: {}", package_id.id(), err);
+    }
+}
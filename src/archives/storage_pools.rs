@@ -0,0 +1,94 @@
+use std::ops::Range;
+use std::path::{Path, PathBuf};
+
+use serde_derive::{Deserialize, Serialize};
+
+use ton_types::Result;
+
+use crate::archives::archive_fs::ArchiveFs;
+
+/// Size of the chunks package files are copied by
+const COPY_CHUNK_SIZE: usize = 4 << 20;
+
+/// Which archives are kept in the storage pool
+#[derive(Debug, Clone, Deserialize, Serialize)]
+#[serde(rename_all = "snake_case")]
+pub enum PoolRule {
+    /// Archives with ids (the first masterchain seq_no) within the range
+    ArchiveIds(Range<u32>),
+    /// Archives starting at least given count of masterchain blocks before the newest archive
+    OlderThan(u32),
+}
+
+impl PoolRule {
+    fn matches(&self, archive_id: u32, newest_archive_id: u32) -> bool {
+        match self {
+            PoolRule::ArchiveIds(range) => range.contains(&archive_id),
+            PoolRule::OlderThan(age) => newest_archive_id.saturating_sub(archive_id) >= *age,
+        }
+    }
+}
+
+/// Root directory (e.g. on another file system) keeping the package files of some archives
+#[derive(Debug, Clone, Deserialize, Serialize)]
+pub struct StoragePool {
+    pub name: String,
+    pub root: PathBuf,
+    pub rule: PoolRule,
+}
+
+/// Storage pools of the archive package files. The first pool whose rule matches the archive keeps
+/// its packages; packages of the archives matching no pool are kept under the database root.
+/// Indexes of the archives always stay under the database root.
+#[derive(Debug, Clone, Default, Deserialize, Serialize)]
+#[serde(default)]
+pub struct StoragePoolsConfig {
+    pub pools: Vec<StoragePool>,
+}
+
+impl StoragePoolsConfig {
+    /// Returns the pool the packages of the archive belong to
+    pub fn pool_for(&self, archive_id: u32, newest_archive_id: u32) -> Option<&StoragePool> {
+        self.pools.iter().find(|pool| pool.rule.matches(archive_id, newest_archive_id))
+    }
+
+    /// Returns root directory the packages of the archive belong to
+    pub fn root_for(&self, archive_id: u32, newest_archive_id: u32, db_root_path: &Path) -> PathBuf {
+        match self.pool_for(archive_id, newest_archive_id) {
+            Some(pool) => pool.root.clone(),
+            None => db_root_path.to_path_buf(),
+        }
+    }
+}
+
+/// Copies the file within the archive file system. The copy is written next to the destination and
+/// renamed into it, so the destination never holds a partial copy; the copy and the rename are
+/// synced, so the copy is durable on return and the source may be removed. Returns size of the file.
+pub(crate) async fn copy_file(fs: &dyn ArchiveFs, from: &Path, to: &Path) -> Result<u64> {
+    let mut temp_path = to.as_os_str().to_os_string();
+    temp_path.push(".relocating");
+    let temp_path = PathBuf::from(temp_path);
+
+    let mut source = fs.open(from, true, false).await?;
+    let size = source.size().await?;
+    let mut dest = fs.open(&temp_path, false, true).await?;
+    dest.truncate(0).await?;
+
+    let mut buf = vec![0; COPY_CHUNK_SIZE];
+    let mut offset = 0;
+    while offset < size {
+        let len = std::cmp::min(COPY_CHUNK_SIZE as u64, size - offset) as usize;
+        source.read_exact_at(offset, &mut buf[..len]).await?;
+        dest.append(&buf[..len]).await?;
+        offset += len as u64;
+    }
+    dest.sync().await?;
+    drop(dest);
+
+    fs.rename(&temp_path, to).await?;
+    if let Some(dir) = to.parent() {
+        fs.sync_dir(dir).await?;
+    }
+
+    Ok(size)
+}
@@ -0,0 +1,109 @@
+use std::ops::RangeInclusive;
+use std::path::PathBuf;
+use std::str::FromStr;
+use std::time::SystemTime;
+
+use lazy_static::lazy_static;
+use regex::Regex;
+use serde_derive::{Deserialize, Serialize};
+
+use ton_block::{BlockIdExt, ShardIdent};
+use ton_types::{error, Result};
+
+use crate::archives::package_entry_id::block_id_short_hash;
+
+/// Default time (in seconds) unapplied files are retained for
+pub const DEFAULT_UNAPPLIED_TTL: u32 = 3600 * 24 * 7;
+
+/// Configuration of the unapplied files retention sweep
+#[derive(Debug, Clone, Deserialize, Serialize)]
+#[serde(default)]
+pub struct UnappliedGcConfig {
+    /// Files modified less than `ttl` seconds ago are never deleted
+    pub ttl: u32,
+    /// When set, the files to delete are only listed
+    pub dry_run: bool,
+}
+
+impl Default for UnappliedGcConfig {
+    fn default() -> Self {
+        Self { ttl: DEFAULT_UNAPPLIED_TTL, dry_run: false }
+    }
+}
+
+/// Description of unapplied file, parsed from its (short) filename
+#[derive(Debug, Clone)]
+pub struct UnappliedFileInfo {
+    pub path: PathBuf,
+    pub size: u64,
+    pub modified: SystemTime,
+    pub entry_type: String,
+    pub shard: ShardIdent,
+    pub seq_no: u32,
+    pub block_id_hash: u64,
+}
+
+/// Filter of the unapplied files listed by `ArchiveManager::list_unapplied`; unset criteria match
+/// any file
+#[derive(Debug, Clone, Default)]
+pub struct UnappliedFilter {
+    /// Entry type prefix of the filename (e.g. "block" or "proof")
+    pub entry_type: Option<String>,
+    pub shard: Option<ShardIdent>,
+    pub seq_no: Option<RangeInclusive<u32>>,
+    /// Files of the given block only (the first block id of the entry is compared)
+    pub block_id: Option<BlockIdExt>,
+}
+
+impl UnappliedFilter {
+    pub fn matches(&self, info: &UnappliedFileInfo) -> bool {
+        if let Some(ref entry_type) = self.entry_type {
+            if *entry_type != info.entry_type {
+                return false;
+            }
+        }
+        if let Some(ref shard) = self.shard {
+            if *shard != info.shard {
+                return false;
+            }
+        }
+        if let Some(ref seq_no) = self.seq_no {
+            if !seq_no.contains(&info.seq_no) {
+                return false;
+            }
+        }
+        if let Some(ref block_id) = self.block_id {
+            if *block_id.shard() != info.shard
+                || block_id.seq_no() != info.seq_no
+                || block_id_short_hash(block_id) != info.block_id_hash
+            {
+                return false;
+            }
+        }
+
+        true
+    }
+}
+
+/// Parses short filename produced by GetFileNameShort. Only the first block id is taken into account.
+/// Returns entry type prefix, shard, seq_no and the block id hash.
+pub(crate) fn parse_filename_short(filename: &str) -> Result<(String, ShardIdent, u32, u64)> {
+    lazy_static! {
+        static ref REGEX: Regex = Regex::new(r"^([a-z]+)_(-?\d+)_([0-9A-F]+)_(\d+)_([0-9A-F]+)")
+            .expect("Failed to compile regular expression");
+    }
+
+    let captures = REGEX.captures(filename)
+        .ok_or_else(|| error!("Incorrect short filename format: {}", filename))?;
+
+    enum Groups { EntryType = 1, Chain, Shard, SeqNo, Hash }
+
+    let workchain_id = i32::from_str(&captures[Groups::Chain as usize])?;
+    let shard_prefix_tagged = u64::from_str_radix(&captures[Groups::Shard as usize], 16)?;
+    let seq_no = u32::from_str(&captures[Groups::SeqNo as usize])?;
+    let hash = u64::from_str_radix(&captures[Groups::Hash as usize], 16)?;
+
+    let shard = ShardIdent::with_tagged_prefix(workchain_id, shard_prefix_tagged)?;
+
+    Ok((captures[Groups::EntryType as usize].to_string(), shard, seq_no, hash))
+}
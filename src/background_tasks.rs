@@ -0,0 +1,172 @@
+use std::future::Future;
+use std::pin::Pin;
+use std::sync::{Arc, Mutex, RwLock};
+use std::sync::atomic::{AtomicBool, Ordering};
+use std::time::Duration;
+
+use futures::future::{FutureExt, RemoteHandle};
+use tokio::sync::watch;
+
+use ton_types::{fail, Result};
+
+/// Future of the background task passed to the spawner
+pub type BackgroundFuture = Pin<Box<dyn Future<Output = ()> + Send + 'static>>;
+
+/// Runs the future on the runtime owned by the node, e.g. `move |task| { handle.spawn(task); }`
+pub type Spawner = Arc<dyn Fn(BackgroundFuture) + Send + Sync>;
+
+/// Shutdown request observed by the background task
+#[derive(Clone)]
+pub struct ShutdownSignal {
+    receiver: watch::Receiver<bool>,
+}
+
+impl ShutdownSignal {
+    pub fn is_requested(&self) -> bool {
+        *self.receiver.borrow()
+    }
+
+    /// Resolves when the shutdown is requested (or the registry is dropped)
+    pub async fn requested(&self) {
+        let mut receiver = self.receiver.clone();
+        while !*receiver.borrow() {
+            if receiver.recv().await.is_none() {
+                break;
+            }
+        }
+    }
+}
+
+struct BackgroundTask {
+    name: String,
+    finished: Arc<AtomicBool>,
+    handle: RemoteHandle<()>,
+}
+
+impl BackgroundTask {
+    fn is_finished(&self) -> bool {
+        self.finished.load(Ordering::Relaxed)
+    }
+}
+
+/// Registry of the storage's background tasks. The crate doesn't create its own runtime: tasks
+/// are run by the spawner supplied by the node. Tasks are owned by the registry until they finish
+/// or the registry is shut down.
+pub struct BackgroundTasks {
+    spawner: RwLock<Option<Spawner>>,
+    tasks: Mutex<Vec<BackgroundTask>>,
+    shutdown_sender: Mutex<Option<watch::Sender<bool>>>,
+    shutdown_receiver: watch::Receiver<bool>,
+}
+
+impl std::fmt::Debug for BackgroundTasks {
+    fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+        f.debug_struct("BackgroundTasks")
+            .field("tasks", &self.running())
+            .finish()
+    }
+}
+
+impl BackgroundTasks {
+    pub fn new() -> Self {
+        let (sender, receiver) = watch::channel(false);
+        Self {
+            spawner: RwLock::new(None),
+            tasks: Mutex::new(Vec::new()),
+            shutdown_sender: Mutex::new(Some(sender)),
+            shutdown_receiver: receiver,
+        }
+    }
+
+    pub fn set_spawner(&self, spawner: impl Fn(BackgroundFuture) + Send + Sync + 'static) {
+        *self.spawner.write().expect("Poisoned RwLock") = Some(Arc::new(spawner));
+    }
+
+    pub fn has_spawner(&self) -> bool {
+        self.spawner.read().expect("Poisoned RwLock").is_some()
+    }
+
+    /// Spawns the task constructed by `task` from the shutdown signal. Fails if no spawner is set
+    /// or the registry is shut down.
+    pub fn spawn<F, Fut>(&self, name: impl Into<String>, task: F) -> Result<()>
+    where
+        F: FnOnce(ShutdownSignal) -> Fut,
+        Fut: Future<Output = ()> + Send + 'static,
+    {
+        let name = name.into();
+        let spawner = match self.spawner.read().expect("Poisoned RwLock").clone() {
+            Some(spawner) => spawner,
+            None => fail!("Can't spawn background task {}: no spawner is set", name),
+        };
+        if self.shutdown_sender.lock().expect("Poisoned Mutex").is_none() {
+            fail!("Can't spawn background task {}: background tasks are shut down", name)
+        }
+
+        let signal = ShutdownSignal { receiver: self.shutdown_receiver.clone() };
+        let finished = Arc::new(AtomicBool::new(false));
+        let future = task(signal);
+        let (remote, handle) = {
+            let finished = Arc::clone(&finished);
+            async move {
+                future.await;
+                finished.store(true, Ordering::Relaxed);
+            }.remote_handle()
+        };
+        spawner(Box::pin(remote));
+
+        let mut tasks = self.tasks.lock().expect("Poisoned Mutex");
+        tasks.retain(|task| !task.is_finished());
+        log::debug!(target: "storage", "Background task {} spawned", name);
+        tasks.push(BackgroundTask { name, finished, handle });
+
+        Ok(())
+    }
+
+    /// Names of the tasks not finished yet
+    pub fn running(&self) -> Vec<String> {
+        let mut tasks = self.tasks.lock().expect("Poisoned Mutex");
+        tasks.retain(|task| !task.is_finished());
+
+        tasks.iter().map(|task| task.name.clone()).collect()
+    }
+
+    /// Requests all the tasks to stop and waits for them up to the timeout; tasks still running
+    /// after the timeout are cancelled. Returns names of the cancelled tasks.
+    pub async fn shutdown(&self, timeout: Duration) -> Vec<String> {
+        if let Some(sender) = self.shutdown_sender.lock().expect("Poisoned Mutex").take() {
+            sender.broadcast(true).ok();
+        }
+
+        let tasks = std::mem::take(&mut *self.tasks.lock().expect("Poisoned Mutex"));
+        if tasks.is_empty() {
+            return Vec::new();
+        }
+        log::info!(target: "storage", "Stopping {} background tasks", tasks.len());
+
+        let mut handles = Vec::with_capacity(tasks.len());
+        let mut states = Vec::with_capacity(tasks.len());
+        for task in tasks {
+            handles.push(task.handle);
+            states.push((task.name, task.finished));
+        }
+        // Handles left unresolved are dropped on timeout, which cancels their tasks
+        if tokio::time::timeout(timeout, futures::future::join_all(handles)).await.is_ok() {
+            log::info!(target: "storage", "Background tasks stopped");
+            return Vec::new();
+        }
+
+        let cancelled = states.into_iter()
+            .filter(|(_name, finished)| !finished.load(Ordering::Relaxed))
+            .map(|(name, _finished)| name)
+            .collect::<Vec<_>>();
+        log::warn!(target: "storage", "Background tasks cancelled on timeout: {:?}", cancelled);
+
+        cancelled
+    }
+}
+
+impl Default for BackgroundTasks {
+    fn default() -> Self {
+        Self::new()
+    }
+}
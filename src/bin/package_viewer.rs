@@ -28,6 +28,7 @@ async fn run(filename: PathBuf) -> Result<()> {
 
     print_separator();
     print_row(&[&"Entries count".to_uppercase(), &count.to_string()]);
+    print_row(&[&"Finalized".to_uppercase(), &reader.trailer().is_some().to_string()]);
     print_separator();
 
     Ok(())
@@ -0,0 +1,246 @@
+use std::path::{Path, PathBuf};
+use std::str::FromStr;
+use std::sync::atomic::Ordering;
+
+use ton_block::{AccountIdPrefixFull, BlockIdExt, ShardIdent};
+use ton_types::{error, fail, Result, UInt256};
+
+use ton_node_storage::archives::package_id::PackageId;
+use ton_node_storage::archives::package_index_db::PackageIndexDb;
+use ton_node_storage::block_handle_db::BlockHandleDb;
+use ton_node_storage::block_index_db::BlockIndexDb;
+use ton_node_storage::db::rocksdb::RocksDb;
+use ton_node_storage::db::traits::KvcReadable;
+use ton_node_storage::lt_db::LtDb;
+use ton_node_storage::lt_desc_db::LtDescDb;
+use ton_node_storage::node_state_db::NodeStateDb;
+use ton_node_storage::schema::{decode_record, storage_schema};
+use ton_node_storage::shardstate_db::DbEntry;
+use ton_node_storage::traits::Serializable;
+use ton_node_storage::types::{BlockId, describe_block_flags, Lt, NodeStateKey, UnixTime};
+
+const USAGE: &str = "\
+Usage: storage_cli <db_root> <command> [args]
+
+Commands:
+    handle <block_id>                         Block handle's meta and flags
+    shardstate <block_id>                     Shard states database entry
+    lt <workchain> <prefix> seq_no|lt|utime <value>
+                                              Block index lookup by account prefix (hex)
+    archive <mc_seq_no>                       Archive containing masterchain block
+    node-state <key>                          Hex dump of node state value
+    schema [record]                           Layouts of the on-disk records (db_root is ignored)
+    decode <record> <value hex>               Value decoded by the record's schema (db_root is ignored)
+    decode-value <db> <key hex> <record>      Value stored in the database under db_root decoded
+                                              by the record's schema
+
+Block id format: <workchain>:<shard hex>:<seq_no>:<root hash hex>:<file hash hex>";
+
+fn parse_hash(value: &str) -> Result<UInt256> {
+    let bytes = hex::decode(value)?;
+    if bytes.len() != 32 {
+        fail!("Hash must be 32 bytes long: {}", value)
+    }
+
+    Ok(UInt256::from(bytes.as_slice()))
+}
+
+fn parse_block_id(value: &str) -> Result<BlockIdExt> {
+    let parts = value.split(':').collect::<Vec<_>>();
+    if parts.len() != 5 {
+        fail!("Incorrect block id format: {}", value)
+    }
+
+    Ok(BlockIdExt {
+        shard_id: ShardIdent::with_tagged_prefix(i32::from_str(parts[0])?, u64::from_str_radix(parts[1], 16)?)?,
+        seq_no: u32::from_str(parts[2])?,
+        root_hash: parse_hash(parts[3])?,
+        file_hash: parse_hash(parts[4])?,
+    })
+}
+
+fn hex_dump(data: &[u8]) {
+    for (index, chunk) in data.chunks(16).enumerate() {
+        let hex = chunk.iter().map(|byte| format!("{:02x}", byte)).collect::<Vec<_>>().join(" ");
+        let ascii = chunk.iter()
+            .map(|&byte| if byte.is_ascii_graphic() || byte == b' ' { byte as char } else { '.' })
+            .collect::<String>();
+        println!("{:08x}  {:<47}  |{}|", index * 16, hex, ascii);
+    }
+}
+
+fn show_handle(db_root: &Path, block_id: &BlockIdExt) -> Result<()> {
+    let db = BlockHandleDb::with_path_read_only(db_root.join("block_handle_db"))?;
+    let meta = db.try_get_value(&BlockId::from(block_id))?
+        .ok_or_else(|| error!("Block handle {} is not found", block_id))?;
+
+    let flags = meta.flags().load(Ordering::Relaxed);
+    println!("Block:                  {}", block_id);
+    println!("Flags:                  {:#06x} [{}]", flags, describe_block_flags(flags).join(", "));
+    println!("Gen utime:              {}", meta.gen_utime().load(Ordering::Relaxed));
+    println!("Gen lt:                 {}", meta.gen_lt().load(Ordering::Relaxed));
+    println!("Masterchain ref seq_no: {}", meta.masterchain_ref_seq_no().load(Ordering::Relaxed));
+    println!("Fetched:                {}", meta.fetched());
+
+    Ok(())
+}
+
+fn show_shardstate(db_root: &Path, block_id: &BlockIdExt) -> Result<()> {
+    let db = RocksDb::with_path_read_only(db_root.join("shardstate_db"))?;
+    let value = db.try_get(&BlockId::from(block_id))?
+        .ok_or_else(|| error!("Shard state of {} is not found", block_id))?;
+    let entry = DbEntry::from_slice(value.as_ref())?;
+
+    println!("Block:     {}", entry.block_id_ext);
+    println!("Root cell: {}", entry.cell_id);
+
+    Ok(())
+}
+
+fn lookup_lt(db_root: &Path, args: &[String]) -> Result<()> {
+    if args.len() != 4 {
+        fail!("Expected: lt <workchain> <prefix> seq_no|lt|utime <value>")
+    }
+    let account_id = AccountIdPrefixFull {
+        workchain_id: i32::from_str(&args[0])?,
+        prefix: u64::from_str_radix(&args[1], 16)?,
+    };
+    let index = BlockIndexDb::with_dbs(
+        LtDescDb::with_path_read_only(db_root.join("lt_desc_db"))?,
+        LtDb::with_path_read_only(db_root.join("lt_db"))?,
+    );
+
+    for len in 0..=ton_block::MAX_SPLIT_DEPTH {
+        let shard = ShardIdent::with_prefix_len(len, account_id.workchain_id, account_id.prefix)?;
+        if let Some(lt_desc) = index.get_lt_desc(&shard)? {
+            println!("Shard {}: {:?}", shard, lt_desc);
+        }
+    }
+
+    let block_id = match args[2].as_str() {
+        "seq_no" => index.get_block_by_seq_no(&account_id, u32::from_str(&args[3])?)?,
+        "lt" => index.get_block_by_lt(&account_id, Lt::new(u64::from_str(&args[3])?))?,
+        "utime" => index.get_block_by_ut(&account_id, UnixTime::new(u32::from_str(&args[3])?))?,
+        kind => fail!("Unknown lookup kind: {}", kind),
+    };
+    println!("Found block: {}", block_id);
+
+    Ok(())
+}
+
+fn show_archive(db_root: &Path, mc_seq_no: u32) -> Result<()> {
+    let db = PackageIndexDb::with_path_read_only(db_root.join("file_maps").join("files"))?;
+    let mut closest = None;
+    db.for_each_deserialized(|archive_id, entry| {
+        if archive_id > mc_seq_no {
+            return Ok(false);
+        }
+        closest = Some((archive_id, entry));
+        Ok(true)
+    })?;
+    let (archive_id, entry) = closest
+        .ok_or_else(|| error!("No archive contains masterchain block {}", mc_seq_no))?;
+
+    let package_id = PackageId::for_block(archive_id);
+    println!("Archive id: {}", archive_id);
+    println!("Deleted:    {}", entry.deleted());
+    println!("Finalized:  {}", entry.finalized());
+
+    let dir = db_root.join(package_id.path());
+    let prefix = package_id.name().to_string_lossy().to_string();
+    let mut packages = Vec::new();
+    if dir.is_dir() {
+        for dir_entry in std::fs::read_dir(&dir)? {
+            let dir_entry = dir_entry?;
+            let filename = dir_entry.file_name().to_string_lossy().to_string();
+            if filename.starts_with(&prefix) && filename.ends_with(".pack") {
+                packages.push((dir_entry.path(), dir_entry.metadata()?.len()));
+            }
+        }
+    }
+    packages.sort();
+    for (path, size) in packages {
+        println!("Package:    {:?} ({} bytes)", path, size);
+    }
+
+    Ok(())
+}
+
+fn show_node_state(db_root: &Path, key: &str) -> Result<()> {
+    let db = NodeStateDb::with_path_read_only(db_root.join("node_state_db"))?;
+    let key = NodeStateKey::from(key);
+    let value = db.try_get(&key)?
+        .ok_or_else(|| error!("Node state key {} is not found", key.as_str()))?;
+
+    println!("Key: {} ({} bytes)", key.as_str(), value.as_ref().len());
+    hex_dump(value.as_ref());
+
+    Ok(())
+}
+
+fn show_schema(record: Option<&String>) -> Result<()> {
+    let schemas = storage_schema().into_iter()
+        .filter(|schema| record.map(|record| schema.name.eq_ignore_ascii_case(record)).unwrap_or(true))
+        .collect::<Vec<_>>();
+    if schemas.is_empty() {
+        fail!("Unknown record type {}", record.map(|record| record.as_str()).unwrap_or_default())
+    }
+    for schema in schemas {
+        println!("{}", schema);
+    }
+
+    Ok(())
+}
+
+fn decode(record: &str, value: &[u8]) -> Result<()> {
+    match decode_record(record, value) {
+        Ok(decoded) => print!("{}", decoded),
+        Err(err) => {
+            hex_dump(value);
+            fail!("Can't decode {} bytes as {}: {}", value.len(), record, err)
+        }
+    }
+
+    Ok(())
+}
+
+fn decode_value(db_root: &Path, db_name: &str, key: &str, record: &str) -> Result<()> {
+    let db = RocksDb::with_path_read_only(db_root.join(db_name))?;
+    let key = hex::decode(key)?;
+    let value = db.try_get(&key.as_slice())?
+        .ok_or_else(|| error!("Key {} is not found in {}", hex::encode(&key), db_name))?;
+
+    decode(record, value.as_ref())
+}
+
+fn run(args: &[String]) -> Result<()> {
+    if args.len() < 3 {
+        fail!("Database root and command are not specified")
+    }
+    let db_root = PathBuf::from(&args[1]);
+    let command_args = &args[3..];
+    let arg = |index: usize| command_args.get(index)
+        .ok_or_else(|| error!("Missing argument of {}", args[2]));
+
+    match args[2].as_str() {
+        "handle" => show_handle(&db_root, &parse_block_id(arg(0)?)?),
+        "shardstate" => show_shardstate(&db_root, &parse_block_id(arg(0)?)?),
+        "lt" => lookup_lt(&db_root, command_args),
+        "archive" => show_archive(&db_root, u32::from_str(arg(0)?)?),
+        "node-state" => show_node_state(&db_root, arg(0)?),
+        "schema" => show_schema(command_args.get(0)),
+        "decode" => decode(arg(0)?, &hex::decode(arg(1)?)?),
+        "decode-value" => decode_value(&db_root, arg(0)?, arg(1)?, arg(2)?),
+        command => fail!("Unknown command: {}", command),
+    }
+}
+
+fn main() -> Result<()> {
+    let args = std::env::args().collect::<Vec<_>>();
+    let result = run(&args);
+    if result.is_err() {
+        println!("{}", USAGE);
+    }
+
+    result
+}
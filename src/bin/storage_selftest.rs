@@ -0,0 +1,255 @@
+use std::path::{Path, PathBuf};
+use std::time::Instant;
+
+use sha2::{Digest, Sha256};
+use ton_api::ton::PublicKey;
+use ton_block::{BlockIdExt, Serializable, ShardIdent, ShardStateUnsplit};
+use ton_types::{Cell, fail, Result, UInt256};
+
+use ton_node_storage::archives::package_entry_id::PackageEntryId;
+use ton_node_storage::node_storage::NodeStorage;
+use ton_node_storage::types::{BlockId, ProofKind};
+
+const USAGE: &str = "\
+Usage: storage_selftest <temp_dir> [blocks_count]
+
+Exercises the whole storage stack in the empty (or missing) directory: creates handles, stores
+fake masterchain blocks and their states, checks the indexes, moves the blocks to the archive,
+runs GC and reopens the storage, asserting the invariants and printing timing of each step.";
+
+const DEFAULT_BLOCKS_COUNT: u32 = 100;
+const GEN_UTIME_BASE: u32 = 1_600_000_000;
+const PIN_OWNER: &str = "storage_selftest";
+
+/// Fake block: its data and proof are arbitrary bytes, the state is the minimal one
+struct FakeBlock {
+    id: BlockIdExt,
+    data: Vec<u8>,
+    proof: Vec<u8>,
+    state: ShardStateUnsplit,
+    state_root: Cell,
+}
+
+fn hash_of(data: &[u8]) -> UInt256 {
+    UInt256::from(Sha256::digest(data).as_slice())
+}
+
+fn fake_block(seq_no: u32) -> Result<FakeBlock> {
+    let shard = ShardIdent::masterchain();
+    let mut state = ShardStateUnsplit::with_ident(shard.clone());
+    state.set_seq_no(seq_no);
+    state.set_gen_time(GEN_UTIME_BASE + seq_no);
+    let state_root = state.serialize()?;
+
+    let data = format!("block {}", seq_no).repeat(64).into_bytes();
+    let proof = format!("proof {}", seq_no).into_bytes();
+    let file_hash = hash_of(&data);
+
+    Ok(FakeBlock {
+        id: BlockIdExt {
+            shard_id: shard,
+            seq_no,
+            root_hash: hash_of(file_hash.as_slice()),
+            file_hash,
+        },
+        data,
+        proof,
+        state,
+        state_root,
+    })
+}
+
+fn check(condition: bool, description: impl FnOnce() -> String) -> Result<()> {
+    if !condition {
+        fail!("Self-test failed: {}", description())
+    }
+
+    Ok(())
+}
+
+fn report(step: &str, started: Instant) {
+    println!("{: <24} {:?}", step, started.elapsed());
+}
+
+async fn populate(storage: &NodeStorage, blocks: &[FakeBlock]) -> Result<()> {
+    for block in blocks {
+        let handle = storage.block_handle_storage().load_block_handle(&block.id)?;
+        handle.fetch_shard_state(&block.state)?;
+
+        storage.archive_manager().add_file(
+            &PackageEntryId::<_, &UInt256, &PublicKey>::Block(&block.id),
+            block.data.clone(),
+        ).await?;
+        handle.set_data_inited();
+        storage.block_info_db().store_proof(&block.id, ProofKind::Proof, &block.proof)?;
+        handle.set_proof_inited();
+
+        storage.shard_state_db().put(&BlockId::from(&block.id), block.state_root.clone())?;
+        handle.set_state_inited();
+        storage.block_handle_storage().store_block_handle(&handle)?;
+        storage.set_block_applied(&handle)?;
+    }
+
+    Ok(())
+}
+
+fn check_indexes(storage: &NodeStorage, blocks: &[FakeBlock]) -> Result<()> {
+    for block in blocks {
+        let found = storage.find_block_by_root_hash(&block.id.root_hash)?;
+        check(found.as_ref() == Some(&block.id), || format!("block {} is not found by root hash", block.id))?;
+    }
+
+    let last = blocks.last().map(|block| block.id.seq_no).unwrap_or(0);
+    let shard = ShardIdent::masterchain();
+    let missing = storage.block_handle_storage().find_missing(&shard, 1..=last)?;
+    check(missing.is_empty(), || format!("blocks {:?} are reported missing", missing))?;
+    let head = storage.chain_head(&shard)?.map(|head| head.seq_no()).unwrap_or(0);
+    check(head == last, || format!("chain head is {}, expected {}", head, last))
+}
+
+async fn archive(storage: &NodeStorage, blocks: &[FakeBlock]) -> Result<()> {
+    for block in blocks {
+        let handle = storage.block_handle_storage().load_block_handle(&block.id)?;
+        storage.archive_manager().move_to_archive(&handle, || {
+            handle.set_moved_to_archive();
+            storage.block_handle_storage().store_block_handle(&handle)
+        }).await?;
+    }
+
+    Ok(())
+}
+
+async fn check_archived(storage: &NodeStorage, blocks: &[FakeBlock]) -> Result<()> {
+    for block in blocks {
+        let handle = storage.block_handle_storage().load_block_handle(&block.id)?;
+        check(handle.applied(), || format!("block {} is not applied", block.id))?;
+        if handle.pruned() {
+            continue;
+        }
+        check(handle.moved_to_archive(), || format!("block {} is not moved to archive", block.id))?;
+
+        let data = storage.archive_manager().get_file(
+            &handle,
+            &PackageEntryId::<_, &UInt256, &PublicKey>::Block(&block.id),
+        ).await?;
+        check(data == block.data, || format!("data of block {} differ", block.id))?;
+        let proof = storage.load_block_proof(&handle, ProofKind::Proof).await?;
+        check(proof == block.proof, || format!("proof of block {} differ", block.id))?;
+        let by_hash = storage.archive_manager().get_file_by_hash(&block.id.file_hash).await?;
+        check(by_hash.as_ref() == Some(&block.data), || format!("block {} is not found by file hash", block.id))?;
+    }
+
+    Ok(())
+}
+
+/// Checks the states which are still stored (GC may have deleted some); the pinned one must be
+fn check_states(storage: &NodeStorage, blocks: &[FakeBlock], pinned: Option<&BlockIdExt>) -> Result<usize> {
+    let mut stored = 0;
+    for block in blocks {
+        let block_id = BlockId::from(&block.id);
+        if !storage.shard_state_db().shardstate_db().contains(&block_id)? {
+            check(pinned != Some(&block.id), || format!("pinned state of {} is collected", block.id))?;
+            continue;
+        }
+        let root = storage.shard_state_db().get(&block_id)?;
+        check(root.repr_hash() == block.state_root.repr_hash(), || format!("state of {} differs", block.id))?;
+        stored += 1;
+    }
+
+    Ok(stored)
+}
+
+async fn run(db_root: PathBuf, blocks_count: u32) -> Result<()> {
+    if is_non_empty_dir(&db_root)? {
+        fail!("Directory {:?} is not empty; the self-test needs a temporary one", db_root)
+    }
+
+    let started = Instant::now();
+    let blocks = (1..=blocks_count).map(fake_block).collect::<Result<Vec<_>>>()?;
+    report("generate blocks", started);
+
+    let started = Instant::now();
+    let storage = NodeStorage::with_path(db_root.clone()).await?;
+    report("open", started);
+
+    let started = Instant::now();
+    populate(&storage, &blocks).await?;
+    report("store blocks", started);
+
+    let started = Instant::now();
+    check_indexes(&storage, &blocks)?;
+    report("check indexes", started);
+
+    let started = Instant::now();
+    archive(&storage, &blocks).await?;
+    report("archive", started);
+
+    let started = Instant::now();
+    check_archived(&storage, &blocks).await?;
+    report("read archived", started);
+
+    let started = Instant::now();
+    let stored = check_states(&storage, &blocks, None)?;
+    check(stored == blocks.len(), || format!("{} of {} states are stored", stored, blocks.len()))?;
+    report("load states", started);
+
+    let started = Instant::now();
+    let pinned = blocks.last().map(|block| block.id.clone());
+    if let Some(ref block_id) = pinned {
+        storage.pin_state(block_id, PIN_OWNER, None)?;
+    }
+    let cells_deleted = storage.states_gc()?.collect()?;
+    let archives_pruned = storage.gc_archives().await?;
+    let stored = check_states(&storage, &blocks, pinned.as_ref())?;
+    check_archived(&storage, &blocks).await?;
+    report("gc", started);
+    println!(
+        "    {} cells deleted and {} archives pruned, {} states left",
+        cells_deleted,
+        archives_pruned.len(),
+        stored
+    );
+
+    let started = Instant::now();
+    drop(storage);
+    let storage = NodeStorage::with_path(db_root).await?;
+    report("reopen", started);
+
+    let started = Instant::now();
+    check_indexes(&storage, &blocks)?;
+    check_archived(&storage, &blocks).await?;
+    check_states(&storage, &blocks, pinned.as_ref())?;
+    report("check after reopen", started);
+
+    println!("Self-test passed");
+
+    Ok(())
+}
+
+fn is_non_empty_dir(path: &Path) -> Result<bool> {
+    if !path.exists() {
+        return Ok(false);
+    }
+    if !path.is_dir() {
+        fail!("{:?} is not a directory", path)
+    }
+
+    Ok(std::fs::read_dir(path)?.next().is_some())
+}
+
+fn main() -> Result<()> {
+    let args = std::env::args().collect::<Vec<_>>();
+    if args.len() < 2 || args.len() > 3 {
+        println!("{}", USAGE);
+        fail!("Temporary directory is not specified")
+    }
+    let blocks_count = match args.get(2) {
+        Some(arg) => arg.parse()?,
+        None => DEFAULT_BLOCKS_COUNT,
+    };
+
+    tokio::runtime::Builder::new()
+        .build()
+        .expect("Can't create tokio runtime")
+        .block_on(run(PathBuf::from(&args[1]), blocks_count))
+}
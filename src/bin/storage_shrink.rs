@@ -0,0 +1,67 @@
+use std::path::PathBuf;
+
+use ton_types::{fail, Result};
+
+use ton_node_storage::node_storage::NodeStorage;
+
+const USAGE: &str = "\
+Usage: storage_shrink <db_root>
+
+Compacts all the collections and sparse archive packages of the stopped node's storage,
+removes empty package directories and prints disk usage per component before and after.";
+
+fn format_size(size: u64) -> String {
+    const UNITS: [&str; 5] = ["B", "KiB", "MiB", "GiB", "TiB"];
+    let mut value = size as f64;
+    let mut unit = 0;
+    while value >= 1024.0 && unit < UNITS.len() - 1 {
+        value /= 1024.0;
+        unit += 1;
+    }
+
+    format!("{:.1} {}", value, UNITS[unit])
+}
+
+fn print_row(name: &str, before: &str, after: &str) {
+    println!("{: <32} {: >12} {: >12}", name, before, after);
+}
+
+async fn run(db_root: PathBuf) -> Result<()> {
+    if !db_root.is_dir() {
+        fail!("Storage root {:?} is not a directory", db_root)
+    }
+
+    let storage = NodeStorage::with_path(db_root).await?;
+    let report = storage.shrink().await?;
+
+    print_row("COMPONENT", "BEFORE", "AFTER");
+    for component in report.components.iter() {
+        print_row(&component.name, &format_size(component.size_before), &format_size(component.size_after));
+    }
+    print_row("TOTAL", &format_size(report.total_before()), &format_size(report.total_after()));
+    println!();
+    println!(
+        "Archive packages: {} -> {}",
+        format_size(report.packages_size_before),
+        format_size(report.packages_size_after)
+    );
+    println!("Empty directories removed: {}", report.removed_dirs.len());
+    for dir in report.removed_dirs.iter() {
+        println!("    {:?}", dir);
+    }
+
+    Ok(())
+}
+
+fn main() -> Result<()> {
+    let args = std::env::args().collect::<Vec<_>>();
+    if args.len() != 2 {
+        println!("{}", USAGE);
+        fail!("Storage root is not specified")
+    }
+
+    tokio::runtime::Builder::new()
+        .build()
+        .expect("Can't create tokio runtime")
+        .block_on(run(PathBuf::from(&args[1])))
+}
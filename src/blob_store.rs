@@ -0,0 +1,186 @@
+use std::sync::Mutex;
+
+use sha2::{Digest, Sha256};
+use ton_types::{fail, Result, UInt256};
+
+use crate::cancellation::CancellationToken;
+use crate::db::traits::{DbKey, KvcTransactional};
+use crate::db_impl_base;
+
+const BLOB_DATA_SUFFIX: u8 = 0;
+const BLOB_REFS_SUFFIX: u8 = 1;
+
+/// Magic prefix of the record referencing the blob instead of holding the value
+const BLOB_REF_MAGIC: [u8; 4] = *b"BREF";
+const BLOB_REF_SIZE: usize = BLOB_REF_MAGIC.len() + 32;
+
+/// Key of BlobDb: the data and the reference count of the blob are stored under the blob's
+/// hash with different suffixes
+pub struct BlobKey {
+    key: [u8; 33],
+}
+
+impl BlobKey {
+    fn data(hash: &UInt256) -> Self {
+        Self::with_suffix(hash, BLOB_DATA_SUFFIX)
+    }
+
+    fn refs(hash: &UInt256) -> Self {
+        Self::with_suffix(hash, BLOB_REFS_SUFFIX)
+    }
+
+    fn with_suffix(hash: &UInt256, suffix: u8) -> Self {
+        let mut key = [0; 33];
+        key[..32].copy_from_slice(hash.as_slice());
+        key[32] = suffix;
+        Self { key }
+    }
+}
+
+impl DbKey for BlobKey {
+    fn key_name(&self) -> &'static str {
+        "BlobKey"
+    }
+
+    fn key(&self) -> &[u8] {
+        &self.key
+    }
+}
+
+db_impl_base!(BlobDb, KvcTransactional, BlobKey);
+
+/// Content-addressed store of large values: equal values are stored once under their SHA-256,
+/// while the records of the primary collections hold the references. Blobs are reference-counted
+/// and removed when the last reference is released.
+#[derive(Debug)]
+pub struct BlobStore {
+    db: BlobDb,
+    // Serializes read-modify-write updates of the reference counters
+    lock: Mutex<()>,
+}
+
+impl BlobStore {
+    pub fn with_db(db: BlobDb) -> Self {
+        Self { db, lock: Mutex::new(()) }
+    }
+
+    pub fn in_memory() -> Self {
+        Self::with_db(BlobDb::in_memory())
+    }
+
+    pub fn with_path(path: impl AsRef<std::path::Path>) -> Self {
+        Self::with_db(BlobDb::with_path(path))
+    }
+
+    pub const fn db(&self) -> &BlobDb {
+        &self.db
+    }
+
+    /// Stores the value (or adds the reference to the stored one) and returns the record
+    /// referencing it
+    pub fn add(&self, data: &[u8]) -> Result<Vec<u8>> {
+        let hash = UInt256::from(Sha256::digest(data).as_slice());
+        let _locked = self.lock.lock().expect("Poisoned Mutex");
+        let refs = self.refs(&hash)?;
+
+        let transaction = self.db.begin_transaction()?;
+        if refs == 0 {
+            transaction.put(&BlobKey::data(&hash), data);
+        }
+        transaction.put(&BlobKey::refs(&hash), &(refs + 1).to_le_bytes());
+        transaction.commit()?;
+
+        let mut record = Vec::with_capacity(BLOB_REF_SIZE);
+        record.extend_from_slice(&BLOB_REF_MAGIC);
+        record.extend_from_slice(hash.as_slice());
+
+        Ok(record)
+    }
+
+    /// Releases the reference held by the record; the blob is removed with its last reference.
+    /// Returns false if the record doesn't reference a blob.
+    pub fn release(&self, record: &[u8]) -> Result<bool> {
+        let hash = match Self::referenced_hash(record) {
+            Some(hash) => hash,
+            None => return Ok(false),
+        };
+        let _locked = self.lock.lock().expect("Poisoned Mutex");
+        let refs = self.refs(&hash)?;
+
+        let transaction = self.db.begin_transaction()?;
+        if refs <= 1 {
+            transaction.delete(&BlobKey::data(&hash));
+            transaction.delete(&BlobKey::refs(&hash));
+        } else {
+            transaction.put(&BlobKey::refs(&hash), &(refs - 1).to_le_bytes());
+        }
+        transaction.commit()?;
+
+        Ok(true)
+    }
+
+    /// Returns the value of the record: loads the referenced blob (verifying its hash) or returns
+    /// the record itself if it holds the value
+    pub fn resolve(&self, record: Vec<u8>) -> Result<Vec<u8>> {
+        let hash = match Self::referenced_hash(&record) {
+            Some(hash) => hash,
+            None => return Ok(record),
+        };
+        let data = match self.db.try_get(&BlobKey::data(&hash))? {
+            Some(data) => data.to_vec(),
+            None => fail!("Blob {:x} is referenced, but it is not stored", hash),
+        };
+        if Sha256::digest(&data).as_slice() != hash.as_slice() {
+            fail!("Blob {:x} is corrupted: hash mismatch", hash)
+        }
+
+        Ok(data)
+    }
+
+    /// Returns hashes of the blobs whose data don't match their hashes or are missing
+    pub fn verify(&self, cancellation: &CancellationToken) -> Result<Vec<UInt256>> {
+        let mut corrupted = Vec::new();
+        self.db.for_each(&mut |key, value| {
+            cancellation.check()?;
+            if key.len() == 33 {
+                let hash = UInt256::from(&key[..32]);
+                match key[32] {
+                    BLOB_DATA_SUFFIX if Sha256::digest(value).as_slice() != hash.as_slice() => corrupted.push(hash),
+                    BLOB_REFS_SUFFIX if !self.db.contains(&BlobKey::data(&hash))? => corrupted.push(hash),
+                    _ => (),
+                }
+            }
+            Ok(true)
+        })?;
+        if !corrupted.is_empty() {
+            log::error!(target: "storage", "{} corrupted blobs found", corrupted.len());
+        }
+
+        Ok(corrupted)
+    }
+
+    /// Returns true if the record references a blob
+    pub fn is_reference(record: &[u8]) -> bool {
+        Self::referenced_hash(record).is_some()
+    }
+
+    fn referenced_hash(record: &[u8]) -> Option<UInt256> {
+        if record.len() == BLOB_REF_SIZE && record.starts_with(&BLOB_REF_MAGIC) {
+            Some(UInt256::from(&record[BLOB_REF_MAGIC.len()..]))
+        } else {
+            None
+        }
+    }
+
+    fn refs(&self, hash: &UInt256) -> Result<u32> {
+        Ok(match self.db.try_get(&BlobKey::refs(hash))? {
+            Some(value) if value.len() == 4 => {
+                let mut buf = [0; 4];
+                buf.copy_from_slice(&value);
+                u32::from_le_bytes(buf)
+            },
+            Some(value) => fail!("Bad blob {:x} reference counter of {} bytes", hash, value.len()),
+            None => 0,
+        })
+    }
+}
@@ -1,4 +1,26 @@
+use ton_types::Result;
+
 use crate::db_impl_base;
-use crate::db::traits::KvcWriteable;
+use crate::db::traits::{DbKey, KvcWriteable};
+use crate::types::{BlockId, check_same_content, WriteMode};
+
+db_impl_base!(BlockDb, KvcWriteable, BlockId);
+
+impl BlockDb {
+    /// Puts block data according to the write mode. Returns true if the data was written.
+    pub fn put_block_data(&self, block_id: &BlockId, data: &[u8], mode: WriteMode) -> Result<bool> {
+        if mode != WriteMode::Overwrite {
+            if let Some(existing) = self.try_get(block_id)? {
+                if mode == WriteMode::WriteOnce {
+                    check_same_content(block_id.as_string(), existing.as_ref(), data)?;
+                }
+                return Ok(false);
+            }
+        } else {
+            log::warn!(target: "storage", "Overwriting block data {}", block_id.as_string());
+        }
+        self.put(block_id, data)?;
 
-db_impl_base!(BlockDb, KvcWriteable, crate::types::BlockId);
+        Ok(true)
+    }
+}
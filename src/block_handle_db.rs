@@ -1,31 +1,158 @@
+use std::io::Cursor;
+use std::ops::RangeInclusive;
 use std::sync::{Arc, Weak};
+use std::sync::atomic::Ordering;
 
-use ton_block::BlockIdExt;
-use ton_types::{error, Result};
+use fnv::FnvHashSet;
 
-use crate::db::traits::KvcWriteable;
+use ton_block::{BlockIdExt, ShardIdent};
+use ton_types::{error, Result, UInt256};
+
+use crate::db::traits::{DbKey, KvcWriteable};
 use crate::db_impl_serializable;
+use crate::secondary_index::{IndexHook, SecondaryIndex};
 use crate::traits::Serializable;
-use crate::types::{BlockHandle, BlockId, BlockMeta};
+use crate::types::{
+    BlockHandle, BlockId, BlockIdMismatch, BlockMeta, FLAG_DATA, FLAG_MOVED_TO_ARCHIVE, ShardIdentKey, WorkchainId
+};
 
 
 db_impl_serializable!(BlockHandleDb, KvcWriteable, BlockId, BlockMeta);
 
 pub(crate) type BlockHandleCache = Arc<lockfree::map::Map<BlockIdExt, Weak<BlockHandle>>>;
 
+/// Writer of the handles' metas keeping the secondary indexes up to date. It is shared with the
+/// handles created by BlockHandleStorage, so they can store themselves.
+#[derive(Clone)]
+pub(crate) struct BlockHandleWriter {
+    block_handle_db: Arc<BlockHandleDb>,
+    indexes: Vec<Arc<dyn IndexHook<BlockIdExt>>>,
+}
+
+impl std::fmt::Debug for BlockHandleWriter {
+    fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+        f.debug_struct("BlockHandleWriter")
+            .field("indexes", &self.indexes.iter().map(|index| index.name()).collect::<Vec<_>>())
+            .finish()
+    }
+}
+
+impl BlockHandleWriter {
+    /// Stores handle's meta. Block id is stored after the meta, so that handles can be enumerated;
+    /// BlockMeta::deserialize ignores it. The handle is written before the secondary indexes, so
+    /// they never refer to a handle which isn't stored; the index entries missed by an interrupted
+    /// store are written by the next store of the handle (or by `rebuild_indexes`).
+    pub(crate) fn store(&self, handle: &BlockHandle) -> Result<()> {
+        let mut value = handle.meta().to_vec()?;
+        handle.id().serialize(&mut value)?;
+        let key: BlockId = handle.id().into();
+        key.validate_write(&value, |value| {
+            let mut reader = Cursor::new(value);
+            BlockMeta::deserialize(&mut reader)?;
+            BlockIdExt::deserialize(&mut reader)
+        })?;
+        let old_value = if self.indexes.is_empty() {
+            None
+        } else {
+            self.block_handle_db.try_get(&key)?
+        };
+        self.block_handle_db.put(&key, &value)?;
+        for index in self.indexes.iter() {
+            index.on_put(handle.id(), old_value.as_ref().map(|value| value.as_ref()), &value)?;
+        }
+        Ok(())
+    }
+}
+
+/// Index of the stored block handles by root hash of the block
+pub type BlockByHashIndex = SecondaryIndex<UInt256, BlockIdExt>;
+
+impl BlockByHashIndex {
+    pub fn block_by_hash(path: impl AsRef<std::path::Path>) -> Self {
+        Self::with_path("block_by_hash", path, |id: &BlockIdExt, _value: &[u8]| Ok(Some(id.root_hash.clone())))
+    }
+}
+
+/// Index of the stored block handles by shard of the block. Secondary keys start with the
+/// workchain id, so the handles of the workchain are scanned by the prefix.
+pub type BlockByShardIndex = SecondaryIndex<ShardIdentKey, BlockIdExt>;
+
+impl BlockByShardIndex {
+    pub fn block_by_shard(path: impl AsRef<std::path::Path>) -> Self {
+        Self::with_path_and_prefix_bloom(
+            "block_by_shard",
+            path,
+            std::mem::size_of::<WorkchainId>(),
+            |id: &BlockIdExt, _value: &[u8]| Ok(Some(ShardIdentKey::new(id.shard())?)),
+        )
+    }
+
+    /// Common prefix of the secondary keys of the workchain's blocks
+    pub fn workchain_prefix(workchain_id: WorkchainId) -> [u8; 4] {
+        workchain_id.to_le_bytes()
+    }
+}
+
+/// Block propagation latency (time from generation to receiving) statistics, in seconds
+#[derive(Debug, Clone, Default)]
+pub struct PropagationStats {
+    pub count: usize,
+    pub min: u32,
+    pub max: u32,
+    pub mean: f64,
+    pub median: u32,
+    pub p90: u32,
+}
+
+/// Returns the ranges of `range` not covered by `present` seq_nos (in ascending order)
+pub fn seq_no_gaps(
+    range: RangeInclusive<u32>,
+    present: impl IntoIterator<Item = u32>
+) -> Vec<RangeInclusive<u32>> {
+    let (start, end) = (*range.start(), *range.end());
+    let mut present: Vec<u32> = present.into_iter()
+        .filter(|seq_no| range.contains(seq_no))
+        .collect();
+    present.sort_unstable();
+    present.dedup();
+
+    let mut gaps = Vec::new();
+    let mut next = start as u64;
+    for seq_no in present {
+        if seq_no as u64 > next {
+            gaps.push(next as u32..=seq_no - 1);
+        }
+        next = seq_no as u64 + 1;
+    }
+    if start <= end && next <= end as u64 {
+        gaps.push(next as u32..=end);
+    }
+
+    gaps
+}
+
 pub struct BlockHandleStorage {
     block_handle_db: Arc<BlockHandleDb>,
     block_handle_cache: BlockHandleCache,
+    writer: Arc<BlockHandleWriter>,
 }
 
 impl BlockHandleStorage {
     pub fn new(block_handle_db: Arc<BlockHandleDb>) -> Self {
         Self {
+            writer: Arc::new(BlockHandleWriter { block_handle_db: Arc::clone(&block_handle_db), indexes: Vec::new() }),
             block_handle_db,
             block_handle_cache: BlockHandleCache::default(),
         }
     }
 
+    /// Makes the storage keep the secondary index up to date with the stored handles. Handles
+    /// loaded before (if any) don't update the index when they store themselves.
+    pub fn with_index(mut self, index: Arc<dyn IndexHook<BlockIdExt>>) -> Self {
+        Arc::make_mut(&mut self.writer).indexes.push(index);
+        self
+    }
+
     pub const fn block_handle_db(&self) -> &Arc<BlockHandleDb> {
         &self.block_handle_db
     }
@@ -48,14 +175,263 @@ impl BlockHandleStorage {
         Ok(handle.ok_or_else(|| error!("unexpected None value in load_block_handle_impl"))?)
     }
 
+    /// Loads handles for all given ids at once: metas of handles which are not cached yet are read
+    /// by `try_get_multi` (RocksDB collections have no multi-get, so it falls back to a read per
+    /// key), and the cache is populated in one pass. Result order corresponds to `ids`.
+    pub fn preload_handles(&self, ids: &[BlockIdExt]) -> Result<Vec<Arc<BlockHandle>>> {
+        log::trace!(target: "storage", "preload_handles: {} ids", ids.len());
+
+        let mut handles = Vec::with_capacity(ids.len());
+        let mut missing = Vec::new();
+        let mut keys = Vec::new();
+        for (index, id) in ids.iter().enumerate() {
+            let cached = self.block_handle_cache.get(id)
+                .and_then(|guard| guard.val().upgrade());
+            if cached.is_none() {
+                missing.push(index);
+                keys.push(BlockId::from(id));
+            }
+            handles.push(cached);
+        }
+
+        let metas = self.block_handle_db.try_get_values(&keys)?;
+        for (index, block_meta) in missing.into_iter().zip(metas) {
+            let id = &ids[index];
+            let loaded = self.create_handle(id.clone(), block_meta.unwrap_or_default());
+            let mut handle = None;
+            adnl::common::add_object_to_map_with_update(&self.block_handle_cache, id.clone(), |val| {
+                if let Some(Some(strong)) = val.map(|weak| weak.upgrade()) {
+                    handle = Some(strong);
+                    return Ok(None)
+                }
+                handle = Some(Arc::clone(&loaded));
+                Ok(Some(Arc::downgrade(&loaded)))
+            })?;
+            handles[index] = handle;
+        }
+
+        handles.into_iter()
+            .map(|handle| handle.ok_or_else(|| error!("unexpected None value in preload_handles")))
+            .collect()
+    }
+
+    /// Stores handle's meta. Block id is stored after the meta, so that handles can be enumerated;
+    /// BlockMeta::deserialize ignores it.
     pub fn store_block_handle(&self, handle: &BlockHandle) -> Result<()> {
-        self.block_handle_db.put_value(&handle.id().into(), handle.meta())?;
+        self.writer.store(handle)
+    }
+
+    /// Clears the secondary indexes and fills them from the stored handles. Returns count of handles indexed.
+    pub fn rebuild_indexes(&self) -> Result<usize> {
+        for index in self.writer.indexes.iter() {
+            index.clear()?;
+        }
+
+        let mut count = 0;
+        self.block_handle_db.for_each(&mut |_key, value| {
+            let mut reader = Cursor::new(value);
+            BlockMeta::deserialize(&mut reader)?;
+            if reader.position() as usize >= value.len() {
+                return Ok(true);
+            }
+            let id = BlockIdExt::deserialize(&mut reader)?;
+            for index in self.writer.indexes.iter() {
+                index.on_put(&id, None, value)?;
+            }
+            count += 1;
+            Ok(true)
+        })?;
+        log::info!(target: "storage", "Block handle indexes rebuilt, {} handles indexed", count);
+
+        Ok(count)
+    }
+
+    /// Appends block ids to the handles stored without them by older versions. The ids can't be
+    /// recovered from the keys (hashes of the ids), so they are looked up among the ids returned by
+    /// `known_ids` (e.g. the ids of the block index), which is called only if there are legacy
+    /// handles. Returns count of the handles migrated; the ones left without ids are reported.
+    pub fn migrate_legacy_handles(&self, known_ids: impl FnOnce() -> Result<Vec<BlockIdExt>>) -> Result<usize> {
+        let mut legacy = FnvHashSet::default();
+        self.block_handle_db.for_each(&mut |key, value| {
+            let mut reader = Cursor::new(value);
+            BlockMeta::deserialize(&mut reader)?;
+            if reader.position() as usize >= value.len() {
+                legacy.insert(key.to_vec());
+            }
+            Ok(true)
+        })?;
+        if legacy.is_empty() {
+            return Ok(0);
+        }
+
+        let mut migrated = 0;
+        for id in known_ids()? {
+            let key = BlockId::from(&id);
+            if !legacy.remove(key.key()) {
+                continue;
+            }
+            let mut value = self.block_handle_db.get(&key)?.to_bytes();
+            id.serialize(&mut value)?;
+            self.block_handle_db.put(&key, &value)?;
+            for index in self.writer.indexes.iter() {
+                index.on_put(&id, None, &value)?;
+            }
+            migrated += 1;
+        }
+        log::info!(target: "storage", "{} legacy block handles are migrated", migrated);
+        if !legacy.is_empty() {
+            log::warn!(
+                target: "storage",
+                "{} legacy block handles have unknown block ids and are skipped by enumeration",
+                legacy.len()
+            );
+        }
+
+        Ok(migrated)
+    }
+
+    /// Iterates over stored handles, running predicate for block id and meta of each one.
+    /// Handles stored without block id (by older versions) are skipped, so they have to be
+    /// migrated first (see `migrate_legacy_handles`).
+    pub fn for_each_stored_handle(
+        &self,
+        mut predicate: impl FnMut(BlockIdExt, BlockMeta) -> Result<bool>
+    ) -> Result<bool> {
+        self.block_handle_db.for_each(&mut |_key, value| {
+            let mut reader = Cursor::new(value);
+            let block_meta = BlockMeta::deserialize(&mut reader)?;
+            if reader.position() as usize >= value.len() {
+                return Ok(true);
+            }
+            let id = BlockIdExt::deserialize(&mut reader)?;
+            predicate(id, block_meta)
+        })
+    }
+
+    /// Finds stored handles whose key doesn't match the block id stored after the meta. Such
+    /// handles can't be found by their block id. Handles stored without block id are skipped.
+    pub fn find_block_id_mismatches(&self) -> Result<Vec<BlockIdMismatch>> {
+        let mut mismatches = Vec::new();
+        self.block_handle_db.for_each(&mut |key, value| {
+            let mut reader = Cursor::new(value);
+            BlockMeta::deserialize(&mut reader)?;
+            if reader.position() as usize >= value.len() {
+                return Ok(true);
+            }
+            let id = BlockIdExt::deserialize(&mut reader)?;
+            if !BlockId::key_matches(key, &id) {
+                mismatches.push(BlockIdMismatch { collection: "BlockHandleDb", key: key.to_vec(), block_id_ext: id });
+            }
+            Ok(true)
+        })?;
+
+        Ok(mismatches)
+    }
+
+    /// Finds seq_no ranges of the shard's blocks within `range` which have no stored handle with
+    /// block data, e.g. lost after prunes or crashes, so the blocks can be downloaded again
+    pub fn find_missing(&self, shard: &ShardIdent, range: RangeInclusive<u32>) -> Result<Vec<RangeInclusive<u32>>> {
+        let mut present = Vec::new();
+        self.for_each_stored_handle(|id, block_meta| {
+            let has_data = block_meta.flags().load(Ordering::Relaxed) & FLAG_DATA != 0;
+            if has_data && id.shard() == shard && range.contains(&id.seq_no()) {
+                present.push(id.seq_no());
+            }
+            Ok(true)
+        })?;
+
+        Ok(seq_no_gaps(range, present))
+    }
+
+    /// Calculates propagation latency statistics over stored handles of blocks generated since
+    /// given time and accepted by the filter. Blocks without receiving time are skipped.
+    pub fn propagation_stats(
+        &self,
+        since_gen_utime: u32,
+        filter: impl Fn(&BlockIdExt) -> bool
+    ) -> Result<PropagationStats> {
+        let mut latencies = Vec::new();
+        self.for_each_stored_handle(|id, block_meta| {
+            let gen_utime = block_meta.gen_utime().load(Ordering::Relaxed);
+            let received_at = block_meta.received_at().load(Ordering::Relaxed);
+            if block_meta.fetched() && received_at != 0 && gen_utime >= since_gen_utime && filter(&id) {
+                latencies.push(received_at.saturating_sub(gen_utime));
+            }
+            Ok(true)
+        })?;
+
+        if latencies.is_empty() {
+            return Ok(PropagationStats::default());
+        }
+        latencies.sort_unstable();
+        let count = latencies.len();
+
+        Ok(PropagationStats {
+            count,
+            min: latencies[0],
+            max: latencies[count - 1],
+            mean: latencies.iter().map(|latency| *latency as f64).sum::<f64>() / count as f64,
+            median: latencies[count / 2],
+            p90: latencies[std::cmp::min(count * 9 / 10, count - 1)],
+        })
+    }
+
+    /// Deletes stored handle, unless the handle is in use now. Returns true if the handle was deleted.
+    pub fn delete_block_handle(&self, id: &BlockIdExt) -> Result<bool> {
+        let in_use = self.block_handle_cache.get(id)
+            .map(|guard| guard.val().strong_count() > 0)
+            .unwrap_or(false);
+        if in_use {
+            return Ok(false);
+        }
+        self.delete_stored_handle(id)?;
+
+        Ok(true)
+    }
+
+    /// Deletes the stored handle, provided the given reference is the only one (i.e. the handle
+    /// isn't used by anybody else). Returns false if the handle is in use.
+    pub fn delete_unused_block_handle(&self, handle: &Arc<BlockHandle>) -> Result<bool> {
+        if Arc::strong_count(handle) > 1 {
+            return Ok(false);
+        }
+        self.delete_stored_handle(handle.id())?;
+
+        Ok(true)
+    }
+
+    fn delete_stored_handle(&self, id: &BlockIdExt) -> Result<()> {
+        let key: BlockId = id.into();
+        let old_value = if self.writer.indexes.is_empty() {
+            None
+        } else {
+            self.block_handle_db.try_get(&key)?
+        };
+        self.block_handle_db.delete(&key)?;
+        if let Some(old_value) = old_value {
+            for index in self.writer.indexes.iter() {
+                index.on_delete(id, old_value.as_ref())?;
+            }
+        }
+
         Ok(())
     }
 
+    /// Constructs the handle of the archived block whose stored handle is gone (e.g. by compaction),
+    /// carrying just enough to read the block's files from the archive: the archived flag and the
+    /// masterchain seq_no the package is chosen by. The handle is neither stored nor cached and
+    /// doesn't store its transitions.
+    pub fn synthetic_archived_handle(&self, id: &BlockIdExt, mc_seq_no: u32) -> Arc<BlockHandle> {
+        let meta = BlockMeta::with_data(FLAG_MOVED_TO_ARCHIVE, 0, 0, mc_seq_no, false);
+        Arc::new(BlockHandle::with_values(id.clone(), meta, BlockHandleCache::default()))
+    }
+
     #[inline]
     pub(super) fn create_handle(&self, id: BlockIdExt, meta: BlockMeta) -> Arc<BlockHandle> {
-        Arc::new(BlockHandle::with_values(id, meta, Arc::clone(&self.block_handle_cache)))
+        Arc::new(
+            BlockHandle::with_values(id, meta, Arc::clone(&self.block_handle_cache))
+                .with_writer(Arc::clone(&self.writer))
+        )
     }
 
     fn load_or_create_handle(&self, id: BlockIdExt) -> Result<Arc<BlockHandle>> {
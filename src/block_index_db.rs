@@ -1,24 +1,121 @@
 use std::cmp::Ordering::{Greater, Less};
 use std::convert::TryInto;
 use std::path::Path;
-use std::sync::RwLock;
+use std::sync::Arc;
+use std::sync::atomic::{AtomicBool, AtomicU64, Ordering};
+use std::time::Instant;
 
-use ton_block::{AccountIdPrefixFull, BlockIdExt, MAX_SPLIT_DEPTH, ShardIdent, UnixTime32};
+use fnv::{FnvHashMap, FnvHashSet};
+use parking_lot::{Mutex, RwLock};
+
+use ton_block::{AccountIdPrefixFull, BlockIdExt, MAX_SPLIT_DEPTH, ShardIdent};
 use ton_types::{fail, Result};
 
+use crate::block_handle_db::BlockHandleStorage;
+use crate::error::StorageError;
 use crate::lt_db::LtDb;
 use crate::lt_desc_db::LtDescDb;
-use crate::types::{BlockHandle, LtDbEntry, LtDbKey, LtDesc, ShardIdentKey};
+use crate::traits::Serializable;
+use crate::types::{
+    BlockHandle, BlockId, FLAG_DATA, FLAG_INDEXED, FLAG_PRUNED, Lt, LtDbEntry, LtDbKey, LtDesc, SHARD_IDENT_KEY_LEN,
+    ShardIdentKey, UnixTime, write_validation_enabled,
+};
+
+/// Hit/miss counters of the LtDesc cache
+#[derive(Debug, Clone, Copy, Default, PartialEq)]
+pub struct LtDescCacheMetrics {
+    pub hits: u64,
+    pub misses: u64,
+}
+
+/// Why the indexed block points nowhere
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum DanglingReason {
+    /// There is no stored handle of the block
+    NoHandle,
+    /// Handle of the block is marked as pruned
+    Pruned,
+    /// Handle of the block has no data
+    NoData,
+}
+
+/// Index entry whose block can't be read
+#[derive(Debug, Clone)]
+pub struct DanglingEntry {
+    pub block_id: BlockIdExt,
+    pub index: u32,
+    pub reason: DanglingReason,
+}
+
+/// Result of the block index check against the stored handles
+#[derive(Debug, Clone, Default)]
+pub struct IndexVerification {
+    /// Count of the checked entries
+    pub checked: usize,
+    /// Entries pointing nowhere, including the already tombstoned ones
+    pub dangling: Vec<DanglingEntry>,
+    /// Count of the entries tombstoned by this check
+    pub tombstoned: usize,
+}
+
+/// Shards of the workchain having descriptors
+#[derive(Debug, Clone, Default)]
+struct ShardPrefixes {
+    /// Bit N is set if there are descriptors of the shards with prefix length N; lookups don't
+    /// probe the other lengths
+    lens: u64,
+    /// Shards with retired descriptors
+    retired: FnvHashSet<ShardIdent>,
+    /// Maximal values of the retired descriptors: retired shards are skipped by the lookups
+    /// beyond them
+    retired_bound: Option<LtDesc>,
+}
 
+impl ShardPrefixes {
+    fn add(&mut self, shard: &ShardIdent, lt_desc: &LtDesc) {
+        self.lens |= 1 << shard.prefix_len() as u32;
+        if !lt_desc.retired() {
+            self.retired.remove(shard);
+            return;
+        }
+
+        self.retired.insert(shard.clone());
+        let bound = self.retired_bound.get_or_insert_with(|| LtDesc::with_values(0, 0, 0, 0, 0));
+        bound.set_last_seq_no(std::cmp::max(bound.last_seq_no(), lt_desc.last_seq_no()));
+        bound.set_last_lt(std::cmp::max(bound.last_lt(), lt_desc.last_lt()));
+        bound.set_last_unix_time(std::cmp::max(bound.last_unix_time(), lt_desc.last_unix_time()));
+    }
+}
+
+/// Block index: LtDesc records describe the indexed range of each shard, LtDb entries hold the
+/// blocks of the range. The locks aren't poisoned by panics of their holders.
 #[derive(Debug)]
 pub struct BlockIndexDb {
     lt_desc_db: RwLock<LtDescDb>,
-    lt_db: LtDb,
+    lt_db: RwLock<LtDb>,
+    lt_desc_cache: RwLock<FnvHashMap<ShardIdent, Option<LtDesc>>>,
+    lt_desc_cache_hits: AtomicU64,
+    lt_desc_cache_misses: AtomicU64,
+    /// Index updates of the same shard are serialized by the shard's lock, while the lock over
+    /// the databases is only taken exclusively by the operations touching all the shards
+    shard_locks: Mutex<FnvHashMap<ShardIdent, Arc<Mutex<()>>>>,
+    fork_tolerant: AtomicBool,
+    /// Shards having descriptors by workchains; loaded on the first lookup
+    shard_prefixes: RwLock<Option<FnvHashMap<i32, ShardPrefixes>>>,
 }
 
 impl BlockIndexDb {
     pub fn with_dbs(lt_desc_db: LtDescDb, lt_db: LtDb) -> Self {
-        Self { lt_desc_db: RwLock::new(lt_desc_db), lt_db }
+        Self {
+            lt_desc_db: RwLock::new(lt_desc_db),
+            lt_db: RwLock::new(lt_db),
+            lt_desc_cache: RwLock::new(FnvHashMap::default()),
+            lt_desc_cache_hits: AtomicU64::new(0),
+            lt_desc_cache_misses: AtomicU64::new(0),
+            shard_locks: Mutex::new(FnvHashMap::default()),
+            fork_tolerant: AtomicBool::new(false),
+            shard_prefixes: RwLock::new(None),
+        }
     }
 
     pub fn in_memory() -> Self {
@@ -34,7 +131,7 @@ impl BlockIndexDb {
     ) -> Self {
         Self::with_dbs(
             LtDescDb::with_path(lt_desc_db_path),
-            LtDb::with_path(lt_db_path),
+            LtDb::with_path_and_prefix_bloom(lt_db_path, SHARD_IDENT_KEY_LEN),
         )
     }
 
@@ -42,24 +139,170 @@ impl BlockIndexDb {
         &self.lt_desc_db
     }
 
-    pub const fn lt_db(&self) -> &LtDb {
+    pub const fn lt_db(&self) -> &RwLock<LtDb> {
         &self.lt_db
     }
 
-    pub fn get_block_by_lt(&self, account_id: &AccountIdPrefixFull, lt: u64) -> Result<BlockIdExt> {
+    pub fn fork_tolerant(&self) -> bool {
+        self.fork_tolerant.load(Ordering::Relaxed)
+    }
+
+    /// Enables (or disables) fork-tolerant mode used during recovery after deep rollbacks: blocks
+    /// at already indexed positions are stored as fork candidates instead of being rejected
+    pub fn set_fork_tolerant(&self, value: bool) {
+        self.fork_tolerant.store(value, Ordering::Relaxed)
+    }
+
+    /// Returns hit/miss counters of the LtDesc cache
+    pub fn lt_desc_cache_metrics(&self) -> LtDescCacheMetrics {
+        LtDescCacheMetrics {
+            hits: self.lt_desc_cache_hits.load(Ordering::Relaxed),
+            misses: self.lt_desc_cache_misses.load(Ordering::Relaxed),
+        }
+    }
+
+    /// Drops cached LtDesc record of the shard, so it will be re-read from the database
+    pub fn invalidate_lt_desc(&self, shard: &ShardIdent) {
+        self.lt_desc_cache.write().remove(shard);
+    }
+
+    /// Drops all cached LtDesc records
+    pub fn clear_lt_desc_cache(&self) {
+        self.lt_desc_cache.write().clear();
+    }
+
+    /// Reads the stored LtDesc records into the cache until the deadline. Returns count of the
+    /// records cached and whether all of them were read.
+    pub fn preload_lt_descs(&self, deadline: Instant) -> Result<(usize, bool)> {
+        let lt_desc_db_locked = self.lt_desc_db.read();
+        let mut lt_descs = Vec::new();
+        let completed = lt_desc_db_locked.for_each(&mut |key, value| {
+            if Instant::now() >= deadline {
+                return Ok(false);
+            }
+            lt_descs.push((ShardIdent::from_slice(key)?, LtDesc::from_slice(value)?));
+            Ok(true)
+        })?;
+
+        let count = lt_descs.len();
+        let mut cache = self.lt_desc_cache.write();
+        for (shard, lt_desc) in lt_descs {
+            // Descriptors written since the read are already cached by the writers
+            cache.entry(shard).or_insert(Some(lt_desc));
+        }
+
+        Ok((count, completed))
+    }
+
+    /// Gets descriptor of the shard's index, if the shard has any indexed blocks
+    pub fn get_lt_desc(&self, shard: &ShardIdent) -> Result<Option<LtDesc>> {
+        if let Some(lt_desc) = self.lt_desc_cache.read().get(shard)
+        {
+            self.lt_desc_cache_hits.fetch_add(1, Ordering::Relaxed);
+            return Ok(lt_desc.clone());
+        }
+        self.lt_desc_cache_misses.fetch_add(1, Ordering::Relaxed);
+
+        // Writers put the descriptors into the cache after writing them, so the value read here
+        // is cached only if no writer has done it meanwhile (otherwise it may be outdated). Read
+        // lock keeps the operations over all the shards from running until the cache is updated.
+        let lt_desc_db_locked = self.lt_desc_db.read();
+        let lt_desc = lt_desc_db_locked.try_get_value(&ShardIdentKey::new(shard)?)?;
+        let lt_desc = self.lt_desc_cache.write()
+            .entry(shard.clone())
+            .or_insert(lt_desc)
+            .clone();
+
+        Ok(lt_desc)
+    }
+
+    /// Puts just written descriptor into the cache
+    fn cache_lt_desc(&self, shard: &ShardIdent, lt_desc: &LtDesc) {
+        self.lt_desc_cache.write().insert(shard.clone(), Some(lt_desc.clone()));
+    }
+
+    /// Brings the descriptors in line with the last entries of their shards. The entry and the
+    /// descriptor are kept in separate databases, so they can't be written atomically; this repairs
+    /// the descriptors left behind by the updates interrupted between the two writes. Returns count
+    /// of the descriptors repaired.
+    pub fn reconcile_lt_descs(&self) -> Result<usize> {
+        let lt_desc_db_locked = self.lt_desc_db.write();
+        let mut lt_descs = Vec::new();
+        lt_desc_db_locked.for_each(&mut |key, value| {
+            lt_descs.push((ShardIdent::from_slice(key)?, LtDesc::from_slice(value)?));
+            Ok(true)
+        })?;
+
+        let lt_db = self.lt_db.read();
+        let mut repaired = 0;
+        for (shard, mut lt_desc) in lt_descs {
+            let entry = match lt_db.try_get_value(&LtDbKey::with_values(&shard, lt_desc.last_index())?)? {
+                Some(entry) => entry,
+                None => continue,
+            };
+            if entry.lt() == lt_desc.last_lt() && entry.unix_time() == lt_desc.last_unix_time() {
+                continue;
+            }
+            log::warn!(target: "storage", "LtDesc of shard {} doesn't match its last entry, repaired", shard);
+            lt_desc.set_last_lt(entry.lt());
+            lt_desc.set_last_unix_time(entry.unix_time());
+            lt_desc_db_locked.put_value(&ShardIdentKey::new(&shard)?, &lt_desc)?;
+            repaired += 1;
+        }
+        if repaired > 0 {
+            self.clear_lt_desc_cache();
+        }
+
+        Ok(repaired)
+    }
+
+    /// Returns shards of the workchain having descriptors, loading them for all the workchains
+    /// on the first call
+    fn shard_prefixes(&self, workchain_id: i32) -> Result<ShardPrefixes> {
+        if let Some(ref shard_prefixes) = *self.shard_prefixes.read() {
+            return Ok(shard_prefixes.get(&workchain_id).cloned().unwrap_or_default());
+        }
+
+        // Descriptors added concurrently update the map once it's loaded, so the lock is held
+        // during the load in order not to miss them (the databases are locked first, as add_handle does)
+        let lt_desc_db_locked = self.lt_desc_db.read();
+        let mut shard_prefixes_locked = self.shard_prefixes.write();
+        if shard_prefixes_locked.is_none() {
+            let mut shard_prefixes: FnvHashMap<i32, ShardPrefixes> = FnvHashMap::default();
+            lt_desc_db_locked.for_each(&mut |key, value| {
+                let shard = ShardIdent::from_slice(key)?;
+                let lt_desc = LtDesc::from_slice(value)?;
+                shard_prefixes.entry(shard.workchain_id()).or_default().add(&shard, &lt_desc);
+                Ok(true)
+            })?;
+            *shard_prefixes_locked = Some(shard_prefixes);
+        }
+
+        Ok(shard_prefixes_locked.as_ref()
+            .and_then(|shard_prefixes| shard_prefixes.get(&workchain_id).cloned())
+            .unwrap_or_default())
+    }
+
+    fn on_lt_desc_written(&self, shard: &ShardIdent, lt_desc: &LtDesc) {
+        if let Some(ref mut shard_prefixes) = *self.shard_prefixes.write() {
+            shard_prefixes.entry(shard.workchain_id()).or_default().add(shard, lt_desc);
+        }
+    }
+
+    pub fn get_block_by_lt(&self, account_id: &AccountIdPrefixFull, lt: Lt) -> Result<BlockIdExt> {
         self.get_block(
             account_id,
-            |desc| lt.cmp(&desc.last_lt()),
-            |entry| lt.cmp(&entry.lt()),
+            |desc| lt.cmp(&Lt::new(desc.last_lt())),
+            |entry| lt.cmp(&Lt::new(entry.lt())),
             false
         )
     }
 
-    pub fn get_block_by_ut(&self, account_id: &AccountIdPrefixFull, unix_time: UnixTime32) -> Result<BlockIdExt> {
+    pub fn get_block_by_ut(&self, account_id: &AccountIdPrefixFull, unix_time: UnixTime) -> Result<BlockIdExt> {
         self.get_block(
             account_id,
-            |desc| unix_time.0.cmp(&desc.last_unix_time()),
-            |entry| unix_time.0.cmp(&entry.unix_time()),
+            |desc| unix_time.cmp(&UnixTime::new(desc.last_unix_time())),
+            |entry| unix_time.cmp(&UnixTime::new(entry.unix_time())),
             false
         )
     }
@@ -85,20 +328,34 @@ impl BlockIndexDb {
         FLtDb: Fn(&LtDbEntry) -> std::cmp::Ordering
     {
         let mut found = false;
-        let mut block_id_opt: Option<BlockIdExt> = None;
+        let mut block_id_opt: Option<(BlockIdExt, bool)> = None;
         let mut max_left_seq_no = 0;
 
+        let shard_prefixes = self.shard_prefixes(account_id.workchain_id)?;
+        let skip_retired = shard_prefixes.retired_bound.as_ref()
+            .map(|bound| compare_desc(bound) == Greater)
+            .unwrap_or(false);
+
         for len in 0..=MAX_SPLIT_DEPTH {
+            if shard_prefixes.lens & (1 << len as u32) == 0 {
+                if found {
+                    break;
+                }
+                continue;
+            }
+
             let shard = ShardIdent::with_prefix_len(
                 len,
                 account_id.workchain_id,
                 account_id.prefix)?;
 
-            let shard_key = ShardIdentKey::new(&shard)?;
-            let lt_desc = match self.lt_desc_db.read()
-                .expect("Poisoned RwLock")
-                .try_get_value(&shard_key)?
-            {
+            // The block looked for is newer than any block of the retired shards
+            if skip_retired && shard_prefixes.retired.contains(&shard) {
+                found = true;
+                continue;
+            }
+
+            let lt_desc = match self.get_lt_desc(&shard)? {
                 Some(lt_desc) => lt_desc,
                 _ if found => break,
                 _ => continue,
@@ -125,23 +382,23 @@ impl BlockIndexDb {
                 last_index = index;
 
                 let lt_db_key = LtDbKey::with_values(&shard, index)?;
-                let entry = self.lt_db.get_value(&lt_db_key)?;
+                let entry = self.lt_db.read().get_value(&lt_db_key)?;
                 let result: BlockIdExt = entry.block_id_ext().try_into()?;
                 match compare_lt_db(&entry) {
                     Less => {
-                        right_seq_no_opt = Some(result);
+                        right_seq_no_opt = Some((result, entry.pruned()));
                         rb = index;
                     },
                     Greater => {
                         left_seq_no_opt = Some(result);
                         lb = index;
                     },
-                    _ => return Ok(result),
+                    _ => return Self::found_block(result, entry.pruned()),
                 }
             }
 
-            if let Some(ref right_seq_no) = right_seq_no_opt {
-                if let Some(ref block_id) = block_id_opt {
+            if let Some((ref right_seq_no, _)) = right_seq_no_opt {
+                if let Some((ref block_id, _)) = block_id_opt {
                     if block_id.seq_no() > right_seq_no.seq_no() as u32 {
                         block_id_opt = right_seq_no_opt;
                     }
@@ -156,10 +413,10 @@ impl BlockIndexDb {
                 }
             }
 
-            if let Some(ref block_id) = block_id_opt {
+            if let Some((ref block_id, pruned)) = block_id_opt {
                 if block_id.seq_no() == max_left_seq_no + 1 {
                     if !exact {
-                        return Ok(block_id.clone());
+                        return Self::found_block(block_id.clone(), pruned);
                     } else {
                         fail!("Block not found");
                     }
@@ -167,20 +424,46 @@ impl BlockIndexDb {
             }
         }
 
-        if !exact && block_id_opt.is_some() {
-            return Ok(block_id_opt.unwrap());
+        if !exact {
+            if let Some((block_id, pruned)) = block_id_opt {
+                return Self::found_block(block_id, pruned);
+            }
         }
 
         fail!("Block not found")
     }
 
+    /// Blocks of the tombstoned entries are reported as pruned rather than returned
+    fn found_block(block_id: BlockIdExt, pruned: bool) -> Result<BlockIdExt> {
+        if pruned {
+            Err(StorageError::Pruned(format!("block {} (tombstoned in the block index)", block_id)))?
+        }
+
+        Ok(block_id)
+    }
+
+    fn shard_lock(&self, shard: &ShardIdent) -> Arc<Mutex<()>> {
+        Arc::clone(
+            self.shard_locks.lock()
+                .entry(shard.clone())
+                .or_default()
+        )
+    }
+
+    /// Indexes the block. Blocks of different shards are indexed concurrently. The entry is
+    /// written before the descriptor referencing it (they are kept in separate databases), so an
+    /// interrupted update leaves at most an unreferenced entry, overwritten by the next update.
     pub fn add_handle(&self, handle: &BlockHandle) -> Result<()> {
         log::trace!(target: "storage", "BlockIndexDb::add_handle {}", handle.id());
+        let shard_lock = self.shard_lock(handle.id().shard());
+        let _shard_locked = shard_lock.lock();
         let desc_key = ShardIdentKey::new(handle.id().shard())?;
-        let lt_desc_db_locked = self.lt_desc_db.write()
-            .expect("Poisoned RwLock");
+        let lt_desc_db_locked = self.lt_desc_db.read();
         let index = if let Some(lt_desc) = lt_desc_db_locked.try_get_value(&desc_key)? {
             match handle.id().seq_no().cmp(&lt_desc.last_seq_no()) {
+                std::cmp::Ordering::Equal | std::cmp::Ordering::Less if self.fork_tolerant() => {
+                    return self.add_fork(&lt_desc_db_locked, handle, &lt_desc)
+                }
                 std::cmp::Ordering::Equal => return Ok(()),
                 std::cmp::Ordering::Less => fail!("Block handles seq_no must be written in the ascending order!"),
                 _ => lt_desc.last_index() + 1,
@@ -191,13 +474,22 @@ impl BlockIndexDb {
 
         let lt_key = LtDbKey::with_values(handle.id().shard(), index)?;
 
-        let lt_entry = LtDbEntry::with_values(
+        let mut lt_entry = LtDbEntry::with_values(
             handle.id().into(),
             handle.gen_lt(),
             handle.gen_utime()?
         );
+        lt_entry.set_applied(handle.applied());
+        if write_validation_enabled() {
+            // The id indexed must lead back to the handle's one
+            let indexed: BlockIdExt = lt_entry.block_id_ext().try_into()?;
+            BlockId::from(indexed).validate(handle.id())?;
+        }
 
-        self.lt_db.put_value(&lt_key, &lt_entry)?;
+        // LtDb and LtDescDb are separate RocksDB instances, and a WriteBatch can't span two of
+        // them. The entry is written first, so an interrupted update leaves a descriptor behind
+        // its shard's last entry, which `reconcile_lt_descs` repairs on open.
+        self.lt_db.read().put_value(&lt_key, &lt_entry)?;
 
         let lt_desc = LtDesc::with_values(
             1,
@@ -208,7 +500,354 @@ impl BlockIndexDb {
         );
 
         lt_desc_db_locked.put_value(&desc_key, &lt_desc)?;
+        self.cache_lt_desc(handle.id().shard(), &lt_desc);
+        self.on_lt_desc_written(handle.id().shard(), &lt_desc);
 
         Ok(())
     }
+
+    /// Finds index of the entry with given seq_no among the shard's entries
+    fn find_index(&self, shard: &ShardIdent, seq_no: u32, lt_desc: &LtDesc) -> Result<Option<(u32, LtDbEntry)>> {
+        let lt_db = self.lt_db.read();
+        let mut lb = lt_desc.first_index();
+        let mut rb = lt_desc.last_index() + 1;
+        while rb > lb {
+            let index = lb + (rb - lb) / 2;
+            let entry = match lt_db.try_get_value(&LtDbKey::with_values(shard, index)?)? {
+                Some(entry) => entry,
+                None => return Ok(None),
+            };
+            match seq_no.cmp(&(entry.block_id_ext().seqno as u32)) {
+                Less => rb = index,
+                Greater => lb = index + 1,
+                _ => return Ok(Some((index, entry))),
+            }
+        }
+
+        Ok(None)
+    }
+
+    /// Adds the block as a fork candidate of the already indexed position
+    fn add_fork(&self, lt_desc_db: &LtDescDb, handle: &BlockHandle, lt_desc: &LtDesc) -> Result<()> {
+        let shard = handle.id().shard();
+        let (index, mut entry) = match self.find_index(shard, handle.id().seq_no(), lt_desc)? {
+            Some(found) => found,
+            None => fail!("Position of block {} is not indexed, fork can't be added", handle.id()),
+        };
+
+        let block_id_ext = handle.id().into();
+        let added = entry.add_fork(block_id_ext, handle.gen_lt(), handle.gen_utime()?);
+        if !added && !handle.applied() {
+            return Ok(());
+        }
+        log::info!(target: "storage", "Fork candidate {} is added into the block index", handle.id());
+        if handle.applied() {
+            entry.promote(&handle.id().into());
+        }
+
+        self.put_entry(lt_desc_db, shard, index, entry, lt_desc)
+    }
+
+    /// Writes the entry; if it is the last entry of the shard, LtDesc is updated accordingly (the
+    /// descriptor left outdated by an interrupted update is repaired by `reconcile_lt_descs`)
+    fn put_entry(
+        &self,
+        lt_desc_db: &LtDescDb,
+        shard: &ShardIdent,
+        index: u32,
+        entry: LtDbEntry,
+        lt_desc: &LtDesc,
+    ) -> Result<()> {
+        self.lt_db.read().put_value(&LtDbKey::with_values(shard, index)?, &entry)?;
+
+        if index == lt_desc.last_index() {
+            let mut lt_desc = lt_desc.clone();
+            lt_desc.set_last_lt(entry.lt());
+            lt_desc.set_last_unix_time(entry.unix_time());
+            lt_desc_db.put_value(&ShardIdentKey::new(shard)?, &lt_desc)?;
+            self.cache_lt_desc(shard, &lt_desc);
+            self.on_lt_desc_written(shard, &lt_desc);
+        }
+
+        Ok(())
+    }
+
+    /// Marks the block as applied, making it preferred by lookups over the fork candidates at the
+    /// same position. Returns false if the block is not indexed.
+    pub fn mark_applied(&self, block_id: &BlockIdExt) -> Result<bool> {
+        let shard = block_id.shard();
+        let shard_lock = self.shard_lock(shard);
+        let _shard_locked = shard_lock.lock();
+        let lt_desc_db_locked = self.lt_desc_db.read();
+        let lt_desc = match lt_desc_db_locked.try_get_value(&ShardIdentKey::new(shard)?)? {
+            Some(lt_desc) => lt_desc,
+            None => return Ok(false),
+        };
+        let (index, mut entry) = match self.find_index(shard, block_id.seq_no(), &lt_desc)? {
+            Some(found) => found,
+            None => return Ok(false),
+        };
+        if !entry.promote(&block_id.into()) {
+            return Ok(false);
+        }
+
+        self.put_entry(&lt_desc_db_locked, shard, index, entry, &lt_desc)?;
+
+        Ok(true)
+    }
+
+    /// Returns all the blocks indexed at the position of the shard with given seq_no, together with
+    /// their "applied" markers. The block preferred by lookups goes first.
+    pub fn get_block_candidates(&self, shard: &ShardIdent, seq_no: u32) -> Result<Vec<(BlockIdExt, bool)>> {
+        let lt_desc = match self.get_lt_desc(shard)? {
+            Some(lt_desc) => lt_desc,
+            None => return Ok(Vec::new()),
+        };
+        let entry = match self.find_index(shard, seq_no, &lt_desc)? {
+            Some((_index, entry)) => entry,
+            None => return Ok(Vec::new()),
+        };
+
+        let mut result = Vec::with_capacity(entry.forks().len() + 1);
+        result.push((entry.block_id_ext().try_into()?, entry.applied()));
+        for candidate in entry.forks() {
+            result.push((candidate.block_id_ext().try_into()?, false));
+        }
+
+        Ok(result)
+    }
+
+    /// Iterates over the entries of the shard in the ascending order of their indexes (which is
+    /// not the order of seq_no, as the index wraps the key by little-endian bytes), without
+    /// touching entries of the other shards
+    pub fn for_each_in_shard(
+        &self,
+        shard: &ShardIdent,
+        mut predicate: impl FnMut(u32, LtDbEntry) -> Result<bool>,
+    ) -> Result<bool> {
+        let prefix = LtDbKey::shard_prefix(shard)?;
+        self.lt_db.read().for_each_value_with_prefix(&prefix, |key, entry| {
+            let mut index = [0; 4];
+            index.copy_from_slice(&key[prefix.len()..]);
+            predicate(u32::from_le_bytes(index), entry)
+        })
+    }
+
+    /// Collects ids of all the indexed blocks, fork candidates included
+    pub fn block_ids(&self) -> Result<Vec<BlockIdExt>> {
+        let mut result = Vec::new();
+        self.lt_db.read().for_each(&mut |_key, value| {
+            let entry = LtDbEntry::from_slice(value)?;
+            result.push(entry.block_id_ext().try_into()?);
+            for candidate in entry.forks() {
+                result.push(candidate.block_id_ext().try_into()?);
+            }
+            Ok(true)
+        })?;
+
+        Ok(result)
+    }
+
+    /// Drops fork candidates of all the positions where one of the blocks is applied.
+    /// Positions without applied blocks are left intact. Returns count of dropped candidates.
+    pub fn drop_losing_forks(&self) -> Result<usize> {
+        let _lt_desc_db_locked = self.lt_desc_db.write();
+        let lt_db = self.lt_db.read();
+
+        let mut forked = Vec::new();
+        lt_db.for_each(&mut |key, value| {
+            let entry = LtDbEntry::from_slice(value)?;
+            if entry.applied() && !entry.forks().is_empty() {
+                forked.push((LtDbKey::from_key(key), entry));
+            }
+            Ok(true)
+        })?;
+
+        let mut dropped = 0;
+        for (key, mut entry) in forked {
+            dropped += entry.drop_forks();
+            lt_db.put_value(&key, &entry)?;
+        }
+
+        log::info!(target: "storage", "{} losing fork candidates dropped from the block index", dropped);
+
+        Ok(dropped)
+    }
+
+    /// Retires descriptors of the shards gone by reconfigurations: the shards without blocks since
+    /// `horizon` whose prefix intersects one of the shards with newer blocks (i.e. the shard was
+    /// split or merged). Retired descriptors and their entries are kept for historical lookups, while
+    /// the lookups beyond them skip the retired shards. A block added into the retired shard makes
+    /// it active again. Returns the shards retired by this call.
+    pub fn retire_inactive_shards(&self, horizon: UnixTime) -> Result<Vec<ShardIdent>> {
+        let lt_desc_db_locked = self.lt_desc_db.write();
+        let mut lt_descs = Vec::new();
+        lt_desc_db_locked.for_each(&mut |key, value| {
+            lt_descs.push((ShardIdent::from_slice(key)?, LtDesc::from_slice(value)?));
+            Ok(true)
+        })?;
+
+        let is_active = |lt_desc: &LtDesc| UnixTime::new(lt_desc.last_unix_time()) >= horizon;
+        let mut retired = Vec::new();
+        for (shard, lt_desc) in lt_descs.iter() {
+            if lt_desc.retired() || is_active(lt_desc) {
+                continue;
+            }
+            let superseded = lt_descs.iter().any(|(other, other_desc)| {
+                other != shard
+                    && other.workchain_id() == shard.workchain_id()
+                    && other.intersect_with(shard)
+                    && is_active(other_desc)
+            });
+            if !superseded {
+                continue;
+            }
+
+            let mut lt_desc = lt_desc.clone();
+            lt_desc.set_retired(true);
+            lt_desc_db_locked.put_value(&ShardIdentKey::new(shard)?, &lt_desc)?;
+            retired.push(shard.clone());
+        }
+
+        self.clear_lt_desc_cache();
+        *self.shard_prefixes.write() = None;
+
+        log::info!(target: "storage", "{} inactive shards retired in the block index", retired.len());
+
+        Ok(retired)
+    }
+
+    /// Cross-checks the primary block of every entry with its stored handle, reporting entries
+    /// whose block has no handle, is pruned or has no data. If `tombstone` is set, such entries
+    /// are marked as pruned, so lookups hitting them fail with `StorageError::Pruned`. Index
+    /// updates aren't blocked by the scan; entries to tombstone are re-checked under the lock of
+    /// their shard, as they might be rewritten meanwhile.
+    pub fn verify_against(&self, handles: &BlockHandleStorage, tombstone: bool) -> Result<IndexVerification> {
+        let mut result = IndexVerification::default();
+        let mut to_tombstone = Vec::new();
+        self.lt_db.read().for_each(&mut |key, value| {
+            let entry = LtDbEntry::from_slice(value)?;
+            let block_id: BlockIdExt = entry.block_id_ext().try_into()?;
+            result.checked += 1;
+
+            let reason = match Self::dangling_reason(handles, &block_id)? {
+                Some(reason) => reason,
+                None => return Ok(true),
+            };
+
+            let mut index = [0; 4];
+            index.copy_from_slice(&key[key.len() - 4..]);
+            log::warn!(target: "storage", "Block index entry of {} is dangling: {:?}", block_id, reason);
+            if tombstone && !entry.pruned() {
+                to_tombstone.push((LtDbKey::from_key(key), block_id.clone()));
+            }
+            result.dangling.push(DanglingEntry { block_id, index: u32::from_le_bytes(index), reason });
+            Ok(true)
+        })?;
+
+        for (key, block_id) in to_tombstone {
+            let shard_lock = self.shard_lock(block_id.shard());
+            let _shard_locked = shard_lock.lock();
+            let _lt_desc_db_locked = self.lt_desc_db.read();
+            let lt_db = self.lt_db.read();
+            let mut entry = match lt_db.try_get_value(&key)? {
+                Some(entry) => entry,
+                None => continue,
+            };
+            let current: BlockIdExt = entry.block_id_ext().try_into()?;
+            if current != block_id || entry.pruned() || Self::dangling_reason(handles, &block_id)?.is_none() {
+                continue;
+            }
+            entry.set_pruned(true);
+            lt_db.put_value(&key, &entry)?;
+            result.tombstoned += 1;
+        }
+
+        log::info!(
+            target: "storage",
+            "Block index verified: {} entries checked, {} dangling, {} tombstoned",
+            result.checked, result.dangling.len(), result.tombstoned
+        );
+
+        Ok(result)
+    }
+
+    /// Reason the entry of the block is dangling; None if the block is stored
+    fn dangling_reason(handles: &BlockHandleStorage, block_id: &BlockIdExt) -> Result<Option<DanglingReason>> {
+        Ok(match handles.block_handle_db().try_get_value(&block_id.into())? {
+            None => Some(DanglingReason::NoHandle),
+            Some(block_meta) => {
+                let flags = block_meta.flags().load(Ordering::Relaxed);
+                if flags & FLAG_PRUNED != 0 {
+                    Some(DanglingReason::Pruned)
+                } else if flags & FLAG_DATA == 0 {
+                    Some(DanglingReason::NoData)
+                } else {
+                    None
+                }
+            }
+        })
+    }
+}
+
+impl BlockIndexDb {
+    /// Regenerates index from the stored block handles (marked as indexed) into given fresh
+    /// databases, and then swaps them in. Returns previously used databases, so the caller is able
+    /// to destroy them. Index updates are blocked during the rebuild. `progress` is called with
+    /// count of processed handles and total count of handles to process.
+    pub fn rebuild_from(
+        &self,
+        handles: &BlockHandleStorage,
+        lt_desc_db: LtDescDb,
+        lt_db: LtDb,
+        mut progress: impl FnMut(usize, usize),
+    ) -> Result<(LtDescDb, LtDb)> {
+        log::info!(target: "storage", "Rebuilding block index...");
+
+        let mut lt_desc_db_locked = self.lt_desc_db.write();
+
+        let mut shards: FnvHashMap<ShardIdent, Vec<(BlockIdExt, u64, u32)>> = FnvHashMap::default();
+        let mut total = 0;
+        handles.for_each_stored_handle(|id, block_meta| {
+            if block_meta.flags().load(Ordering::Relaxed) & FLAG_INDEXED != 0 {
+                let gen_lt = block_meta.gen_lt().load(Ordering::Relaxed);
+                let gen_utime = block_meta.gen_utime().load(Ordering::Relaxed);
+                shards.entry(id.shard().clone())
+                    .or_default()
+                    .push((id, gen_lt, gen_utime));
+                total += 1;
+            }
+            Ok(true)
+        })?;
+
+        let mut processed = 0;
+        progress(processed, total);
+        for (shard, mut entries) in shards {
+            entries.sort_by_key(|(id, _gen_lt, _gen_utime)| id.seq_no());
+            entries.dedup_by_key(|(id, _gen_lt, _gen_utime)| id.seq_no());
+
+            let mut index = 0;
+            for (id, gen_lt, gen_utime) in entries.iter() {
+                index += 1;
+                let lt_key = LtDbKey::with_values(&shard, index)?;
+                lt_db.put_value(&lt_key, &LtDbEntry::with_values(id.into(), *gen_lt, *gen_utime))?;
+                processed += 1;
+                progress(processed, total);
+            }
+
+            if let Some((id, gen_lt, gen_utime)) = entries.last() {
+                let lt_desc = LtDesc::with_values(1, index, id.seq_no(), *gen_lt, *gen_utime);
+                lt_desc_db.put_value(&ShardIdentKey::new(&shard)?, &lt_desc)?;
+            }
+        }
+
+        let old_lt_db = std::mem::replace(&mut *self.lt_db.write(), lt_db);
+        let old_lt_desc_db = std::mem::replace(&mut *lt_desc_db_locked, lt_desc_db);
+        self.clear_lt_desc_cache();
+        *self.shard_prefixes.write() = None;
+
+        log::info!(target: "storage", "Block index is rebuilt, {} blocks indexed", processed);
+
+        Ok((old_lt_desc_db, old_lt_db))
+    }
 }
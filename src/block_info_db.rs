@@ -1,5 +1,144 @@
-use crate::db_impl_base;
+use std::ops::{Deref, DerefMut};
+use std::path::Path;
+use std::sync::Arc;
+use std::sync::atomic::{AtomicUsize, Ordering};
+
+use ton_block::BlockIdExt;
+use ton_types::{fail, Result};
+
+use crate::blob_store::BlobStore;
+use crate::db::chunked::ChunkedDb;
 use crate::db::traits::KvcWriteable;
-use crate::types::BlockId;
+use crate::types::{BlockHandle, BlockId, BlockInfoKey, ProofKind};
+
+#[derive(Debug)]
+pub struct BlockInfoDb {
+    db: ChunkedDb<BlockInfoKey>,
+    blob_store: Option<Arc<BlobStore>>,
+    dedup_min_size: AtomicUsize,
+}
+
+impl BlockInfoDb {
+    /// Constructs new instance using in-memory key-value collection
+    pub fn in_memory() -> Self {
+        Self::with_db(ChunkedDb::in_memory())
+    }
+
+    /// Constructs new instance using RocksDB with given path
+    pub fn with_path<P: AsRef<Path>>(path: P) -> Self {
+        Self::with_db(ChunkedDb::with_path(path, "BlockInfoDb"))
+    }
+
+    fn with_db(db: ChunkedDb<BlockInfoKey>) -> Self {
+        Self { db, blob_store: None, dedup_min_size: AtomicUsize::new(usize::MAX) }
+    }
+
+    /// Underlying collection; large proofs are stored split into chunks
+    pub const fn chunked_db(&self) -> &ChunkedDb<BlockInfoKey> {
+        &self.db
+    }
+
+    /// Sets the blob store proofs are deduplicated with. Proofs referencing blobs can't be loaded
+    /// without the store, so once deduplication was enabled, the store must always be set.
+    pub fn set_blob_store(&mut self, blob_store: Arc<BlobStore>) {
+        self.blob_store = Some(blob_store);
+    }
+
+    /// Enables deduplication of proofs of at least given size (requires the blob store set);
+    /// None disables deduplication of the proofs stored afterwards
+    pub fn set_dedup_min_size(&self, value: Option<usize>) {
+        self.dedup_min_size.store(value.unwrap_or(usize::MAX), Ordering::Relaxed)
+    }
+
+    /// Stores proof (or prooflink) of the block
+    pub fn store_proof(&self, block_id: &BlockIdExt, proof_kind: ProofKind, data: &[u8]) -> Result<()> {
+        let key = BlockInfoKey::proof(BlockId::from(block_id), proof_kind);
+        let blob_store = match self.blob_store {
+            Some(ref blob_store) => blob_store,
+            None => return self.put(&key, data),
+        };
+
+        // The new blob reference is added before the old one is released, so a failure in between
+        // leaks a reference instead of losing the blob
+        let old_record = self.try_get(&key)?.map(|value| value.to_vec());
+        if data.len() >= self.dedup_min_size.load(Ordering::Relaxed) {
+            self.put(&key, &blob_store.add(data)?)?;
+        } else {
+            self.put(&key, data)?;
+        }
+        if let Some(old_record) = old_record {
+            blob_store.release(&old_record)?;
+        }
+
+        Ok(())
+    }
+
+    /// Tries to load proof (or prooflink) of the block; returns Ok(None) if it is not stored
+    pub fn try_load_proof(&self, block_id: &BlockIdExt, proof_kind: ProofKind) -> Result<Option<Vec<u8>>> {
+        match self.try_get(&BlockInfoKey::proof(BlockId::from(block_id), proof_kind))? {
+            Some(value) => Ok(Some(self.resolve(value.into())?)),
+            None => Ok(None),
+        }
+    }
+
+    /// Loads proof (or prooflink) of the block
+    pub fn load_proof(&self, block_id: &BlockIdExt, proof_kind: ProofKind) -> Result<Vec<u8>> {
+        self.resolve(self.get_owned(&BlockInfoKey::proof(BlockId::from(block_id), proof_kind))?)
+    }
+
+    fn resolve(&self, record: Vec<u8>) -> Result<Vec<u8>> {
+        match self.blob_store {
+            Some(ref blob_store) => blob_store.resolve(record),
+            None if BlobStore::is_reference(&record) => fail!("Proof is stored in the blob store, which is not set"),
+            None => Ok(record),
+        }
+    }
+
+    /// Checks whether proof (or prooflink) of the block is stored. Fails if the result disagrees
+    /// with the corresponding flag of the block handle.
+    pub fn has_proof(&self, handle: &BlockHandle, proof_kind: ProofKind) -> Result<bool> {
+        let flag = match proof_kind {
+            ProofKind::Proof => handle.proof_inited(),
+            ProofKind::ProofLink => handle.proof_link_inited(),
+        };
+        let stored = self.contains(&BlockInfoKey::proof(BlockId::from(handle.id()), proof_kind))?;
+        if flag && !stored {
+            fail!("Block {} is marked as having {}, but it is not stored", handle.id(), proof_kind)
+        }
+
+        Ok(stored)
+    }
+
+    /// Deletes proofs and prooflinks of all given blocks
+    pub fn delete_proofs(&self, block_ids: &[BlockIdExt]) -> Result<()> {
+        for block_id in block_ids {
+            for proof_kind in &[ProofKind::Proof, ProofKind::ProofLink] {
+                let key = BlockInfoKey::proof(BlockId::from(block_id), *proof_kind);
+                let record = match self.blob_store {
+                    Some(_) => self.try_get(&key)?.map(|value| value.to_vec()),
+                    None => None,
+                };
+                self.delete(&key)?;
+                if let (Some(blob_store), Some(record)) = (&self.blob_store, record) {
+                    blob_store.release(&record)?;
+                }
+            }
+        }
+
+        Ok(())
+    }
+}
+
+impl Deref for BlockInfoDb {
+    type Target = dyn KvcWriteable<BlockInfoKey> + Send + Sync;
+
+    fn deref(&self) -> &Self::Target {
+        &self.db
+    }
+}
 
-db_impl_base!(BlockInfoDb, KvcWriteable, BlockId);
+impl DerefMut for BlockInfoDb {
+    fn deref_mut(&mut self) -> &mut Self::Target {
+        &mut self.db
+    }
+}
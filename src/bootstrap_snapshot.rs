@@ -0,0 +1,102 @@
+use std::path::Path;
+
+use serde_derive::{Deserialize, Serialize};
+use sha2::{Digest, Sha256};
+
+use ton_types::{fail, Result};
+
+use crate::archives::package_manifest::file_digest;
+
+/// Version of the bootstrap snapshot format
+pub const BOOTSTRAP_SNAPSHOT_VERSION: u32 = 1;
+
+/// Name of the manifest file in the snapshot directory
+pub const BOOTSTRAP_MANIFEST_FILENAME: &str = "manifest.json";
+
+/// File of the bootstrap snapshot
+#[derive(Debug, Clone, PartialEq, Serialize, Deserialize)]
+pub struct BootstrapFile {
+    /// Package entry filename describing the content (persistent state, key block or proof)
+    pub entry: String,
+    /// Path of the file relative to the snapshot directory
+    pub path: String,
+    pub size: u64,
+    /// Hex-encoded SHA-256 of the file
+    pub sha256: String,
+}
+
+/// Manifest of the bootstrap snapshot: the masterchain key block the snapshot is made for, and the
+/// files with persistent states of the block and of its shards' top blocks, the key blocks up to
+/// the block and their proofs
+#[derive(Debug, Clone, PartialEq, Serialize, Deserialize)]
+pub struct BootstrapManifest {
+    pub version: u32,
+    /// Masterchain block id in the package entry filename format
+    pub mc_block: String,
+    pub created_at: u32,
+    pub files: Vec<BootstrapFile>,
+}
+
+impl BootstrapManifest {
+    pub async fn write_to_dir(&self, dir: impl AsRef<Path>) -> Result<()> {
+        tokio::fs::write(dir.as_ref().join(BOOTSTRAP_MANIFEST_FILENAME), serde_json::to_vec_pretty(self)?).await?;
+
+        Ok(())
+    }
+
+    pub async fn read_from_dir(dir: impl AsRef<Path>) -> Result<Self> {
+        let manifest: Self = serde_json::from_slice(
+            &tokio::fs::read(dir.as_ref().join(BOOTSTRAP_MANIFEST_FILENAME)).await?
+        )?;
+        if manifest.version != BOOTSTRAP_SNAPSHOT_VERSION {
+            fail!("Unsupported bootstrap snapshot version {}", manifest.version)
+        }
+
+        Ok(manifest)
+    }
+
+    /// Checks sizes and hashes of all the files of the snapshot located in the directory
+    pub async fn verify_files(&self, dir: impl AsRef<Path>) -> Result<()> {
+        for file in self.files.iter() {
+            check_relative_path(&file.path)?;
+            let (size, digest) = file_digest(dir.as_ref().join(&file.path)).await?;
+            if size != file.size || hex::encode(digest) != file.sha256 {
+                fail!(
+                    "Bootstrap snapshot file {} ({}) is damaged: size {}, sha256 {}, expected size {}, sha256 {}",
+                    file.path,
+                    file.entry,
+                    size,
+                    hex::encode(digest),
+                    file.size,
+                    file.sha256
+                )
+            }
+        }
+
+        Ok(())
+    }
+
+    /// Writes the file of the entry into the snapshot directory and adds it into the manifest
+    pub(crate) async fn add_file(&mut self, dir: &Path, entry: String, data: &[u8]) -> Result<()> {
+        let path = format!("{:06}.bin", self.files.len());
+        tokio::fs::write(dir.join(&path), data).await?;
+        self.files.push(BootstrapFile {
+            entry,
+            path,
+            size: data.len() as u64,
+            sha256: hex::encode(Sha256::digest(data)),
+        });
+
+        Ok(())
+    }
+}
+
+/// Rejects paths escaping the snapshot directory
+fn check_relative_path(path: &str) -> Result<()> {
+    let path = Path::new(path);
+    if path.is_absolute() || path.components().any(|component| component.as_os_str() == "..") {
+        fail!("Bootstrap snapshot file path {:?} is outside of the snapshot", path)
+    }
+
+    Ok(())
+}
@@ -0,0 +1,43 @@
+use std::sync::Arc;
+use std::sync::atomic::{AtomicBool, Ordering};
+
+use ton_types::Result;
+
+use crate::error::StorageError;
+
+/// Token for cooperative cancellation of long-running operations. Clones share the state, so the
+/// operation given one clone is cancelled through any other. Operations check the token between
+/// batches of work and fail with `StorageError::Cancelled`.
+#[derive(Debug, Clone, Default)]
+pub struct CancellationToken {
+    cancelled: Arc<AtomicBool>,
+}
+
+impl CancellationToken {
+    pub fn new() -> Self {
+        Self::default()
+    }
+
+    /// Requests the operations checking the token to stop
+    pub fn cancel(&self) {
+        self.cancelled.store(true, Ordering::SeqCst)
+    }
+
+    pub fn is_cancelled(&self) -> bool {
+        self.cancelled.load(Ordering::SeqCst)
+    }
+
+    /// Fails with `StorageError::Cancelled` if the cancellation is requested
+    pub fn check(&self) -> Result<()> {
+        if self.is_cancelled() {
+            Err(StorageError::Cancelled)?
+        }
+
+        Ok(())
+    }
+}
+
+/// Returns true if the error is the cancellation (rather than a failure) of the operation
+pub fn is_cancelled(err: &failure::Error) -> bool {
+    err.downcast_ref::<StorageError>() == Some(&StorageError::Cancelled)
+}
@@ -0,0 +1,181 @@
+use std::cmp::Reverse;
+use std::collections::BinaryHeap;
+use std::path::Path;
+use std::sync::Arc;
+use std::sync::atomic::{AtomicU32, Ordering};
+
+use fnv::FnvHashMap;
+use parking_lot::Mutex;
+use serde_derive::{Deserialize, Serialize};
+
+use ton_types::Result;
+
+use crate::clock::{Clock, system_clock};
+use crate::db::traits::KvcWriteable;
+use crate::db_impl_serializable;
+use crate::traits::Serializable;
+use crate::types::{CellAccessInfo, CellId};
+
+db_impl_serializable!(CellAccessDb, KvcWriteable, CellId, CellAccessInfo);
+
+/// Count of the sampled cells accumulated in memory before they are merged into the database
+const FLUSH_THRESHOLD: usize = 4096;
+
+pub const DEFAULT_ACCESS_SAMPLE_RATE: u32 = 64;
+pub const DEFAULT_CELL_IDLE_TIME: u32 = 3600;
+
+/// Configuration of the cells access statistics and of keeping the recently read states by GC
+#[derive(Debug, Clone, PartialEq, Eq, Deserialize, Serialize)]
+#[serde(default, deny_unknown_fields)]
+pub struct CellAccessStatsConfig {
+    /// Only each `sample_rate`-th access is registered
+    pub sample_rate: u32,
+    /// States whose root cells were read during last `cell_idle_time` seconds are not collected
+    pub cell_idle_time: u32,
+}
+
+impl Default for CellAccessStatsConfig {
+    fn default() -> Self {
+        Self { sample_rate: DEFAULT_ACCESS_SAMPLE_RATE, cell_idle_time: DEFAULT_CELL_IDLE_TIME }
+    }
+}
+
+/// Collects approximate per-cell access counters. Only each `sample_rate`-th access is
+/// registered (with the corresponding weight), so the overhead on the loading path stays low.
+/// Sampled accesses are accumulated in memory and merged into the database by batches, so the
+/// loading path doesn't touch the database and concurrent accesses of one cell aren't lost.
+/// Access times are taken from the clock GC decisions are made against.
+#[derive(Debug)]
+pub struct CellAccessStats {
+    db: CellAccessDb,
+    sample_rate: u32,
+    counter: AtomicU32,
+    clock: Arc<dyn Clock>,
+    pending: Mutex<FnvHashMap<CellId, CellAccessInfo>>,
+    /// Serializes the merges, so read-modify-write of the stored records isn't interleaved
+    flush_lock: Mutex<()>,
+}
+
+impl CellAccessStats {
+    pub fn with_db(db: CellAccessDb, sample_rate: u32) -> Self {
+        Self {
+            db,
+            sample_rate: std::cmp::max(sample_rate, 1),
+            counter: AtomicU32::new(0),
+            clock: system_clock(),
+            pending: Mutex::new(FnvHashMap::default()),
+            flush_lock: Mutex::new(()),
+        }
+    }
+
+    pub fn in_memory(sample_rate: u32) -> Self {
+        Self::with_db(CellAccessDb::in_memory(), sample_rate)
+    }
+
+    pub fn with_path(path: impl AsRef<Path>, sample_rate: u32) -> Self {
+        Self::with_db(CellAccessDb::with_path(path), sample_rate)
+    }
+
+    /// Takes access times from the clock instead of the system one
+    pub fn with_clock(mut self, clock: Arc<dyn Clock>) -> Self {
+        self.clock = clock;
+        self
+    }
+
+    pub const fn sample_rate(&self) -> u32 {
+        self.sample_rate
+    }
+
+    /// Registers access to the cell, if the access is hit by sampling. The access is accumulated
+    /// in memory; the accumulated ones are merged into the database once there are enough of them
+    /// (skipped if another merge is running).
+    pub fn on_cell_accessed(&self, cell_id: &CellId) -> Result<()> {
+        if self.counter.fetch_add(1, Ordering::Relaxed) % self.sample_rate != 0 {
+            return Ok(());
+        }
+
+        let pending_len = {
+            let mut pending = self.pending.lock();
+            pending.entry(cell_id.clone())
+                .or_default()
+                .register_access(self.sample_rate as u64, self.clock.now());
+            pending.len()
+        };
+        if pending_len >= FLUSH_THRESHOLD {
+            if let Some(_flushing) = self.flush_lock.try_lock() {
+                self.merge_pending()?;
+            }
+        }
+
+        Ok(())
+    }
+
+    /// Merges the accesses accumulated in memory into the database
+    pub fn flush(&self) -> Result<()> {
+        let _flushing = self.flush_lock.lock();
+        self.merge_pending()
+    }
+
+    // Must be called under the flush lock
+    fn merge_pending(&self) -> Result<()> {
+        let pending = std::mem::take(&mut *self.pending.lock());
+        for (cell_id, accesses) in pending {
+            let mut info = self.db.try_get_value(&cell_id)?.unwrap_or_default();
+            info.merge(&accesses);
+            self.db.put_value(&cell_id, info)?;
+        }
+
+        Ok(())
+    }
+
+    /// Gets access statistics of the cell, if the cell was ever sampled
+    pub fn get(&self, cell_id: &CellId) -> Result<Option<CellAccessInfo>> {
+        let pending = self.pending.lock().get(cell_id).cloned();
+        let stored = self.db.try_get_value(cell_id)?;
+        Ok(match (stored, pending) {
+            (Some(mut info), Some(accesses)) => {
+                info.merge(&accesses);
+                Some(info)
+            },
+            (stored, pending) => stored.or(pending),
+        })
+    }
+
+    /// Returns true if the cell was not accessed (according to the sampled data) since given time
+    pub fn idle_since(&self, cell_id: &CellId, utime: u32) -> Result<bool> {
+        Ok(self.get(cell_id)?
+            .map(|info| info.last_access_utime() < utime)
+            .unwrap_or(true))
+    }
+
+    /// Returns up to `top_k` the most frequently accessed cells in descending order of access count
+    pub fn top(&self, top_k: usize) -> Result<Vec<(CellId, CellAccessInfo)>> {
+        self.flush()?;
+        let mut heap = BinaryHeap::with_capacity(top_k + 1);
+        if top_k > 0 {
+            self.db.for_each(&mut |key, value| {
+                let info = CellAccessInfo::from_slice(value)?;
+                heap.push(Reverse((info.access_count(), key.to_vec(), info.last_access_utime())));
+                if heap.len() > top_k {
+                    heap.pop();
+                }
+                Ok(true)
+            })?;
+        }
+
+        let mut result = Vec::with_capacity(heap.len());
+        for Reverse((access_count, key, last_access_utime)) in heap.into_sorted_vec() {
+            result.push((CellId::from_key(&key)?, CellAccessInfo::with_values(access_count, last_access_utime)));
+        }
+
+        Ok(result)
+    }
+}
+
+impl Drop for CellAccessStats {
+    fn drop(&mut self) {
+        if let Err(err) = self.flush() {
+            log::warn!(target: "storage", "Cell access statistics are not flushed: {}", err);
+        }
+    }
+}
@@ -1,21 +1,114 @@
-use std::io::{Cursor, Write};
+use std::io::Write;
+use std::path::Path;
 use std::sync::Arc;
 
-use ton_types::{ByteOrderRead, Cell, CellData, Result, MAX_REFERENCES_COUNT};
+use sha2::{Digest, Sha256};
+
+use ton_types::{Cell, CellData, CellType, fail, Result, MAX_LEVEL, MAX_REFERENCES_COUNT};
 use ton_types::UInt256;
 
+use crate::cell_format;
+use crate::cell_gc_horizon::{CellGcHorizon, split_cell_header};
 use crate::db_impl_base;
+use crate::db::collection_metadata::{check_collection_metadata, key_type_name};
+use crate::db::memorydb::MemoryDb;
+use crate::db::rocksdb::RocksDb;
 use crate::db::traits::{KvcTransaction, KvcTransactional};
-use crate::dynamic_boc_db::DynamicBocDb;
+use crate::error::StorageError;
+use crate::traits::CellLoader;
 use crate::types::{CellId, Reference, StorageCell};
 
 db_impl_base!(CellDb, KvcTransactional, CellId);
 
 impl CellDb {
+    /// Constructs new instance using RocksDB with given path, which drops cells below the GC horizon
+    /// during compactions
+    pub fn with_path_and_gc_horizon(path: impl AsRef<Path>, gc_horizon: &Arc<CellGcHorizon>) -> Self {
+        check_collection_metadata(path.as_ref(), "CellDb", key_type_name::<CellId>())
+            .expect("Cannot open DB");
+        Self {
+            db: Box::new(RocksDb::with_options(path, |options| gc_horizon.configure_compaction_filter(options)))
+        }
+    }
+
+    /// Constructs new instance using in-memory key-value collection, which drops cells below the GC
+    /// horizon by `compact_range`
+    pub fn in_memory_with_gc_horizon(gc_horizon: &Arc<CellGcHorizon>) -> Self {
+        let gc_horizon = Arc::clone(gc_horizon);
+        Self {
+            db: Box::new(MemoryDb::with_compaction_filter(move |_key, value| gc_horizon.is_expired(value)))
+        }
+    }
+
     /// Gets cell from key-value storage by cell id
-    pub fn get_cell(&self, cell_id: &CellId, boc_db: Arc<DynamicBocDb>) -> Result<StorageCell> {
-        let (cell_data, references) = Self::deserialize_cell(self.db.get(&cell_id)?.as_ref())?;
-        Ok(StorageCell::with_params(cell_data, references, boc_db))
+    pub fn get_cell(&self, cell_id: &CellId, loader: Arc<dyn CellLoader>) -> Result<StorageCell> {
+        let (cell_data, references) = self.deserialize_cell(self.db.get(&cell_id)?.as_ref())?;
+        Ok(StorageCell::with_params(cell_data, references, loader))
+    }
+
+    /// Gets cell from key-value storage by cell id without parsing the record, which is deferred to
+    /// the first access to the cell's data or references
+    pub fn get_lazy_cell(&self, cell_id: &CellId, loader: Arc<dyn CellLoader>) -> Result<StorageCell> {
+        let value = self.db.get(&cell_id)?;
+        let (_generation, data) = split_cell_header(value.as_ref());
+        if data.is_empty() {
+            fail!("Data of cell {} is empty", cell_id);
+        }
+
+        Ok(StorageCell::with_raw_data(cell_id, data.to_vec(), loader))
+    }
+
+    /// Gets cell from key-value storage by cell id and verifies its representation hash against the
+    /// id. Cells whose hash can't be recalculated (exotic ones and ones with non-zero level) are
+    /// returned unverified.
+    pub fn get_verified_cell(&self, cell_id: &CellId, loader: Arc<dyn CellLoader>) -> Result<StorageCell> {
+        let (cell_data, references) = self.deserialize_cell(self.db.get(&cell_id)?.as_ref())?;
+        if cell_data.cell_type() == CellType::Ordinary && cell_data.level_mask().level() == 0 {
+            let mut ref_hashes = Vec::with_capacity(references.len());
+            for reference in references.iter() {
+                let ref_hash = reference.hash();
+                let (ref_data, _) = self.deserialize_cell(self.db.get(&CellId::from(ref_hash.clone()))?.as_ref())?;
+                ref_hashes.push((ref_data.depth(MAX_LEVEL as usize), ref_hash));
+            }
+            if let Some(hash) = Self::calc_repr_hash(&cell_data, &ref_hashes) {
+                let recalculated_id = CellId::new(hash);
+                if &recalculated_id != cell_id {
+                    log::error!(target: "storage", "Cell {} is corrupted: recalculated hash is {}", cell_id, recalculated_id);
+                    Err(StorageError::CorruptedData(format!("cell {} hash mismatch", cell_id)))?
+                }
+            }
+        }
+
+        Ok(StorageCell::with_params(cell_data, references, loader))
+    }
+
+    /// Gets the stored record of the cell without building the cell (the GC header is stripped)
+    pub fn get_raw(&self, cell_id: &CellId) -> Result<Vec<u8>> {
+        self.raw_record(cell_id, self.db.get(cell_id)?.as_ref())
+    }
+
+    /// Gets the stored records of the cells (see `get_raw`); the result contains an element for each
+    /// id, None for the missing cells
+    pub fn get_raw_multi(&self, cell_ids: &[CellId]) -> Result<Vec<Option<Vec<u8>>>> {
+        let values = self.db.try_get_multi(cell_ids)?;
+        cell_ids.iter()
+            .zip(values)
+            .map(|(cell_id, value)| value.map(|value| self.raw_record(cell_id, value.as_ref())).transpose())
+            .collect()
+    }
+
+    fn raw_record(&self, cell_id: &CellId, value: &[u8]) -> Result<Vec<u8>> {
+        let (_generation, data) = split_cell_header(value);
+        if data.is_empty() {
+            fail!("Data of cell {} is empty", cell_id);
+        }
+
+        Ok(data.to_vec())
+    }
+
+    /// Gets generation the cell is stamped with; Ok(None) is returned for legacy records
+    pub fn get_cell_generation(&self, cell_id: &CellId) -> Result<Option<u32>> {
+        Ok(split_cell_header(self.db.get(&cell_id)?.as_ref()).0)
     }
 
     /// Puts cell into transaction
@@ -25,7 +118,7 @@ impl CellDb {
     }
 
     /// Binary serialization of cell data
-    fn serialize_cell(cell: Cell) -> Result<Vec<u8>> {
+    pub(crate) fn serialize_cell(cell: Cell) -> Result<Vec<u8>> {
         let references_count = cell.references_count() as u8;
 
         assert!(references_count as usize <= MAX_REFERENCES_COUNT);
@@ -44,19 +137,39 @@ impl CellDb {
         Ok(data)
     }
 
-    /// Binary deserialization of cell data
-    pub(crate) fn deserialize_cell(data: &[u8]) -> Result<(CellData, Vec<Reference>)> {
-        assert!(!data.is_empty());
+    /// Calculates representation hash of the ordinary cell with zero level, given its data and
+    /// depths and hashes of the references. Returns None for other kinds of cells.
+    pub(crate) fn calc_repr_hash(cell_data: &CellData, references: &[(u16, UInt256)]) -> Option<UInt256> {
+        if cell_data.cell_type() != CellType::Ordinary || cell_data.level_mask().level() != 0 {
+            return None;
+        }
+
+        let bit_length = cell_data.bit_length() as usize;
+        let data = cell_data.data().get(..(bit_length + 7) / 8)?;
+
+        let mut hasher = Sha256::new();
+        hasher.input(&[references.len() as u8, (bit_length / 8 + (bit_length + 7) / 8) as u8]);
+        hasher.input(data);
+        for (depth, _hash) in references {
+            hasher.input(&depth.to_be_bytes());
+        }
+        for (_depth, hash) in references {
+            hasher.input(hash.as_slice());
+        }
+
+        let mut hash = [0; 32];
+        hash.copy_from_slice(hasher.result().as_slice());
+
+        Some(hash.into())
+    }
 
-        let mut reader = Cursor::new(data);
-        let cell_data = CellData::deserialize(&mut reader)?;
-        let references_count = reader.read_byte()?;
-        let mut references = Vec::with_capacity(references_count as usize);
-        for _ in 0..references_count {
-            let hash = UInt256::from(reader.read_u256()?);
-            references.push(Reference::NeedToLoad(hash));
+    /// Binary deserialization of the stored cell record which fails on empty data instead of panic
+    pub(crate) fn deserialize_cell(&self, data: &[u8]) -> Result<(CellData, Vec<Reference>)> {
+        let (_generation, data) = split_cell_header(data);
+        if data.is_empty() {
+            fail!("Cell data is empty");
         }
 
-        Ok((cell_data, references))
+        cell_format::decode_record(data)
     }
 }
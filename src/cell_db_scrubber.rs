@@ -0,0 +1,168 @@
+use std::sync::Arc;
+use std::sync::atomic::{AtomicBool, Ordering};
+use std::time::Duration;
+
+use serde_derive::{Deserialize, Serialize};
+
+use ton_types::{MAX_LEVEL, Result};
+
+use crate::cell_db::CellDb;
+use crate::node_state_db::NodeStateDb;
+use crate::types::{CellId, NodeStateKey};
+
+/// Configuration of CellDb scrubbing
+#[derive(Debug, Clone, Deserialize, Serialize)]
+#[serde(default)]
+pub struct CellDbScrubberConfig {
+    /// Maximal count of cells verified per second
+    pub cells_per_second: u32,
+    /// Count of cells verified between progress saves
+    pub batch_size: usize,
+}
+
+impl Default for CellDbScrubberConfig {
+    fn default() -> Self {
+        Self { cells_per_second: 10_000, batch_size: 1_000 }
+    }
+}
+
+/// Results of the scrubbing pass
+#[derive(Debug, Clone, Default)]
+pub struct CellDbScrubStats {
+    pub verified: usize,
+    pub unverifiable: usize,
+    pub corrupted: usize,
+    pub finished: bool,
+}
+
+/// Reason of the cell to be considered corrupted
+#[derive(Debug, Clone, PartialEq)]
+pub enum CellCorruption {
+    /// Stored record can't be deserialized
+    BadRecord(String),
+    /// Recalculated representation hash doesn't match the key
+    HashMismatch,
+    /// Referenced cell is missing in the database
+    MissingReference(CellId),
+}
+
+/// Background verifier of CellDb. Iterates over the cells keyspace in the order of keys,
+/// recalculates hash of every cell and compares it with the key. Position is persisted in
+/// NodeStateDb, so scrubbing resumes after restarts.
+pub struct CellDbScrubber {
+    cell_db: Arc<CellDb>,
+    node_state_db: Arc<NodeStateDb>,
+    config: CellDbScrubberConfig,
+    stopped: AtomicBool,
+}
+
+impl CellDbScrubber {
+    pub fn new(cell_db: Arc<CellDb>, node_state_db: Arc<NodeStateDb>, config: CellDbScrubberConfig) -> Self {
+        Self {
+            cell_db,
+            node_state_db,
+            config,
+            stopped: AtomicBool::new(false),
+        }
+    }
+
+    /// Requests running pass to stop after the current batch. The stop is sticky: passes started
+    /// afterwards return at once, until `reset` is called.
+    pub fn stop(&self) {
+        self.stopped.store(true, Ordering::SeqCst);
+    }
+
+    /// Clears the stop request, so the scrubber may be run again
+    pub fn reset(&self) {
+        self.stopped.store(false, Ordering::SeqCst);
+    }
+
+    /// Runs scrubbing pass from the saved position up to the end of the keyspace (or until stopped),
+    /// calling `on_corrupted` for each corrupted cell found
+    pub async fn run(
+        &self,
+        mut on_corrupted: impl FnMut(&CellId, &CellCorruption),
+    ) -> Result<CellDbScrubStats> {
+        let batch_size = std::cmp::max(self.config.batch_size, 1);
+        let delay = Duration::from_millis(
+            1000 * batch_size as u64 / std::cmp::max(self.config.cells_per_second, 1) as u64
+        );
+
+        let mut stats = CellDbScrubStats::default();
+        let mut position = self.node_state_db.try_get(&NodeStateKey::CellDbScrubPosition)?
+            .map(|value| value.to_vec())
+            .unwrap_or_default();
+        log::info!(target: "storage", "CellDb scrubbing started from position {}", hex::encode(&position));
+
+        while !self.stopped.load(Ordering::SeqCst) {
+            let batch = self.read_batch(&position, batch_size)?;
+            if batch.is_empty() {
+                self.node_state_db.delete(&NodeStateKey::CellDbScrubPosition)?;
+                stats.finished = true;
+                break;
+            }
+
+            for (key, value) in batch.iter() {
+                let cell_id = CellId::from_key(key)?;
+                match self.verify_cell(&cell_id, value)? {
+                    Ok(true) => stats.verified += 1,
+                    Ok(false) => stats.unverifiable += 1,
+                    Err(corruption) => {
+                        log::error!(target: "storage", "Corrupted cell {}: {:?}", cell_id, corruption);
+                        stats.corrupted += 1;
+                        on_corrupted(&cell_id, &corruption);
+                    }
+                }
+            }
+
+            position = batch.last().map(|(key, _value)| key.clone()).unwrap_or_default();
+            self.node_state_db.put(&NodeStateKey::CellDbScrubPosition, &position)?;
+
+            tokio::time::delay_for(delay).await;
+        }
+
+        log::info!(target: "storage", "CellDb scrubbing stopped: {:?}", stats);
+
+        Ok(stats)
+    }
+
+    fn read_batch(&self, position: &[u8], batch_size: usize) -> Result<Vec<(Vec<u8>, Vec<u8>)>> {
+        let mut batch = Vec::with_capacity(batch_size);
+        self.cell_db.for_each_from(position, &mut |key, value| {
+            if key != position {
+                batch.push((key.to_vec(), value.to_vec()));
+            }
+            Ok(batch.len() < batch_size)
+        })?;
+
+        Ok(batch)
+    }
+
+    /// Returns Ok(true) if the cell is verified, Ok(false) if hash of the cell can't be recalculated
+    /// (exotic cells and cells with non-zero level), and the corruption otherwise
+    fn verify_cell(&self, cell_id: &CellId, value: &[u8]) -> Result<std::result::Result<bool, CellCorruption>> {
+        let (cell_data, references) = match self.cell_db.deserialize_cell(value) {
+            Ok(cell) => cell,
+            Err(err) => return Ok(Err(CellCorruption::BadRecord(err.to_string()))),
+        };
+
+        let mut ref_hashes = Vec::with_capacity(references.len());
+        for reference in references {
+            let ref_id = CellId::from(reference.hash());
+            let ref_data = match self.cell_db.try_get(&ref_id)? {
+                Some(ref_value) => match self.cell_db.deserialize_cell(ref_value.as_ref()) {
+                    Ok((ref_data, _)) => ref_data,
+                    Err(_) => return Ok(Ok(false)),
+                },
+                None => return Ok(Err(CellCorruption::MissingReference(ref_id))),
+            };
+            ref_hashes.push((ref_data.depth(MAX_LEVEL as usize), reference.hash()));
+        }
+
+        Ok(match CellDb::calc_repr_hash(&cell_data, &ref_hashes) {
+            Some(hash) if &CellId::new(hash) == cell_id => Ok(true),
+            Some(_) => Err(CellCorruption::HashMismatch),
+            None => Ok(false),
+        })
+    }
+}
@@ -0,0 +1,61 @@
+use std::io::Cursor;
+
+use ton_types::{ByteOrderRead, CellData, Result, UInt256};
+
+use crate::cell_gc_horizon::{CELL_HEADER_LEN, CELL_HEADER_TAG};
+use crate::schema::{FieldSchema, FieldType, RecordSchema, RecordSchemas};
+use crate::types::Reference;
+
+/// Cell record stored in CellDb: the cell data followed by the representation hashes of the
+/// references
+pub struct CellRecord;
+
+const CELL_FIELDS: [FieldSchema; 2] = [
+    FieldSchema::new("cell_data", FieldType::CellData, "Cell type, level, hashes and bits"),
+    FieldSchema::new("references", FieldType::Hashes, "Representation hashes of the referred cells"),
+];
+const CELL_STAMP_FIELDS: [FieldSchema; 2] = [
+    FieldSchema::new("header_tag", FieldType::Magic(&[CELL_HEADER_TAG]), "GC horizon header tag"),
+    FieldSchema::new("generation", FieldType::U32, "Generation the cell was last referenced in"),
+];
+
+fn is_cell(data: &[u8]) -> bool {
+    !data.is_empty() && data[0] != CELL_HEADER_TAG
+}
+
+fn is_stamped(data: &[u8], payload_matches: fn(&[u8]) -> bool) -> bool {
+    data.first() == Some(&CELL_HEADER_TAG) && data.len() > CELL_HEADER_LEN && payload_matches(&data[CELL_HEADER_LEN..])
+}
+
+impl RecordSchemas for CellRecord {
+    const SCHEMAS: &'static [RecordSchema] = &[
+        RecordSchema {
+            name: "CellRecord",
+            version: 1,
+            description: "Cell",
+            fields: &CELL_FIELDS,
+            matches: is_cell,
+        },
+        RecordSchema {
+            name: "StampedCellRecord",
+            version: 1,
+            description: "Cell with the GC horizon header",
+            fields: &[CELL_STAMP_FIELDS[0], CELL_STAMP_FIELDS[1], CELL_FIELDS[0], CELL_FIELDS[1]],
+            matches: |data| is_stamped(data, is_cell),
+        },
+    ];
+}
+
+/// Decodes cell record
+pub(crate) fn decode_record(data: &[u8]) -> Result<(CellData, Vec<Reference>)> {
+    let mut reader = Cursor::new(data);
+    let cell_data = CellData::deserialize(&mut reader)?;
+    let references_count = reader.read_byte()?;
+    let mut references = Vec::with_capacity(references_count as usize);
+    for _ in 0..references_count {
+        let hash = UInt256::from(reader.read_u256()?);
+        references.push(Reference::NeedToLoad(hash));
+    }
+
+    Ok((cell_data, references))
+}
@@ -0,0 +1,155 @@
+use std::sync::Arc;
+use std::sync::atomic::{AtomicBool, AtomicU64, Ordering};
+
+use fnv::{FnvHashMap, FnvHashSet};
+use parking_lot::{Mutex, RwLock, RwLockWriteGuard};
+
+use ton_types::Result;
+
+use crate::types::CellId;
+
+/// Generation fencing between cell GC and the writers of new trees. Every writer is
+/// registered with the current GC generation and records the cells it reaches (both written and
+/// found in the database). The sweep doesn't delete the cells recorded by the writers active at
+/// the moment or finished after its mark snapshot, since their roots may be not registered yet.
+///
+/// With compaction filter-based GC the recorded cells are restamped before the horizon is raised.
+///
+/// The writers never wait for each other or for the whole GC run: the only exclusive section is the
+/// final commit of the sweep, which is atomic with respect to the writer's check of a cell.
+#[derive(Debug, Default)]
+pub struct CellGcFence {
+    generation: AtomicU64,
+    next_writer_id: AtomicU64,
+    writers: Mutex<FnvHashMap<u64, FnvHashSet<CellId>>>,
+    // Cells of the writers finished during the running sweep
+    finished: Mutex<Vec<FnvHashSet<CellId>>>,
+    sweeping: AtomicBool,
+    commit_lock: RwLock<()>,
+}
+
+impl CellGcFence {
+    pub fn new() -> Self {
+        Self::default()
+    }
+
+    /// Generation the writers are currently registered with
+    pub fn generation(&self) -> u64 {
+        self.generation.load(Ordering::SeqCst)
+    }
+
+    /// Count of the writers registered at the moment
+    pub fn active_writers(&self) -> usize {
+        self.writers.lock().len()
+    }
+
+    /// Registers the writer with the current generation. The writer stays registered until the
+    /// registration is dropped, which must happen only after the root of the written tree is
+    /// registered (so GC finds the tree live).
+    pub fn register(self: &Arc<Self>) -> WriterRegistration {
+        let id = self.next_writer_id.fetch_add(1, Ordering::SeqCst);
+        self.writers.lock().insert(id, FnvHashSet::default());
+
+        WriterRegistration {
+            fence: Arc::clone(self),
+            id,
+            generation: self.generation(),
+        }
+    }
+
+    /// Takes the mark snapshot: writers registered afterwards get the newer generation. Cells of the
+    /// writers finished during the sweep stay fenced until the returned guard is dropped.
+    /// Sweeps must not run concurrently.
+    pub fn begin_sweep(self: &Arc<Self>) -> SweepGuard {
+        let snapshot = self.generation.fetch_add(1, Ordering::SeqCst);
+        self.sweeping.store(true, Ordering::SeqCst);
+
+        SweepGuard { fence: Arc::clone(self), snapshot }
+    }
+}
+
+/// Registration of the writer in the GC fence (see `CellGcFence::register`)
+#[derive(Debug)]
+pub struct WriterRegistration {
+    fence: Arc<CellGcFence>,
+    id: u64,
+    generation: u64,
+}
+
+impl WriterRegistration {
+    pub const fn generation(&self) -> u64 {
+        self.generation
+    }
+
+    /// Records the cell as reached by the writer and runs the check of its presence in the database.
+    /// The sweep can't commit deletion of the cell between these steps, so either the cell is kept
+    /// by the sweep or the check finds it already deleted.
+    pub fn check_cell<T>(&self, cell_id: &CellId, check: impl FnOnce() -> Result<T>) -> Result<T> {
+        let _commit_locked = self.fence.commit_lock.read();
+        if let Some(cells) = self.fence.writers.lock().get_mut(&self.id) {
+            cells.insert(cell_id.clone());
+        }
+
+        check()
+    }
+}
+
+impl Drop for WriterRegistration {
+    fn drop(&mut self) {
+        let cells = self.fence.writers.lock().remove(&self.id);
+        if let Some(cells) = cells {
+            if self.fence.sweeping.load(Ordering::SeqCst) && !cells.is_empty() {
+                self.fence.finished.lock().push(cells);
+            }
+        }
+    }
+}
+
+/// Running sweep of the GC (see `CellGcFence::begin_sweep`)
+#[derive(Debug)]
+pub struct SweepGuard {
+    fence: Arc<CellGcFence>,
+    snapshot: u64,
+}
+
+impl SweepGuard {
+    /// Generation of the mark snapshot; writers registered with newer generations started after it
+    pub const fn snapshot(&self) -> u64 {
+        self.snapshot
+    }
+
+    /// Blocks the checks of the writers while the sweep picks the cells to keep and commits
+    /// deletion of the rest
+    pub fn lock_commit(&self) -> RwLockWriteGuard<'_, ()> {
+        self.fence.commit_lock.write()
+    }
+
+    /// Returns the candidates for deletion reached by the writers
+    pub fn fenced_cells(&self, candidates: &FnvHashSet<CellId>) -> Vec<CellId> {
+        self.collect_cells(|cell_id| candidates.contains(cell_id))
+    }
+
+    /// Returns all the cells reached by the writers active at the moment or finished since the
+    /// snapshot. Used by compaction filter-based GC, which restamps them instead of the sweep.
+    pub fn reached_cells(&self) -> Vec<CellId> {
+        self.collect_cells(|_cell_id| true)
+    }
+
+    fn collect_cells(&self, filter: impl Fn(&CellId) -> bool) -> Vec<CellId> {
+        let mut result = Vec::new();
+        let mut collect = |cells: &FnvHashSet<CellId>| {
+            result.extend(cells.iter().filter(|cell_id| filter(*cell_id)).cloned());
+        };
+        self.fence.writers.lock().values().for_each(&mut collect);
+        self.fence.finished.lock().iter().for_each(&mut collect);
+
+        result
+    }
+}
+
+impl Drop for SweepGuard {
+    fn drop(&mut self) {
+        self.fence.sweeping.store(false, Ordering::SeqCst);
+        self.fence.finished.lock().clear();
+    }
+}
@@ -0,0 +1,135 @@
+use std::convert::TryInto;
+use std::sync::Arc;
+use std::sync::atomic::{AtomicBool, AtomicU32, Ordering};
+
+use rocksdb::{CompactionDecision, Options};
+
+use ton_types::{error, Result};
+
+use crate::node_state_db::NodeStateDb;
+use crate::types::NodeStateKey;
+
+/// First byte of the cell record header. Legacy records start with the cell type, which never
+/// takes this value, so both kinds of records can be stored in the same database.
+pub(crate) const CELL_HEADER_TAG: u8 = 0xFF;
+pub(crate) const CELL_HEADER_LEN: usize = 5;
+
+/// Prepends serialized cell with the header carrying the generation the cell was last referenced in
+pub(crate) fn stamp_cell(generation: u32, payload: &[u8]) -> Vec<u8> {
+    let mut data = Vec::with_capacity(CELL_HEADER_LEN + payload.len());
+    data.push(CELL_HEADER_TAG);
+    data.extend_from_slice(&generation.to_le_bytes());
+    data.extend_from_slice(payload);
+
+    data
+}
+
+/// Splits stored cell record into the generation (None for legacy records) and serialized cell
+pub(crate) fn split_cell_header(data: &[u8]) -> (Option<u32>, &[u8]) {
+    if data.len() > CELL_HEADER_LEN && data[0] == CELL_HEADER_TAG {
+        let generation = u32::from_le_bytes(data[1..CELL_HEADER_LEN].try_into().expect("Header length is checked"));
+        return (Some(generation), &data[CELL_HEADER_LEN..]);
+    }
+
+    (None, data)
+}
+
+/// Generation counters of compaction filter-based cell GC. Every cell written into CellDb (or
+/// referenced again) is stamped with the current generation; GC restamps all the live cells and
+/// raises the horizon, and the cells stamped below the horizon are dropped by RocksDB during
+/// regular compactions. Both counters are persisted in NodeStateDb.
+///
+/// Records written before the mode was enabled have no stamp and are never dropped by the filter
+/// until they are restamped (i.e. found live by GC or referenced by a newly saved state).
+#[derive(Debug)]
+pub struct CellGcHorizon {
+    node_state_db: Arc<NodeStateDb>,
+    generation: AtomicU32,
+    horizon: AtomicU32,
+    // Set when states are collected: their cells get below the horizon by the next generation
+    collection_pending: AtomicBool,
+}
+
+impl CellGcHorizon {
+    /// Loads persisted counters (zeros, if there are none)
+    pub fn with_node_state_db(node_state_db: Arc<NodeStateDb>) -> Result<Self> {
+        let generation = Self::load_counter(&node_state_db, &NodeStateKey::CellGcGeneration)?;
+        let horizon = Self::load_counter(&node_state_db, &NodeStateKey::CellGcHorizon)?;
+        log::info!(target: "storage", "Cell GC generation is {}, horizon is {}", generation, horizon);
+
+        Ok(Self {
+            node_state_db,
+            generation: AtomicU32::new(generation),
+            horizon: AtomicU32::new(horizon),
+            // Collection interrupted by restart is finished by the first run
+            collection_pending: AtomicBool::new(true),
+        })
+    }
+
+    fn load_counter(node_state_db: &NodeStateDb, key: &NodeStateKey) -> Result<u32> {
+        match node_state_db.try_get(key)? {
+            Some(value) => Ok(u32::from_le_bytes(value.as_ref().try_into()
+                .map_err(|_| error!("Bad value of {}", key.as_str()))?)),
+            None => Ok(0),
+        }
+    }
+
+    /// Generation the written cells are currently stamped with
+    pub fn generation(&self) -> u32 {
+        self.generation.load(Ordering::SeqCst)
+    }
+
+    /// Cells stamped with lower generations are dropped by compactions
+    pub fn horizon(&self) -> u32 {
+        self.horizon.load(Ordering::SeqCst)
+    }
+
+    /// Starts new generation and returns it
+    pub fn advance(&self) -> Result<u32> {
+        let generation = self.generation.fetch_add(1, Ordering::SeqCst) + 1;
+        self.node_state_db.put(&NodeStateKey::CellGcGeneration, &generation.to_le_bytes())?;
+
+        Ok(generation)
+    }
+
+    /// Returns true if the cells of the states collected by the previous run are not below the
+    /// horizon yet, so the next run must start new generation even if it collects nothing
+    pub fn collection_pending(&self) -> bool {
+        self.collection_pending.load(Ordering::SeqCst)
+    }
+
+    pub fn set_collection_pending(&self, value: bool) {
+        self.collection_pending.store(value, Ordering::SeqCst)
+    }
+
+    /// Raises the horizon up to given generation (the horizon never goes down)
+    pub fn raise_horizon(&self, horizon: u32) -> Result<()> {
+        let horizon = std::cmp::min(horizon, self.generation());
+        if self.horizon.fetch_max(horizon, Ordering::SeqCst) < horizon {
+            self.node_state_db.put(&NodeStateKey::CellGcHorizon, &horizon.to_le_bytes())?;
+            log::info!(target: "storage", "Cell GC horizon raised to {}", horizon);
+        }
+
+        Ok(())
+    }
+
+    /// Returns true if stored cell record is below the horizon
+    pub fn is_expired(&self, data: &[u8]) -> bool {
+        match split_cell_header(data) {
+            (Some(generation), _) => generation < self.horizon(),
+            (None, _) => false,
+        }
+    }
+
+    /// Installs compaction filter dropping expired cell records into RocksDB options
+    pub fn configure_compaction_filter(self: &Arc<Self>, options: &mut Options) {
+        let gc_horizon = Arc::clone(self);
+        options.set_compaction_filter("cell_gc_horizon", move |_level: u32, _key: &[u8], value: &[u8]| {
+            if gc_horizon.is_expired(value) {
+                CompactionDecision::Remove
+            } else {
+                CompactionDecision::Keep
+            }
+        });
+    }
+}
@@ -0,0 +1,210 @@
+use std::collections::VecDeque;
+use std::sync::{Arc, Weak};
+use std::sync::atomic::{AtomicU64, Ordering};
+use std::sync::mpsc::{Receiver, RecvTimeoutError, SyncSender, TrySendError};
+use std::time::Duration;
+
+use fnv::FnvHashSet;
+use parking_lot::Mutex;
+use serde_derive::{Deserialize, Serialize};
+
+use ton_types::Result;
+
+use crate::dynamic_boc_db::DynamicBocDb;
+use crate::types::{CellId, StorageCell};
+
+/// Configuration of the background prefetching of child cells
+#[derive(Debug, Clone, Deserialize, Serialize)]
+#[serde(default)]
+pub struct PrefetchConfig {
+    /// Maximal count of the cells waiting for prefetching; requests exceeding it are dropped
+    pub queue_capacity: usize,
+    /// Maximal count of the cells read from the database and put into the loaded cells map at once
+    pub batch_size: usize,
+    /// Pending prefetches are cancelled while count of the cached cells exceeds the limit
+    pub max_cached_cells: usize,
+    /// Maximal count of the prefetched cells kept alive until they are requested
+    pub max_retained_cells: usize,
+    /// Prefetched cells not requested within the timeout are released (in milliseconds in the
+    /// configuration)
+    #[serde(with = "crate::storage_config::duration_ms")]
+    pub idle_timeout: Duration,
+}
+
+impl Default for PrefetchConfig {
+    fn default() -> Self {
+        Self {
+            queue_capacity: 4096,
+            batch_size: 64,
+            max_cached_cells: 1_000_000,
+            max_retained_cells: 65536,
+            idle_timeout: Duration::from_secs(1),
+        }
+    }
+}
+
+/// Counters of the prefetcher since it was enabled
+#[derive(Debug, Clone, Default, PartialEq, Eq)]
+pub struct PrefetchStats {
+    pub scheduled: u64,
+    /// Requests dropped because the queue was full
+    pub dropped: u64,
+    /// Requests cancelled because of the cache pressure
+    pub cancelled: u64,
+    pub loaded: u64,
+    /// Prefetched cells requested afterwards
+    pub hits: u64,
+}
+
+#[derive(Debug, Default)]
+struct PrefetchCounters {
+    scheduled: AtomicU64,
+    dropped: AtomicU64,
+    cancelled: AtomicU64,
+    loaded: AtomicU64,
+    hits: AtomicU64,
+}
+
+/// Prefetcher of the cells' children: once a cell is loaded, its not yet loaded references are read
+/// on the background thread by batches and put into the loaded cells map, so sequential scans of
+/// the state (e.g. snapshot export) find them cached. When a prefetched cell is requested, its
+/// children are scheduled in turn, so the prefetching keeps ahead of the scan.
+#[derive(Debug)]
+pub struct CellPrefetcher {
+    sender: SyncSender<CellId>,
+    // Prefetched cells not requested yet
+    pending_hits: Arc<Mutex<FnvHashSet<CellId>>>,
+    counters: Arc<PrefetchCounters>,
+}
+
+impl CellPrefetcher {
+    /// Starts the prefetching thread; it stops once the prefetcher is dropped
+    pub(crate) fn start(db: Weak<DynamicBocDb>, config: PrefetchConfig) -> Result<Self> {
+        let (sender, receiver) = std::sync::mpsc::sync_channel(std::cmp::max(config.queue_capacity, 1));
+        let pending_hits = Arc::new(Mutex::new(FnvHashSet::default()));
+        let counters = Arc::new(PrefetchCounters::default());
+        let worker = PrefetchWorker {
+            db,
+            config,
+            receiver,
+            pending_hits: Arc::clone(&pending_hits),
+            counters: Arc::clone(&counters),
+            retained: VecDeque::new(),
+        };
+        std::thread::Builder::new()
+            .name("cell-prefetcher".to_string())
+            .spawn(move || worker.run())?;
+
+        Ok(Self { sender, pending_hits, counters })
+    }
+
+    pub fn stats(&self) -> PrefetchStats {
+        PrefetchStats {
+            scheduled: self.counters.scheduled.load(Ordering::Relaxed),
+            dropped: self.counters.dropped.load(Ordering::Relaxed),
+            cancelled: self.counters.cancelled.load(Ordering::Relaxed),
+            loaded: self.counters.loaded.load(Ordering::Relaxed),
+            hits: self.counters.hits.load(Ordering::Relaxed),
+        }
+    }
+
+    /// Schedules prefetching of the children of the cell read from the database
+    pub(crate) fn on_cell_loaded(&self, cell: &StorageCell) {
+        for cell_id in cell.unloaded_reference_ids() {
+            match self.sender.try_send(cell_id) {
+                Ok(()) => self.counters.scheduled.fetch_add(1, Ordering::Relaxed),
+                Err(TrySendError::Full(_)) => self.counters.dropped.fetch_add(1, Ordering::Relaxed),
+                Err(TrySendError::Disconnected(_)) => return,
+            };
+        }
+    }
+
+    /// Schedules prefetching of the children of the cell found in the cache, if the cell was
+    /// prefetched
+    pub(crate) fn on_cell_hit(&self, cell_id: &CellId, cell: &StorageCell) {
+        if self.pending_hits.lock().remove(cell_id) {
+            self.counters.hits.fetch_add(1, Ordering::Relaxed);
+            self.on_cell_loaded(cell);
+        }
+    }
+}
+
+struct PrefetchWorker {
+    db: Weak<DynamicBocDb>,
+    config: PrefetchConfig,
+    receiver: Receiver<CellId>,
+    pending_hits: Arc<Mutex<FnvHashSet<CellId>>>,
+    counters: Arc<PrefetchCounters>,
+    // Keeps the prefetched cells alive, as the loaded cells map holds weak references only
+    retained: VecDeque<Arc<StorageCell>>,
+}
+
+impl PrefetchWorker {
+    fn run(mut self) {
+        loop {
+            let first = match self.receiver.recv_timeout(self.config.idle_timeout) {
+                Ok(cell_id) => cell_id,
+                Err(RecvTimeoutError::Timeout) => {
+                    // The retained cells keep the database alive, so they are released when idle
+                    self.release_retained(self.retained.len());
+                    continue;
+                }
+                Err(RecvTimeoutError::Disconnected) => break,
+            };
+            let mut batch = vec![first];
+            while batch.len() < self.config.batch_size {
+                match self.receiver.try_recv() {
+                    Ok(cell_id) => batch.push(cell_id),
+                    Err(_) => break,
+                }
+            }
+
+            let db = match self.db.upgrade() {
+                Some(db) => db,
+                None => break,
+            };
+            if db.cached_cells() > self.config.max_cached_cells {
+                self.cancel_pending(batch.len());
+                continue;
+            }
+            if let Err(err) = self.prefetch(&db, batch) {
+                log::warn!(target: "storage", "Prefetching of cells failed: {}", err);
+            }
+        }
+        log::debug!(target: "storage", "Cell prefetcher stopped");
+    }
+
+    fn prefetch(&mut self, db: &Arc<DynamicBocDb>, batch: Vec<CellId>) -> Result<()> {
+        for cell in db.prefetch_cells(batch)? {
+            self.counters.loaded.fetch_add(1, Ordering::Relaxed);
+            self.pending_hits.lock().insert(cell.id());
+            self.retained.push_back(cell);
+        }
+        let excess = self.retained.len().saturating_sub(self.config.max_retained_cells);
+        self.release_retained(excess);
+
+        Ok(())
+    }
+
+    /// Drops the batch taken and all the requests queued
+    fn cancel_pending(&mut self, taken: usize) {
+        let cancelled = taken + self.receiver.try_iter().count();
+        self.counters.cancelled.fetch_add(cancelled as u64, Ordering::Relaxed);
+        log::debug!(target: "storage", "{} cell prefetches cancelled because of the cache pressure", cancelled);
+    }
+
+    fn release_retained(&mut self, count: usize) {
+        if count == 0 {
+            return;
+        }
+        let released: Vec<_> = self.retained.drain(..count).collect();
+        {
+            let mut pending_hits = self.pending_hits.lock();
+            for cell in released.iter() {
+                pending_hits.remove(&cell.id());
+            }
+        }
+        // Dropped cells lock the loaded cells map, so they are dropped out of the lock above
+        drop(released);
+    }
+}
@@ -0,0 +1,139 @@
+use std::collections::VecDeque;
+use std::hash::Hash;
+
+use fnv::FnvHashSet;
+use ton_types::Result;
+
+/// Set of the nodes already visited by a traversal
+pub trait VisitedSet<N> {
+    /// Adds the node to the set; returns false if it is already there
+    fn insert_new(&mut self, node: &N) -> Result<bool>;
+}
+
+impl<N: Hash + Eq + Clone> VisitedSet<N> for FnvHashSet<N> {
+    fn insert_new(&mut self, node: &N) -> Result<bool> {
+        Ok(self.insert(node.clone()))
+    }
+}
+
+/// Iterative depth-first traversal with an explicit stack, so deep trees (cell chains may be
+/// thousands of levels deep) don't exhaust the thread stack. `visit` is called for each node in
+/// pre-order and returns the children to be traversed (empty vector to stop descending); the
+/// children are visited in the given order.
+pub fn depth_first<N>(
+    roots: impl IntoIterator<Item = N>,
+    mut visit: impl FnMut(N) -> Result<Vec<N>>,
+) -> Result<()> {
+    let mut stack: Vec<N> = roots.into_iter().collect();
+    stack.reverse();
+    while let Some(node) = stack.pop() {
+        let children = visit(node)?;
+        stack.extend(children.into_iter().rev());
+    }
+
+    Ok(())
+}
+
+/// Depth-first traversal (see `depth_first`) visiting each node once: the nodes already in the
+/// visited set are skipped. The set may be shared by several traversals to skip each other's nodes.
+pub fn depth_first_unique<N>(
+    roots: impl IntoIterator<Item = N>,
+    visited: &mut dyn VisitedSet<N>,
+    mut visit: impl FnMut(N) -> Result<Vec<N>>,
+) -> Result<()> {
+    depth_first(roots, |node| {
+        if !visited.insert_new(&node)? {
+            return Ok(Vec::new());
+        }
+        visit(node)
+    })
+}
+
+/// Iterative depth-first traversal calling `leave` for each node once all its children are left
+/// (post-order). `visit` is called in pre-order and returns the children to be traversed, or None
+/// to skip the node (it is not left then). Unless the children were skipped, they are left before
+/// their parents, even if shared with the nodes visited earlier.
+pub fn depth_first_post_order<N>(
+    roots: impl IntoIterator<Item = N>,
+    mut visit: impl FnMut(&N) -> Result<Option<Vec<N>>>,
+    mut leave: impl FnMut(N) -> Result<()>,
+) -> Result<()> {
+    let mut stack: Vec<(N, bool)> = roots.into_iter().map(|node| (node, false)).collect();
+    stack.reverse();
+    while let Some((node, expanded)) = stack.pop() {
+        if expanded {
+            leave(node)?;
+            continue;
+        }
+        if let Some(children) = visit(&node)? {
+            stack.push((node, true));
+            stack.extend(children.into_iter().rev().map(|child| (child, false)));
+        }
+    }
+
+    Ok(())
+}
+
+/// Iterative breadth-first traversal: `visit` is called for each node level by level and returns
+/// the children to be traversed (empty vector to stop descending)
+pub fn breadth_first<N>(
+    roots: impl IntoIterator<Item = N>,
+    mut visit: impl FnMut(N) -> Result<Vec<N>>,
+) -> Result<()> {
+    let mut queue: VecDeque<N> = roots.into_iter().collect();
+    while let Some(node) = queue.pop_front() {
+        queue.extend(visit(node)?);
+    }
+
+    Ok(())
+}
+
+/// Breadth-first traversal (see `breadth_first`) visiting each node once
+pub fn breadth_first_unique<N>(
+    roots: impl IntoIterator<Item = N>,
+    visited: &mut dyn VisitedSet<N>,
+    mut visit: impl FnMut(N) -> Result<Vec<N>>,
+) -> Result<()> {
+    breadth_first(roots, |node| {
+        if !visited.insert_new(&node)? {
+            return Ok(Vec::new());
+        }
+        visit(node)
+    })
+}
+
+/// Collects items produced by a traversal into batches of given size, passing each full batch
+/// (and the rest on finish) to the flush function
+pub struct BatchSink<T, F: FnMut(Vec<T>) -> Result<()>> {
+    batch: Vec<T>,
+    batch_size: usize,
+    flush: F,
+}
+
+impl<T, F: FnMut(Vec<T>) -> Result<()>> BatchSink<T, F> {
+    pub fn new(batch_size: usize, flush: F) -> Self {
+        let batch_size = std::cmp::max(batch_size, 1);
+        Self { batch: Vec::with_capacity(batch_size), batch_size, flush }
+    }
+
+    pub fn push(&mut self, item: T) -> Result<()> {
+        self.batch.push(item);
+        if self.batch.len() >= self.batch_size {
+            let batch = std::mem::replace(&mut self.batch, Vec::with_capacity(self.batch_size));
+            (self.flush)(batch)?;
+        }
+
+        Ok(())
+    }
+
+    /// Count of the items waiting for the flush
+    pub fn pending(&self) -> usize {
+        self.batch.len()
+    }
+
+    /// Flushes the rest of the items (even if there are none)
+    pub fn finish(mut self) -> Result<()> {
+        let batch = std::mem::take(&mut self.batch);
+        (self.flush)(batch)
+    }
+}
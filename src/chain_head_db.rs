@@ -0,0 +1,117 @@
+use std::ops::{Deref, DerefMut};
+use std::path::Path;
+use std::sync::Arc;
+
+use fnv::FnvHashMap;
+use parking_lot::Mutex;
+
+use ton_block::{BlockIdExt, ShardIdent};
+use ton_types::Result;
+
+use crate::db::collection_metadata::{check_collection_metadata, key_type_name};
+use crate::db::instrumented::InstrumentedDb;
+use crate::db::memorydb::MemoryDb;
+use crate::db::rocksdb::RocksDb;
+use crate::db::traits::{KvcReadable, KvcSnapshotable, KvcTransactional, KvcWriteable};
+use crate::metrics;
+use crate::traits::Serializable;
+use crate::types::{ChainHead, ShardIdentKey};
+
+#[derive(Debug)]
+pub struct ChainHeadDb {
+    db: Box<dyn KvcTransactional<ShardIdentKey> + Send + Sync>,
+    /// Head updates of the same shard are serialized by the shard's lock, so the check of the
+    /// current head and the put of the new one are not interleaved with another update
+    shard_locks: Mutex<FnvHashMap<ShardIdent, Arc<Mutex<()>>>>,
+}
+
+impl ChainHeadDb {
+    /// Constructs new instance using in-memory key-value collection
+    pub fn in_memory() -> Self {
+        Self::with_db(Box::new(MemoryDb::new()))
+    }
+
+    /// Constructs new instance using RocksDB with given path
+    pub fn with_path<P: AsRef<Path>>(path: P) -> Self {
+        check_collection_metadata(path.as_ref(), "ChainHeadDb", key_type_name::<ShardIdentKey>())
+            .expect("Cannot open DB");
+        let db = RocksDb::with_path(path);
+        if metrics::is_enabled() {
+            Self::with_db(Box::new(InstrumentedDb::new(db, metrics::collection_metrics("ChainHeadDb"))))
+        } else {
+            Self::with_db(Box::new(db))
+        }
+    }
+
+    fn with_db(db: Box<dyn KvcTransactional<ShardIdentKey> + Send + Sync>) -> Self {
+        Self { db, shard_locks: Mutex::new(FnvHashMap::default()) }
+    }
+
+    /// Moves the head of the block's shard to the block; must be called when the block is marked
+    /// applied. The head never goes back, so applying the block not above the head is a no-op.
+    /// Updates of the same shard are serialized, so concurrent calls can't move the head back.
+    /// Returns true if the head was moved.
+    pub fn on_block_applied(&self, block_id_ext: &BlockIdExt, gen_utime: u32) -> Result<bool> {
+        let shard_lock = self.shard_lock(block_id_ext.shard());
+        let _shard_locked = shard_lock.lock();
+        let key = ShardIdentKey::new(block_id_ext.shard())?;
+        if let Some(head) = self.try_get_value(&key)? {
+            if head.seq_no() >= block_id_ext.seq_no() {
+                return Ok(false);
+            }
+        }
+
+        self.db.put(&key, &serde_cbor::to_vec(&ChainHead::with_values(block_id_ext, gen_utime))?)?;
+        log::trace!(target: "storage", "Chain head of {} moved to {}", block_id_ext.shard(), block_id_ext);
+
+        Ok(true)
+    }
+
+    /// Gets the head of the shard
+    pub fn get_head(&self, shard: &ShardIdent) -> Result<Option<ChainHead>> {
+        self.try_get_value(&ShardIdentKey::new(shard)?)
+    }
+
+    /// Gets the heads of all the shards, read from the single snapshot of the collection (so the
+    /// heads are consistent with each other, e.g. for collation)
+    pub fn get_all_heads(&self) -> Result<Vec<(ShardIdent, ChainHead)>> {
+        let snapshot = self.db.snapshot()?;
+        let mut result = Vec::new();
+        snapshot.for_each(&mut |key, value| {
+            result.push((ShardIdent::from_slice(key)?, serde_cbor::from_slice(value)?));
+            Ok(true)
+        })?;
+
+        Ok(result)
+    }
+
+    fn try_get_value(&self, key: &ShardIdentKey) -> Result<Option<ChainHead>> {
+        if let Some(db_slice) = self.db.try_get(key)? {
+            return Ok(Some(serde_cbor::from_slice(db_slice.as_ref())?));
+        }
+
+        Ok(None)
+    }
+
+    fn shard_lock(&self, shard: &ShardIdent) -> Arc<Mutex<()>> {
+        Arc::clone(
+            self.shard_locks.lock()
+                .entry(shard.clone())
+                .or_default()
+        )
+    }
+}
+
+impl Deref for ChainHeadDb {
+    type Target = dyn KvcTransactional<ShardIdentKey> + Send + Sync;
+
+    fn deref(&self) -> &Self::Target {
+        self.db.deref()
+    }
+}
+
+impl DerefMut for ChainHeadDb {
+    fn deref_mut(&mut self) -> &mut Self::Target {
+        self.db.deref_mut()
+    }
+}
@@ -0,0 +1,54 @@
+use std::fmt::Debug;
+use std::sync::Arc;
+use std::sync::atomic::{AtomicU32, Ordering};
+
+use ton_block::UnixTime32;
+
+/// Source of the current time for TTL and GC decisions
+pub trait Clock: Debug + Send + Sync {
+    /// Current unix time in seconds
+    fn now(&self) -> u32;
+}
+
+/// Clock reading the system time
+#[derive(Debug, Default)]
+pub struct SystemClock;
+
+impl Clock for SystemClock {
+    fn now(&self) -> u32 {
+        UnixTime32::now().0
+    }
+}
+
+/// Clock which is set and advanced explicitly, e.g. by tests or by the node following the chain
+/// time instead of the system one
+#[derive(Debug)]
+pub struct ManualClock {
+    now: AtomicU32,
+}
+
+impl ManualClock {
+    pub const fn new(now: u32) -> Self {
+        Self { now: AtomicU32::new(now) }
+    }
+
+    pub fn set(&self, now: u32) {
+        self.now.store(now, Ordering::SeqCst)
+    }
+
+    /// Moves the clock forward by given count of seconds. Returns the resulting time.
+    pub fn advance(&self, seconds: u32) -> u32 {
+        self.now.fetch_add(seconds, Ordering::SeqCst).saturating_add(seconds)
+    }
+}
+
+impl Clock for ManualClock {
+    fn now(&self) -> u32 {
+        self.now.load(Ordering::SeqCst)
+    }
+}
+
+/// Shared instance of the system clock, used by default
+pub fn system_clock() -> Arc<dyn Clock> {
+    Arc::new(SystemClock)
+}
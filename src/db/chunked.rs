@@ -0,0 +1,532 @@
+use std::fmt::{Debug, Formatter};
+use std::path::Path;
+use std::sync::atomic::{AtomicUsize, Ordering};
+
+use parking_lot::Mutex;
+
+use ton_types::Result;
+
+use crate::db::collection_metadata::{check_collection_metadata, key_type_name, validate_collection_metadata};
+use crate::db::instrumented::InstrumentedDb;
+use crate::db::memorydb::MemoryDb;
+use crate::db::rocksdb::RocksDb;
+use crate::db::traits::{DbKey, Kvc, KvcReadable, KvcWriteable};
+use crate::error::StorageError;
+use crate::metrics;
+use crate::types::{DbRope, DbSlice};
+
+/// Default size of the value chunks; larger values are split
+pub const DEFAULT_CHUNK_SIZE: usize = 4 << 20;
+
+/// Prefix of the manifest record stored under the key of the chunked value. Values having this
+/// prefix are always chunked, so the manifest can't be confused with the value itself.
+const MANIFEST_MAGIC: [u8; 8] = *b"\0\xffCHUNKS";
+const MANIFEST_SIZE: usize = MANIFEST_MAGIC.len() + 4 + 8 + 4 + 4;
+
+/// Chunk key is the key of the value followed by the marker, the version and the chunk index
+const CHUNK_KEY_MARKER: [u8; 7] = *b"\0\xffchunk";
+const CHUNK_KEY_SUFFIX_SIZE: usize = CHUNK_KEY_MARKER.len() + 4 + 4;
+
+/// Key of the chunk of the large value
+pub struct ChunkKey {
+    key: Vec<u8>,
+}
+
+impl ChunkKey {
+    fn new(value_key: &[u8], version: u32, index: u32) -> Self {
+        let mut key = Vec::with_capacity(value_key.len() + CHUNK_KEY_SUFFIX_SIZE);
+        key.extend_from_slice(value_key);
+        key.extend_from_slice(&CHUNK_KEY_MARKER);
+        key.extend_from_slice(&version.to_be_bytes());
+        key.extend_from_slice(&index.to_be_bytes());
+        Self { key }
+    }
+
+    /// Raw key taken as it is, e.g. to read the record of the value by the key bytes
+    fn raw(key: &[u8]) -> Self {
+        Self { key: key.to_vec() }
+    }
+
+    /// Returns true if the raw key is the key of a chunk
+    fn is_chunk_key(key: &[u8]) -> bool {
+        key.len() >= CHUNK_KEY_SUFFIX_SIZE
+            && key[key.len() - CHUNK_KEY_SUFFIX_SIZE..].starts_with(&CHUNK_KEY_MARKER)
+    }
+
+    /// Splits the raw key of the chunk into the key of the value and the version of the chunk
+    fn split(key: &[u8]) -> Option<(&[u8], u32)> {
+        if !Self::is_chunk_key(key) {
+            return None;
+        }
+        let value_key_len = key.len() - CHUNK_KEY_SUFFIX_SIZE;
+        let mut version = [0; 4];
+        version.copy_from_slice(&key[value_key_len + CHUNK_KEY_MARKER.len()..value_key_len + CHUNK_KEY_MARKER.len() + 4]);
+
+        Some((&key[..value_key_len], u32::from_be_bytes(version)))
+    }
+}
+
+impl DbKey for ChunkKey {
+    fn key_name(&self) -> &'static str {
+        "ChunkKey"
+    }
+
+    fn key(&self) -> &[u8] {
+        &self.key
+    }
+}
+
+/// Record stored in place of the chunked value
+#[derive(Debug, Clone, Copy)]
+struct ChunkManifest {
+    /// Chunks of every new value get the new version, so readers of the previous manifest never
+    /// see the chunks of the next value
+    version: u32,
+    total_len: u64,
+    chunk_size: u32,
+    chunk_count: u32,
+}
+
+impl ChunkManifest {
+    fn new(version: u32, total_len: usize, chunk_size: usize) -> Self {
+        Self {
+            version,
+            total_len: total_len as u64,
+            chunk_size: chunk_size as u32,
+            chunk_count: ((total_len + chunk_size - 1) / chunk_size) as u32,
+        }
+    }
+
+    /// Returns the manifest if the record is one
+    fn parse(record: &[u8]) -> Result<Option<Self>> {
+        if !record.starts_with(&MANIFEST_MAGIC) {
+            return Ok(None);
+        }
+        if record.len() != MANIFEST_SIZE {
+            Err(StorageError::CorruptedData(format!("chunk manifest of {} bytes", record.len())))?
+        }
+
+        let field = |offset: usize, len: usize| &record[MANIFEST_MAGIC.len() + offset..MANIFEST_MAGIC.len() + offset + len];
+        let mut u32_buf = [0; 4];
+        let mut u64_buf = [0; 8];
+        u32_buf.copy_from_slice(field(0, 4));
+        let version = u32::from_le_bytes(u32_buf);
+        u64_buf.copy_from_slice(field(4, 8));
+        let total_len = u64::from_le_bytes(u64_buf);
+        u32_buf.copy_from_slice(field(12, 4));
+        let chunk_size = u32::from_le_bytes(u32_buf);
+        u32_buf.copy_from_slice(field(16, 4));
+        let chunk_count = u32::from_le_bytes(u32_buf);
+
+        let manifest = Self { version, total_len, chunk_size, chunk_count };
+        if manifest.chunk_size == 0
+            || (manifest.total_len + manifest.chunk_size as u64 - 1) / manifest.chunk_size as u64 != manifest.chunk_count as u64
+        {
+            Err(StorageError::CorruptedData(format!("inconsistent chunk manifest {:?}", manifest)))?
+        }
+
+        Ok(Some(manifest))
+    }
+
+    fn to_vec(&self) -> Vec<u8> {
+        let mut result = Vec::with_capacity(MANIFEST_SIZE);
+        result.extend_from_slice(&MANIFEST_MAGIC);
+        result.extend_from_slice(&self.version.to_le_bytes());
+        result.extend_from_slice(&self.total_len.to_le_bytes());
+        result.extend_from_slice(&self.chunk_size.to_le_bytes());
+        result.extend_from_slice(&self.chunk_count.to_le_bytes());
+
+        result
+    }
+
+    fn chunk_len(&self, index: u32) -> usize {
+        let start = index as u64 * self.chunk_size as u64;
+        std::cmp::min(self.chunk_size as u64, self.total_len - start) as usize
+    }
+}
+
+/// Key-value collection holding both the values and the chunks of the large values
+pub trait KvcChunkable<K: DbKey + Send + Sync>: KvcWriteable<K> + KvcWriteable<ChunkKey> {}
+
+impl<K: DbKey + Send + Sync, T: KvcWriteable<K> + KvcWriteable<ChunkKey>> KvcChunkable<K> for T {}
+
+/// Value of the record: the record itself or the chunks of the chunked value
+enum RecordValue<'a> {
+    Plain(DbSlice<'a>),
+    Chunked(Vec<DbSlice<'a>>),
+}
+
+/// Key-value collection transparently splitting the values larger than the chunk size into
+/// chunks stored under the sub-keys of the value's key; the manifest record is stored under the
+/// key itself. Chunk sub-keys are hidden from iteration.
+///
+/// The chunks are written before the manifest and the chunks of the replaced value are deleted
+/// after it, so readers see either the old value or the new one: a reader missing a chunk of the
+/// old value re-reads the manifest. Chunks left by an interrupted write are removed on opening.
+pub struct ChunkedDb<K: DbKey + Send + Sync> {
+    db: Box<dyn KvcChunkable<K> + Send + Sync>,
+    chunk_size: AtomicUsize,
+    /// Writes are serialized, so concurrent puts of the value never write chunks of one version
+    write_lock: Mutex<()>,
+}
+
+impl<K: DbKey + Send + Sync> Debug for ChunkedDb<K> {
+    fn fmt(&self, f: &mut Formatter<'_>) -> std::fmt::Result {
+        f.debug_struct("ChunkedDb")
+            .field("db", &self.db)
+            .field("chunk_size", &self.chunk_size)
+            .finish()
+    }
+}
+
+impl<K: DbKey + Send + Sync + 'static> ChunkedDb<K> {
+    pub fn with_db(db: Box<dyn KvcChunkable<K> + Send + Sync>) -> Self {
+        Self { db, chunk_size: AtomicUsize::new(DEFAULT_CHUNK_SIZE), write_lock: Mutex::new(()) }
+    }
+
+    /// Constructs new instance using in-memory key-value collection
+    pub fn in_memory() -> Self {
+        Self::with_db(Box::new(MemoryDb::new()))
+    }
+
+    /// Constructs new instance using RocksDB with given path; `name` identifies the collection
+    /// in the metrics and in the collection metadata
+    pub fn with_path(path: impl AsRef<Path>, name: &str) -> Self {
+        check_collection_metadata(path.as_ref(), name, key_type_name::<K>())
+            .expect("Cannot open DB");
+        let db = RocksDb::with_path(path);
+        let db = if metrics::is_enabled() {
+            Self::with_db(Box::new(InstrumentedDb::new(db, metrics::collection_metrics(name))))
+        } else {
+            Self::with_db(Box::new(db))
+        };
+        db.remove_orphan_chunks().expect("Cannot remove orphan chunks");
+
+        db
+    }
+
+    /// Opens existing RocksDB with given path in read-only mode
+    pub fn with_path_read_only(path: impl AsRef<Path>, name: &str) -> Result<Self> {
+        validate_collection_metadata(path.as_ref(), name, key_type_name::<K>())?;
+        Ok(Self::with_db(Box::new(RocksDb::with_path_read_only(path)?)))
+    }
+}
+
+impl<K: DbKey + Send + Sync> ChunkedDb<K> {
+    pub fn chunk_size(&self) -> usize {
+        self.chunk_size.load(Ordering::Relaxed)
+    }
+
+    /// Sets size of the chunks of the values written afterwards; values of any chunk size are
+    /// readable
+    pub fn set_chunk_size(&self, value: usize) {
+        self.chunk_size.store(std::cmp::max(value, 1), Ordering::Relaxed)
+    }
+
+    /// Tries to get value by the key without copying chunks of the large value into one buffer
+    pub fn try_get_rope(&self, key: &K) -> Result<Option<DbRope>> {
+        let record = match KvcReadable::<K>::try_get(&*self.db, key)? {
+            Some(record) => record,
+            None => return Ok(None),
+        };
+        Ok(self.read_value(key.key(), record)?.map(|value| match value {
+            RecordValue::Plain(record) => record.into(),
+            RecordValue::Chunked(chunks) => DbRope::with_chunks(chunks),
+        }))
+    }
+
+    /// Reads the chunks of the value; Ok(None) is returned if a chunk is missing
+    fn read_chunks(&self, key: &[u8], manifest: &ChunkManifest, from: u32, to: u32) -> Result<Option<Vec<DbSlice>>> {
+        let mut chunks = Vec::with_capacity((to - from) as usize);
+        for index in from..to {
+            let chunk = KvcReadable::<ChunkKey>::try_get(&*self.db, &ChunkKey::new(key, manifest.version, index))?;
+            match chunk {
+                Some(chunk) if chunk.len() == manifest.chunk_len(index) => chunks.push(chunk),
+                Some(chunk) => Err(StorageError::CorruptedData(format!(
+                    "chunk {} of value {} has {} bytes, {} expected",
+                    index, hex::encode(key), chunk.len(), manifest.chunk_len(index)
+                )))?,
+                None => return Ok(None),
+            }
+        }
+
+        Ok(Some(chunks))
+    }
+
+    /// Re-reads the record of the value whose chunk is found missing: the value may have been
+    /// replaced or deleted meanwhile. If the record still refers to the same chunks, they are lost.
+    fn reread_record(&self, key: &[u8], manifest: &ChunkManifest) -> Result<Option<DbSlice>> {
+        let record = KvcReadable::<ChunkKey>::try_get(&*self.db, &ChunkKey::raw(key))?;
+        if let Some(ref record) = record {
+            if let Some(current) = ChunkManifest::parse(record)? {
+                if current.version == manifest.version {
+                    Err(StorageError::CorruptedData(format!("chunk of value {} is missing", hex::encode(key))))?
+                }
+            }
+        }
+
+        Ok(record)
+    }
+
+    /// Reads the value of the record; Ok(None) is returned if the value is deleted concurrently
+    fn read_value<'a>(&'a self, key: &[u8], mut record: DbSlice<'a>) -> Result<Option<RecordValue<'a>>> {
+        loop {
+            let manifest = match ChunkManifest::parse(&record)? {
+                Some(manifest) => manifest,
+                None => return Ok(Some(RecordValue::Plain(record))),
+            };
+            if let Some(chunks) = self.read_chunks(key, &manifest, 0, manifest.chunk_count)? {
+                return Ok(Some(RecordValue::Chunked(chunks)));
+            }
+            record = match self.reread_record(key, &manifest)? {
+                Some(record) => record,
+                None => return Ok(None),
+            };
+        }
+    }
+
+    /// Returns the value of the record, assembling the chunked one
+    fn resolve<'a>(&'a self, key: &[u8], record: DbSlice<'a>) -> Result<Option<DbSlice<'a>>> {
+        Ok(self.read_value(key, record)?.map(|value| match value {
+            RecordValue::Plain(record) => record,
+            RecordValue::Chunked(chunks) => DbRope::with_chunks(chunks).into_slice(),
+        }))
+    }
+
+    fn get_record(&self, key: &K) -> Result<DbSlice> {
+        KvcReadable::<K>::try_get(&*self.db, key)?
+            .ok_or_else(|| StorageError::KeyNotFound(key.key_name(), key.as_string()).into())
+    }
+
+    fn manifest(&self, key: &K) -> Result<Option<ChunkManifest>> {
+        match KvcReadable::<K>::try_get(&*self.db, key)? {
+            Some(record) => ChunkManifest::parse(&record),
+            None => Ok(None),
+        }
+    }
+
+    fn delete_chunks(&self, key: &[u8], manifest: &ChunkManifest) -> Result<()> {
+        for index in 0..manifest.chunk_count {
+            KvcWriteable::<ChunkKey>::delete(&*self.db, &ChunkKey::new(key, manifest.version, index))?;
+        }
+
+        Ok(())
+    }
+
+    /// Removes the chunks not referred by the manifests, left by interrupted writes: the chunks
+    /// written before the manifest or the chunks of the replaced value not deleted yet.
+    /// Returns count of the removed chunks.
+    fn remove_orphan_chunks(&self) -> Result<usize> {
+        let _write_locked = self.write_lock.lock();
+        let mut orphans = Vec::new();
+        KvcReadable::<K>::for_each_key_only(&*self.db, &mut |key| {
+            if let Some((value_key, version)) = ChunkKey::split(key) {
+                let record = KvcReadable::<ChunkKey>::try_get(&*self.db, &ChunkKey::raw(value_key))?;
+                let referred = match record {
+                    Some(record) => matches!(ChunkManifest::parse(&record), Ok(Some(manifest)) if manifest.version == version),
+                    None => false,
+                };
+                if !referred {
+                    orphans.push(ChunkKey::raw(key));
+                }
+            }
+            Ok(true)
+        })?;
+
+        for chunk_key in orphans.iter() {
+            KvcWriteable::<ChunkKey>::delete(&*self.db, chunk_key)?;
+        }
+        if !orphans.is_empty() {
+            log::warn!(target: "storage", "{} orphan value chunks removed", orphans.len());
+        }
+
+        Ok(orphans.len())
+    }
+
+    fn for_each_value(
+        &self,
+        start: Option<&[u8]>,
+        predicate: &mut dyn FnMut(&[u8], &[u8]) -> Result<bool>,
+    ) -> Result<bool> {
+        let mut filter = |key: &[u8], value: &[u8]| {
+            if ChunkKey::is_chunk_key(key) {
+                return Ok(true);
+            }
+            match ChunkManifest::parse(value)? {
+                // Values deleted during the iteration are skipped
+                Some(_) => match self.resolve(key, value.into())? {
+                    Some(value) => predicate(key, &value),
+                    None => Ok(true),
+                },
+                None => predicate(key, value),
+            }
+        };
+        match start {
+            Some(start) => KvcReadable::<K>::for_each_from(&*self.db, start, &mut filter),
+            None => KvcReadable::<K>::for_each(&*self.db, &mut filter),
+        }
+    }
+}
+
+impl<K: DbKey + Send + Sync> Kvc for ChunkedDb<K> {
+    fn len(&self) -> Result<usize> {
+        let mut len = 0;
+        KvcReadable::<K>::for_each_key_only(&*self.db, &mut |key| {
+            if !ChunkKey::is_chunk_key(key) {
+                len += 1;
+            }
+            Ok(true)
+        })?;
+
+        Ok(len)
+    }
+
+    fn approx_len(&self) -> Result<usize> {
+        self.db.approx_len()
+    }
+
+    fn approx_size_bytes(&self) -> Result<u64> {
+        self.db.approx_size_bytes()
+    }
+
+    fn destroy(&mut self) -> Result<()> {
+        self.db.destroy()
+    }
+
+    fn flush(&self) -> Result<()> {
+        self.db.flush()
+    }
+
+    fn compact_range(&self, start: Option<&[u8]>, end: Option<&[u8]>) -> Result<()> {
+        self.db.compact_range(start, end)
+    }
+}
+
+impl<K: DbKey + Send + Sync> KvcReadable<K> for ChunkedDb<K> {
+    fn try_get(&self, key: &K) -> Result<Option<DbSlice>> {
+        match KvcReadable::<K>::try_get(&*self.db, key)? {
+            Some(record) => self.resolve(key.key(), record),
+            None => Ok(None),
+        }
+    }
+
+    fn try_get_multi(&self, keys: &[K]) -> Result<Vec<Option<DbSlice>>> {
+        let records = KvcReadable::<K>::try_get_multi(&*self.db, keys)?;
+        let mut result = Vec::with_capacity(records.len());
+        for (key, record) in keys.iter().zip(records) {
+            result.push(match record {
+                Some(record) => self.resolve(key.key(), record)?,
+                None => None,
+            });
+        }
+
+        Ok(result)
+    }
+
+    fn get_slice(&self, key: &K, offset: u64, size: u64) -> Result<DbSlice> {
+        let mut record = self.get_record(key)?;
+        loop {
+            let manifest = match ChunkManifest::parse(&record)? {
+                Some(manifest) => manifest,
+                None => {
+                    if offset >= record.len() as u64 || offset + size > record.len() as u64 {
+                        return Err(StorageError::OutOfRange.into());
+                    }
+                    return Ok(record[offset as usize..(offset + size) as usize].to_vec().into());
+                }
+            };
+            if offset >= manifest.total_len || offset + size > manifest.total_len {
+                return Err(StorageError::OutOfRange.into());
+            }
+
+            // Only the chunks covering the requested range are read
+            let chunk_size = manifest.chunk_size as u64;
+            let from = (offset / chunk_size) as u32;
+            let to = ((offset + size + chunk_size - 1) / chunk_size) as u32;
+            let chunks = match self.read_chunks(key.key(), &manifest, from, to)? {
+                Some(chunks) => chunks,
+                None => {
+                    record = self.reread_record(key.key(), &manifest)?
+                        .ok_or_else(|| StorageError::KeyNotFound(key.key_name(), key.as_string()))?;
+                    continue;
+                }
+            };
+            let mut result = Vec::with_capacity(size as usize);
+            let mut position = from as u64 * chunk_size;
+            for chunk in chunks {
+                let start = offset.saturating_sub(position) as usize;
+                let end = std::cmp::min(chunk.len() as u64, offset + size - position) as usize;
+                result.extend_from_slice(&chunk[start..end]);
+                position += chunk.len() as u64;
+            }
+
+            return Ok(result.into());
+        }
+    }
+
+    fn get_size(&self, key: &K) -> Result<u64> {
+        let record = self.get_record(key)?;
+        match ChunkManifest::parse(&record)? {
+            Some(manifest) => Ok(manifest.total_len),
+            None => Ok(record.len() as u64),
+        }
+    }
+
+    fn contains(&self, key: &K) -> Result<bool> {
+        KvcReadable::<K>::contains(&*self.db, key)
+    }
+
+    fn for_each(&self, predicate: &mut dyn FnMut(&[u8], &[u8]) -> Result<bool>) -> Result<bool> {
+        self.for_each_value(None, predicate)
+    }
+
+    fn for_each_from(&self, start: &[u8], predicate: &mut dyn FnMut(&[u8], &[u8]) -> Result<bool>) -> Result<bool> {
+        self.for_each_value(Some(start), predicate)
+    }
+
+    fn for_each_key_only(&self, predicate: &mut dyn FnMut(&[u8]) -> Result<bool>) -> Result<bool> {
+        // Chunks are not assembled, unlike in `for_each`
+        KvcReadable::<K>::for_each_key_only(&*self.db, &mut |key| {
+            if ChunkKey::is_chunk_key(key) {
+                return Ok(true);
+            }
+            predicate(key)
+        })
+    }
+}
+
+impl<K: DbKey + Send + Sync> KvcWriteable<K> for ChunkedDb<K> {
+    fn put(&self, key: &K, value: &[u8]) -> Result<()> {
+        let _write_locked = self.write_lock.lock();
+        let old_manifest = self.manifest(key)?;
+        let chunk_size = self.chunk_size();
+        if value.len() > chunk_size || value.starts_with(&MANIFEST_MAGIC) {
+            let version = old_manifest.map(|manifest| manifest.version.wrapping_add(1)).unwrap_or(0);
+            let manifest = ChunkManifest::new(version, value.len(), chunk_size);
+            // Chunks are written before the manifest, so the value is replaced at once
+            for (index, chunk) in value.chunks(chunk_size).enumerate() {
+                KvcWriteable::<ChunkKey>::put(&*self.db, &ChunkKey::new(key.key(), version, index as u32), chunk)?;
+            }
+            KvcWriteable::<K>::put(&*self.db, key, &manifest.to_vec())?;
+        } else {
+            KvcWriteable::<K>::put(&*self.db, key, value)?;
+        }
+
+        if let Some(old_manifest) = old_manifest {
+            self.delete_chunks(key.key(), &old_manifest)?;
+        }
+
+        Ok(())
+    }
+
+    fn delete(&self, key: &K) -> Result<()> {
+        let _write_locked = self.write_lock.lock();
+        let manifest = self.manifest(key)?;
+        KvcWriteable::<K>::delete(&*self.db, key)?;
+        if let Some(manifest) = manifest {
+            self.delete_chunks(key.key(), &manifest)?;
+        }
+
+        Ok(())
+    }
+}
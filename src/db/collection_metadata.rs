@@ -0,0 +1,130 @@
+use std::io::ErrorKind;
+use std::path::Path;
+use std::time::{SystemTime, UNIX_EPOCH};
+
+use serde_derive::{Deserialize, Serialize};
+use ton_types::{error, Result};
+
+use crate::error::StorageError;
+
+/// Name of the file holding the metadata in the directory of the collection
+pub const COLLECTION_METADATA_FILENAME: &str = "COLLECTION_METADATA";
+/// Version of the collections' layout written by this version of the storage
+pub const COLLECTION_SCHEMA_VERSION: u32 = 1;
+
+/// Self-describing record of the collection, written on the first open of the collection and
+/// validated on the subsequent ones
+#[derive(Debug, Clone, PartialEq, Serialize, Deserialize)]
+pub struct CollectionMetadata {
+    name: String,
+    key_type: String,
+    schema_version: u32,
+    created_at: u64,
+}
+
+impl CollectionMetadata {
+    pub fn new(name: &str, key_type: &str) -> Self {
+        let created_at = SystemTime::now().duration_since(UNIX_EPOCH)
+            .map(|duration| duration.as_secs())
+            .unwrap_or_default();
+        Self {
+            name: name.to_string(),
+            key_type: key_type.to_string(),
+            schema_version: COLLECTION_SCHEMA_VERSION,
+            created_at,
+        }
+    }
+
+    pub fn name(&self) -> &str {
+        &self.name
+    }
+
+    pub fn key_type(&self) -> &str {
+        &self.key_type
+    }
+
+    pub const fn schema_version(&self) -> u32 {
+        self.schema_version
+    }
+
+    /// Unix time the collection was created at
+    pub const fn created_at(&self) -> u64 {
+        self.created_at
+    }
+
+    /// Reads metadata of the collection located at given path; Ok(None) is returned if there is no
+    /// metadata (e.g. the collection is new or created by older versions)
+    pub fn read(path: impl AsRef<Path>) -> Result<Option<Self>> {
+        let path = path.as_ref().join(COLLECTION_METADATA_FILENAME);
+        let data = match std::fs::read(&path) {
+            Ok(data) => data,
+            Err(err) if err.kind() == ErrorKind::NotFound => return Ok(None),
+            Err(err) => return Err(error!("Cannot read {:?}: {}", path, err)),
+        };
+
+        Ok(Some(serde_json::from_slice(&data)
+            .map_err(|err| error!("Cannot parse {:?}: {}", path, err))?))
+    }
+
+    fn write(&self, path: &Path) -> Result<()> {
+        std::fs::create_dir_all(path)?;
+        let tmp_path = path.join(format!("{}.tmp", COLLECTION_METADATA_FILENAME));
+        std::fs::write(&tmp_path, serde_json::to_vec_pretty(self)?)?;
+        std::fs::rename(&tmp_path, path.join(COLLECTION_METADATA_FILENAME))?;
+
+        Ok(())
+    }
+
+    fn check(&self, name: &str, key_type: &str, path: &Path) -> Result<()> {
+        if self.name != name || self.key_type != key_type {
+            Err(StorageError::CollectionMismatch(format!(
+                "{:?} belongs to {}<{}>, can't be opened as {}<{}>",
+                path, self.name, self.key_type, name, key_type
+            )))?
+        }
+        if self.schema_version > COLLECTION_SCHEMA_VERSION {
+            Err(StorageError::CollectionMismatch(format!(
+                "{:?} has schema version {}, the latest supported one is {}",
+                path, self.schema_version, COLLECTION_SCHEMA_VERSION
+            )))?
+        }
+
+        Ok(())
+    }
+}
+
+/// Validates metadata of the collection at given path against the expected name and key type,
+/// writing the metadata if there is none. Fails with `StorageError::CollectionMismatch` if the
+/// path belongs to another collection.
+pub fn check_collection_metadata(path: impl AsRef<Path>, name: &str, key_type: &str) -> Result<CollectionMetadata> {
+    let path = path.as_ref();
+    if let Some(metadata) = CollectionMetadata::read(path)? {
+        metadata.check(name, key_type, path)?;
+        return Ok(metadata);
+    }
+
+    let metadata = CollectionMetadata::new(name, key_type);
+    metadata.write(path)?;
+    log::debug!(target: "storage", "Metadata of collection {} written to {:?}", name, path);
+
+    Ok(metadata)
+}
+
+/// Validates metadata of the collection at given path like `check_collection_metadata`, but never
+/// writes it (for read-only opens)
+pub fn validate_collection_metadata(path: impl AsRef<Path>, name: &str, key_type: &str) -> Result<Option<CollectionMetadata>> {
+    let path = path.as_ref();
+    let metadata = CollectionMetadata::read(path)?;
+    if let Some(ref metadata) = metadata {
+        metadata.check(name, key_type, path)?;
+    }
+
+    Ok(metadata)
+}
+
+/// Short name of the type (without module path and generic parameters) used as the key type name
+pub fn key_type_name<K: ?Sized>() -> &'static str {
+    let name = std::any::type_name::<K>();
+    let name = &name[..name.find('<').unwrap_or_else(|| name.len())];
+    name.rsplit("::").next().unwrap_or(name)
+}
@@ -1,13 +1,14 @@
 use std::io::{ErrorKind, SeekFrom};
 use std::path::{Path, PathBuf};
 
-use tokio::io::AsyncReadExt;
+use tokio::io::{AsyncReadExt, AsyncWriteExt};
 
 use async_trait::async_trait;
 use ton_types::{error, fail, Result};
 
 use crate::db::traits::{DbKey, KvcAsync, KvcReadableAsync, KvcWriteableAsync};
 use crate::error::StorageError;
+use crate::path_safety::{hashed_filename, MAX_FILENAME_LEN};
 use crate::types::DbSlice;
 
 #[derive(Debug)]
@@ -40,12 +41,46 @@ impl FileDb {
             key_str = remaining;
             depth += 1;
         }
+        if key_str.len() > MAX_FILENAME_LEN {
+            return result.join(hashed_filename(&key_str));
+        }
         if key_str.len() > 0 {
             return result.join(key_str);
         }
         result
     }
 
+    /// Writes the value by chunks of the given size into the temporary file, which then replaces
+    /// the value's file, so readers never observe partially written value. `on_chunk` is called
+    /// with the total count of bytes written after each chunk.
+    pub async fn put_chunked<K: DbKey>(
+        &self,
+        key: &K,
+        value: &[u8],
+        chunk_size: usize,
+        mut on_chunk: impl FnMut(u64),
+    ) -> Result<()> {
+        let path = self.make_path(key.key());
+        let dir = path.parent()
+            .ok_or_else(|| error!("Unable to get parent path"))?;
+        tokio::fs::create_dir_all(dir).await?;
+
+        let temp_path = path.with_extension("tmp");
+        let mut file = tokio::fs::File::create(&temp_path).await?;
+        let mut written = 0;
+        for chunk in value.chunks(std::cmp::max(chunk_size, 1)) {
+            file.write_all(chunk).await?;
+            written += chunk.len() as u64;
+            on_chunk(written);
+        }
+        file.flush().await?;
+        file.sync_all().await?;
+        drop(file);
+        tokio::fs::rename(&temp_path, &path).await?;
+
+        Ok(())
+    }
+
     fn transform_io_error(err: std::io::Error, key: &[u8]) -> failure::Error {
         match err.kind() {
             ErrorKind::NotFound => StorageError::KeyNotFound("&[u8]", hex::encode(key)).into(),
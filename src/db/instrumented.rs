@@ -0,0 +1,175 @@
+use std::sync::Arc;
+use std::time::Instant;
+
+use ton_types::Result;
+
+use crate::db::traits::{DbKey, Kvc, KvcReadable, KvcSnapshotable, KvcTransaction, KvcTransactional, KvcWriteable};
+use crate::metrics::{CollectionMetrics, CollectionOp};
+use crate::types::DbSlice;
+
+/// Key-value collection recording latencies and traffic of the operations into the metrics
+#[derive(Debug)]
+pub struct InstrumentedDb<D> {
+    db: D,
+    metrics: Arc<CollectionMetrics>,
+}
+
+impl<D> InstrumentedDb<D> {
+    pub fn new(db: D, metrics: Arc<CollectionMetrics>) -> Self {
+        Self { db, metrics }
+    }
+
+    fn measure<T>(&self, op: CollectionOp, f: impl FnOnce() -> Result<T>) -> Result<T> {
+        let started = Instant::now();
+        let result = f();
+        self.metrics.record(op, started.elapsed());
+
+        result
+    }
+}
+
+impl<D: Kvc> Kvc for InstrumentedDb<D> {
+    fn len(&self) -> Result<usize> {
+        self.db.len()
+    }
+
+    fn approx_len(&self) -> Result<usize> {
+        self.db.approx_len()
+    }
+
+    fn approx_size_bytes(&self) -> Result<u64> {
+        self.db.approx_size_bytes()
+    }
+
+    fn is_empty(&self) -> Result<bool> {
+        self.db.is_empty()
+    }
+
+    fn destroy(&mut self) -> Result<()> {
+        self.db.destroy()
+    }
+
+    fn flush(&self) -> Result<()> {
+        self.db.flush()
+    }
+
+    fn compact_range(&self, start: Option<&[u8]>, end: Option<&[u8]>) -> Result<()> {
+        self.db.compact_range(start, end)
+    }
+}
+
+impl<K: DbKey + Send + Sync, D: KvcReadable<K>> KvcReadable<K> for InstrumentedDb<D> {
+    fn try_get(&self, key: &K) -> Result<Option<DbSlice>> {
+        let value = self.measure(CollectionOp::Get, || self.db.try_get(key))?;
+        if let Some(ref value) = value {
+            self.metrics.add_bytes_read(value.len());
+        }
+
+        Ok(value)
+    }
+
+    fn get(&self, key: &K) -> Result<DbSlice> {
+        let value = self.measure(CollectionOp::Get, || self.db.get(key))?;
+        self.metrics.add_bytes_read(value.len());
+
+        Ok(value)
+    }
+
+    fn try_get_multi(&self, keys: &[K]) -> Result<Vec<Option<DbSlice>>> {
+        let values = self.measure(CollectionOp::Get, || self.db.try_get_multi(keys))?;
+        self.metrics.add_bytes_read(values.iter().flatten().map(|value| value.len()).sum());
+
+        Ok(values)
+    }
+
+    fn get_slice(&self, key: &K, offset: u64, size: u64) -> Result<DbSlice> {
+        let value = self.measure(CollectionOp::Get, || self.db.get_slice(key, offset, size))?;
+        self.metrics.add_bytes_read(value.len());
+
+        Ok(value)
+    }
+
+    fn get_size(&self, key: &K) -> Result<u64> {
+        self.db.get_size(key)
+    }
+
+    fn contains(&self, key: &K) -> Result<bool> {
+        self.measure(CollectionOp::Get, || self.db.contains(key))
+    }
+
+    fn for_each(&self, predicate: &mut dyn FnMut(&[u8], &[u8]) -> Result<bool>) -> Result<bool> {
+        self.db.for_each(predicate)
+    }
+
+    fn for_each_from(&self, start: &[u8], predicate: &mut dyn FnMut(&[u8], &[u8]) -> Result<bool>) -> Result<bool> {
+        self.db.for_each_from(start, predicate)
+    }
+
+    fn for_each_key_only(&self, predicate: &mut dyn FnMut(&[u8]) -> Result<bool>) -> Result<bool> {
+        self.db.for_each_key_only(predicate)
+    }
+
+    fn for_each_with_prefix(&self, prefix: &[u8], predicate: &mut dyn FnMut(&[u8], &[u8]) -> Result<bool>) -> Result<bool> {
+        self.db.for_each_with_prefix(prefix, predicate)
+    }
+}
+
+impl<K: DbKey + Send + Sync, D: KvcWriteable<K>> KvcWriteable<K> for InstrumentedDb<D> {
+    fn put(&self, key: &K, value: &[u8]) -> Result<()> {
+        self.measure(CollectionOp::Put, || self.db.put(key, value))?;
+        self.metrics.add_bytes_written(value.len());
+
+        Ok(())
+    }
+
+    fn delete(&self, key: &K) -> Result<()> {
+        self.measure(CollectionOp::Delete, || self.db.delete(key))
+    }
+}
+
+impl<K: DbKey + Send + Sync, D: KvcSnapshotable<K>> KvcSnapshotable<K> for InstrumentedDb<D> {
+    fn snapshot<'db>(&'db self) -> Result<Arc<dyn KvcReadable<K> + 'db>> {
+        self.db.snapshot()
+    }
+}
+
+impl<K: DbKey + Send + Sync + 'static, D: KvcTransactional<K>> KvcTransactional<K> for InstrumentedDb<D> {
+    fn begin_transaction(&self) -> Result<Box<dyn KvcTransaction<K>>> {
+        Ok(Box::new(InstrumentedTransaction {
+            transaction: self.db.begin_transaction()?,
+            metrics: Arc::clone(&self.metrics),
+        }))
+    }
+}
+
+struct InstrumentedTransaction<K: DbKey + Send + Sync> {
+    transaction: Box<dyn KvcTransaction<K>>,
+    metrics: Arc<CollectionMetrics>,
+}
+
+impl<K: DbKey + Send + Sync> KvcTransaction<K> for InstrumentedTransaction<K> {
+    fn put(&self, key: &K, value: &[u8]) {
+        self.metrics.add_bytes_written(value.len());
+        self.transaction.put(key, value)
+    }
+
+    fn delete(&self, key: &K) {
+        self.transaction.delete(key)
+    }
+
+    fn clear(&self) {
+        self.transaction.clear()
+    }
+
+    fn commit(self: Box<Self>) -> Result<()> {
+        let started = Instant::now();
+        let result = self.transaction.commit();
+        self.metrics.record(CollectionOp::Commit, started.elapsed());
+
+        result
+    }
+
+    fn len(&self) -> usize {
+        self.transaction.len()
+    }
+}
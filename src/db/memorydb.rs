@@ -1,62 +1,258 @@
+use std::collections::BTreeMap;
 use std::sync::Arc;
 use std::sync::Mutex;
 
 use fnv::FnvHashMap;
 
-use ton_types::Result;
+use ton_types::{fail, Result};
 
 use crate::db::traits::{DbKey, Kvc, KvcReadable, KvcSnapshotable, KvcTransaction, KvcTransactional, KvcWriteable};
 use crate::error::StorageError;
 use crate::types::DbSlice;
 
-/// In-memory key-value collection
+type MemoryMap = FnvHashMap<Vec<u8>, Vec<u8>>;
+
+/// Order in which the records of the bounded MemoryDb are evicted
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum EvictionPolicy {
+    /// Least recently read or written records are evicted first
+    Lru,
+    /// Records are evicted in the order they were inserted; overwriting doesn't refresh the record
+    Fifo,
+}
+
+/// Limit of the bounded MemoryDb
+#[derive(Debug, Clone, Copy)]
+pub struct MemoryDbLimit {
+    /// Maximal total size of the keys and the values; records exceeding it are evicted after writes
+    pub max_bytes: u64,
+    pub policy: EvictionPolicy,
+}
+
+/// Order of the records of the bounded MemoryDb
+#[derive(Debug)]
+struct Eviction {
+    limit: MemoryDbLimit,
+    next_stamp: u64,
+    stamps: FnvHashMap<Vec<u8>, u64>,
+    order: BTreeMap<u64, Vec<u8>>,
+}
+
+impl Eviction {
+    fn new(limit: MemoryDbLimit) -> Self {
+        Self { limit, next_stamp: 0, stamps: FnvHashMap::default(), order: BTreeMap::new() }
+    }
+
+    fn stamp(&mut self, key: &[u8]) {
+        let stamp = self.next_stamp;
+        self.next_stamp += 1;
+        if let Some(old_stamp) = self.stamps.insert(key.to_vec(), stamp) {
+            self.order.remove(&old_stamp);
+        }
+        self.order.insert(stamp, key.to_vec());
+    }
+
+    fn on_read(&mut self, key: &[u8]) {
+        if self.limit.policy == EvictionPolicy::Lru && self.stamps.contains_key(key) {
+            self.stamp(key);
+        }
+    }
+
+    fn on_inserted(&mut self, key: &[u8]) {
+        if self.limit.policy == EvictionPolicy::Lru || !self.stamps.contains_key(key) {
+            self.stamp(key);
+        }
+    }
+
+    fn on_removed(&mut self, key: &[u8]) {
+        if let Some(stamp) = self.stamps.remove(key) {
+            self.order.remove(&stamp);
+        }
+    }
+
+    fn pop_oldest(&mut self) -> Option<Vec<u8>> {
+        let stamp = *self.order.keys().next()?;
+        let key = self.order.remove(&stamp)?;
+        self.stamps.remove(&key);
+        Some(key)
+    }
+}
+
+/// Filter deciding which records are dropped by `compact_range`, like the RocksDB compaction filter
+/// does during compactions; returns true for the records to drop
+#[derive(Clone)]
+pub struct MemoryCompactionFilter(Arc<dyn Fn(&[u8], &[u8]) -> bool + Send + Sync>);
+
+impl std::fmt::Debug for MemoryCompactionFilter {
+    fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+        f.write_str("MemoryCompactionFilter")
+    }
+}
+
+/// Contents of MemoryDb: the map shared with the snapshots, its size and the order of eviction
+#[derive(Debug)]
+struct MemoryState {
+    map: Arc<MemoryMap>,
+    size_bytes: u64,
+    eviction: Option<Eviction>,
+}
+
+impl MemoryState {
+    fn get(&mut self, key: &[u8]) -> Option<&Vec<u8>> {
+        if let Some(ref mut eviction) = self.eviction {
+            eviction.on_read(key);
+        }
+        self.map.get(key)
+    }
+
+    fn insert(&mut self, key: Vec<u8>, value: Vec<u8>) {
+        if let Some(ref mut eviction) = self.eviction {
+            eviction.on_inserted(&key);
+        }
+        self.size_bytes += (key.len() + value.len()) as u64;
+        let key_len = key.len();
+        if let Some(old_value) = Arc::make_mut(&mut self.map).insert(key, value) {
+            self.size_bytes -= (key_len + old_value.len()) as u64;
+        }
+    }
+
+    fn remove(&mut self, key: &[u8]) {
+        if let Some(ref mut eviction) = self.eviction {
+            eviction.on_removed(key);
+        }
+        if let Some(old_value) = Arc::make_mut(&mut self.map).remove(key) {
+            self.size_bytes -= (key.len() + old_value.len()) as u64;
+        }
+    }
+
+    /// Evicts the records until the size fits the limit
+    fn evict(&mut self) {
+        let eviction = match self.eviction {
+            Some(ref mut eviction) => eviction,
+            None => return,
+        };
+        while self.size_bytes > eviction.limit.max_bytes {
+            let key = match eviction.pop_oldest() {
+                Some(key) => key,
+                None => break,
+            };
+            if let Some(value) = Arc::make_mut(&mut self.map).remove(&key) {
+                self.size_bytes -= (key.len() + value.len()) as u64;
+            }
+        }
+    }
+}
+
+/// In-memory key-value collection. The map is shared with the snapshots taken from the collection
+/// and copied on the first write after a snapshot is taken (copy-on-write), so the snapshots are
+/// cheap and don't see later writes. The collection may be bounded by the total size of the records,
+/// evicting them by the chosen policy, so it may serve as a cache. The records may be dropped by
+/// the compaction filter, so the code relying on RocksDB compactions runs against the collection.
 #[derive(Debug, Clone)]
 pub struct MemoryDb {
-    map: Arc<Option<Mutex<FnvHashMap<Vec<u8>, Vec<u8>>>>>
+    state: Arc<Option<Mutex<MemoryState>>>,
+    compaction_filter: Option<MemoryCompactionFilter>,
 }
 
 /// Implementation of in-memory key-value collection
 impl MemoryDb {
     /// Constructs empty collection
     pub fn new() -> Self {
-        Self::with_map(FnvHashMap::default())
+        Self::with_state(None)
     }
 
-    fn with_map(map: FnvHashMap<Vec<u8>, Vec<u8>>) -> Self {
+    /// Constructs empty collection bounded by given limit
+    pub fn with_limit(limit: MemoryDbLimit) -> Self {
+        Self::with_state(Some(Eviction::new(limit)))
+    }
+
+    /// Constructs empty collection whose `compact_range` drops the records the filter returns
+    /// true for
+    pub fn with_compaction_filter(filter: impl Fn(&[u8], &[u8]) -> bool + Send + Sync + 'static) -> Self {
         Self {
-            map: Arc::new(Some(Mutex::new(map)))
+            compaction_filter: Some(MemoryCompactionFilter(Arc::new(filter))),
+            ..Self::with_state(None)
         }
     }
 
-    fn map(&self) -> Result<&Mutex<FnvHashMap<Vec<u8>, Vec<u8>>>> {
-        if let Some(ref map) = *self.map {
-            Ok(map)
+    fn with_state(eviction: Option<Eviction>) -> Self {
+        Self {
+            state: Arc::new(Some(Mutex::new(MemoryState {
+                map: Arc::new(FnvHashMap::default()),
+                size_bytes: 0,
+                eviction,
+            }))),
+            compaction_filter: None,
+        }
+    }
+
+    fn state(&self) -> Result<&Mutex<MemoryState>> {
+        if let Some(ref state) = *self.state {
+            Ok(state)
         } else {
             Err(StorageError::DbIsDropped)?
         }
     }
+
+    /// Current state of the map, shared without copying
+    fn shared_map(&self) -> Result<Arc<MemoryMap>> {
+        Ok(Arc::clone(&self.state()?.lock().unwrap().map))
+    }
+
+    /// Total size of the keys and the values stored
+    pub fn size_bytes(&self) -> Result<u64> {
+        Ok(self.state()?.lock().unwrap().size_bytes)
+    }
+
+    /// Limit the collection is bounded by, if any
+    pub fn limit(&self) -> Result<Option<MemoryDbLimit>> {
+        Ok(self.state()?.lock().unwrap().eviction.as_ref().map(|eviction| eviction.limit))
+    }
 }
 
 /// Implementation of key-value collection for MemoryDb
 impl Kvc for MemoryDb {
     fn len(&self) -> Result<usize> {
-        Ok(self.map()?
+        Ok(self.state()?
             .lock().unwrap()
-            .len())
+            .map.len())
     }
 
     fn is_empty(&self) -> Result<bool> {
-        Ok(self.map()?
+        Ok(self.state()?
             .lock().unwrap()
-            .is_empty())
+            .map.is_empty())
+    }
+
+    fn approx_size_bytes(&self) -> Result<u64> {
+        self.size_bytes()
+    }
+
+    fn compact_range(&self, start: Option<&[u8]>, end: Option<&[u8]>) -> Result<()> {
+        let filter = match self.compaction_filter {
+            Some(MemoryCompactionFilter(ref filter)) => filter,
+            None => return Ok(()),
+        };
+        let mut state = self.state()?.lock().unwrap();
+        let dropped: Vec<Vec<u8>> = state.map.iter()
+            .filter(|(key, _value)| start.map(|start| key.as_slice() >= start).unwrap_or(true))
+            .filter(|(key, _value)| end.map(|end| key.as_slice() <= end).unwrap_or(true))
+            .filter(|(key, value)| filter(key, value))
+            .map(|(key, _value)| key.clone())
+            .collect();
+        for key in dropped {
+            state.remove(&key);
+        }
+
+        Ok(())
     }
 
     fn destroy(&mut self) -> Result<()> {
-        if Arc::get_mut(&mut self.map)
+        if Arc::get_mut(&mut self.state)
             .ok_or(StorageError::HasActiveTransactions)?
             .is_some()
         {
-            self.map = Arc::new(None);
+            self.state = Arc::new(None);
         }
 
         Ok(())
@@ -66,61 +262,96 @@ impl Kvc for MemoryDb {
 /// Implementation of readable key-value collection for MemoryDb. Actual implementation is blocking.
 impl<K: DbKey + Send + Sync> KvcReadable<K> for MemoryDb {
     fn try_get(&self, key: &K) -> Result<Option<DbSlice>> {
-        Ok(self.map()?
+        Ok(self.state()?
             .lock().unwrap()
             .get(key.key())
             .map(|vec| vec.clone().into()))
     }
 
     fn contains(&self, key: &K) -> Result<bool> {
-        Ok(self.map()?
+        Ok(self.state()?
             .lock().unwrap()
-            .contains_key(key.key()))
+            .map.contains_key(key.key()))
     }
 
     fn for_each(&self, predicate: &mut dyn FnMut(&[u8], &[u8]) -> Result<bool>) -> Result<bool> {
-        let mut pairs = Vec::with_capacity(self.map()?.lock().unwrap().len());
-        for (key, value) in self.map()?.lock().unwrap().iter() {
-            pairs.push((key.clone(), value.clone()));
-        }
-
-        for (key, value) in pairs {
-            if !predicate(&key[..], &value[..])? {
-                return Ok(false);
-            }
-        }
-        Ok(true)
+        // Iterating over the shared map doesn't hold the lock, so the predicate may write
+        for_each_in_map(&self.shared_map()?, predicate)
     }
 }
 
 /// Implementation of wriatable key-value collection for MemoryDb. Actual implementation is blocking.
 impl<K: DbKey + Send + Sync> KvcWriteable<K> for MemoryDb {
     fn put(&self, key: &K, value: &[u8]) -> Result<()> {
-        self.map()?
-            .lock().unwrap()
-            .insert(key.key().to_vec(), value.to_vec());
+        let mut state = self.state()?.lock().unwrap();
+        state.insert(key.key().to_vec(), value.to_vec());
+        state.evict();
         Ok(())
     }
 
     fn delete(&self, key: &K) -> Result<()> {
-        self.map()?
-            .lock().unwrap()
+        self.state()?.lock().unwrap()
             .remove(key.key());
         Ok(())
     }
 }
 
-/// Implementation of support for take snapshots for MemoryDb.
+/// Implementation of support for take snapshots for MemoryDb. Taking snapshot doesn't copy the data.
 impl<K: DbKey + Send + Sync> KvcSnapshotable<K> for MemoryDb {
     fn snapshot<'db>(&'db self) -> Result<Arc<dyn KvcReadable<K> + 'db>> {
-        Ok(Arc::new(Self::with_map(self.map()?.lock().unwrap().clone())))
+        Ok(Arc::new(MemoryDbSnapshot { map: self.shared_map()? }))
+    }
+}
+
+fn for_each_in_map(map: &MemoryMap, predicate: &mut dyn FnMut(&[u8], &[u8]) -> Result<bool>) -> Result<bool> {
+    for (key, value) in map.iter() {
+        if !predicate(&key[..], &value[..])? {
+            return Ok(false);
+        }
+    }
+    Ok(true)
+}
+
+/// Read-only snapshot of MemoryDb
+#[derive(Debug)]
+pub struct MemoryDbSnapshot {
+    map: Arc<MemoryMap>,
+}
+
+impl Kvc for MemoryDbSnapshot {
+    fn len(&self) -> Result<usize> {
+        Ok(self.map.len())
+    }
+
+    fn approx_size_bytes(&self) -> Result<u64> {
+        Ok(self.map.iter()
+            .map(|(key, value)| (key.len() + value.len()) as u64)
+            .sum())
+    }
+
+    fn destroy(&mut self) -> Result<()> {
+        fail!("Snapshot can't be destroyed")
+    }
+}
+
+impl<K: DbKey + Send + Sync> KvcReadable<K> for MemoryDbSnapshot {
+    fn try_get(&self, key: &K) -> Result<Option<DbSlice>> {
+        Ok(self.map.get(key.key()).map(|vec| vec.clone().into()))
+    }
+
+    fn contains(&self, key: &K) -> Result<bool> {
+        Ok(self.map.contains_key(key.key()))
+    }
+
+    fn for_each(&self, predicate: &mut dyn FnMut(&[u8], &[u8]) -> Result<bool>) -> Result<bool> {
+        for_each_in_map(&self.map, predicate)
     }
 }
 
 /// Implementation of transaction support for key-value collection for MemoryDb.
 impl<K: DbKey + Send + Sync> KvcTransactional<K> for MemoryDb {
     fn begin_transaction(&self) -> Result<Box<dyn KvcTransaction<K>>> {
-        Ok(Box::new(MemoryDbTransaction::new(Arc::clone(&self.map))))
+        Ok(Box::new(MemoryDbTransaction::new(Arc::clone(&self.state))))
     }
 }
 
@@ -138,15 +369,15 @@ enum PendingOperation {
 
 #[derive(Debug)]
 pub struct MemoryDbTransaction {
-    db_map: Arc<Option<Mutex<FnvHashMap<Vec<u8>, Vec<u8>>>>>,
+    db_state: Arc<Option<Mutex<MemoryState>>>,
     pending: Mutex<Vec<PendingOperation>>,
 }
 
 /// Implementation of transaction for MemoryDb.
 impl MemoryDbTransaction {
-    fn new(db_map: Arc<Option<Mutex<FnvHashMap<Vec<u8>, Vec<u8>>>>>) -> Self {
+    fn new(db_state: Arc<Option<Mutex<MemoryState>>>) -> Self {
         Self {
-            db_map,
+            db_state,
             pending: Mutex::new(Vec::new()),
         }
     }
@@ -175,15 +406,17 @@ impl<K: DbKey + Send + Sync> KvcTransaction<K> for MemoryDbTransaction {
     }
 
     fn commit(self: Box<Self>) -> Result<()> {
-        let mut guard = self.db_map.as_ref().as_ref()
+        let mut state = self.db_state.as_ref().as_ref()
             .ok_or(StorageError::DbIsDropped)?
             .lock().unwrap();
+        // The map is copied at most once per commit, and only if a snapshot shares it
         for operation in self.pending.lock().unwrap().drain(..) {
             match operation {
-                PendingOperation::Put(pair) => guard.insert(pair.key, pair.value),
-                PendingOperation::Delete(key) => guard.remove(&key),
+                PendingOperation::Put(pair) => state.insert(pair.key, pair.value),
+                PendingOperation::Delete(key) => state.remove(&key),
             };
         }
+        state.evict();
 
         Ok(())
     }
@@ -196,3 +429,87 @@ impl<K: DbKey + Send + Sync> KvcTransaction<K> for MemoryDbTransaction {
         self.pending.lock().unwrap().is_empty()
     }
 }
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn get(db: &dyn KvcReadable<&'static str>, key: &'static str) -> Option<Vec<u8>> {
+        db.try_get(&key).unwrap().map(|value| value.to_vec())
+    }
+
+    fn put(db: &MemoryDb, key: &'static str, value: &[u8]) {
+        KvcWriteable::<&str>::put(db, &key, value).unwrap()
+    }
+
+    #[test]
+    fn snapshot_does_not_see_later_writes() {
+        let db = MemoryDb::new();
+        put(&db, "a", b"1");
+        put(&db, "b", b"2");
+        let snapshot = KvcSnapshotable::<&str>::snapshot(&db).unwrap();
+
+        put(&db, "a", b"3");
+        put(&db, "c", b"4");
+        KvcWriteable::<&str>::delete(&db, &"b").unwrap();
+
+        assert_eq!(get(&*snapshot, "a"), Some(b"1".to_vec()));
+        assert_eq!(get(&*snapshot, "b"), Some(b"2".to_vec()));
+        assert_eq!(get(&*snapshot, "c"), None);
+        assert_eq!(snapshot.len().unwrap(), 2);
+        assert_eq!(get(&db, "a"), Some(b"3".to_vec()));
+        assert_eq!(get(&db, "b"), None);
+        assert_eq!(get(&db, "c"), Some(b"4".to_vec()));
+        assert_eq!(db.size_bytes().unwrap(), 4);
+    }
+
+    #[test]
+    fn transaction_is_applied_on_commit() {
+        let db = MemoryDb::new();
+        put(&db, "a", b"1");
+        let snapshot = KvcSnapshotable::<&str>::snapshot(&db).unwrap();
+        let transaction = KvcTransactional::<&str>::begin_transaction(&db).unwrap();
+        transaction.put(&"b", b"2");
+        transaction.delete(&"a");
+        assert_eq!(transaction.len(), 2);
+        assert_eq!(get(&db, "a"), Some(b"1".to_vec()));
+        assert_eq!(get(&db, "b"), None);
+
+        transaction.commit().unwrap();
+        assert_eq!(get(&db, "a"), None);
+        assert_eq!(get(&db, "b"), Some(b"2".to_vec()));
+        assert_eq!(get(&*snapshot, "a"), Some(b"1".to_vec()));
+        assert_eq!(get(&*snapshot, "b"), None);
+    }
+
+    #[test]
+    fn compact_range_drops_filtered_records() {
+        let db = MemoryDb::with_compaction_filter(|_key, value| value == b"expired");
+        put(&db, "a", b"expired");
+        put(&db, "b", b"live");
+        put(&db, "c", b"expired");
+
+        db.compact_range(Some(&b"b"[..]), None).unwrap();
+        assert_eq!(get(&db, "a"), Some(b"expired".to_vec()));
+        assert_eq!(get(&db, "c"), None);
+
+        db.compact_range(None, None).unwrap();
+        assert_eq!(get(&db, "a"), None);
+        assert_eq!(get(&db, "b"), Some(b"live".to_vec()));
+        assert_eq!(Kvc::len(&db).unwrap(), 1);
+    }
+
+    #[test]
+    fn bounded_db_evicts_least_recently_used() {
+        let db = MemoryDb::with_limit(MemoryDbLimit { max_bytes: 4, policy: EvictionPolicy::Lru });
+        put(&db, "a", b"1");
+        put(&db, "b", b"2");
+        assert_eq!(get(&db, "a"), Some(b"1".to_vec()));
+
+        put(&db, "c", b"3");
+        assert_eq!(get(&db, "a"), Some(b"1".to_vec()));
+        assert_eq!(get(&db, "b"), None);
+        assert_eq!(get(&db, "c"), Some(b"3".to_vec()));
+        assert_eq!(db.size_bytes().unwrap(), 4);
+    }
+}
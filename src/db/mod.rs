@@ -3,4 +3,7 @@ pub mod async_adapter;
 pub mod rocksdb;
 pub mod memorydb;
 pub mod filedb;
+pub mod chunked;
+pub mod collection_metadata;
+pub mod instrumented;
 
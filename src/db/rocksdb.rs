@@ -2,8 +2,12 @@ use std::fmt::{Debug, Formatter};
 use std::path::{Path, PathBuf};
 use std::sync::Arc;
 use std::sync::Mutex;
+use std::sync::atomic::{AtomicU8, Ordering};
 
-use rocksdb::{DB, IteratorMode, Options, Snapshot, WriteBatch};
+use rocksdb::{
+    BlockBasedOptions, DB, DBRawIterator, Options, ReadOptions, SliceTransform, Snapshot, WriteBatch, WriteOptions,
+};
+use serde_derive::{Deserialize, Serialize};
 
 use ton_types::{fail, Result};
 
@@ -11,10 +15,54 @@ use crate::db::traits::{DbKey, Kvc, KvcReadable, KvcSnapshotable, KvcTransaction
 use crate::error::StorageError;
 use crate::types::DbSlice;
 
+/// Durability of the writes into RocksDB collections
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Deserialize, Serialize)]
+#[serde(rename_all = "snake_case")]
+pub enum FsyncPolicy {
+    /// The WAL is synced by the OS; the last writes may be lost on power failure (but not on crash)
+    Never,
+    /// Commits of the transactions wait for the WAL to be synced, single writes don't
+    Commits,
+    /// Every write waits for the WAL to be synced
+    Always,
+}
+
+impl Default for FsyncPolicy {
+    fn default() -> Self {
+        FsyncPolicy::Never
+    }
+}
+
+static FSYNC_POLICY: AtomicU8 = AtomicU8::new(FsyncPolicy::Never as u8);
+
+/// Sets the durability of the writes into the collections opened afterwards (collections already
+/// opened are not affected, so it is to be set before the storage is opened)
+pub fn set_fsync_policy(policy: FsyncPolicy) {
+    FSYNC_POLICY.store(policy as u8, Ordering::Relaxed)
+}
+
+pub fn fsync_policy() -> FsyncPolicy {
+    match FSYNC_POLICY.load(Ordering::Relaxed) {
+        x if x == FsyncPolicy::Always as u8 => FsyncPolicy::Always,
+        x if x == FsyncPolicy::Commits as u8 => FsyncPolicy::Commits,
+        _ => FsyncPolicy::Never,
+    }
+}
+
+fn write_options(sync: bool) -> WriteOptions {
+    let mut write_options = WriteOptions::default();
+    write_options.set_sync(sync);
+
+    write_options
+}
+
 #[derive(Debug)]
 pub struct RocksDb {
     db: Arc<Option<DB>>,
     path: PathBuf,
+    // Length of the key prefix the prefix bloom filter is built over, if configured
+    prefix_len: Option<usize>,
+    fsync_policy: FsyncPolicy,
 }
 
 impl RocksDb {
@@ -36,10 +84,60 @@ impl RocksDb {
         Self {
             db: Arc::new(Some(DB::open(&options, path)
                 .expect("Cannot open DB"))),
-            path: pathbuf
+            path: pathbuf,
+            prefix_len: None,
+            fsync_policy: fsync_policy(),
         }
     }
 
+    /// Creates new instance with given path and bloom filters over the first `prefix_len` bytes
+    /// of the keys, so scans of the keys sharing such prefix (`for_each_with_prefix`) skip the
+    /// files not containing it
+    pub fn with_prefix_bloom(path: impl AsRef<Path>, prefix_len: usize) -> Self {
+        let mut db = Self::with_options(path, |options| {
+            options.set_prefix_extractor(SliceTransform::create_fixed_prefix(prefix_len));
+            options.set_memtable_prefix_bloom_ratio(0.1);
+            let mut table_options = BlockBasedOptions::default();
+            table_options.set_bloom_filter(10, false);
+            options.set_block_based_table_factory(&table_options);
+        });
+        db.prefix_len = Some(prefix_len);
+
+        db
+    }
+
+    /// Opens existing database at given path in read-only mode; writes to such instance fail
+    pub fn with_path_read_only(path: impl AsRef<Path>) -> Result<Self> {
+        let pathbuf = path.as_ref().to_path_buf();
+        let db = DB::open_for_read_only(&Options::default(), path, false)?;
+
+        Ok(Self {
+            db: Arc::new(Some(db)),
+            path: pathbuf,
+            prefix_len: None,
+            fsync_policy: FsyncPolicy::Never,
+        })
+    }
+
+    /// Options of the iterator over the keys with given prefix (over all the keys if None). The
+    /// prefix bloom filter is used only if the prefix is covered by it; other scans have to be
+    /// made in the total order, as the prefix mode doesn't cross prefix boundaries.
+    fn read_options(&self, prefix: Option<&[u8]>) -> ReadOptions {
+        match (prefix, self.prefix_len) {
+            (Some(prefix), Some(prefix_len)) if prefix.len() >= prefix_len => {
+                let mut read_options = ReadOptions::default();
+                read_options.set_prefix_same_as_start(true);
+                read_options
+            }
+            _ => total_order_read_options(),
+        }
+    }
+
+    fn property_int_value(&self, name: &str) -> Result<u64> {
+        Ok(self.db()?.property_int_value(name)?
+            .unwrap_or_default())
+    }
+
     pub(crate) fn db(&self) -> Result<&DB> {
         if let Some(ref db) = *self.db {
             Ok(db)
@@ -52,7 +150,24 @@ impl RocksDb {
 /// Implementation of key-value collection for RocksDB
 impl Kvc for RocksDb {
     fn len(&self) -> Result<usize> {
-        fail!("len() is not supported for RocksDb")
+        count_raw(self.db()?.raw_iterator_opt(self.read_options(None)))
+    }
+
+    fn is_empty(&self) -> Result<bool> {
+        let mut iterator = self.db()?.raw_iterator_opt(self.read_options(None));
+        iterator.seek_to_first();
+        let is_empty = !iterator.valid();
+        iterator.status()?;
+
+        Ok(is_empty)
+    }
+
+    fn approx_len(&self) -> Result<usize> {
+        Ok(self.property_int_value("rocksdb.estimate-num-keys")? as usize)
+    }
+
+    fn approx_size_bytes(&self) -> Result<u64> {
+        self.property_int_value("rocksdb.estimate-live-data-size")
     }
 
     fn destroy(&mut self) -> Result<()> {
@@ -65,6 +180,15 @@ impl Kvc for RocksDb {
 
         Ok(DB::destroy(&Options::default(), &self.path)?)
     }
+
+    fn flush(&self) -> Result<()> {
+        Ok(self.db()?.flush()?)
+    }
+
+    fn compact_range(&self, start: Option<&[u8]>, end: Option<&[u8]>) -> Result<()> {
+        self.db()?.compact_range(start, end);
+        Ok(())
+    }
 }
 
 /// Implementation of readable key-value collection for RocksDB. Actual implementation is blocking.
@@ -75,24 +199,96 @@ impl<K: DbKey + Send + Sync> KvcReadable<K> for RocksDb {
     }
 
     fn for_each(&self, predicate: &mut dyn FnMut(&[u8], &[u8]) -> Result<bool>) -> Result<bool> {
-        for (key, value) in self.db()?.iterator(IteratorMode::Start) {
-            if !predicate(key.as_ref(), value.as_ref())? {
+        let mut iterator = self.db()?.raw_iterator_opt(self.read_options(None));
+        iterator.seek_to_first();
+        for_each_raw(iterator, predicate)
+    }
+
+    fn for_each_from(&self, start: &[u8], predicate: &mut dyn FnMut(&[u8], &[u8]) -> Result<bool>) -> Result<bool> {
+        let mut iterator = self.db()?.raw_iterator_opt(self.read_options(None));
+        iterator.seek(start);
+        for_each_raw(iterator, predicate)
+    }
+
+    fn for_each_key_only(&self, predicate: &mut dyn FnMut(&[u8]) -> Result<bool>) -> Result<bool> {
+        let mut iterator = self.db()?.raw_iterator_opt(self.read_options(None));
+        iterator.seek_to_first();
+        for_each_key_raw(iterator, predicate)
+    }
+
+    fn for_each_with_prefix(&self, prefix: &[u8], predicate: &mut dyn FnMut(&[u8], &[u8]) -> Result<bool>) -> Result<bool> {
+        let mut iterator = self.db()?.raw_iterator_opt(self.read_options(Some(prefix)));
+        iterator.seek(prefix);
+        for_each_raw(iterator, &mut |key, value| {
+            if !key.starts_with(prefix) {
+                return Ok(false);
+            }
+            predicate(key, value)
+        })
+    }
+}
+
+/// Options of the iterator over all the keys: with the prefix bloom filter configured, iterators
+/// in the default (prefix) mode may stop at the prefix boundaries
+fn total_order_read_options() -> ReadOptions {
+    let mut read_options = ReadOptions::default();
+    read_options.set_total_order_seek(true);
+
+    read_options
+}
+
+/// Runs predicate for each pair starting from the current position of the iterator. Keys and
+/// values are borrowed from RocksDB, not copied as by the regular iterator.
+fn for_each_raw(mut iterator: DBRawIterator, predicate: &mut dyn FnMut(&[u8], &[u8]) -> Result<bool>) -> Result<bool> {
+    while iterator.valid() {
+        if let (Some(key), Some(value)) = (iterator.key(), iterator.value()) {
+            if !predicate(key, value)? {
                 return Ok(false);
             }
         }
-        Ok(true)
+        iterator.next();
+    }
+    iterator.status()?;
+
+    Ok(true)
+}
+
+/// Runs predicate for each key starting from the current position of the iterator; values are
+/// not read at all
+fn for_each_key_raw(mut iterator: DBRawIterator, predicate: &mut dyn FnMut(&[u8]) -> Result<bool>) -> Result<bool> {
+    while iterator.valid() {
+        if let Some(key) = iterator.key() {
+            if !predicate(key)? {
+                return Ok(false);
+            }
+        }
+        iterator.next();
     }
+    iterator.status()?;
+
+    Ok(true)
+}
+
+fn count_raw(mut iterator: DBRawIterator) -> Result<usize> {
+    let mut count = 0;
+    iterator.seek_to_first();
+    for_each_key_raw(iterator, &mut |_key| {
+        count += 1;
+        Ok(true)
+    })?;
+
+    Ok(count)
 }
 
 /// Implementation of writable key-value collection for RocksDB. Actual implementation is blocking.
 impl<K: DbKey + Send + Sync> KvcWriteable<K> for RocksDb {
     fn put(&self, key: &K, value: &[u8]) -> Result<()> {
-        self.db()?.put(key.key(), value)
+        self.db()?.put_opt(key.key(), value, &write_options(self.fsync_policy == FsyncPolicy::Always))
             .map_err(|err| err.into())
     }
 
     fn delete(&self, key: &K) -> Result<()> {
-        self.db()?.delete(key.key())
+        self.db()?.delete_opt(key.key(), &write_options(self.fsync_policy == FsyncPolicy::Always))
             .map_err(|err| err.into())
     }
 }
@@ -114,7 +310,11 @@ impl Debug for RocksDbSnapshot<'_> {
 
 impl Kvc for RocksDbSnapshot<'_> {
     fn len(&self) -> Result<usize> {
-        fail!("len() is not supported for RocksDb")
+        count_raw(self.0.raw_iterator_opt(total_order_read_options()))
+    }
+
+    fn approx_size_bytes(&self) -> Result<u64> {
+        fail!("approx_size_bytes() is not supported for snapshots")
     }
 
     fn destroy(&mut self) -> Result<()> {
@@ -129,33 +329,45 @@ impl<K: DbKey + Send + Sync> KvcReadable<K> for RocksDbSnapshot<'_> {
     }
 
     fn for_each(&self, predicate: &mut dyn FnMut(&[u8], &[u8]) -> Result<bool>) -> Result<bool> {
-        for (key, value) in self.0.iterator(IteratorMode::Start) {
-            if !predicate(key.as_ref(), value.as_ref())? {
-                return Ok(false);
-            }
-        }
-        Ok(true)
+        let mut iterator = self.0.raw_iterator_opt(total_order_read_options());
+        iterator.seek_to_first();
+        for_each_raw(iterator, predicate)
+    }
+
+    fn for_each_from(&self, start: &[u8], predicate: &mut dyn FnMut(&[u8], &[u8]) -> Result<bool>) -> Result<bool> {
+        let mut iterator = self.0.raw_iterator_opt(total_order_read_options());
+        iterator.seek(start);
+        for_each_raw(iterator, predicate)
+    }
+
+    fn for_each_key_only(&self, predicate: &mut dyn FnMut(&[u8]) -> Result<bool>) -> Result<bool> {
+        let mut iterator = self.0.raw_iterator_opt(total_order_read_options());
+        iterator.seek_to_first();
+        for_each_key_raw(iterator, predicate)
     }
 }
 
 /// Implementation of transaction support for key-value collection for RocksDB.
 impl<K: DbKey + Send + Sync> KvcTransactional<K> for RocksDb {
     fn begin_transaction(&self) -> Result<Box<dyn KvcTransaction<K>>> {
-        Ok(Box::new(RocksDbTransaction::new(Arc::clone(&self.db))))
+        Ok(Box::new(RocksDbTransaction::new(Arc::clone(&self.db), self.fsync_policy != FsyncPolicy::Never)))
     }
 }
 
 pub struct RocksDbTransaction {
     db: Arc<Option<DB>>,
     batch: Mutex<WriteBatch>,
+    // Whether the commit waits for the WAL to be synced
+    sync: bool,
 }
 
 /// Implementation of transaction for key-value collection for RocksDB.
 impl RocksDbTransaction {
-    fn new(db: Arc<Option<DB>>) -> Self {
+    fn new(db: Arc<Option<DB>>, sync: bool) -> Self {
         Self {
             db,
-            batch: Mutex::new(WriteBatch::default())
+            batch: Mutex::new(WriteBatch::default()),
+            sync,
         }
     }
 }
@@ -179,7 +391,7 @@ impl<K: DbKey + Send + Sync> KvcTransaction<K> for RocksDbTransaction {
     fn commit(self: Box<Self>) -> Result<()> {
         let batch = self.batch.into_inner().unwrap();
         if let Some(ref db) = *self.db {
-            db.write(batch)
+            db.write_opt(batch, &write_options(self.sync))
             .map_err(|err| err.into())
         } else {
             Err(StorageError::DbIsDropped)?
@@ -9,9 +9,18 @@ use crate::error::StorageError;
 
 /// Trait for key-value collections
 pub trait Kvc: Debug + Send + Sync {
-    /// Element count of collection
+    /// Exact element count of collection. Might be expensive (e.g. iterates the whole RocksDB
+    /// collection); use `approx_len` for monitoring.
     fn len(&self) -> Result<usize>;
 
+    /// Estimated element count of collection; cheap
+    fn approx_len(&self) -> Result<usize> {
+        self.len()
+    }
+
+    /// Estimated size of the collection data in bytes; cheap
+    fn approx_size_bytes(&self) -> Result<u64>;
+
     /// Returns true, if collection is empty; false otherwise
     fn is_empty(&self) -> Result<bool> {
         Ok(self.len()? == 0)
@@ -19,6 +28,16 @@ pub trait Kvc: Debug + Send + Sync {
 
     /// Destroys this key-value collection and underlying database
     fn destroy(&mut self) -> Result<()>;
+
+    /// Flushes in-memory buffers of the collection (if any) to the persistent storage
+    fn flush(&self) -> Result<()> {
+        Ok(())
+    }
+
+    /// Runs manual compaction on the given key range; None means an open bound
+    fn compact_range(&self, _start: Option<&[u8]>, _end: Option<&[u8]>) -> Result<()> {
+        Ok(())
+    }
 }
 
 /// Trait for readable key-value collections
@@ -32,6 +51,20 @@ pub trait KvcReadable<K: DbKey + Send + Sync>: Kvc {
             .ok_or_else(|| StorageError::KeyNotFound(key.key_name(), key.as_string()).into())
     }
 
+    /// Gets value from collection by the key as owned bytes. Prefer it for the values held for long
+    /// (e.g. served to peers), as the slice returned by `get` keeps the database memory pinned.
+    fn get_owned(&self, key: &K) -> Result<Vec<u8>> {
+        Ok(self.get(key)?.into())
+    }
+
+    /// Tries to get values from collection by the keys; the result contains an element for each key.
+    /// The default implementation reads the keys one by one.
+    fn try_get_multi(&self, keys: &[K]) -> Result<Vec<Option<DbSlice>>> {
+        keys.iter()
+            .map(|key| self.try_get(key))
+            .collect()
+    }
+
     /// Gets slice with given size starting from given offset from collection by the key
     fn get_slice(&self, key: &K, offset: u64, size: u64) -> Result<DbSlice> {
         self.get(key).and_then(|value| {
@@ -57,6 +90,48 @@ pub trait KvcReadable<K: DbKey + Send + Sync>: Kvc {
 
     /// Iterates over items in key-value collection, running predicate for each key-value pair
     fn for_each(&self, predicate: &mut dyn FnMut(&[u8], &[u8]) -> Result<bool>) -> Result<bool>;
+
+    /// Iterates over keys in key-value collection, running predicate for each key. Values are not
+    /// read where the backend allows, so scans which don't need them (counting, gap detection and
+    /// the like) should prefer it to `for_each`.
+    fn for_each_key_only(&self, predicate: &mut dyn FnMut(&[u8]) -> Result<bool>) -> Result<bool> {
+        self.for_each(&mut |key, _value| predicate(key))
+    }
+
+    /// Iterates over items whose keys start with given prefix in the ascending order of keys,
+    /// running predicate for each key-value pair
+    fn for_each_with_prefix(&self, prefix: &[u8], predicate: &mut dyn FnMut(&[u8], &[u8]) -> Result<bool>) -> Result<bool> {
+        let mut completed = true;
+        self.for_each_from(prefix, &mut |key, value| {
+            if !key.starts_with(prefix) {
+                return Ok(false);
+            }
+            completed = predicate(key, value)?;
+            Ok(completed)
+        })?;
+
+        Ok(completed)
+    }
+
+    /// Iterates over items with keys greater or equal to given one in the ascending order of keys,
+    /// running predicate for each key-value pair
+    fn for_each_from(&self, start: &[u8], predicate: &mut dyn FnMut(&[u8], &[u8]) -> Result<bool>) -> Result<bool> {
+        let mut pairs = Vec::new();
+        self.for_each(&mut |key, value| {
+            if key >= start {
+                pairs.push((key.to_vec(), value.to_vec()));
+            }
+            Ok(true)
+        })?;
+        pairs.sort();
+
+        for (key, value) in pairs {
+            if !predicate(&key, &value)? {
+                return Ok(false);
+            }
+        }
+        Ok(true)
+    }
 }
 
 /// Trait for writable key-value collections
@@ -0,0 +1,20 @@
+use std::convert::TryInto;
+
+use ton_types::Result;
+
+use crate::db::traits::{KvcWriteable, U32Key};
+use crate::db_impl_cbor;
+use crate::types::SlowOpRecord;
+
+db_impl_cbor!(DiagnosticsDb, KvcWriteable, U32Key, SlowOpRecord);
+
+impl DiagnosticsDb {
+    /// Iterates over the records with the slots they are stored in
+    pub fn for_each_record(&self, mut predicate: impl FnMut(u32, SlowOpRecord) -> Result<bool>) -> Result<bool> {
+        self.for_each(&mut |key_data, data| {
+            let slot = u32::from_le_bytes(key_data.try_into()?);
+            let record = serde_cbor::from_slice(data)?;
+            predicate(slot, record)
+        })
+    }
+}
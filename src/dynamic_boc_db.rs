@@ -1,20 +1,73 @@
 use std::ops::{Deref, DerefMut};
 use std::path::Path;
-use std::sync::{Arc, RwLock, Weak};
+use std::sync::{Arc, Weak};
+use std::sync::atomic::{AtomicBool, AtomicU32, AtomicU64, Ordering};
 
-use fnv::FnvHashMap;
+use fnv::{FnvHashMap, FnvHashSet};
+use parking_lot::RwLock;
+use serde_derive::{Deserialize, Serialize};
 
-use ton_types::{Cell, Result};
+use ton_types::{Cell, error, fail, Result};
 
+use crate::cell_access_db::CellAccessStats;
 use crate::cell_db::CellDb;
-use crate::dynamic_boc_diff_writer::{DynamicBocDiffFactory, DynamicBocDiffWriter};
-use crate::types::{CellId, StorageCell};
+use crate::cell_gc_fence::{CellGcFence, WriterRegistration};
+use crate::cell_gc_horizon::{CellGcHorizon, split_cell_header};
+use crate::cell_prefetcher::{CellPrefetcher, PrefetchConfig, PrefetchStats};
+use crate::cell_traversal::{depth_first, depth_first_post_order};
+use crate::dynamic_boc_diff_writer::{
+    DiffCoalescingConfig, DynamicBocDiffFactory, DynamicBocDiffWriter, SubBatchLimits,
+};
+use crate::read_repair::{MissingCellResolver, MissingReference, ReadRepairReport};
+use crate::storage_config::CellsConfig;
+use crate::traits::CellLoader;
+use crate::types::{CellAccessInfo, CellId, StorageCell};
+
+/// Entry of the loaded cells map
+#[derive(Debug, Default)]
+pub struct CellsMapEntry {
+    cell: Weak<StorageCell>,
+    // Count of loads of the cell in flight. While the entry is pinned, it is not removed on
+    // dropping of the previous instance of the cell, which is about to be resurrected.
+    pins: u32,
+}
+
+impl CellsMapEntry {
+    pub fn cell(&self) -> Option<Arc<StorageCell>> {
+        self.cell.upgrade()
+    }
+
+    pub const fn pins(&self) -> u32 {
+        self.pins
+    }
+}
+
+pub type CellsMap = FnvHashMap<CellId, CellsMapEntry>;
+
+/// Verification of the cells read from the database against their ids
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Deserialize, Serialize)]
+#[serde(rename_all = "snake_case")]
+pub enum LoadVerification {
+    Disabled,
+    /// Every loaded cell is verified
+    Strict,
+    /// Each N-th loaded cell is verified
+    Sampled(u32),
+}
 
 #[derive(Debug)]
 pub struct DynamicBocDb {
     db: Arc<CellDb>,
-    cells: Arc<RwLock<FnvHashMap<CellId, Weak<StorageCell>>>>,
+    // The lock isn't poisoned by panics, so a panicking loader doesn't break loads of the others
+    cells: Arc<RwLock<CellsMap>>,
     diff_factory: DynamicBocDiffFactory,
+    access_stats: Option<Arc<CellAccessStats>>,
+    gc_horizon: Option<Arc<CellGcHorizon>>,
+    db_loads: AtomicU64,
+    // Zero disables verification, N means verification of each N-th load
+    verification_rate: AtomicU32,
+    lazy_cell_parsing: AtomicBool,
+    prefetcher: RwLock<Option<CellPrefetcher>>,
 }
 
 impl DynamicBocDb {
@@ -30,11 +83,33 @@ impl DynamicBocDb {
 
     /// Constructs new instance using given key-value collection implementation
     pub(crate) fn with_db(db: CellDb) -> Self {
+        Self::with_db_and_access_stats(db, None)
+    }
+
+    /// Constructs new instance using given key-value collection implementation and
+    /// (optionally) collector of cells access statistics
+    pub(crate) fn with_db_and_access_stats(db: CellDb, access_stats: Option<Arc<CellAccessStats>>) -> Self {
+        Self::with_params(db, access_stats, None)
+    }
+
+    /// Constructs new instance; if GC horizon is given, the cells are stamped with generations
+    /// for compaction filter-based GC (the database must be opened with the same horizon)
+    pub(crate) fn with_params(
+        db: CellDb,
+        access_stats: Option<Arc<CellAccessStats>>,
+        gc_horizon: Option<Arc<CellGcHorizon>>,
+    ) -> Self {
         let db = Arc::new(db);
         Self {
             db: Arc::clone(&db),
             cells: Arc::new(RwLock::new(FnvHashMap::default())),
-            diff_factory: DynamicBocDiffFactory::new(db),
+            diff_factory: DynamicBocDiffFactory::new(db, gc_horizon.clone()),
+            access_stats,
+            gc_horizon,
+            db_loads: AtomicU64::new(0),
+            verification_rate: AtomicU32::new(0),
+            lazy_cell_parsing: AtomicBool::new(false),
+            prefetcher: RwLock::new(None),
         }
     }
 
@@ -42,82 +117,494 @@ impl DynamicBocDb {
         &self.db
     }
 
-    pub fn cells_map(&self) -> Arc<RwLock<FnvHashMap<CellId, Weak<StorageCell>>>> {
+    pub fn cell_access_stats(&self) -> Option<&Arc<CellAccessStats>> {
+        self.access_stats.as_ref()
+    }
+
+    /// Returns up to `top_k` the most frequently accessed cells (empty, if statistics is disabled)
+    pub fn access_stats(&self, top_k: usize) -> Result<Vec<(CellId, CellAccessInfo)>> {
+        match self.access_stats {
+            Some(ref access_stats) => access_stats.top(top_k),
+            None => Ok(Vec::new()),
+        }
+    }
+
+    pub fn gc_horizon(&self) -> Option<&Arc<CellGcHorizon>> {
+        self.gc_horizon.as_ref()
+    }
+
+    pub fn cells_map(&self) -> Arc<RwLock<CellsMap>> {
         Arc::clone(&self.cells)
     }
 
+    /// Count of cells read from the database since the instance creation
+    pub fn db_loads(&self) -> u64 {
+        self.db_loads.load(Ordering::Relaxed)
+    }
+
+    /// Count of the entries of the loaded cells map
+    pub fn cached_cells(&self) -> usize {
+        self.cells.read().len()
+    }
+
     /// Converts tree of cells into DynamicBoc
     pub fn save_as_dynamic_boc(self: &Arc<Self>, root_cell: Cell) -> Result<usize> {
-        let diff_writer = self.diff_factory.construct();
+        self.save_as_dynamic_boc_registered(root_cell, Arc::new(self.gc_fence().register()))
+    }
+
+    /// Converts tree of cells into DynamicBoc under given registration in the GC fence. The caller
+    /// keeps the registration until the root is registered, so GC sweeps running meanwhile don't
+    /// delete the cells of the tree.
+    #[cfg_attr(feature = "tracing", tracing::instrument(
+        target = "storage",
+        name = "save_as_dynamic_boc",
+        skip(self, root_cell, registration),
+        fields(root = %CellId::from(root_cell.repr_hash()), cells_written = tracing::field::Empty)
+    ))]
+    pub fn save_as_dynamic_boc_registered(
+        self: &Arc<Self>,
+        root_cell: Cell,
+        registration: Arc<WriterRegistration>,
+    ) -> Result<usize> {
+        let diff_writer = self.diff_factory.construct_registered(registration);
 
-        let written_count = self.save_tree_of_cells_recursive(
+        let written_count = self.save_tree_of_cells(
             root_cell.clone(),
             Arc::clone(&self.db),
             &diff_writer)?;
 
         diff_writer.apply()?;
 
+        #[cfg(feature = "tracing")]
+        tracing::Span::current().record("cells_written", &written_count);
+
         Ok(written_count)
     }
 
-    /// Gets root cell from key-value storage
+    /// Converts tree of cells into DynamicBoc, committing the cells together with other trees
+    /// saved within the coalescing window (see `set_diff_coalescing`)
+    #[cfg_attr(feature = "tracing", tracing::instrument(
+        target = "storage",
+        skip(self, root_cell),
+        fields(root = %CellId::from(root_cell.repr_hash()), cells_written = tracing::field::Empty)
+    ))]
+    pub async fn save_as_dynamic_boc_coalesced(self: &Arc<Self>, root_cell: Cell) -> Result<usize> {
+        let diff_writer = self.diff_factory.construct();
+
+        let written_count = self.save_tree_of_cells(
+            root_cell,
+            Arc::clone(&self.db),
+            &diff_writer)?;
+
+        diff_writer.apply_coalesced().await?;
+
+        #[cfg(feature = "tracing")]
+        tracing::Span::current().record("cells_written", &written_count);
+
+        Ok(written_count)
+    }
+
+    /// Fence between mark-and-sweep GC and the trees being saved
+    pub fn gc_fence(&self) -> &Arc<CellGcFence> {
+        self.diff_factory.gc_fence()
+    }
+
+    /// Enables (or disables, if None) merging of the diffs saved with `save_as_dynamic_boc_coalesced`
+    /// into common commits, which reduces write amplification during fast sync
+    pub fn set_diff_coalescing(&self, config: Option<DiffCoalescingConfig>) {
+        self.diff_factory.set_coalescing(config)
+    }
+
+    /// Enables (or disables, if None) committing of the saved trees by bounded sub-batches, so saving
+    /// of a huge tree doesn't stall other writes. The cells are committed after their references,
+    /// so the tree becomes visible atomically once its root is registered by the caller.
+    pub fn set_sub_batch_limits(&self, limits: Option<SubBatchLimits>) {
+        self.diff_factory.set_sub_batch_limits(limits)
+    }
+
+    /// Enables (or disables, if None) background prefetching of the children of the loaded cells,
+    /// which speeds up sequential scans of the states. The previous prefetcher is stopped.
+    pub fn set_prefetch(self: &Arc<Self>, config: Option<PrefetchConfig>) -> Result<()> {
+        let prefetcher = match config {
+            Some(config) => Some(CellPrefetcher::start(Arc::downgrade(self), config)?),
+            None => None,
+        };
+        *self.prefetcher.write() = prefetcher;
+
+        Ok(())
+    }
+
+    /// Applies the cells section of the storage configuration
+    pub fn apply_config(self: &Arc<Self>, config: &CellsConfig) -> Result<()> {
+        self.set_lazy_cell_parsing(config.lazy_parsing);
+        self.set_load_verification(config.load_verification);
+        self.set_sub_batch_limits(config.sub_batches.clone());
+        self.set_diff_coalescing(config.diff_coalescing.clone());
+        self.set_prefetch(config.prefetch.clone())
+    }
+
+    /// Counters of the prefetcher (None if prefetching is disabled)
+    pub fn prefetch_stats(&self) -> Option<PrefetchStats> {
+        self.prefetcher.read().as_ref().map(|prefetcher| prefetcher.stats())
+    }
+
+    pub fn load_verification(&self) -> LoadVerification {
+        match self.verification_rate.load(Ordering::Relaxed) {
+            0 => LoadVerification::Disabled,
+            1 => LoadVerification::Strict,
+            rate => LoadVerification::Sampled(rate),
+        }
+    }
+
+    /// Sets verification of the loaded cells; verification recalculates the hash of the cell, so it
+    /// requires reading of the cell's references from the database as well
+    pub fn set_load_verification(&self, verification: LoadVerification) {
+        let rate = match verification {
+            LoadVerification::Disabled => 0,
+            LoadVerification::Strict => 1,
+            LoadVerification::Sampled(rate) => std::cmp::max(rate, 1),
+        };
+        self.verification_rate.store(rate, Ordering::Relaxed)
+    }
+
+    pub fn lazy_cell_parsing(&self) -> bool {
+        self.lazy_cell_parsing.load(Ordering::Relaxed)
+    }
+
+    /// Enables lazy parsing of the loaded cells: the record is parsed on the first access to the
+    /// cell's data or references rather than on load, which saves CPU when only hashes are needed.
+    /// Note that the corrupted record is detected only on the access then: the accessors unable
+    /// to return an error log it and see the empty cell. Verified loads are always parsed at once.
+    pub fn set_lazy_cell_parsing(&self, lazy: bool) {
+        self.lazy_cell_parsing.store(lazy, Ordering::Relaxed)
+    }
+
+    /// Gets root cell from key-value storage; the record of the root is parsed even in the lazy
+    /// mode, so its corruption is reported here
     pub fn load_dynamic_boc(self: &Arc<Self>, root_cell_id: &CellId) -> Result<Cell> {
         let storage_cell = self.load_cell(root_cell_id)?;
+        storage_cell.try_parse()?;
 
         Ok(Cell::with_cell_impl_arc(storage_cell))
     }
 
+    /// Traverses the stored tree of cells collecting all the references to the cells missing in the
+    /// database, so a damaged tree is diagnosed in one pass rather than failing on the first of them
+    pub fn find_missing_references(&self, root_cell_id: &CellId) -> Result<ReadRepairReport> {
+        let root_value = match self.db.try_get(root_cell_id)? {
+            Some(value) => value.to_vec(),
+            None => fail!("Root cell {} is missing", root_cell_id),
+        };
+
+        let mut report = ReadRepairReport::default();
+        let mut visited = FnvHashSet::default();
+        visited.insert(root_cell_id.clone());
+        depth_first(Some((root_cell_id.clone(), root_value)), |(cell_id, value)| {
+            report.visited += 1;
+            let (_cell_data, references) = self.db.deserialize_cell(&value)
+                .map_err(|err| error!("Can't read cell {} of tree {}: {}", cell_id, root_cell_id, err))?;
+            let mut children = Vec::with_capacity(references.len());
+            for (index, reference) in references.iter().enumerate() {
+                let ref_id = CellId::from(reference.hash());
+                if visited.contains(&ref_id) {
+                    continue;
+                }
+                match self.db.try_get(&ref_id)? {
+                    Some(ref_value) => {
+                        visited.insert(ref_id.clone());
+                        children.push((ref_id, ref_value.to_vec()));
+                    }
+                    None => report.missing.push(MissingReference {
+                        parent: cell_id.clone(),
+                        index,
+                        hash: reference.hash(),
+                    }),
+                }
+            }
+            Ok(children)
+        })?;
+
+        if !report.missing.is_empty() {
+            log::warn!(
+                target: "storage",
+                "Tree {} has {} references to missing cells",
+                root_cell_id,
+                report.missing.len()
+            );
+        }
+        report.unresolved = report.missing.clone();
+
+        Ok(report)
+    }
+
+    /// Finds the references of the stored tree to the missing cells and repairs them with the cells
+    /// supplied by the resolver: the supplied cells are written together with their subtrees, then
+    /// the parents which got all their missing children are re-persisted (and so restamped for GC)
+    pub fn repair_missing_references(
+        self: &Arc<Self>,
+        root_cell_id: &CellId,
+        resolver: &dyn MissingCellResolver,
+    ) -> Result<ReadRepairReport> {
+        let mut report = self.find_missing_references(root_cell_id)?;
+        if report.missing.is_empty() {
+            return Ok(report);
+        }
+
+        let mut supplied = FnvHashSet::default();
+        let diff_writer = self.diff_factory.construct();
+        for missing in report.missing.iter() {
+            if supplied.contains(&missing.hash) {
+                continue;
+            }
+            match resolver.resolve(&missing.hash)? {
+                Some(cell) if cell.repr_hash() == missing.hash => {
+                    self.save_tree_of_cells(cell, Arc::clone(&self.db), &diff_writer)?;
+                    supplied.insert(missing.hash.clone());
+                    report.supplied.push(CellId::from(missing.hash.clone()));
+                }
+                Some(cell) => log::error!(
+                    target: "storage",
+                    "Resolver supplied cell {:x} instead of missing cell {:x}",
+                    cell.repr_hash(),
+                    missing.hash
+                ),
+                None => (),
+            }
+        }
+        // Children are committed first, as re-persisting of the parents reads them
+        diff_writer.apply()?;
+
+        report.unresolved = report.missing.iter()
+            .filter(|missing| !supplied.contains(&missing.hash))
+            .cloned()
+            .collect();
+
+        let diff_writer = self.diff_factory.construct();
+        for missing in report.missing.iter() {
+            let parent = &missing.parent;
+            if report.repersisted.contains(parent)
+                || report.unresolved.iter().any(|unresolved| &unresolved.parent == parent)
+            {
+                continue;
+            }
+            let cell = Cell::with_cell_impl_arc(self.load_cell(parent)?);
+            diff_writer.add_cell(parent.clone(), cell)?;
+            report.repersisted.push(parent.clone());
+        }
+        diff_writer.apply()?;
+
+        log::info!(
+            target: "storage",
+            "Tree {} repaired: {} cells supplied, {} parents re-persisted, {} references unresolved",
+            root_cell_id,
+            report.supplied.len(),
+            report.repersisted.len(),
+            report.unresolved.len()
+        );
+
+        Ok(report)
+    }
+
+    /// Returns the serialized cell (see `CellDb::get_raw`) without building the cell, so neither the
+    /// loaded cells map nor the access statistics are touched. Intended for pass-through consumers
+    /// like state hashing and serving cells to peers.
+    pub fn cell_bytes(&self, cell_id: &CellId) -> Result<Vec<u8>> {
+        self.db.get_raw(cell_id)
+    }
+
+    /// Returns the serialized cells (see `cell_bytes`); None for the missing cells
+    pub fn cell_bytes_multi(&self, cell_ids: &[CellId]) -> Result<Vec<Option<Vec<u8>>>> {
+        self.db.get_raw_multi(cell_ids)
+    }
+
     pub(crate) fn diff_factory(&self) -> &DynamicBocDiffFactory {
         &self.diff_factory
     }
 
+    #[cfg_attr(feature = "tracing", tracing::instrument(
+        target = "storage",
+        level = "trace",
+        skip(self, cell_id),
+        fields(cell_id = %cell_id)
+    ))]
     pub(crate) fn load_cell(self: &Arc<Self>, cell_id: &CellId) -> Result<Arc<StorageCell>> {
-        if let Some(cell) = self.cells.read()
-            .expect("Poisoned RwLock")
-            .get(&cell_id)
+        if let Some(ref access_stats) = self.access_stats {
+            access_stats.on_cell_accessed(cell_id)?;
+        }
+
+        let cached = self.cells.read().get(&cell_id)
+            .and_then(|entry| entry.cell());
+        if let Some(cell) = cached {
+            if let Some(ref prefetcher) = *self.prefetcher.read() {
+                prefetcher.on_cell_hit(cell_id, &cell);
+            }
+            return Ok(cell);
+        }
+
         {
-            if let Some(ref cell) = Weak::upgrade(&cell) {
-                return Ok(Arc::clone(cell));
+            let mut cells = self.cells.write();
+            let entry = cells.entry(cell_id.clone()).or_default();
+            if let Some(cell) = entry.cell() {
+                return Ok(cell);
             }
-            // Even if the cell is disposed, we will load and store it later,
-            // so we don't need to remove garbage here.
+            entry.pins += 1;
         }
-        let storage_cell = Arc::new(
-            CellDb::get_cell(&*self.db, &cell_id, Arc::clone(self))?
-        );
-        self.cells.write()
-            .expect("Poisoned RwLock")
-            .insert(cell_id.clone(), Arc::downgrade(&storage_cell));
 
-        Ok(storage_cell)
+        let db_loads = self.db_loads.fetch_add(1, Ordering::Relaxed);
+        let verification_rate = self.verification_rate.load(Ordering::Relaxed) as u64;
+        let result = if verification_rate != 0 && db_loads % verification_rate == 0 {
+            CellDb::get_verified_cell(&*self.db, &cell_id, Arc::clone(self))
+        } else if self.lazy_cell_parsing() {
+            CellDb::get_lazy_cell(&*self.db, &cell_id, Arc::clone(self))
+        } else {
+            CellDb::get_cell(&*self.db, &cell_id, Arc::clone(self))
+        };
+
+        let mut cells = self.cells.write();
+        let entry = cells.entry(cell_id.clone()).or_default();
+        entry.pins -= 1;
+        match result {
+            Ok(storage_cell) => {
+                // Another thread might have resurrected the cell concurrently
+                if let Some(cell) = entry.cell() {
+                    return Ok(cell);
+                }
+                let storage_cell = Arc::new(storage_cell);
+                entry.cell = Arc::downgrade(&storage_cell);
+                drop(cells);
+                if let Some(ref prefetcher) = *self.prefetcher.read() {
+                    prefetcher.on_cell_loaded(&storage_cell);
+                }
+
+                Ok(storage_cell)
+            }
+            Err(err) => {
+                if entry.pins == 0 && entry.cell.strong_count() == 0 {
+                    cells.remove(cell_id);
+                }
+
+                Err(err)
+            }
+        }
     }
 
-    fn save_tree_of_cells_recursive(
+    /// Reads the cells missing in the loaded cells map and puts them into the map under one lock.
+    /// Returns the cells read; the map holds weak references only, so the caller keeps them alive.
+    pub(crate) fn prefetch_cells(self: &Arc<Self>, cell_ids: Vec<CellId>) -> Result<Vec<Arc<StorageCell>>> {
+        let cell_ids: Vec<CellId> = {
+            let cells = self.cells.read();
+            cell_ids.into_iter()
+                .filter(|cell_id| match cells.get(cell_id) {
+                    Some(entry) => entry.pins == 0 && entry.cell().is_none(),
+                    None => true,
+                })
+                .collect()
+        };
+        if cell_ids.is_empty() {
+            return Ok(Vec::new());
+        }
+
+        let values = self.db.try_get_multi(&cell_ids)?;
+        let mut loaded = Vec::with_capacity(cell_ids.len());
+        for (cell_id, value) in cell_ids.iter().zip(values) {
+            // Cells deleted meanwhile are just skipped: the scan reports them itself
+            if let Some(value) = value {
+                let (cell_data, references) = self.db.deserialize_cell(value.as_ref())?;
+                let loader = Arc::clone(self) as Arc<dyn CellLoader>;
+                loaded.push((cell_id, StorageCell::with_params(cell_data, references, loader)));
+            }
+        }
+
+        let mut prefetched = Vec::with_capacity(loaded.len());
+        let mut superseded = Vec::new();
+        {
+            let mut cells = self.cells.write();
+            for (cell_id, storage_cell) in loaded {
+                let entry = cells.entry(cell_id.clone()).or_default();
+                // Cells loaded concurrently on demand are preferred
+                if entry.cell().is_some() {
+                    superseded.push(storage_cell);
+                    continue;
+                }
+                let storage_cell = Arc::new(storage_cell);
+                entry.cell = Arc::downgrade(&storage_cell);
+                prefetched.push(storage_cell);
+            }
+        }
+        // Dropped cells lock the map, so the superseded ones are dropped out of the lock
+        drop(superseded);
+
+        Ok(prefetched)
+    }
+
+    fn save_tree_of_cells(
         self: &Arc<Self>,
-        cell: Cell,
+        root_cell: Cell,
         cell_db: Arc<CellDb>,
         diff_writer: &DynamicBocDiffWriter
     ) -> Result<usize> {
-        let cell_id = CellId::new(cell.repr_hash());
-        if cell_db.contains(&cell_id)? {
-            return Ok(0);
-        }
+        let mut count = 0;
+        // Cells shared within the tree are checked once, as they aren't in the database until the
+        // diff is applied. The cells are added in post-order, so the diff committed in sub-batches
+        // never stores a cell before its new references.
+        let mut visited = FnvHashSet::default();
+        depth_first_post_order(
+            Some(root_cell),
+            |cell| {
+                let cell_id = CellId::new(cell.repr_hash());
+                if !visited.insert(cell_id.clone()) {
+                    return Ok(None);
+                }
+                match self.gc_horizon {
+                    // Existing cells stamped with older generations are re-referenced by the tree,
+                    // so they are restamped (together with their subtrees) in order to survive GC.
+                    // Cells reached are fenced as well: GC restamps them until the root is
+                    // registered, so the writer may span several GC runs (e.g. by sub-batches).
+                    Some(ref gc_horizon) => {
+                        if let Some(value) = diff_writer.check_cell(&cell_id, || cell_db.try_get(&cell_id))? {
+                            if split_cell_header(value.as_ref()).0 >= Some(gc_horizon.generation()) {
+                                return Ok(None);
+                            }
+                        }
+                    }
+                    // Existing cells are fenced from the running sweep, as the tree relies on them
+                    None => if diff_writer.check_cell(&cell_id, || cell_db.contains(&cell_id))? {
+                        return Ok(None);
+                    }
+                }
 
-        diff_writer.add_cell(cell_id, cell.clone());
-
-        let mut count = 1;
-        for i in 0..cell.references_count() {
-            count += self.save_tree_of_cells_recursive(
-                cell.reference(i)?,
-                Arc::clone(&cell_db),
-                diff_writer
-            )?;
-        }
+                (0..cell.references_count())
+                    .map(|i| cell.reference(i))
+                    .collect::<Result<Vec<_>>>()
+                    .map(Some)
+            },
+            |cell| {
+                diff_writer.add_cell(CellId::new(cell.repr_hash()), cell)?;
+                count += 1;
+                Ok(())
+            },
+        )?;
 
         Ok(count)
     }
 }
 
+impl CellLoader for DynamicBocDb {
+    fn load_cell(self: Arc<Self>, cell_id: &CellId) -> Result<Arc<StorageCell>> {
+        DynamicBocDb::load_cell(&self, cell_id)
+    }
+
+    fn on_cell_dropped(&self, cell_id: &CellId) {
+        let mut cells = self.cells.write();
+        let remove = cells.get(cell_id)
+            .map(|entry| entry.pins == 0 && entry.cell.strong_count() == 0)
+            .unwrap_or(false);
+        if remove {
+            cells.remove(cell_id);
+        }
+    }
+}
+
 impl Deref for DynamicBocDb {
     type Target = Arc<CellDb>;
 
@@ -1,53 +1,210 @@
-use std::sync::{Arc, RwLock};
+use std::sync::{Arc, Mutex, RwLock};
 
 use fnv::FnvHashMap;
 
 use ton_types::{Cell, Result};
 
 use crate::cell_db::CellDb;
+use crate::cell_gc_horizon::{CellGcHorizon, stamp_cell};
+use crate::db::traits::KvcTransaction;
+use crate::dynamic_boc_diff_writer::SubBatchLimits;
+use crate::error::StorageError;
 use crate::types::CellId;
 
+#[derive(Debug)]
+struct PendingCell {
+    payload: Arc<Vec<u8>>,
+    writers: usize,
+}
+
+/// Registry of cells added by all the diffs not yet applied (or dropped). Used to detect
+/// overlapping writes of different payloads for the same cell id.
+#[derive(Debug, Default)]
+pub(super) struct PendingCells {
+    cells: Mutex<FnvHashMap<CellId, PendingCell>>,
+}
+
+impl PendingCells {
+    /// Registers serialized cell. Returns the payload to be written: if the cell is already added by
+    /// another pending diff with identical payload, the payloads are merged.
+    fn register(&self, cell_id: &CellId, payload: Vec<u8>) -> Result<Arc<Vec<u8>>> {
+        let mut guard = self.cells.lock()
+            .expect("Poisoned Mutex");
+        if let Some(pending) = guard.get_mut(cell_id) {
+            if *pending.payload != payload {
+                log::error!(target: "storage", "Conflicting writes of cell {} in pending diffs", cell_id);
+                return Err(StorageError::DiffConflict(cell_id.to_string()).into());
+            }
+            log::trace!(target: "storage", "Identical writes of cell {} in pending diffs merged", cell_id);
+            pending.writers += 1;
+            return Ok(Arc::clone(&pending.payload));
+        }
+
+        let payload = Arc::new(payload);
+        guard.insert(cell_id.clone(), PendingCell { payload: Arc::clone(&payload), writers: 1 });
+
+        Ok(payload)
+    }
+
+    fn unregister(&self, cell_id: &CellId) {
+        let mut guard = self.cells.lock()
+            .expect("Poisoned Mutex");
+        if let Some(pending) = guard.get_mut(cell_id) {
+            pending.writers -= 1;
+            if pending.writers == 0 {
+                guard.remove(cell_id);
+            }
+        }
+    }
+}
+
+/// Operations of the diff: added cells (None payload means deletion) and the order the cells were
+/// added in
+#[derive(Debug, Default)]
+struct DiffCells {
+    cells: FnvHashMap<CellId, Option<Arc<Vec<u8>>>>,
+    added: Vec<CellId>,
+}
+
 #[derive(Debug)]
 pub(super) struct DynamicBocDiff {
     db: Arc<CellDb>,
-    diff: RwLock<FnvHashMap<CellId, Option<Cell>>>,
+    pending: Arc<PendingCells>,
+    gc_horizon: Option<Arc<CellGcHorizon>>,
+    diff: RwLock<DiffCells>,
 }
 
 impl DynamicBocDiff {
-    pub fn new(db: Arc<CellDb>) -> Self {
+    pub fn new(db: Arc<CellDb>, pending: Arc<PendingCells>, gc_horizon: Option<Arc<CellGcHorizon>>) -> Self {
         Self {
             db,
-            diff: RwLock::new(FnvHashMap::default()),
+            pending,
+            gc_horizon,
+            diff: RwLock::new(DiffCells::default()),
         }
     }
 
-    pub fn add_cell(&self, cell_id: CellId, cell: Cell) {
-        self.diff.write()
-            .expect("Poisoned RwLock")
-            .insert(cell_id, Some(cell));
+    /// Adds cell to the diff. Fails with StorageError::DiffConflict if the cell with the same id but
+    /// different payload is added by this or another pending diff.
+    pub fn add_cell(&self, cell_id: CellId, cell: Cell) -> Result<()> {
+        let payload = CellDb::serialize_cell(cell)?;
+        let mut write_guard = self.diff.write()
+            .expect("Poisoned RwLock");
+        if let Some(Some(existing)) = write_guard.cells.get(&cell_id) {
+            if **existing != payload {
+                return Err(StorageError::DiffConflict(cell_id.to_string()).into());
+            }
+            return Ok(());
+        }
+
+        let payload = self.pending.register(&cell_id, payload)?;
+        write_guard.added.push(cell_id.clone());
+        write_guard.cells.insert(cell_id, Some(payload));
+
+        Ok(())
     }
 
     pub fn delete_cell(&self, cell_id: &CellId) {
         let mut write_guard = self.diff.write()
             .expect("Poisoned RwLock");
-        if !write_guard.contains_key(cell_id) {
-            write_guard.insert(cell_id.clone(), None);
+        if !write_guard.cells.contains_key(cell_id) {
+            write_guard.cells.insert(cell_id.clone(), None);
+        }
+    }
+
+    /// Total size of the cells added
+    pub fn payload_size(&self) -> usize {
+        self.diff.read()
+            .expect("Poisoned RwLock")
+            .cells
+            .values()
+            .map(|payload_opt| payload_opt.as_ref().map(|payload| payload.len()).unwrap_or(0))
+            .sum()
+    }
+
+    /// Adds operations of the diff into the transaction. If GC horizon is set, the cells are stamped
+    /// with the current generation.
+    pub fn write_to(&self, transaction: &dyn KvcTransaction<CellId>) -> Result<()> {
+        let diff = self.diff.read()
+            .expect("Poisoned RwLock");
+        self.write_cells_to(transaction, diff.cells.iter())
+    }
+
+    fn write_cells_to<'a>(
+        &self,
+        transaction: &dyn KvcTransaction<CellId>,
+        cells: impl IntoIterator<Item = (&'a CellId, &'a Option<Arc<Vec<u8>>>)>,
+    ) -> Result<()> {
+        let generation = self.gc_horizon.as_ref().map(|gc_horizon| gc_horizon.generation());
+        for (cell_id, payload_opt) in cells {
+            match (payload_opt, generation) {
+                (Some(payload), Some(generation)) => transaction.put(cell_id, &stamp_cell(generation, payload)),
+                (Some(payload), None) => transaction.put(cell_id, payload),
+                (None, _) => transaction.delete(cell_id),
+            }
         }
+
+        Ok(())
     }
 
     pub fn apply(self) -> Result<()> {
         let transaction = self.db.begin_transaction()?;
+        self.write_to(&*transaction)?;
+        transaction.commit()
+    }
 
-        for (cell_id, cell_opt) in self.diff.write()
+    /// Applies the diff by several transactions, each bounded by the limits, so a huge diff doesn't
+    /// stall other writes. Cells are committed in the order they were added (the references must be
+    /// added before the cells referring them), so every committed cell has its subtree stored;
+    /// deletions are committed by the last transaction. Returns count of the transactions.
+    pub fn apply_in_sub_batches(self, limits: &SubBatchLimits) -> Result<usize> {
+        let diff = self.diff.read()
+            .expect("Poisoned RwLock");
+        let mut sub_batch = Vec::new();
+        let mut sub_batch_bytes = 0;
+        let mut committed = 0;
+        for cell_id in diff.added.iter() {
+            if let Some((cell_id, payload_opt)) = diff.cells.get_key_value(cell_id) {
+                sub_batch_bytes += payload_opt.as_ref().map(|payload| payload.len()).unwrap_or(0);
+                sub_batch.push((cell_id, payload_opt));
+            }
+            if sub_batch_bytes >= limits.max_bytes || sub_batch.len() >= limits.max_cells {
+                self.commit_sub_batch(sub_batch.drain(..))?;
+                sub_batch_bytes = 0;
+                committed += 1;
+            }
+        }
+        sub_batch.extend(diff.cells.iter().filter(|(_cell_id, payload_opt)| payload_opt.is_none()));
+        if !sub_batch.is_empty() || committed == 0 {
+            self.commit_sub_batch(sub_batch.drain(..))?;
+            committed += 1;
+        }
+
+        log::debug!(target: "storage", "Diff of {} cells committed by {} transactions", diff.added.len(), committed);
+
+        Ok(committed)
+    }
+
+    fn commit_sub_batch<'a>(
+        &self,
+        cells: impl IntoIterator<Item = (&'a CellId, &'a Option<Arc<Vec<u8>>>)>,
+    ) -> Result<()> {
+        let transaction = self.db.begin_transaction()?;
+        self.write_cells_to(&*transaction, cells)?;
+        transaction.commit()
+    }
+}
+
+impl Drop for DynamicBocDiff {
+    fn drop(&mut self) {
+        for (cell_id, payload_opt) in self.diff.write()
             .expect("Poisoned RwLock")
+            .cells
             .drain()
         {
-            match cell_opt {
-                Some(cell) => CellDb::put_cell(&*transaction, &cell_id, cell)?,
-                None => transaction.delete(&cell_id),
+            if payload_opt.is_some() {
+                self.pending.unregister(&cell_id);
             }
         }
-
-        transaction.commit()
     }
 }
@@ -1,26 +1,215 @@
-use std::sync::{Arc, RwLock, Weak};
+use std::sync::{Arc, Mutex, RwLock, Weak};
+use std::time::Duration;
 
-use ton_types::{Cell, Result};
+use futures::future::{self, Either};
+use serde_derive::{Deserialize, Serialize};
+use tokio::sync::oneshot;
+use tokio::time::Instant;
+use ton_types::{Cell, error, Result};
 
 use crate::cell_db::CellDb;
-use crate::dynamic_boc_diff::DynamicBocDiff;
+use crate::cell_gc_fence::{CellGcFence, WriterRegistration};
+use crate::cell_gc_horizon::CellGcHorizon;
+use crate::dynamic_boc_diff::{DynamicBocDiff, PendingCells};
 use crate::types::CellId;
 
+/// Configuration of diffs commit coalescing
+#[derive(Debug, Clone, Deserialize, Serialize)]
+#[serde(default)]
+pub struct DiffCoalescingConfig {
+    /// Maximal time the first diff of the batch waits for other diffs to be merged with (in
+    /// milliseconds in the configuration)
+    #[serde(with = "crate::storage_config::duration_ms")]
+    pub window: Duration,
+    /// Batch is committed immediately once size of the cells added reaches the budget
+    pub max_batch_bytes: usize,
+}
+
+impl Default for DiffCoalescingConfig {
+    fn default() -> Self {
+        Self {
+            window: Duration::from_millis(50),
+            max_batch_bytes: 16 << 20,
+        }
+    }
+}
+
+/// Limits of the sub-batches huge diffs are committed by, so other writes aren't stalled by a single
+/// transaction
+#[derive(Debug, Clone, Deserialize, Serialize)]
+#[serde(default)]
+pub struct SubBatchLimits {
+    /// Sub-batch is committed once size of its cells reaches the limit
+    pub max_bytes: usize,
+    /// Sub-batch is committed once count of its cells reaches the limit
+    pub max_cells: usize,
+}
+
+impl Default for SubBatchLimits {
+    fn default() -> Self {
+        Self {
+            max_bytes: 8 << 20,
+            max_cells: 100_000,
+        }
+    }
+}
+
+#[derive(Default)]
+struct DiffBatch {
+    id: u64,
+    diffs: Vec<DynamicBocDiff>,
+    bytes: usize,
+    waiters: Vec<oneshot::Sender<std::result::Result<(), String>>>,
+    // Time the batch is committed at, set by the first diff
+    deadline: Option<Instant>,
+}
+
+/// Merges diffs applied within the coalescing window into single transaction commits
+pub(super) struct DiffBatcher {
+    db: Arc<CellDb>,
+    config: RwLock<Option<DiffCoalescingConfig>>,
+    batch: Mutex<DiffBatch>,
+}
+
+impl std::fmt::Debug for DiffBatcher {
+    fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+        f.debug_struct("DiffBatcher")
+            .field("config", &self.config)
+            .finish()
+    }
+}
+
+impl DiffBatcher {
+    fn new(db: Arc<CellDb>) -> Self {
+        Self {
+            db,
+            config: RwLock::new(None),
+            batch: Mutex::new(DiffBatch::default()),
+        }
+    }
+
+    /// Applies the diff as a part of the batch. Resolves when the batch is committed. Every diff of
+    /// the batch waits for the window to pass, and the first one woken commits the batch, so the
+    /// batch is committed even if the future of the diff which opened it is dropped.
+    async fn submit(&self, diff: DynamicBocDiff) -> Result<()> {
+        let config = match self.config.read().expect("Poisoned RwLock").clone() {
+            Some(config) => config,
+            None => return diff.apply(),
+        };
+
+        let (sender, mut receiver) = oneshot::channel();
+        let (batch_id, deadline, full) = {
+            let mut batch = self.batch.lock().expect("Poisoned Mutex");
+            batch.bytes += diff.payload_size();
+            batch.diffs.push(diff);
+            batch.waiters.push(sender);
+            let deadline = *batch.deadline.get_or_insert_with(|| Instant::now() + config.window);
+            (batch.id, deadline, batch.bytes >= config.max_batch_bytes)
+        };
+
+        if full {
+            self.flush(None);
+        } else {
+            let delay = Box::pin(tokio::time::delay_until(deadline));
+            if let Either::Left((result, _delay)) = future::select(&mut receiver, delay).await {
+                return Self::commit_result(result);
+            }
+            self.flush(Some(batch_id));
+        }
+
+        Self::commit_result(receiver.await)
+    }
+
+    fn commit_result(
+        result: std::result::Result<std::result::Result<(), String>, oneshot::error::RecvError>,
+    ) -> Result<()> {
+        result
+            .map_err(|_| error!("Diff batch was dropped without commit"))?
+            .map_err(|err| error!("Diff batch commit failed: {}", err))
+    }
+
+    /// Commits the current batch; if `batch_id` is given, only the batch with this id is committed
+    fn flush(&self, batch_id: Option<u64>) {
+        let batch = {
+            let mut batch = self.batch.lock().expect("Poisoned Mutex");
+            if batch_id.map(|id| id != batch.id).unwrap_or(false) || batch.diffs.is_empty() {
+                return;
+            }
+            let next_id = batch.id + 1;
+            std::mem::replace(&mut *batch, DiffBatch { id: next_id, ..Default::default() })
+        };
+
+        log::debug!(
+            target: "storage",
+            "Committing {} coalesced diffs ({} bytes)",
+            batch.diffs.len(),
+            batch.bytes
+        );
+        let result = self.commit(&batch.diffs)
+            .map_err(|err| err.to_string());
+        drop(batch.diffs);
+        for waiter in batch.waiters {
+            let _ = waiter.send(result.clone());
+        }
+    }
+
+    fn commit(&self, diffs: &[DynamicBocDiff]) -> Result<()> {
+        let transaction = self.db.begin_transaction()?;
+        for diff in diffs {
+            diff.write_to(&*transaction)?;
+        }
+        transaction.commit()
+    }
+}
+
 #[derive(Debug)]
 pub(super) struct DynamicBocDiffFactory {
     db: Arc<CellDb>,
+    pending: Arc<PendingCells>,
+    gc_horizon: Option<Arc<CellGcHorizon>>,
+    sub_batch_limits: RwLock<Option<SubBatchLimits>>,
+    gc_fence: Arc<CellGcFence>,
+    batcher: Arc<DiffBatcher>,
     diff: RwLock<Weak<DynamicBocDiff>>,
 }
 
 impl DynamicBocDiffFactory {
-    pub fn new(db: Arc<CellDb>) -> Self {
+    pub fn new(db: Arc<CellDb>, gc_horizon: Option<Arc<CellGcHorizon>>) -> Self {
         Self {
+            batcher: Arc::new(DiffBatcher::new(Arc::clone(&db))),
             db,
+            pending: Arc::new(PendingCells::default()),
+            gc_horizon,
+            sub_batch_limits: RwLock::new(None),
+            gc_fence: Arc::new(CellGcFence::new()),
             diff: RwLock::new(Weak::new()),
         }
     }
 
+    /// Enables (or disables, if None) coalescing of the diffs applied with `apply_coalesced`
+    pub fn set_coalescing(&self, config: Option<DiffCoalescingConfig>) {
+        *self.batcher.config.write().expect("Poisoned RwLock") = config;
+        self.batcher.flush(None);
+    }
+
+    /// Enables (or disables, if None) committing of the diffs constructed afterwards by sub-batches
+    pub fn set_sub_batch_limits(&self, limits: Option<SubBatchLimits>) {
+        *self.sub_batch_limits.write().expect("Poisoned RwLock") = limits;
+    }
+
+    /// Fence between GC sweeps and the writers constructed by the factory
+    pub fn gc_fence(&self) -> &Arc<CellGcFence> {
+        &self.gc_fence
+    }
+
+    /// Constructs the writer registered in the GC fence with the current generation
     pub fn construct(&self) -> DynamicBocDiffWriter {
+        self.construct_registered(Arc::new(self.gc_fence.register()))
+    }
+
+    /// Constructs the writer under given registration, which the caller may keep after the diff is
+    /// applied (e.g. until the root of the written tree is registered)
+    pub fn construct_registered(&self, registration: Arc<WriterRegistration>) -> DynamicBocDiffWriter {
         // TODO: Temporary disabled behavior because of issues with saving under high load
         DynamicBocDiffWriter::new({
             // let mut guard = self.diff.write()
@@ -28,25 +217,48 @@ impl DynamicBocDiffFactory {
             // match Weak::upgrade(&guard) {
                 // Some(diff) => diff,
                 // None => {
-                    let diff = Arc::new(DynamicBocDiff::new(Arc::clone(&self.db)));
+                    let diff = Arc::new(DynamicBocDiff::new(
+                        Arc::clone(&self.db),
+                        Arc::clone(&self.pending),
+                        self.gc_horizon.clone(),
+                    ));
                     // *guard = Arc::downgrade(&diff);
                     diff
                 // }
             // }
-        })
+        }, Arc::clone(&self.batcher), self.sub_batch_limits.read().expect("Poisoned RwLock").clone(), registration)
     }
 }
 
 pub struct DynamicBocDiffWriter {
     diff: Arc<DynamicBocDiff>,
+    batcher: Arc<DiffBatcher>,
+    sub_batch_limits: Option<SubBatchLimits>,
+    registration: Arc<WriterRegistration>,
 }
 
 impl DynamicBocDiffWriter {
-    fn new(diff: Arc<DynamicBocDiff>) -> Self {
-        Self { diff }
+    fn new(
+        diff: Arc<DynamicBocDiff>,
+        batcher: Arc<DiffBatcher>,
+        sub_batch_limits: Option<SubBatchLimits>,
+        registration: Arc<WriterRegistration>,
+    ) -> Self {
+        Self { diff, batcher, sub_batch_limits, registration }
+    }
+
+    /// Registration of the writer in the GC fence
+    pub fn registration(&self) -> &Arc<WriterRegistration> {
+        &self.registration
     }
 
-    pub fn add_cell(&self, cell_id: CellId, cell: Cell) {
+    /// Checks presence of the cell in the database, fencing it from the running GC sweep (see
+    /// `WriterRegistration::check_cell`)
+    pub fn check_cell<T>(&self, cell_id: &CellId, check: impl FnOnce() -> Result<T>) -> Result<T> {
+        self.registration.check_cell(cell_id, check)
+    }
+
+    pub fn add_cell(&self, cell_id: CellId, cell: Cell) -> Result<()> {
         self.diff.add_cell(cell_id, cell)
     }
 
@@ -54,13 +266,34 @@ impl DynamicBocDiffWriter {
         self.diff.delete_cell(cell_id)
     }
 
+    /// Applies the diff; if sub-batch limits are set, the diff is committed by several transactions
+    /// and the caller has to register the root of the saved tree only after this returns
     pub fn apply(self) -> Result<()> {
         if let Ok(diff) = Arc::try_unwrap(self.diff) {
-            return diff.apply();
+            return match self.sub_batch_limits {
+                Some(ref limits) => diff.apply_in_sub_batches(limits).map(|_| ()),
+                None => diff.apply(),
+            };
         }
 
         // TODO: Make function async and do not return until data is saved
 
         Ok(())
     }
+
+    /// Applies the diff merging it with other diffs applied within the coalescing window into one
+    /// commit (if coalescing is enabled). Resolves when the merged batch is committed. The diff
+    /// exceeding sub-batch limits (if set) is committed by sub-batches instead.
+    pub async fn apply_coalesced(self) -> Result<()> {
+        if let Ok(diff) = Arc::try_unwrap(self.diff) {
+            if let Some(ref limits) = self.sub_batch_limits {
+                if diff.payload_size() >= limits.max_bytes {
+                    return diff.apply_in_sub_batches(limits).map(|_| ());
+                }
+            }
+            return self.batcher.submit(diff).await;
+        }
+
+        Ok(())
+    }
 }
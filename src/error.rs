@@ -19,4 +19,28 @@ pub enum StorageError {
     /// Reading out of buffer range
     #[fail(display = "Reading out of buffer range")]
     OutOfRange,
+
+    /// Pending diffs disagree on the cell payload
+    #[fail(display = "Conflicting payloads of cell {} in pending diffs", 0)]
+    DiffConflict(String),
+
+    /// Entry already exists with different content
+    #[fail(display = "Content mismatch for existing entry {}", 0)]
+    ContentMismatch(String),
+
+    /// Stored data don't match their key
+    #[fail(display = "Corrupted data: {}", 0)]
+    CorruptedData(String),
+
+    /// Data are deleted by pruning
+    #[fail(display = "Data are pruned: {}", 0)]
+    Pruned(String),
+
+    /// Path of the collection belongs to another collection
+    #[fail(display = "Collection mismatch: {}", 0)]
+    CollectionMismatch(String),
+
+    /// Operation is cancelled with its cancellation token
+    #[fail(display = "Operation is cancelled")]
+    Cancelled,
 }
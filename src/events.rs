@@ -0,0 +1,83 @@
+use std::path::PathBuf;
+use std::sync::{Arc, RwLock};
+
+use ton_block::BlockIdExt;
+
+/// Storage lifecycle event
+#[derive(Debug, Clone)]
+pub enum StorageEvent {
+    /// Shard state is saved; `cells_written` is zero if the state was already stored
+    StateSaved {
+        block_id: BlockIdExt,
+        cells_written: usize,
+    },
+    /// Block data and proof are moved into the archive
+    BlockArchived {
+        block_id: BlockIdExt,
+    },
+    /// Shard states garbage collection is finished
+    GcFinished {
+        states_deleted: usize,
+        cells_deleted: usize,
+    },
+    /// Archive package is finalized, i.e. the trailer is written
+    PackageFinalized {
+        path: PathBuf,
+        entry_count: u32,
+        payload_size: u64,
+    },
+}
+
+/// Receiver of storage events. Called synchronously on the thread emitting the event,
+/// so implementations must not block.
+pub trait StorageEventListener: Send + Sync {
+    fn on_event(&self, event: &StorageEvent);
+}
+
+/// Dispatches storage events to the registered listeners
+#[derive(Default)]
+pub struct StorageEventBus {
+    listeners: RwLock<Vec<Arc<dyn StorageEventListener>>>,
+}
+
+impl StorageEventBus {
+    pub fn new() -> Self {
+        Self::default()
+    }
+
+    pub fn subscribe(&self, listener: Arc<dyn StorageEventListener>) {
+        self.listeners.write()
+            .expect("Poisoned RwLock")
+            .push(listener);
+    }
+
+    /// Removes the listener; returns true if it was registered
+    pub fn unsubscribe(&self, listener: &Arc<dyn StorageEventListener>) -> bool {
+        let mut listeners = self.listeners.write()
+            .expect("Poisoned RwLock");
+        let count = listeners.len();
+        listeners.retain(|registered| !Arc::ptr_eq(registered, listener));
+
+        listeners.len() != count
+    }
+
+    /// Calls the listeners registered at the moment. The lock is not held during the calls, so
+    /// listeners may (un)subscribe, and slow listeners don't stall the subscriptions.
+    pub fn emit(&self, event: StorageEvent) {
+        log::trace!(target: "storage", "Storage event: {:?}", event);
+        let listeners = self.listeners.read()
+            .expect("Poisoned RwLock")
+            .clone();
+        for listener in listeners.iter() {
+            listener.on_event(&event);
+        }
+    }
+}
+
+impl std::fmt::Debug for StorageEventBus {
+    fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+        f.debug_struct("StorageEventBus")
+            .field("listeners", &self.listeners.read().expect("Poisoned RwLock").len())
+            .finish()
+    }
+}
@@ -0,0 +1,108 @@
+use std::sync::atomic::Ordering;
+
+use ton_block::BlockIdExt;
+use ton_types::{fail, Result};
+
+use crate::block_handle_db::BlockHandleStorage;
+use crate::db::traits::{DbKey, KvcWriteable};
+use crate::db_impl_serializable;
+use crate::traits::Serializable;
+use crate::types::{BlockHandle, FLAG_KEY_BLOCK, KeyBlockKey};
+
+db_impl_serializable!(KeyBlockDb, KvcWriteable, KeyBlockKey, BlockIdExt);
+
+impl KeyBlockDb {
+    /// Adds masterchain key block id into the index
+    pub fn add_key_block(&self, block_id: &BlockIdExt) -> Result<()> {
+        if !block_id.shard().is_masterchain() {
+            fail!("Block {} is not a masterchain block", block_id)
+        }
+        self.put_value(&block_id.seq_no().into(), block_id)
+    }
+
+    /// Adds block of the handle into the index, if the handle belongs to key block. Returns true if added.
+    pub fn add_handle(&self, handle: &BlockHandle) -> Result<bool> {
+        if !handle.id().shard().is_masterchain() || !handle.is_key_block()? {
+            return Ok(false);
+        }
+        self.add_key_block(handle.id())?;
+
+        Ok(true)
+    }
+
+    /// Checks whether key block with given masterchain seq_no is stored in the index
+    pub fn is_key_block_stored(&self, seq_no: u32) -> Result<bool> {
+        self.contains(&seq_no.into())
+    }
+
+    /// Gets id of key block with given masterchain seq_no
+    pub fn get_key_block_id(&self, seq_no: u32) -> Result<Option<BlockIdExt>> {
+        self.try_get_value(&seq_no.into())
+    }
+
+    /// Checks whether any key block with masterchain seq_no in range [from_seq_no, to_seq_no) is stored in the index
+    pub fn has_key_block_in_range(&self, from_seq_no: u32, to_seq_no: u32) -> Result<bool> {
+        let mut found = false;
+        self.for_each_from(KeyBlockKey::new(from_seq_no).key(), &mut |key, _value| {
+            let mut seq_no = [0; 4];
+            seq_no.copy_from_slice(&key[..4]);
+            found = u32::from_be_bytes(seq_no) < to_seq_no;
+            Ok(false)
+        })?;
+
+        Ok(found)
+    }
+
+    /// Returns ids of key blocks with masterchain seq_no in range [from_seq_no, to_seq_no), in ascending order
+    pub fn key_block_ids_in_range(&self, from_seq_no: u32, to_seq_no: u32) -> Result<Vec<BlockIdExt>> {
+        let mut result = Vec::new();
+        self.for_each_from(KeyBlockKey::new(from_seq_no).key(), &mut |_key, value| {
+            let block_id = BlockIdExt::from_slice(value)?;
+            if block_id.seq_no() >= to_seq_no {
+                return Ok(false);
+            }
+            result.push(block_id);
+            Ok(true)
+        })?;
+
+        Ok(result)
+    }
+
+    /// Returns up to `limit` ids of key blocks following the given masterchain block, in ascending order
+    pub fn get_next_key_block_ids(&self, from_block_id: &BlockIdExt, limit: usize) -> Result<Vec<BlockIdExt>> {
+        if !from_block_id.shard().is_masterchain() {
+            fail!("Block {} is not a masterchain block", from_block_id)
+        }
+        if let Some(stored_id) = self.get_key_block_id(from_block_id.seq_no())? {
+            if &stored_id != from_block_id {
+                fail!("Key block {} mismatches the stored one {}", from_block_id, stored_id)
+            }
+        }
+
+        let mut result = Vec::new();
+        if limit == 0 {
+            return Ok(result);
+        }
+        let start = KeyBlockKey::new(from_block_id.seq_no().saturating_add(1));
+        self.for_each_from(start.key(), &mut |_key, value| {
+            result.push(BlockIdExt::from_slice(value)?);
+            Ok(result.len() < limit)
+        })?;
+
+        Ok(result)
+    }
+
+    /// Fills the index by key blocks of all the stored block handles. Returns count of key blocks added.
+    pub fn rebuild_from(&self, handles: &BlockHandleStorage) -> Result<usize> {
+        let mut count = 0;
+        handles.for_each_stored_handle(|id, block_meta| {
+            if id.shard().is_masterchain() && block_meta.flags().load(Ordering::Relaxed) & FLAG_KEY_BLOCK != 0 {
+                self.add_key_block(&id)?;
+                count += 1;
+            }
+            Ok(true)
+        })?;
+
+        Ok(count)
+    }
+}
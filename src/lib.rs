@@ -1,23 +1,55 @@
+pub mod apply_checkpoint_db;
 pub mod archives;
+pub mod background_tasks;
+pub mod blob_store;
 pub mod block_db;
 pub mod block_handle_db;
 pub mod block_index_db;
 pub mod block_info_db;
+pub mod bootstrap_snapshot;
+pub mod cancellation;
 pub mod catchain_persistent_db;
+pub mod chain_head_db;
+pub mod clock;
+pub mod cell_access_db;
 pub mod cell_db;
+pub mod cell_db_scrubber;
+pub mod cell_format;
+pub mod cell_gc_fence;
+pub mod cell_gc_horizon;
+pub mod cell_prefetcher;
+pub mod cell_traversal;
 pub mod db;
+pub mod diagnostics_db;
 pub mod dynamic_boc_db;
 pub mod dynamic_boc_diff;
 pub mod dynamic_boc_diff_writer;
 pub mod error;
+pub mod events;
+pub mod key_block_db;
 pub mod lt_db;
 pub mod lt_desc_db;
+pub mod metrics;
 pub mod node_state_db;
+pub mod node_storage;
+pub mod path_safety;
+pub mod pruning_coordinator;
+pub mod read_repair;
+pub mod retention_profile;
+pub mod schema;
+pub mod secondary_index;
 pub mod shardstate_db;
 pub mod shardstate_persistent_db;
+pub mod slow_op_recorder;
+pub mod state_pins_db;
 pub mod status_db;
+pub mod storage_config;
+pub mod storage_shrink;
 pub mod traits;
 pub mod types;
+pub mod warm_up;
+pub mod zerostate_db;
 
 mod macros;
+mod marked_cells;
 
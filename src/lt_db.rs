@@ -1,5 +1,6 @@
-use crate::db_impl_cbor;
+use crate::db_impl_serializable;
 use crate::db::traits::KvcWriteable;
+use crate::traits::Serializable;
 use crate::types::{LtDbEntry, LtDbKey};
 
-db_impl_cbor!(LtDb, KvcWriteable, LtDbKey, LtDbEntry);
+db_impl_serializable!(LtDb, KvcWriteable, LtDbKey, LtDbEntry);
@@ -1,5 +1,6 @@
-use crate::db_impl_cbor;
+use crate::db_impl_serializable;
 use crate::db::traits::KvcWriteable;
+use crate::traits::Serializable;
 use crate::types::{LtDesc, ShardIdentKey};
 
-db_impl_cbor!(LtDescDb, KvcWriteable, ShardIdentKey, LtDesc);
+db_impl_serializable!(LtDescDb, KvcWriteable, ShardIdentKey, LtDesc);
@@ -15,13 +15,63 @@ macro_rules! db_impl_base {
                 }
             }
 
+            /// Constructs new instance using in-memory key-value collection bounded by given limit;
+            /// records exceeding it are evicted, so the instance may only serve as a cache
+            #[allow(dead_code)]
+            pub fn in_memory_bounded(limit: $crate::db::memorydb::MemoryDbLimit) -> Self {
+                Self {
+                    db: Box::new($crate::db::memorydb::MemoryDb::with_limit(limit))
+                }
+            }
+
             /// Constructs new instance using RocksDB with given path
             #[allow(dead_code)]
             pub fn with_path<P: AsRef<std::path::Path>>(path: P) -> Self {
-                Self {
-                    db: Box::new($crate::db::rocksdb::RocksDb::with_path(path))
+                Self::with_rocksdb(path, $crate::db::rocksdb::RocksDb::with_path)
+            }
+
+            /// Constructs new instance using RocksDB with given path and bloom filters over the
+            /// first `prefix_len` bytes of the keys, speeding up `for_each_with_prefix`
+            #[allow(dead_code)]
+            pub fn with_path_and_prefix_bloom<P: AsRef<std::path::Path>>(path: P, prefix_len: usize) -> Self {
+                Self::with_rocksdb(path, |path| $crate::db::rocksdb::RocksDb::with_prefix_bloom(path, prefix_len))
+            }
+
+            #[allow(dead_code)]
+            fn with_rocksdb<P: AsRef<std::path::Path>>(
+                path: P,
+                open: impl FnOnce(P) -> $crate::db::rocksdb::RocksDb,
+            ) -> Self {
+                $crate::db::collection_metadata::check_collection_metadata(
+                    path.as_ref(),
+                    stringify!($type),
+                    $crate::db::collection_metadata::key_type_name::<$key_type>(),
+                ).expect("Cannot open DB");
+                let db = open(path);
+                if $crate::metrics::is_enabled() {
+                    let metrics = $crate::metrics::collection_metrics(stringify!($type));
+                    Self {
+                        db: Box::new($crate::db::instrumented::InstrumentedDb::new(db, metrics))
+                    }
+                } else {
+                    Self {
+                        db: Box::new(db)
+                    }
                 }
             }
+
+            /// Opens existing RocksDB with given path in read-only mode
+            #[allow(dead_code)]
+            pub fn with_path_read_only<P: AsRef<std::path::Path>>(path: P) -> ton_types::Result<Self> {
+                $crate::db::collection_metadata::validate_collection_metadata(
+                    path.as_ref(),
+                    stringify!($type),
+                    $crate::db::collection_metadata::key_type_name::<$key_type>(),
+                )?;
+                Ok(Self {
+                    db: Box::new($crate::db::rocksdb::RocksDb::with_path_read_only(path)?)
+                })
+            }
         }
 
         impl std::ops::Deref for $type {
@@ -55,11 +105,34 @@ macro_rules! db_impl_cbor {
                 Ok(None)
             }
 
+            #[allow(dead_code)]
+            pub fn try_get_values(&self, keys: &[$key_type]) -> ton_types::Result<Vec<Option<$value_type>>> {
+                let mut result = Vec::with_capacity(keys.len());
+                for db_slice in self.try_get_multi(keys)? {
+                    result.push(match db_slice {
+                        Some(db_slice) => Some(serde_cbor::from_slice(db_slice.as_ref())?),
+                        None => None,
+                    });
+                }
+
+                Ok(result)
+            }
+
             #[allow(dead_code)]
             pub fn get_value(&self, key: &$key_type) -> ton_types::Result<$value_type> {
                 Ok(serde_cbor::from_slice(self.get(key)?.as_ref())?)
             }
 
+            /// Iterates over the values with keys starting with given prefix in the ascending order of keys
+            #[allow(dead_code)]
+            pub fn for_each_value_with_prefix(
+                &self,
+                prefix: &[u8],
+                mut predicate: impl FnMut(&[u8], $value_type) -> ton_types::Result<bool>,
+            ) -> ton_types::Result<bool> {
+                self.for_each_with_prefix(prefix, &mut |key, value| predicate(key, serde_cbor::from_slice(value)?))
+            }
+
             #[allow(dead_code)]
             pub fn put_value(&self, key: &$key_type, value: impl std::borrow::Borrow<$value_type>) -> ton_types::Result<()> {
                 self.put(key, &serde_cbor::to_vec(value.borrow())?)
@@ -83,11 +156,34 @@ macro_rules! db_impl_serializable {
                 Ok(None)
             }
 
+            #[allow(dead_code)]
+            pub fn try_get_values(&self, keys: &[$key_type]) -> ton_types::Result<Vec<Option<$value_type>>> {
+                let mut result = Vec::with_capacity(keys.len());
+                for db_slice in self.try_get_multi(keys)? {
+                    result.push(match db_slice {
+                        Some(db_slice) => Some(<$value_type>::from_slice(db_slice.as_ref())?),
+                        None => None,
+                    });
+                }
+
+                Ok(result)
+            }
+
             #[allow(dead_code)]
             pub fn get_value(&self, key: &$key_type) -> ton_types::Result<$value_type> {
                 Ok(<$value_type>::from_slice(self.get(key)?.as_ref())?)
             }
 
+            /// Iterates over the values with keys starting with given prefix in the ascending order of keys
+            #[allow(dead_code)]
+            pub fn for_each_value_with_prefix(
+                &self,
+                prefix: &[u8],
+                mut predicate: impl FnMut(&[u8], $value_type) -> ton_types::Result<bool>,
+            ) -> ton_types::Result<bool> {
+                self.for_each_with_prefix(prefix, &mut |key, value| predicate(key, <$value_type>::from_slice(value)?))
+            }
+
             #[allow(dead_code)]
             pub fn put_value(&self, key: &$key_type, value: impl std::borrow::Borrow<$value_type>) -> ton_types::Result<()> {
                 self.put(key, &value.borrow().to_vec()?)
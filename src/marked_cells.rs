@@ -0,0 +1,70 @@
+use std::path::{Path, PathBuf};
+
+use fnv::FnvHashSet;
+
+use ton_types::Result;
+
+use crate::db::traits::KvcWriteable;
+use crate::db_impl_base;
+use crate::types::CellId;
+
+/// Set of cells visited by GC marking
+pub(crate) trait MarkedCells {
+    fn contains(&self, cell_id: &CellId) -> Result<bool>;
+    fn insert(&mut self, cell_id: &CellId) -> Result<()>;
+}
+
+impl MarkedCells for FnvHashSet<CellId> {
+    fn contains(&self, cell_id: &CellId) -> Result<bool> {
+        Ok(FnvHashSet::contains(self, cell_id))
+    }
+
+    fn insert(&mut self, cell_id: &CellId) -> Result<()> {
+        FnvHashSet::insert(self, cell_id.clone());
+        Ok(())
+    }
+}
+
+db_impl_base!(MarkedCellsDb, KvcWriteable, CellId);
+
+/// Disk-backed set of marked cells, so marking is not limited by the available memory.
+/// The underlying database is temporary: it is destroyed when the set is dropped.
+#[derive(Debug)]
+pub(crate) struct DiskMarkedCells {
+    db: MarkedCellsDb,
+    path: PathBuf,
+}
+
+impl DiskMarkedCells {
+    /// Creates empty set at given path, destroying leftovers of the previous (interrupted) run
+    pub fn with_path(path: impl AsRef<Path>) -> Result<Self> {
+        let path = path.as_ref().to_path_buf();
+        if path.exists() {
+            log::warn!(target: "storage", "Removing stale marked cells database {:?}", path);
+            MarkedCellsDb::with_path(&path).destroy()?;
+        }
+
+        Ok(Self {
+            db: MarkedCellsDb::with_path(&path),
+            path,
+        })
+    }
+}
+
+impl MarkedCells for DiskMarkedCells {
+    fn contains(&self, cell_id: &CellId) -> Result<bool> {
+        self.db.contains(cell_id)
+    }
+
+    fn insert(&mut self, cell_id: &CellId) -> Result<()> {
+        self.db.put(cell_id, &[])
+    }
+}
+
+impl Drop for DiskMarkedCells {
+    fn drop(&mut self) {
+        if let Err(err) = self.db.destroy() {
+            log::warn!(target: "storage", "Can't destroy marked cells database {:?}: {}", self.path, err);
+        }
+    }
+}
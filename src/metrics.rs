@@ -0,0 +1,145 @@
+use std::collections::BTreeMap;
+use std::sync::{Arc, Mutex};
+use std::sync::atomic::{AtomicBool, AtomicU64, Ordering};
+use std::time::Duration;
+
+use lazy_static::lazy_static;
+
+/// Upper bounds (in microseconds) of the latency histogram buckets; the last bucket is unbounded
+pub const LATENCY_BUCKETS_US: [u64; 12] = [10, 25, 50, 100, 250, 500, 1_000, 2_500, 5_000, 10_000, 50_000, 250_000];
+
+/// Operation of the key-value collection measured
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum CollectionOp {
+    Get,
+    Put,
+    Delete,
+    /// Commit of the transaction (batch)
+    Commit,
+}
+
+#[derive(Debug, Default)]
+struct LatencyHistogram {
+    buckets: [AtomicU64; LATENCY_BUCKETS_US.len() + 1],
+    count: AtomicU64,
+    total_us: AtomicU64,
+}
+
+impl LatencyHistogram {
+    fn record(&self, latency: Duration) {
+        let latency_us = latency.as_micros() as u64;
+        let bucket = LATENCY_BUCKETS_US.iter()
+            .position(|bound| latency_us <= *bound)
+            .unwrap_or(LATENCY_BUCKETS_US.len());
+        self.buckets[bucket].fetch_add(1, Ordering::Relaxed);
+        self.count.fetch_add(1, Ordering::Relaxed);
+        self.total_us.fetch_add(latency_us, Ordering::Relaxed);
+    }
+
+    fn snapshot(&self) -> LatencyHistogramSnapshot {
+        LatencyHistogramSnapshot {
+            buckets: self.buckets.iter().map(|bucket| bucket.load(Ordering::Relaxed)).collect(),
+            count: self.count.load(Ordering::Relaxed),
+            total_us: self.total_us.load(Ordering::Relaxed),
+        }
+    }
+}
+
+/// Latency histogram values; `buckets[i]` counts operations not longer than
+/// `LATENCY_BUCKETS_US[i]` (and longer than the previous bound), the last one counts the rest
+#[derive(Debug, Clone, Default, PartialEq)]
+pub struct LatencyHistogramSnapshot {
+    pub buckets: Vec<u64>,
+    pub count: u64,
+    pub total_us: u64,
+}
+
+impl LatencyHistogramSnapshot {
+    pub fn average(&self) -> Option<Duration> {
+        if self.count == 0 {
+            return None;
+        }
+
+        Some(Duration::from_micros(self.total_us / self.count))
+    }
+}
+
+/// Latencies and traffic of the key-value collection
+#[derive(Debug, Default)]
+pub struct CollectionMetrics {
+    get: LatencyHistogram,
+    put: LatencyHistogram,
+    delete: LatencyHistogram,
+    commit: LatencyHistogram,
+    bytes_read: AtomicU64,
+    bytes_written: AtomicU64,
+}
+
+impl CollectionMetrics {
+    pub fn record(&self, op: CollectionOp, latency: Duration) {
+        match op {
+            CollectionOp::Get => self.get.record(latency),
+            CollectionOp::Put => self.put.record(latency),
+            CollectionOp::Delete => self.delete.record(latency),
+            CollectionOp::Commit => self.commit.record(latency),
+        }
+    }
+
+    pub fn add_bytes_read(&self, bytes: usize) {
+        self.bytes_read.fetch_add(bytes as u64, Ordering::Relaxed);
+    }
+
+    pub fn add_bytes_written(&self, bytes: usize) {
+        self.bytes_written.fetch_add(bytes as u64, Ordering::Relaxed);
+    }
+}
+
+/// Metrics of the collection at the moment of the snapshot
+#[derive(Debug, Clone, Default, PartialEq)]
+pub struct CollectionMetricsSnapshot {
+    pub name: String,
+    pub get: LatencyHistogramSnapshot,
+    pub put: LatencyHistogramSnapshot,
+    pub delete: LatencyHistogramSnapshot,
+    pub commit: LatencyHistogramSnapshot,
+    pub bytes_read: u64,
+    pub bytes_written: u64,
+}
+
+lazy_static! {
+    static ref ENABLED: AtomicBool = AtomicBool::new(false);
+    static ref REGISTRY: Mutex<BTreeMap<String, Arc<CollectionMetrics>>> = Mutex::new(BTreeMap::new());
+}
+
+/// Enables instrumentation of the collections opened afterwards (collections already opened are
+/// not affected, so it is to be set before the storage is opened)
+pub fn set_enabled(value: bool) {
+    ENABLED.store(value, Ordering::Relaxed)
+}
+
+pub fn is_enabled() -> bool {
+    ENABLED.load(Ordering::Relaxed)
+}
+
+/// Returns metrics of the collection with given name; collections of the same name (e.g. the
+/// package indexes of different archives) share the metrics
+pub fn collection_metrics(name: &str) -> Arc<CollectionMetrics> {
+    let mut registry = REGISTRY.lock().expect("Poisoned Mutex");
+    Arc::clone(registry.entry(name.to_string()).or_default())
+}
+
+/// Returns metrics of all the instrumented collections, sorted by name
+pub fn snapshot_metrics() -> Vec<CollectionMetricsSnapshot> {
+    REGISTRY.lock().expect("Poisoned Mutex")
+        .iter()
+        .map(|(name, metrics)| CollectionMetricsSnapshot {
+            name: name.clone(),
+            get: metrics.get.snapshot(),
+            put: metrics.put.snapshot(),
+            delete: metrics.delete.snapshot(),
+            commit: metrics.commit.snapshot(),
+            bytes_read: metrics.bytes_read.load(Ordering::Relaxed),
+            bytes_written: metrics.bytes_written.load(Ordering::Relaxed),
+        })
+        .collect()
+}
@@ -1,4 +1,81 @@
-use crate::db_impl_base;
+use std::ops::{Deref, DerefMut};
+use std::path::Path;
+
+use ton_types::Result;
+
+use crate::db::chunked::ChunkedDb;
 use crate::db::traits::KvcWriteable;
+use crate::types::{LEGACY_NODE_STATE_KEYS, NodeStateKey};
+
+#[derive(Debug)]
+pub struct NodeStateDb {
+    db: ChunkedDb<NodeStateKey>,
+}
+
+impl NodeStateDb {
+    /// Constructs new instance using in-memory key-value collection
+    pub fn in_memory() -> Self {
+        Self { db: ChunkedDb::in_memory() }
+    }
+
+    /// Constructs new instance using RocksDB with given path
+    pub fn with_path<P: AsRef<Path>>(path: P) -> Self {
+        Self { db: ChunkedDb::with_path(path, "NodeStateDb") }
+    }
+
+    /// Opens existing RocksDB with given path in read-only mode
+    pub fn with_path_read_only<P: AsRef<Path>>(path: P) -> Result<Self> {
+        Ok(Self { db: ChunkedDb::with_path_read_only(path, "NodeStateDb")? })
+    }
+
+    /// Underlying collection; large values are stored split into chunks
+    pub const fn chunked_db(&self) -> &ChunkedDb<NodeStateKey> {
+        &self.db
+    }
+
+    /// Lists all the keys stored in the database
+    pub fn list(&self) -> Result<Vec<NodeStateKey>> {
+        let mut result = Vec::new();
+        self.for_each_key_only(&mut |key| {
+            result.push(NodeStateKey::from(String::from_utf8_lossy(key).as_ref()));
+            Ok(true)
+        })?;
+
+        Ok(result)
+    }
+
+    /// Moves values stored under legacy string keys to the corresponding typed keys.
+    /// Returns count of migrated values. It is safe to call the migration repeatedly.
+    pub fn migrate_legacy_keys(&self) -> Result<usize> {
+        let mut migrated = 0;
+        for (legacy_key, key) in LEGACY_NODE_STATE_KEYS.iter() {
+            let legacy_key = NodeStateKey::Other(legacy_key.to_string());
+            let value = match self.try_get(&legacy_key)? {
+                Some(value) => value.to_vec(),
+                None => continue,
+            };
+            if !self.contains(key)? {
+                self.put(key, &value)?;
+            }
+            self.delete(&legacy_key)?;
+            log::info!(target: "storage", "Node state key migrated: {} -> {}", legacy_key.as_str(), key.as_str());
+            migrated += 1;
+        }
+
+        Ok(migrated)
+    }
+}
+
+impl Deref for NodeStateDb {
+    type Target = dyn KvcWriteable<NodeStateKey> + Send + Sync;
+
+    fn deref(&self) -> &Self::Target {
+        &self.db
+    }
+}
 
-db_impl_base!(NodeStateDb, KvcWriteable, &'static str);
+impl DerefMut for NodeStateDb {
+    fn deref_mut(&mut self) -> &mut Self::Target {
+        &mut self.db
+    }
+}
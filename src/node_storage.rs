@@ -0,0 +1,1335 @@
+use std::borrow::Borrow;
+use std::hash::Hash;
+use std::io::Cursor;
+use std::path::{Path, PathBuf};
+use std::sync::{Arc, RwLock};
+use std::sync::atomic::Ordering;
+use std::time::{Duration, Instant};
+
+use fnv::FnvHashMap;
+use futures::future::Either;
+use sha2::{Digest, Sha256};
+use ton_api::ton::PublicKey;
+use ton_block::{AccountIdPrefixFull, Block, BlockIdExt, Deserializable, ShardIdent, ShardStateUnsplit};
+use ton_types::{Cell, deserialize_tree_of_cells, error, fail, Result, SliceData, UInt256};
+
+use crate::apply_checkpoint_db::ApplyCheckpointDb;
+use crate::archives::archive_manager::ArchiveManager;
+use crate::archives::block_data_locator::BlockDataLocator;
+use crate::archives::package_entry_id::{FromFileName, GetFileName, PackageEntryId};
+use crate::background_tasks::BackgroundTasks;
+use crate::blob_store::BlobStore;
+use crate::block_handle_db::{BlockByHashIndex, BlockByShardIndex, BlockHandleDb, BlockHandleStorage};
+use crate::block_index_db::BlockIndexDb;
+use crate::block_info_db::BlockInfoDb;
+use crate::bootstrap_snapshot::{BOOTSTRAP_SNAPSHOT_VERSION, BootstrapManifest};
+use crate::cancellation::{CancellationToken, is_cancelled};
+use crate::catchain_persistent_db::CatchainPersistentDb;
+use crate::chain_head_db::ChainHeadDb;
+use crate::cell_access_db::CellAccessStats;
+use crate::cell_db_scrubber::CellDbScrubber;
+use crate::cell_gc_horizon::CellGcHorizon;
+use crate::clock::{Clock, system_clock};
+use crate::db::rocksdb::set_fsync_policy;
+use crate::db::traits::Kvc;
+use crate::diagnostics_db::DiagnosticsDb;
+use crate::events::{StorageEventBus, StorageEventListener};
+use crate::key_block_db::KeyBlockDb;
+use crate::metrics::CollectionMetricsSnapshot;
+use crate::node_state_db::NodeStateDb;
+use crate::pruning_coordinator::PruningCoordinator;
+use crate::retention_profile::RetentionConfig;
+use crate::shardstate_db::{GC, ShardStateDb, ShardStatePutResult, StatesGcMode};
+use crate::shardstate_persistent_db::{PersistenceProgress, ShardStatePersistentDb};
+use crate::slow_op_recorder::SlowOpRecorder;
+use crate::state_pins_db::StatePinsDb;
+use crate::status_db::StatusDb;
+use crate::storage_config::StorageConfig;
+use crate::storage_shrink::{components_usage, merge_usage, ShrinkReport};
+use crate::traits::Serializable;
+use crate::types::{ApplyCheckpoint, BlockFlags, BlockHandle, BlockId, BlockIdMismatch, BlockMeta, ChainHead, FLAG_DATA, FLAG_KEY_BLOCK, FLAG_MOVED_TO_ARCHIVE, FLAG_PROOF, FLAG_PROOF_LINK, FLAG_PRUNED, McSeqNo, ProofKind, SlowOpRecord, StatePin, StatusKey, WorkchainId};
+use crate::warm_up::{preload_cells, run_blocking, WarmUpResult, WarmUpStage};
+use crate::zerostate_db::ZerostateDb;
+
+/// Flags of the block's data and proofs, kept until the block is archived and pruned
+const BLOCK_FILE_FLAGS: u32 = FLAG_DATA | FLAG_PROOF | FLAG_PROOF_LINK;
+
+/// Configuration of orphan block handles removal
+#[derive(Debug, Clone)]
+pub struct HandlesCompactionConfig {
+    /// Handles of blocks with masterchain seq_no at or above the horizon are never removed
+    pub safety_horizon_mc_seq_no: u32,
+    /// Maximal count of handles removed per run
+    pub batch_size: usize,
+}
+
+/// Parts of the block saved by `NodeStorage::save_block`; absent parts are left as they are
+#[derive(Debug, Clone, Default)]
+pub struct BlockParts {
+    pub data: Option<Vec<u8>>,
+    pub proof: Option<Vec<u8>>,
+    pub proof_link: Option<Vec<u8>>,
+}
+
+/// Parts of the block stored by the call of `NodeStorage::save_block` (the ones stored before
+/// aren't reported)
+#[derive(Debug, Clone, Copy, Default, PartialEq, Eq)]
+pub struct SavedBlockParts {
+    pub data: bool,
+    pub proof: bool,
+    pub proof_link: bool,
+}
+
+impl SavedBlockParts {
+    pub const fn any(&self) -> bool {
+        self.data || self.proof || self.proof_link
+    }
+}
+
+/// Rough figures of the key-value collection, intended for monitoring
+#[derive(Debug, Clone)]
+pub struct CollectionStats {
+    pub name: &'static str,
+    pub approx_len: usize,
+    pub approx_size_bytes: u64,
+}
+
+/// Facade uniting all the node's databases located under the common root directory
+pub struct NodeStorage {
+    db_root_path: Arc<PathBuf>,
+    block_handle_storage: Arc<BlockHandleStorage>,
+    block_by_hash_index: Arc<BlockByHashIndex>,
+    block_by_shard_index: Arc<BlockByShardIndex>,
+    block_index_db: Arc<BlockIndexDb>,
+    block_info_db: BlockInfoDb,
+    blob_store: Arc<BlobStore>,
+    key_block_db: KeyBlockDb,
+    shard_state_db: Arc<ShardStateDb>,
+    shard_state_persistent_db: ShardStatePersistentDb,
+    apply_checkpoint_db: ApplyCheckpointDb,
+    chain_head_db: ChainHeadDb,
+    node_state_db: Arc<NodeStateDb>,
+    status_db: Arc<StatusDb>,
+    pruning_coordinator: Arc<PruningCoordinator>,
+    retention: RwLock<RetentionConfig>,
+    config: StorageConfig,
+    state_pins_db: Arc<StatePinsDb>,
+    catchain_persistent_db: CatchainPersistentDb,
+    zerostate_db: ZerostateDb,
+    archive_manager: ArchiveManager,
+    event_bus: Arc<StorageEventBus>,
+    slow_op_recorder: Arc<SlowOpRecorder>,
+    clock: Arc<dyn Clock>,
+    background_tasks: Arc<BackgroundTasks>,
+}
+
+impl NodeStorage {
+    /// Opens (or creates) all the databases under given root path
+    pub async fn with_path(db_root_path: impl Into<PathBuf>) -> Result<Self> {
+        Self::with_path_and_clock(db_root_path, system_clock()).await
+    }
+
+    /// Opens (or creates) all the databases under given root path; TTL and GC decisions are made
+    /// against the clock
+    pub async fn with_path_and_clock(db_root_path: impl Into<PathBuf>, clock: Arc<dyn Clock>) -> Result<Self> {
+        Self::with_config_and_clock(db_root_path, StorageConfig::default(), clock).await
+    }
+
+    /// Opens (or creates) all the databases under given root path, configured by the storage
+    /// configuration (it is validated first)
+    pub async fn with_config(db_root_path: impl Into<PathBuf>, config: StorageConfig) -> Result<Self> {
+        Self::with_config_and_clock(db_root_path, config, system_clock()).await
+    }
+
+    /// Opens (or creates) all the databases under given root path, configured by the storage
+    /// configuration; TTL and GC decisions are made against the clock
+    pub async fn with_config_and_clock(
+        db_root_path: impl Into<PathBuf>,
+        config: StorageConfig,
+        clock: Arc<dyn Clock>,
+    ) -> Result<Self> {
+        config.validate()?;
+        let db_root_path = Arc::new(db_root_path.into());
+        log::info!(target: "storage", "Opening node storage at {:?}", db_root_path);
+        crate::metrics::set_enabled(config.diagnostics.metrics);
+        set_fsync_policy(config.fsync);
+
+        let block_handle_db = Arc::new(BlockHandleDb::with_path(db_root_path.join("block_handle_db")));
+        let block_by_hash_index = Arc::new(BlockByHashIndex::block_by_hash(db_root_path.join("block_by_hash_index")));
+        let block_by_shard_index = Arc::new(BlockByShardIndex::block_by_shard(db_root_path.join("block_by_shard_index")));
+        let block_handle_storage = BlockHandleStorage::new(Arc::clone(&block_handle_db))
+            .with_index(Arc::clone(&block_by_hash_index))
+            .with_index(Arc::clone(&block_by_shard_index));
+        let index_missing = block_by_hash_index.is_empty()? || block_by_shard_index.is_empty()?;
+        if index_missing && !block_handle_db.is_empty()? {
+            block_handle_storage.rebuild_indexes()?;
+        }
+        let block_index_db = BlockIndexDb::with_paths(
+            db_root_path.join("lt_desc_db"),
+            db_root_path.join("lt_db"),
+        );
+        block_index_db.reconcile_lt_descs()?;
+        block_handle_storage.migrate_legacy_handles(|| block_index_db.block_ids())?;
+        block_index_db.set_fork_tolerant(config.block_index.fork_tolerant);
+        let blob_store = Arc::new(BlobStore::with_path(db_root_path.join("blob_db")));
+        let mut block_info_db = BlockInfoDb::with_path(db_root_path.join("block_info_db"));
+        block_info_db.set_blob_store(Arc::clone(&blob_store));
+        block_info_db.set_dedup_min_size(config.block_info_dedup_min_size);
+        let event_bus = Arc::new(StorageEventBus::new());
+        let node_state_db = Arc::new(NodeStateDb::with_path(db_root_path.join("node_state_db")));
+        node_state_db.migrate_legacy_keys()?;
+        let cell_access_stats = config.states_gc.access_stats.as_ref().map(|access_stats| Arc::new(
+            CellAccessStats::with_path(db_root_path.join("cell_access_db"), access_stats.sample_rate)
+                .with_clock(Arc::clone(&clock))
+        ));
+        let mut shard_state_db = match (config.states_gc.mode, cell_access_stats) {
+            (StatesGcMode::Horizon, cell_access_stats) => ShardStateDb::with_paths_and_gc_horizon(
+                db_root_path.join("shardstate_db"),
+                db_root_path.join("cells_db"),
+                Arc::new(CellGcHorizon::with_node_state_db(Arc::clone(&node_state_db))?),
+                cell_access_stats,
+            ),
+            (StatesGcMode::Marking, Some(cell_access_stats)) => ShardStateDb::with_paths_and_access_stats(
+                db_root_path.join("shardstate_db"),
+                db_root_path.join("cells_db"),
+                cell_access_stats,
+            ),
+            (StatesGcMode::Marking, None) => ShardStateDb::with_paths(
+                db_root_path.join("shardstate_db"),
+                db_root_path.join("cells_db"),
+            ),
+        };
+        let slow_op_recorder = Arc::new(SlowOpRecorder::with_db(
+            DiagnosticsDb::with_path(db_root_path.join("diagnostics_db")),
+            config.diagnostics.slow_op_capacity,
+        )?);
+        slow_op_recorder.set_default_threshold_ms(config.diagnostics.slow_op_threshold_ms);
+        shard_state_db.migrate_legacy_entries()?;
+        shard_state_db.dynamic_boc_db().apply_config(&config.cells)?;
+        shard_state_db.set_event_bus(Arc::clone(&event_bus));
+        shard_state_db.set_slow_op_recorder(Arc::clone(&slow_op_recorder));
+        let background_tasks = Arc::new(BackgroundTasks::new());
+        let mut archive_manager = ArchiveManager::with_archives_config(
+            Arc::clone(&db_root_path),
+            &config.archives,
+        ).await?;
+        archive_manager.set_background_tasks(Arc::clone(&background_tasks));
+        archive_manager.set_event_bus(Arc::clone(&event_bus));
+        archive_manager.set_slow_op_recorder(Arc::clone(&slow_op_recorder));
+        archive_manager.set_clock(Arc::clone(&clock));
+        let shard_state_persistent_db = ShardStatePersistentDb::with_path(db_root_path.join("shardstate_persistent_db"));
+        shard_state_persistent_db.set_max_slice_size(config.persistent_states.max_slice_size);
+        shard_state_persistent_db.set_write_chunk_size(config.persistent_states.write_chunk_size);
+        let pruning_coordinator = Arc::new(
+            PruningCoordinator::with_node_state_db(Arc::clone(&node_state_db), config.pruning.margin)?
+                .with_clock(Arc::clone(&clock))
+        );
+
+        Ok(Self {
+            block_handle_storage: Arc::new(block_handle_storage),
+            block_by_hash_index,
+            block_by_shard_index,
+            block_index_db: Arc::new(block_index_db),
+            block_info_db,
+            blob_store,
+            key_block_db: KeyBlockDb::with_path(db_root_path.join("key_block_db")),
+            shard_state_db: Arc::new(shard_state_db),
+            shard_state_persistent_db,
+            apply_checkpoint_db: ApplyCheckpointDb::with_path(db_root_path.join("apply_checkpoint_db")),
+            chain_head_db: ChainHeadDb::with_path(db_root_path.join("chain_head_db")),
+            node_state_db,
+            status_db: Arc::new(StatusDb::with_path(db_root_path.join("status_db"))),
+            pruning_coordinator,
+            retention: RwLock::new(config.retention.clone()),
+            config,
+            state_pins_db: Arc::new(StatePinsDb::with_path(db_root_path.join("state_pins_db"))),
+            catchain_persistent_db: CatchainPersistentDb::with_path(db_root_path.join("catchain_persistent_db")),
+            zerostate_db: ZerostateDb::with_path(db_root_path.join("zerostate_db")),
+            archive_manager,
+            event_bus,
+            slow_op_recorder,
+            clock,
+            background_tasks,
+            db_root_path,
+        })
+    }
+
+    /// Storage configuration the instance was opened with; retention set afterwards isn't reflected
+    pub const fn config(&self) -> &StorageConfig {
+        &self.config
+    }
+
+    /// Clock TTL and GC decisions are made against
+    pub const fn clock(&self) -> &Arc<dyn Clock> {
+        &self.clock
+    }
+
+    /// Registry of the background tasks; the node has to set the spawner before any background
+    /// component is started
+    pub const fn background_tasks(&self) -> &Arc<BackgroundTasks> {
+        &self.background_tasks
+    }
+
+    /// Stops all the background tasks, cancelling ones not finished within the timeout.
+    /// Returns names of the cancelled tasks.
+    pub async fn shutdown_background_tasks(&self, timeout: Duration) -> Vec<String> {
+        self.background_tasks.shutdown(timeout).await
+    }
+
+    pub const fn db_root_path(&self) -> &Arc<PathBuf> {
+        &self.db_root_path
+    }
+
+    pub const fn block_handle_storage(&self) -> &Arc<BlockHandleStorage> {
+        &self.block_handle_storage
+    }
+
+    pub const fn block_by_hash_index(&self) -> &Arc<BlockByHashIndex> {
+        &self.block_by_hash_index
+    }
+
+    pub const fn block_by_shard_index(&self) -> &Arc<BlockByShardIndex> {
+        &self.block_by_shard_index
+    }
+
+    /// Iterates over stored handles of the workchain's blocks (ordered by shard), running predicate
+    /// for block id and meta of each one. Unlike `BlockHandleStorage::for_each_stored_handle`,
+    /// handles of the other workchains are not read. Dangling index entries are skipped.
+    pub fn for_each_handle_in_workchain(
+        &self,
+        workchain_id: WorkchainId,
+        mut predicate: impl FnMut(BlockIdExt, BlockMeta) -> Result<bool>,
+    ) -> Result<bool> {
+        let block_handle_db = self.block_handle_storage.block_handle_db();
+        self.block_by_shard_index.for_each_with_prefix(&BlockByShardIndex::workchain_prefix(workchain_id), |id| {
+            match block_handle_db.try_get_value(&BlockId::from(&id))? {
+                Some(block_meta) => predicate(id, block_meta),
+                None => Ok(true),
+            }
+        })
+    }
+
+    /// Finds id of the block with stored handle by root hash. Dangling index entries (left by
+    /// interrupted deletion of the handle) are skipped.
+    pub fn find_block_by_root_hash(&self, root_hash: &UInt256) -> Result<Option<BlockIdExt>> {
+        for block_id in self.block_by_hash_index.get(root_hash)? {
+            if self.block_handle_storage.block_handle_db().contains(&BlockId::from(&block_id))? {
+                return Ok(Some(block_id));
+            }
+        }
+
+        Ok(None)
+    }
+
+    pub const fn block_index_db(&self) -> &Arc<BlockIndexDb> {
+        &self.block_index_db
+    }
+
+    pub const fn block_info_db(&self) -> &BlockInfoDb {
+        &self.block_info_db
+    }
+
+    /// Content-addressed store of the deduplicated values (see `BlockInfoDb::set_dedup_min_size`)
+    pub const fn blob_store(&self) -> &Arc<BlobStore> {
+        &self.blob_store
+    }
+
+    pub const fn key_block_db(&self) -> &KeyBlockDb {
+        &self.key_block_db
+    }
+
+    pub const fn shard_state_db(&self) -> &Arc<ShardStateDb> {
+        &self.shard_state_db
+    }
+
+    pub const fn shard_state_persistent_db(&self) -> &ShardStatePersistentDb {
+        &self.shard_state_persistent_db
+    }
+
+    /// Progress of the persistent states being saved at the moment (e.g. for monitoring)
+    pub fn persistence_jobs(&self) -> Vec<PersistenceProgress> {
+        self.shard_state_persistent_db.running_jobs()
+    }
+
+    pub const fn apply_checkpoint_db(&self) -> &ApplyCheckpointDb {
+        &self.apply_checkpoint_db
+    }
+
+    /// Stores the state of the applied block and then advances the apply checkpoint of its shard.
+    /// The checkpoint is written only after the state is stored, so after a crash the state of the
+    /// checkpointed block is always present and application may resume right after it. Replay of
+    /// the blocks up to the checkpoint (e.g. when the application is repeated after a crash) stores
+    /// their states but leaves the checkpoint as it is.
+    pub fn store_applied_state(&self, block_id: &BlockIdExt, state_root: Cell) -> Result<(ShardStatePutResult, ApplyCheckpoint)> {
+        let result = self.shard_state_db.put(&BlockId::from(block_id), state_root)?;
+        let checkpoint = match self.apply_checkpoint_db.get_checkpoint(block_id.shard())? {
+            Some(checkpoint) if checkpoint.block_id_ext().seq_no() > block_id.seq_no() => {
+                log::debug!(
+                    target: "storage",
+                    "State of {} is replayed below the apply checkpoint {}",
+                    block_id,
+                    checkpoint.block_id_ext()
+                );
+                checkpoint
+            }
+            _ => self.apply_checkpoint_db.advance(block_id)?,
+        };
+
+        Ok((result, checkpoint))
+    }
+
+    /// The last fully applied block of the shard, if any
+    pub fn apply_checkpoint(&self, shard: &ShardIdent) -> Result<Option<ApplyCheckpoint>> {
+        self.apply_checkpoint_db.get_checkpoint(shard)
+    }
+
+    /// Sets the apply checkpoint of the shard to given block (or removes it if None); intended for
+    /// recovery logic, e.g. after the states of the checkpointed blocks are found damaged
+    pub fn reset_apply_checkpoint(&self, shard: &ShardIdent, block_id: Option<&BlockIdExt>) -> Result<Option<ApplyCheckpoint>> {
+        self.apply_checkpoint_db.reset(shard, block_id)
+    }
+
+    /// Saves given parts of the block. The parts are written first (the data goes into the unapplied
+    /// files before the proofs go into BlockInfoDb, where the proofs are kept), then the flags of all
+    /// the parts written are set and the handle is stored once, so the handle never refers to the
+    /// parts not written. Parts already saved are skipped, so the call may be
+    /// repeated with the same or other parts; the files of an interrupted call are just rewritten.
+    /// Block info (utime, key block flag) is fetched from the data, if not fetched yet.
+    pub async fn save_block(&self, block_id: &BlockIdExt, parts: BlockParts) -> Result<(Arc<BlockHandle>, SavedBlockParts)> {
+        let handle = self.block_handle_storage.load_block_handle(block_id)?;
+        let mut saved = SavedBlockParts::default();
+
+        if let Some(data) = parts.data {
+            if !handle.data_inited() {
+                if !handle.fetched() {
+                    handle.fetch_block_info(&Block::construct_from_bytes(&data)?)?;
+                }
+                self.archive_manager.add_block_file(&handle, &PackageEntryId::Block(block_id), data).await?;
+                saved.data = true;
+            }
+        }
+        if let Some(proof) = parts.proof {
+            if !handle.proof_inited() {
+                self.block_info_db.store_proof(block_id, ProofKind::Proof, &proof)?;
+                saved.proof = true;
+            }
+        }
+        if let Some(proof_link) = parts.proof_link {
+            if !handle.proof_link_inited() {
+                self.block_info_db.store_proof(block_id, ProofKind::ProofLink, &proof_link)?;
+                saved.proof_link = true;
+            }
+        }
+
+        if !saved.any() {
+            return Ok((handle, saved));
+        }
+        let mut flags = BlockFlags::default();
+        if saved.data {
+            flags = flags | BlockFlags::DATA;
+        }
+        if saved.proof {
+            flags = flags | BlockFlags::PROOF;
+        }
+        if saved.proof_link {
+            flags = flags | BlockFlags::PROOF_LINK;
+        }
+        handle.apply_transition(flags)?;
+        log::debug!(target: "storage", "Block {} saved: {:?}", block_id, saved);
+
+        Ok((handle, saved))
+    }
+
+    /// Loads proof (or prooflink) of the block. Proofs are kept in BlockInfoDb; the ones written as
+    /// files by the node (into the unapplied directory, then moved into the archive) are read from
+    /// there.
+    pub async fn load_block_proof(&self, handle: &BlockHandle, proof_kind: ProofKind) -> Result<Vec<u8>> {
+        if let Some(proof) = self.block_info_db.try_load_proof(handle.id(), proof_kind)? {
+            return Ok(proof);
+        }
+        match proof_kind {
+            ProofKind::Proof => self.archive_manager.get_file(handle, &PackageEntryId::<_, &UInt256, &PublicKey>::Proof(handle.id())).await,
+            ProofKind::ProofLink => self.archive_manager.get_file(handle, &PackageEntryId::<_, &UInt256, &PublicKey>::ProofLink(handle.id())).await,
+        }
+    }
+
+    pub const fn chain_head_db(&self) -> &ChainHeadDb {
+        &self.chain_head_db
+    }
+
+    /// Marks the block applied: sets the flag of the handle, stores the handle and moves the head
+    /// of the block's shard (and the last applied masterchain seq_no status for masterchain blocks).
+    /// Returns false if the block was already marked applied.
+    pub fn set_block_applied(&self, handle: &BlockHandle) -> Result<bool> {
+        let newly_applied = !handle.set_applied();
+        if newly_applied {
+            self.block_handle_storage.store_block_handle(handle)?;
+        }
+        self.chain_head_db.on_block_applied(handle.id(), handle.meta().gen_utime().load(Ordering::Relaxed))?;
+        if newly_applied && handle.id().shard().is_masterchain() {
+            let mc_seq_no = McSeqNo::new(handle.id().seq_no());
+            let last = self.status_db.try_get_value::<McSeqNo>(&StatusKey::LastAppliedMcSeqNo)?;
+            if last.map(|last| last < mc_seq_no).unwrap_or(true) {
+                self.status_db.put_value::<McSeqNo>(&StatusKey::LastAppliedMcSeqNo, mc_seq_no)?;
+            }
+        }
+
+        Ok(newly_applied)
+    }
+
+    /// The last applied block of the shard, if any
+    pub fn chain_head(&self, shard: &ShardIdent) -> Result<Option<ChainHead>> {
+        self.chain_head_db.get_head(shard)
+    }
+
+    /// The last applied blocks of all the shards, taken atomically
+    pub fn chain_heads(&self) -> Result<Vec<(ShardIdent, ChainHead)>> {
+        self.chain_head_db.get_all_heads()
+    }
+
+    pub const fn node_state_db(&self) -> &Arc<NodeStateDb> {
+        &self.node_state_db
+    }
+
+    /// Statuses other components may subscribe to (see `StatusKey` for the statuses kept)
+    pub const fn status_db(&self) -> &Arc<StatusDb> {
+        &self.status_db
+    }
+
+    /// Safety horizon shared by the states GC and the archives GC; states GC should be attached to
+    /// it with `GC::with_pruning_coordinator`
+    pub const fn pruning_coordinator(&self) -> &Arc<PruningCoordinator> {
+        &self.pruning_coordinator
+    }
+
+    /// Recomputes the pruning horizon from the stored key blocks and persistent states, keeping
+    /// the blocks retained by the retention configuration; the horizon is published as the status
+    pub fn update_pruning_horizon(&self) -> Result<McSeqNo> {
+        let horizon = self.pruning_coordinator.update_with_retention(
+            &self.key_block_db,
+            self.block_handle_storage.block_handle_db(),
+            &self.retention(),
+        )?;
+        self.status_db.put_value::<McSeqNo>(&StatusKey::PruningHorizon, horizon)?;
+
+        Ok(horizon)
+    }
+
+    /// Retention configuration interpreted by the archives GC, the states GC and the block handles
+    /// compaction
+    pub fn retention(&self) -> RetentionConfig {
+        self.retention.read().expect("Poisoned RwLock").clone()
+    }
+
+    /// Validates and applies the retention configuration. It may be changed at runtime: the next
+    /// runs of the archives GC and the handles compaction follow it, as do the states GCs created
+    /// by `states_gc` afterwards.
+    pub fn set_retention(&self, retention: RetentionConfig) -> Result<()> {
+        retention.validate()?;
+        log::info!(target: "storage", "Retention configuration set: {:?}", retention);
+        *self.retention.write().expect("Poisoned RwLock") = retention;
+
+        Ok(())
+    }
+
+    /// Creates GC of the shard states attached to the retention configuration, the pruning
+    /// horizon, the state pins and the event bus. The recently read states are kept for the cell
+    /// idle time of the configuration.
+    pub fn states_gc(&self) -> Result<GC> {
+        Ok(GC::with_retention(
+            &self.shard_state_db,
+            Arc::clone(self.block_handle_storage.block_handle_db()),
+            self.config.states_gc.cell_idle_time(),
+            &self.retention(),
+            Arc::clone(&self.pruning_coordinator),
+        )?
+            .with_state_pins(Arc::clone(&self.state_pins_db))
+            .with_event_bus(Arc::clone(&self.event_bus))
+            .with_clock(Arc::clone(&self.clock)))
+    }
+
+    /// Prunes the archives below the pruning horizon according to the retention configuration
+    /// (keeping the key blocks), then marks the handles of the blocks of the pruned archives pruned.
+    /// Returns ids of the pruned archives.
+    pub async fn gc_archives(&self) -> Result<Vec<u32>> {
+        let retention = self.retention();
+        let pruned = self.archive_manager.gc_archives(&self.pruning_coordinator, &retention, &self.key_block_db).await?;
+        if !pruned.is_empty() {
+            self.mark_pruned_handles()?;
+        }
+
+        Ok(pruned)
+    }
+
+    /// Marks the handles of the archived blocks lying in the pruned archives ranges pruned (so they
+    /// no longer claim data, proofs and being moved to archive) and deletes their proofs. Archives GC
+    /// runs it after deleting archives; it may be run again if that was interrupted.
+    /// Returns count of the marked handles.
+    pub fn mark_pruned_handles(&self) -> Result<usize> {
+        let pruned_archives = self.archive_manager.pruned_archives();
+        let mut block_ids = Vec::new();
+        self.block_handle_storage.for_each_stored_handle(|id, block_meta| {
+            let mc_seq_no = if id.shard().is_masterchain() {
+                id.seq_no()
+            } else {
+                block_meta.masterchain_ref_seq_no().load(Ordering::Relaxed)
+            };
+            let flags = block_meta.flags().load(Ordering::Relaxed);
+            // Zero masterchain reference of a shard block is unknown one: the block is kept, as are
+            // the key blocks (archives GC keeps their entries)
+            if flags & FLAG_MOVED_TO_ARCHIVE != 0
+                && (mc_seq_no != 0 || id.shard().is_masterchain())
+                && !(id.shard().is_masterchain() && flags & FLAG_KEY_BLOCK != 0)
+                && pruned_archives.find(mc_seq_no).is_some()
+            {
+                block_ids.push(id);
+            }
+            Ok(true)
+        })?;
+
+        for block_id in block_ids.iter() {
+            let handle = self.block_handle_storage.load_block_handle(block_id)?;
+            handle.set_pruned();
+            self.block_handle_storage.store_block_handle(&handle)?;
+        }
+        self.block_info_db.delete_proofs(&block_ids)?;
+        log::info!(target: "storage", "{} block handles marked pruned", block_ids.len());
+
+        Ok(block_ids.len())
+    }
+
+    /// Scans the block handles and the shard state entries for the records whose BlockId key
+    /// doesn't match the block id stored in the record (written by construction bugs before the
+    /// write paths were validated). Such records can't be found by their block id.
+    pub fn find_block_id_mismatches(&self) -> Result<Vec<BlockIdMismatch>> {
+        let mut mismatches = self.block_handle_storage.find_block_id_mismatches()?;
+        mismatches.extend(self.shard_state_db.find_block_id_mismatches()?);
+        for mismatch in mismatches.iter() {
+            log::warn!(
+                target: "storage",
+                "{} record {} doesn't match block id {}",
+                mismatch.collection, hex::encode(&mismatch.key), mismatch.block_id_ext
+            );
+        }
+
+        Ok(mismatches)
+    }
+
+    /// Pins of the states protected from GC; states GC should be attached to it with
+    /// `GC::with_state_pins`
+    pub const fn state_pins_db(&self) -> &Arc<StatePinsDb> {
+        &self.state_pins_db
+    }
+
+    /// Protects the shard state of the block from GC on behalf of the owner for `ttl` seconds
+    /// (until unpinned, if None). Pins survive restarts.
+    pub fn pin_state(&self, block_id: &BlockIdExt, owner: &str, ttl: Option<u32>) -> Result<StatePin> {
+        if !self.shard_state_db.shardstate_db().contains(&BlockId::from(block_id))? {
+            fail!("Shard state of {} is not stored", block_id)
+        }
+        self.state_pins_db.pin(block_id, owner, ttl, self.clock.now())
+    }
+
+    /// Removes the pin. Returns true if the pin existed.
+    pub fn unpin_state(&self, pin: &StatePin) -> Result<bool> {
+        self.state_pins_db.unpin(pin.block_id_ext(), pin.owner())
+    }
+
+    /// Lists all the state pins, including the expired ones
+    pub fn list_state_pins(&self) -> Result<Vec<StatePin>> {
+        self.state_pins_db.list()
+    }
+
+    /// Removes the expired state pins. Returns the removed pins.
+    pub fn release_stale_pins(&self) -> Result<Vec<StatePin>> {
+        self.state_pins_db.release_expired(self.clock.now())
+    }
+
+    /// Removes all the pins of the block's state regardless of their owners and TTLs
+    pub fn force_release_pins(&self, block_id: &BlockIdExt) -> Result<Vec<StatePin>> {
+        self.state_pins_db.force_release(block_id)
+    }
+
+    pub const fn catchain_persistent_db(&self) -> &CatchainPersistentDb {
+        &self.catchain_persistent_db
+    }
+
+    pub const fn zerostate_db(&self) -> &ZerostateDb {
+        &self.zerostate_db
+    }
+
+    pub const fn archive_manager(&self) -> &ArchiveManager {
+        &self.archive_manager
+    }
+
+    /// Creates locator of package entries over the archive manager; remote fetcher may be attached
+    /// with `BlockDataLocator::with_remote_fetcher`
+    pub fn block_data_locator(&self) -> BlockDataLocator {
+        BlockDataLocator::new(&self.archive_manager)
+    }
+
+    /// Reads the file of the archived block, with or without its stored handle, so the history
+    /// can be served after the handles are compacted. Blocks having stored handles are read as
+    /// usual. For the rest the package is resolved from the archive indexes by the masterchain
+    /// seq_no: the block's own one for masterchain blocks, `mc_seq_no` for shard blocks. Data of
+    /// shard blocks may be found without it, by the file hash.
+    pub async fn get_archived_file<B, U256, PK>(
+        &self,
+        block_id: &BlockIdExt,
+        entry_id: &PackageEntryId<B, U256, PK>,
+        mc_seq_no: Option<McSeqNo>,
+    ) -> Result<Vec<u8>>
+    where
+        B: Borrow<BlockIdExt> + Hash,
+        U256: Borrow<UInt256> + Hash,
+        PK: Borrow<PublicKey> + Hash
+    {
+        if self.block_handle_storage.block_handle_db().contains(&BlockId::from(block_id))? {
+            let handle = self.block_handle_storage.load_block_handle(block_id)?;
+            return self.archive_manager.get_file(&handle, entry_id).await;
+        }
+
+        let mc_seq_no = if block_id.shard().is_masterchain() {
+            block_id.seq_no()
+        } else if let Some(mc_seq_no) = mc_seq_no {
+            mc_seq_no.value()
+        } else if let PackageEntryId::Block(_) = entry_id {
+            log::debug!(target: "storage", "Looking up handle-less block {} by file hash", block_id);
+            return self.archive_manager.get_file_by_hash(block_id.file_hash()).await?
+                .ok_or_else(|| error!("Block {} is not found in the archives", block_id));
+        } else {
+            fail!("Masterchain seq_no is required to read {} without the block handle", entry_id)
+        };
+
+        log::debug!(target: "storage", "Reading {} of handle-less block from archive of mc block {}", entry_id, mc_seq_no);
+        let handle = self.block_handle_storage.synthetic_archived_handle(block_id, mc_seq_no);
+        self.archive_manager.get_file(&handle, entry_id).await
+    }
+
+    /// Bus the storage lifecycle events are emitted to; GC instances should be attached to it
+    /// with `GC::with_event_bus`
+    pub const fn event_bus(&self) -> &Arc<StorageEventBus> {
+        &self.event_bus
+    }
+
+    pub fn subscribe(&self, listener: Arc<dyn StorageEventListener>) {
+        self.event_bus.subscribe(listener)
+    }
+
+    /// Recorder of the slow operations; thresholds may be configured through it
+    pub const fn slow_op_recorder(&self) -> &Arc<SlowOpRecorder> {
+        &self.slow_op_recorder
+    }
+
+    /// Returns up to `limit` most recent slow operations (e.g. for support bundles), the most recent first
+    pub fn dump_slow_ops(&self, limit: usize) -> Result<Vec<SlowOpRecord>> {
+        self.slow_op_recorder.dump(limit)
+    }
+
+    /// Stores zerostate of the workchain: the tree of cells goes into the shard states database
+    /// (zerostates are never collected by GC) and the canonical BOC into the persistent states
+    /// database, both keyed by the zerostate id. Storing is idempotent. Returns the zerostate id.
+    pub async fn store_zerostate(&self, workchain_id: i32, root_cell: Cell, raw_boc: &[u8]) -> Result<BlockIdExt> {
+        let zerostate_id = ZerostateDb::zerostate_id(workchain_id, &root_cell, raw_boc)?;
+        if let Some(stored_id) = self.zerostate_db.get_zerostate_id(workchain_id)? {
+            if stored_id != zerostate_id {
+                fail!("Another zerostate {} is already stored for workchain {}", stored_id, workchain_id)
+            }
+        }
+
+        let block_id = BlockId::from(&zerostate_id);
+        self.shard_state_db.put(&block_id, root_cell)?;
+        self.shard_state_persistent_db.put(&block_id, raw_boc).await?;
+        self.zerostate_db.put_value(&workchain_id.into(), &zerostate_id)?;
+        log::info!(target: "storage", "Zerostate of workchain {} stored: {}", workchain_id, zerostate_id);
+
+        Ok(zerostate_id)
+    }
+
+    /// Loads id and root cell of the zerostate of the workchain, if stored
+    pub fn load_zerostate(&self, workchain_id: i32) -> Result<Option<(BlockIdExt, Cell)>> {
+        match self.zerostate_db.get_zerostate_id(workchain_id)? {
+            Some(zerostate_id) => {
+                let root_cell = self.shard_state_db.get(&BlockId::from(&zerostate_id))?;
+                Ok(Some((zerostate_id, root_cell)))
+            }
+            None => Ok(None),
+        }
+    }
+
+    /// Loads canonical BOC of the zerostate of the workchain (e.g. for serving peers), if stored
+    pub async fn load_zerostate_boc(&self, workchain_id: i32) -> Result<Option<Vec<u8>>> {
+        match self.zerostate_db.get_zerostate_id(workchain_id)? {
+            Some(zerostate_id) => Ok(Some(
+                self.shard_state_persistent_db.get(&BlockId::from(&zerostate_id)).await?.into()
+            )),
+            None => Ok(None),
+        }
+    }
+
+    /// Exports the bootstrap snapshot of the masterchain key block into the new directory `dest`:
+    /// persistent states of the block and of the top blocks of its shards, all the stored key
+    /// blocks up to the block with their proofs, and the manifest with the hashes of the files.
+    /// The directory may be packed into a tarball for the distribution. If the export is
+    /// cancelled, the partially written directory is removed.
+    pub async fn export_bootstrap_snapshot(
+        &self,
+        dest: impl AsRef<Path>,
+        mc_seq_no: u32,
+        cancellation: &CancellationToken,
+    ) -> Result<BootstrapManifest> {
+        let dest = dest.as_ref();
+        let mc_block_id = self.key_block_db.get_key_block_id(mc_seq_no)?
+            .ok_or_else(|| error!("Masterchain block {} is not a stored key block", mc_seq_no))?;
+        if tokio::fs::metadata(dest).await.is_ok() {
+            fail!("Bootstrap snapshot destination {:?} already exists", dest)
+        }
+        tokio::fs::create_dir_all(dest).await?;
+        log::info!(target: "storage", "Exporting bootstrap snapshot of {} to {:?}", mc_block_id, dest);
+
+        let result = self.write_bootstrap_snapshot(dest, mc_seq_no, mc_block_id, cancellation).await;
+        if let Err(ref err) = result {
+            if is_cancelled(err) {
+                log::info!(target: "storage", "Bootstrap snapshot export to {:?} is cancelled", dest);
+                tokio::fs::remove_dir_all(dest).await?;
+            }
+        }
+
+        result
+    }
+
+    async fn write_bootstrap_snapshot(
+        &self,
+        dest: &Path,
+        mc_seq_no: u32,
+        mc_block_id: BlockIdExt,
+        cancellation: &CancellationToken,
+    ) -> Result<BootstrapManifest> {
+        let mut manifest = BootstrapManifest {
+            version: BOOTSTRAP_SNAPSHOT_VERSION,
+            mc_block: mc_block_id.filename(),
+            created_at: self.clock.now(),
+            files: Vec::new(),
+        };
+
+        let mc_state: Vec<u8> = self.shard_state_persistent_db.get(&BlockId::from(&mc_block_id)).await
+            .map_err(|err| error!("Persistent state of {} is not stored: {}", mc_block_id, err))?
+            .into();
+        let mut state_block_ids = vec![mc_block_id.clone()];
+        state_block_ids.append(&mut self.shard_top_blocks(&mc_block_id, &mc_state)?);
+        for block_id in state_block_ids.iter() {
+            cancellation.check()?;
+            let entry = PackageEntryId::<&BlockIdExt, &UInt256, &PublicKey>::PersistentState {
+                mc_block_id: &mc_block_id,
+                block_id,
+            }.filename();
+            if block_id == &mc_block_id {
+                manifest.add_file(dest, entry, &mc_state).await?;
+                continue;
+            }
+            let state = self.shard_state_persistent_db.get(&BlockId::from(block_id)).await
+                .map_err(|err| error!("Persistent state of {} is not stored: {}", block_id, err))?
+                .into_owned();
+            manifest.add_file(dest, entry, state.as_ref()).await?;
+        }
+
+        let mut key_block_ids = Vec::new();
+        self.key_block_db.for_each(&mut |_key, value| {
+            let block_id = BlockIdExt::from_slice(value)?;
+            if block_id.seq_no() > mc_seq_no {
+                return Ok(false);
+            }
+            key_block_ids.push(block_id);
+            Ok(true)
+        })?;
+        for block_id in key_block_ids.iter() {
+            cancellation.check()?;
+            let handle = self.block_handle_storage.load_block_handle(block_id)?;
+            let entries = [
+                PackageEntryId::<&BlockIdExt, &UInt256, &PublicKey>::Block(block_id),
+                PackageEntryId::<&BlockIdExt, &UInt256, &PublicKey>::Proof(block_id),
+            ];
+            for entry_id in entries.iter() {
+                let data = match entry_id {
+                    PackageEntryId::Proof(_) => self.load_block_proof(&handle, ProofKind::Proof).await,
+                    _ => self.archive_manager.get_file(&handle, entry_id).await,
+                };
+                match data {
+                    Ok(data) => manifest.add_file(dest, entry_id.filename(), &data).await?,
+                    Err(err) if block_id == &mc_block_id => fail!("Can't export {}: {}", entry_id, err),
+                    Err(err) => log::warn!(target: "storage", "Key block entry {} is not exported: {}", entry_id, err),
+                }
+            }
+        }
+
+        manifest.write_to_dir(dest).await?;
+        log::info!(target: "storage", "Bootstrap snapshot of {} exported: {} files", mc_block_id, manifest.files.len());
+
+        Ok(manifest)
+    }
+
+    /// Imports the bootstrap snapshot exported by `export_bootstrap_snapshot` from the directory
+    /// `src`. All the files are verified against the manifest, and the blocks against their ids,
+    /// before anything is written; every state is checked against its block id (and against the
+    /// state hash of the block, if the block is in the snapshot) before it is stored. Persistent
+    /// states are stored into the persistent and the shard states databases with their blocks marked
+    /// applied and checkpointed, key blocks and proofs are put into the archive and the key blocks index.
+    /// Cancellation stops the import between the files; files already imported stay in the storage,
+    /// and the import may be repeated.
+    pub async fn import_bootstrap_snapshot(
+        &self,
+        src: impl AsRef<Path>,
+        cancellation: &CancellationToken,
+    ) -> Result<BootstrapManifest> {
+        let src = src.as_ref();
+        let manifest = BootstrapManifest::read_from_dir(src).await?;
+        let mc_block_id = BlockIdExt::from_filename(&manifest.mc_block)?;
+        log::info!(target: "storage", "Importing bootstrap snapshot of {} from {:?}", mc_block_id, src);
+
+        manifest.verify_files(src).await?;
+
+        // Blocks are small, so they are verified by a separate pass; hashes of their states are
+        // kept to verify the persistent states
+        let mut state_hashes = FnvHashMap::default();
+        for file in manifest.files.iter() {
+            cancellation.check()?;
+            if let PackageEntryId::Block(block_id) = PackageEntryId::from_filename(&file.entry)? {
+                let block = Self::verify_block_data(&block_id, &tokio::fs::read(src.join(&file.path)).await?)?;
+                state_hashes.insert(block_id, block.read_state_update()?.new_hash);
+            }
+        }
+
+        for file in manifest.files.iter() {
+            cancellation.check()?;
+            let data = tokio::fs::read(src.join(&file.path)).await?;
+            let entry_id = PackageEntryId::from_filename(&file.entry)?;
+            match &entry_id {
+                PackageEntryId::PersistentState { mc_block_id: state_mc_block_id, block_id } => {
+                    if state_mc_block_id != &mc_block_id {
+                        fail!("Persistent state {} doesn't belong to snapshot of {}", file.entry, mc_block_id)
+                    }
+                    self.import_persistent_state(block_id, data, state_hashes.get(block_id)).await?;
+                }
+                PackageEntryId::Block(block_id) => {
+                    let handle = self.block_handle_storage.load_block_handle(block_id)?;
+                    handle.fetch_block_info(&Self::verify_block_data(block_id, &data)?)?;
+                    self.archive_manager.add_file_to_archive(block_id.seq_no(), true, &entry_id, data).await?;
+                    handle.apply_transition(BlockFlags::DATA | BlockFlags::MOVED_TO_ARCHIVE)?;
+                    self.key_block_db.add_handle(&handle)?;
+                }
+                PackageEntryId::Proof(block_id) => {
+                    let handle = self.block_handle_storage.load_block_handle(block_id)?;
+                    self.block_info_db.store_proof(block_id, ProofKind::Proof, &data)?;
+                    handle.apply_transition(BlockFlags::PROOF)?;
+                }
+                _ => fail!("Unexpected entry {} in bootstrap snapshot", file.entry),
+            }
+        }
+
+        log::info!(target: "storage", "Bootstrap snapshot of {} imported: {} files", mc_block_id, manifest.files.len());
+
+        Ok(manifest)
+    }
+
+    /// Ids of the top shard blocks of the masterchain block, taken from its state (the stored one,
+    /// or the persistent one if the former is collected)
+    fn shard_top_blocks(&self, mc_block_id: &BlockIdExt, persistent_state: &[u8]) -> Result<Vec<BlockIdExt>> {
+        let root = match self.shard_state_db.get(&BlockId::from(mc_block_id)) {
+            Ok(root) => root,
+            Err(_) => deserialize_tree_of_cells(&mut Cursor::new(persistent_state))?,
+        };
+        let state = ShardStateUnsplit::construct_from(&mut SliceData::from(root))?;
+        let extra = state.read_custom()?
+            .ok_or_else(|| error!("State of {} is not a masterchain state", mc_block_id))?;
+
+        let mut result = Vec::new();
+        extra.shards().iterate_shards(|shard_id, descr| {
+            result.push(BlockIdExt {
+                shard_id,
+                seq_no: descr.seq_no,
+                root_hash: descr.root_hash,
+                file_hash: descr.file_hash,
+            });
+            Ok(true)
+        })?;
+
+        Ok(result)
+    }
+
+    /// Checks that the block data matches the file and root hashes of the id, returns the block
+    fn verify_block_data(block_id: &BlockIdExt, data: &[u8]) -> Result<Block> {
+        let file_hash = UInt256::from(Sha256::digest(data).as_slice());
+        if file_hash != block_id.file_hash {
+            fail!("Data of block {} has wrong file hash {:x}", block_id, file_hash)
+        }
+        let root = deserialize_tree_of_cells(&mut Cursor::new(data))?;
+        if root.repr_hash() != block_id.root_hash {
+            fail!("Data of block {} has wrong root hash {:x}", block_id, root.repr_hash())
+        }
+
+        Block::construct_from(&mut SliceData::from(root))
+    }
+
+    /// Imports the state after checking it belongs to the block (and has the hash given, if any)
+    async fn import_persistent_state(
+        &self,
+        block_id: &BlockIdExt,
+        data: Vec<u8>,
+        state_hash: Option<&UInt256>,
+    ) -> Result<()> {
+        let root = deserialize_tree_of_cells(&mut Cursor::new(&data))?;
+        if let Some(state_hash) = state_hash {
+            if &root.repr_hash() != state_hash {
+                fail!("Persistent state of {} has wrong root hash {:x}", block_id, root.repr_hash())
+            }
+        }
+        let state = ShardStateUnsplit::construct_from(&mut SliceData::from(root.clone()))?;
+        if state.shard() != block_id.shard() || state.seq_no() != block_id.seq_no() {
+            fail!(
+                "Persistent state of {}:{} doesn't belong to block {}",
+                state.shard(),
+                state.seq_no(),
+                block_id
+            )
+        }
+        let handle = self.block_handle_storage.load_block_handle(block_id)?;
+        handle.fetch_shard_state(&state)?;
+
+        let key = BlockId::from(block_id);
+        self.shard_state_persistent_db.put(&key, &data).await?;
+        self.shard_state_db.put(&key, root)?;
+        handle.apply_transition(BlockFlags::PERSISTENT_STATE | BlockFlags::STATE)?;
+        self.set_block_applied(&handle)?;
+        self.apply_checkpoint_db.reset(block_id.shard(), Some(block_id))?;
+
+        Ok(())
+    }
+
+    /// Removes handles of fully pruned blocks (i.e. having neither files, unapplied or archived, nor
+    /// stored shard state) below the safety horizon. Handles of key blocks and of blocks retained by
+    /// the retention configuration are never removed. The candidates are re-checked under the
+    /// handle's lock right before the removal, so handles changed concurrently (e.g. by `save_block`
+    /// or the move to the archive) are kept. Returns count of removed handles.
+    pub async fn remove_orphan_handles(&self, config: &HandlesCompactionConfig) -> Result<usize> {
+        let retention = self.retention();
+        if !retention.compacts_handles() {
+            log::debug!(target: "storage", "Handles compaction is disabled by retention profile {:?}", retention.profile);
+            return Ok(0);
+        }
+        log::info!(
+            target: "storage",
+            "Orphan block handles removal started (horizon = {}, batch size = {})",
+            config.safety_horizon_mc_seq_no,
+            config.batch_size
+        );
+
+        let shardstate_db = self.shard_state_db.shardstate_db();
+        let now = self.clock.now();
+        let mut candidates = Vec::new();
+        self.block_handle_storage.for_each_stored_handle(|id, block_meta| {
+            let is_key_block = block_meta.flags().load(Ordering::Relaxed) & FLAG_KEY_BLOCK != 0;
+            if !retention.allows_block_removal(block_meta.gen_utime().load(Ordering::Relaxed), is_key_block, now) {
+                return Ok(true);
+            }
+            let mc_seq_no = if id.shard().is_masterchain() {
+                id.seq_no()
+            } else {
+                block_meta.masterchain_ref_seq_no().load(Ordering::Relaxed)
+            };
+            // Zero masterchain reference of a shard block is unknown one: the handle is kept
+            if (mc_seq_no == 0 && !id.shard().is_masterchain())
+                || mc_seq_no >= config.safety_horizon_mc_seq_no
+                || shardstate_db.contains(&BlockId::from(&id))?
+            {
+                return Ok(true);
+            }
+            // Data and proofs of the block not moved to the archive are still stored (the data in
+            // the unapplied directory, the proofs in BlockInfoDb)
+            let flags = block_meta.flags().load(Ordering::Relaxed);
+            if flags & FLAG_MOVED_TO_ARCHIVE == 0 && flags & BLOCK_FILE_FLAGS != 0 {
+                return Ok(true);
+            }
+            candidates.push((id, mc_seq_no));
+
+            Ok(true)
+        })?;
+
+        let mut removed = 0;
+        for (id, mc_seq_no) in candidates {
+            if removed >= config.batch_size {
+                break;
+            }
+            let handle = self.block_handle_storage.load_block_handle(&id)?;
+            let _locked = handle.temp_lock().write().await;
+            if !self.is_orphan_handle(&handle, mc_seq_no).await?
+                || !self.block_handle_storage.delete_unused_block_handle(&handle)?
+            {
+                continue;
+            }
+            log::debug!(target: "storage", "Orphan block handle removed: {}", id);
+            removed += 1;
+        }
+
+        log::info!(target: "storage", "Orphan block handles removal finished, {} handles removed", removed);
+
+        Ok(removed)
+    }
+
+    /// Checks the current state of the candidate handle: it is an orphan if the block has no stored
+    /// shard state and its files are neither unapplied nor reachable in the archive (the archive is
+    /// gone or the block is pruned)
+    async fn is_orphan_handle(&self, handle: &BlockHandle, mc_seq_no: u32) -> Result<bool> {
+        if self.shard_state_db.shardstate_db().contains(&BlockId::from(handle.id()))? {
+            return Ok(false);
+        }
+        let flags = handle.meta().flags().load(Ordering::Relaxed);
+        if flags & FLAG_MOVED_TO_ARCHIVE == 0 {
+            return Ok(flags & BLOCK_FILE_FLAGS == 0);
+        }
+
+        Ok(flags & FLAG_PRUNED != 0 || self.archive_manager.get_archive_id(McSeqNo::new(mc_seq_no)).await.is_none())
+    }
+
+    /// Preloads the data needed right after the node start: top cells of the masterchain state of
+    /// the last masterchain block, handles of the recent masterchain blocks and the LtDesc records.
+    /// The three run in parallel, each on the thread of its own (so the database reads don't block
+    /// the caller's runtime). Returns early when the budget is exhausted; the preloaded data stays
+    /// cached while the result is alive. Depths, limits and the budget are taken from the storage
+    /// configuration.
+    pub async fn warm_up(
+        &self,
+        last_mc_block: &BlockIdExt,
+        progress: impl Fn(WarmUpStage, usize) + Send + Sync + 'static,
+    ) -> Result<WarmUpResult> {
+        let config = &self.config.warm_up;
+        let started = Instant::now();
+        let deadline = started + config.budget;
+        let progress: Arc<dyn Fn(WarmUpStage, usize) + Send + Sync> = Arc::new(progress);
+        log::info!(target: "storage", "Warm-up started from {}", last_mc_block);
+
+        let cells = {
+            let (shard_state_db, progress) = (Arc::clone(&self.shard_state_db), Arc::clone(&progress));
+            let (block_id, depth, max_cells) = (BlockId::from(last_mc_block), config.state_depth, config.max_cells);
+            run_blocking("state cells", move || {
+                let root = shard_state_db.get(&block_id)?;
+                preload_cells(root, depth, max_cells, deadline, &*progress)
+            })
+        };
+        let handles = {
+            let block_index_db = Arc::clone(&self.block_index_db);
+            let block_handle_storage = Arc::clone(&self.block_handle_storage);
+            let (last_mc_block, count, progress) = (last_mc_block.clone(), config.recent_mc_blocks, Arc::clone(&progress));
+            run_blocking("block handles", move || Self::preload_recent_mc_handles(
+                &block_index_db,
+                &block_handle_storage,
+                &last_mc_block,
+                count,
+                deadline,
+                &*progress,
+            ))
+        };
+        let lt_descs = {
+            let (block_index_db, progress) = (Arc::clone(&self.block_index_db), Arc::clone(&progress));
+            run_blocking("LtDesc records", move || {
+                let result = block_index_db.preload_lt_descs(deadline)?;
+                progress(WarmUpStage::LtDescs, result.0);
+                Ok(result)
+            })
+        };
+
+        let (cells, handles, lt_descs) = futures::join!(cells, handles, lt_descs);
+        let ((cells, cells_completed), (handles, handles_completed), (lt_descs, lt_descs_completed)) =
+            (cells?, handles?, lt_descs?);
+
+        let result = WarmUpResult {
+            completed: cells_completed && handles_completed && lt_descs_completed,
+            cells,
+            handles,
+            lt_descs,
+        };
+        log::info!(
+            target: "storage",
+            "Warm-up {} in {} ms: {} cells, {} handles, {} LtDesc records",
+            if result.completed { "finished" } else { "interrupted" },
+            started.elapsed().as_millis(),
+            result.cells.len(),
+            result.handles.len(),
+            result.lt_descs
+        );
+
+        Ok(result)
+    }
+
+    fn preload_recent_mc_handles(
+        block_index_db: &BlockIndexDb,
+        block_handle_storage: &BlockHandleStorage,
+        last_mc_block: &BlockIdExt,
+        count: u32,
+        deadline: Instant,
+        progress: &(dyn Fn(WarmUpStage, usize) + Send + Sync),
+    ) -> Result<(Vec<Arc<BlockHandle>>, bool)> {
+        const BATCH_SIZE: usize = 100;
+
+        let account_id = AccountIdPrefixFull {
+            workchain_id: last_mc_block.shard().workchain_id(),
+            prefix: last_mc_block.shard().shard_prefix_with_tag(),
+        };
+        let first_seq_no = last_mc_block.seq_no().saturating_sub(count.saturating_sub(1));
+        let mut handles = Vec::new();
+        let mut ids = vec![last_mc_block.clone()];
+        for seq_no in (first_seq_no..last_mc_block.seq_no()).rev() {
+            if Instant::now() >= deadline {
+                return Ok((handles, false));
+            }
+            // Blocks below the first unindexed one aren't needed by the node
+            match block_index_db.get_block_by_seq_no(&account_id, seq_no) {
+                Ok(id) => ids.push(id),
+                Err(_) => break,
+            }
+            if ids.len() >= BATCH_SIZE {
+                handles.append(&mut block_handle_storage.preload_handles(&ids)?);
+                ids.clear();
+                progress(WarmUpStage::BlockHandles, handles.len());
+            }
+        }
+        handles.append(&mut block_handle_storage.preload_handles(&ids)?);
+        progress(WarmUpStage::BlockHandles, handles.len());
+
+        Ok((handles, true))
+    }
+
+    /// Creates scrubber of the cells database configured by the storage configuration; the scrubber
+    /// keeps its progress in the node state database
+    pub fn cell_db_scrubber(&self) -> CellDbScrubber {
+        CellDbScrubber::new(
+            Arc::clone(self.shard_state_db.cell_db()),
+            Arc::clone(&self.node_state_db),
+            self.config.scrubber.clone(),
+        )
+    }
+
+    /// Starts scrubbing pass of the cells database as a background task. The pass is stopped
+    /// after the current batch on shutdown; corrupted cells are reported to the log.
+    pub fn start_cell_db_scrubber(&self) -> Result<()> {
+        let scrubber = Arc::new(self.cell_db_scrubber());
+        self.background_tasks.spawn("cell_db_scrubber", move |shutdown| async move {
+            let run = scrubber.run(|_cell_id, _corruption| {});
+            let requested = shutdown.requested();
+            futures::pin_mut!(run, requested);
+            let result = match futures::future::select(run, requested).await {
+                Either::Left((result, _requested)) => result,
+                Either::Right(((), run)) => {
+                    scrubber.stop();
+                    run.await
+                }
+            };
+            if let Err(err) = result {
+                log::error!(target: "storage", "CellDb scrubbing failed: {}", err);
+            }
+        })
+    }
+
+    /// Returns estimated element counts and sizes of the key-value collections. Cheap, so it is
+    /// suitable for periodic monitoring.
+    pub fn collection_stats(&self) -> Result<Vec<CollectionStats>> {
+        Ok(vec![
+            collection_stats("block_handle_db", &***self.block_handle_storage.block_handle_db())?,
+            collection_stats("block_by_hash_index", self.block_by_hash_index.db())?,
+            collection_stats("block_by_shard_index", self.block_by_shard_index.db())?,
+            collection_stats("lt_desc_db", &**self.block_index_db.lt_desc_db().read())?,
+            collection_stats("lt_db", &**self.block_index_db.lt_db().read())?,
+            collection_stats("block_info_db", &*self.block_info_db)?,
+            collection_stats("blob_db", &**self.blob_store.db())?,
+            collection_stats("key_block_db", &*self.key_block_db)?,
+            collection_stats("shardstate_db", &*self.shard_state_db.shardstate_db())?,
+            collection_stats("cells_db", &***self.shard_state_db.cell_db())?,
+            collection_stats("apply_checkpoint_db", &*self.apply_checkpoint_db)?,
+            collection_stats("chain_head_db", &*self.chain_head_db)?,
+            collection_stats("node_state_db", &**self.node_state_db)?,
+            collection_stats("state_pins_db", &**self.state_pins_db)?,
+            collection_stats("diagnostics_db", &**self.slow_op_recorder.db())?,
+            collection_stats("catchain_persistent_db", &*self.catchain_persistent_db)?,
+            collection_stats("zerostate_db", &*self.zerostate_db)?,
+        ])
+    }
+
+    /// Returns latency histograms and traffic of the key-value collections; empty unless the
+    /// instrumentation is enabled by `diagnostics.metrics` of the configuration
+    pub fn snapshot_metrics(&self) -> Vec<CollectionMetricsSnapshot> {
+        crate::metrics::snapshot_metrics()
+    }
+
+    /// Flushes and fully compacts all the key-value collections. Intended to be called after bulk
+    /// imports (e.g. fast sync) in order to reclaim disk space. Blocking and might take a long time.
+    pub fn optimize(&self) -> Result<()> {
+        log::info!(target: "storage", "Optimizing node storage...");
+
+        optimize_collection("block_handle_db", &***self.block_handle_storage.block_handle_db())?;
+        optimize_collection("block_by_hash_index", self.block_by_hash_index.db())?;
+        optimize_collection("block_by_shard_index", self.block_by_shard_index.db())?;
+        optimize_collection("lt_desc_db", &**self.block_index_db.lt_desc_db().read())?;
+        optimize_collection("lt_db", &**self.block_index_db.lt_db().read())?;
+        optimize_collection("block_info_db", &*self.block_info_db)?;
+        optimize_collection("blob_db", &**self.blob_store.db())?;
+        optimize_collection("key_block_db", &*self.key_block_db)?;
+        optimize_collection("shardstate_db", &*self.shard_state_db.shardstate_db())?;
+        optimize_collection("cells_db", &***self.shard_state_db.cell_db())?;
+        optimize_collection("apply_checkpoint_db", &*self.apply_checkpoint_db)?;
+        optimize_collection("chain_head_db", &*self.chain_head_db)?;
+        optimize_collection("node_state_db", &**self.node_state_db)?;
+        optimize_collection("state_pins_db", &**self.state_pins_db)?;
+        optimize_collection("diagnostics_db", &**self.slow_op_recorder.db())?;
+        optimize_collection("catchain_persistent_db", &*self.catchain_persistent_db)?;
+        optimize_collection("zerostate_db", &*self.zerostate_db)?;
+
+        log::info!(target: "storage", "Node storage optimization finished");
+
+        Ok(())
+    }
+
+    /// Reclaims disk space after pruning: fully compacts all the key-value collections, rewrites
+    /// sparse archive packages and removes empty package directories. Reports disk usage of every
+    /// component before and after. Intended for offline maintenance: the storage must not be used
+    /// by the node meanwhile.
+    pub async fn shrink(&self) -> Result<ShrinkReport> {
+        log::info!(target: "storage", "Shrinking node storage...");
+        let usage_before = components_usage(&self.db_root_path)?;
+
+        self.optimize()?;
+        let (packages_size_before, packages_size_after) = self.archive_manager.compact_packages().await?;
+        let removed_dirs = self.archive_manager.remove_empty_package_dirs().await?;
+
+        let report = ShrinkReport {
+            components: merge_usage(usage_before, components_usage(&self.db_root_path)?),
+            packages_size_before,
+            packages_size_after,
+            removed_dirs,
+        };
+        log::info!(
+            target: "storage",
+            "Node storage shrink finished: {} -> {} bytes",
+            report.total_before(),
+            report.total_after()
+        );
+
+        Ok(report)
+    }
+}
+
+fn collection_stats<T: Kvc + ?Sized>(name: &'static str, kvc: &T) -> Result<CollectionStats> {
+    Ok(CollectionStats {
+        name,
+        approx_len: kvc.approx_len()?,
+        approx_size_bytes: kvc.approx_size_bytes()?,
+    })
+}
+
+fn optimize_collection<T: Kvc + ?Sized>(name: &str, kvc: &T) -> Result<()> {
+    log::info!(target: "storage", "Flushing {}...", name);
+    kvc.flush()?;
+    log::info!(target: "storage", "Compacting {}...", name);
+    kvc.compact_range(None, None)?;
+    log::info!(target: "storage", "{} is optimized", name);
+
+    Ok(())
+}
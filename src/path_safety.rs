@@ -0,0 +1,132 @@
+use std::path::{Path, PathBuf};
+
+use sha2::{Digest, Sha256};
+use ton_types::{fail, Result, UInt256};
+
+use crate::db::traits::KvcWriteable;
+use crate::db_impl_base;
+
+/// Maximal length (in bytes) of the filename kept as is. Longer names are replaced by hashes,
+/// so that full paths fit into the path length limits of all supported platforms.
+pub const MAX_FILENAME_LEN: usize = 128;
+const HASHED_FILENAME_PREFIX: &str = "h_";
+const RESERVED_CHARS: &[char] = &['<', '>', ':', '"', '/', '\\', '|', '?', '*'];
+
+/// Checks that the filename is a single path component valid on all supported platforms
+pub fn validate_filename(filename: &str) -> Result<()> {
+    if filename.is_empty() || filename == "." || filename == ".." {
+        fail!("Invalid filename: {:?}", filename)
+    }
+    if filename.chars().any(|c| c.is_control() || RESERVED_CHARS.contains(&c)) {
+        fail!("Filename contains reserved characters: {:?}", filename)
+    }
+    if filename.ends_with('.') || filename.ends_with(' ') {
+        fail!("Filename ends with dot or space: {:?}", filename)
+    }
+
+    Ok(())
+}
+
+/// Builds relative path from the string with either '/' or '\' separators, skipping empty and
+/// "current dir" components. Fails on "parent dir" components.
+pub fn normalize_separators(path: &str) -> Result<PathBuf> {
+    let mut result = PathBuf::new();
+    for component in path.split(|c| c == '/' || c == '\\') {
+        match component {
+            "" | "." => continue,
+            ".." => fail!("Parent dir components are not allowed: {:?}", path),
+            _ => result.push(component),
+        }
+    }
+
+    Ok(result)
+}
+
+/// Returns fixed-length filename derived from the hash of the original one
+pub fn hashed_filename(filename: &str) -> String {
+    format!("{}{}", HASHED_FILENAME_PREFIX, hex::encode(Sha256::digest(filename.as_bytes())))
+}
+
+/// Returns relative path of the file in the two-level fan-out directory layout: the directories
+/// are named by the first two bytes of the filename's hash, so no directory grows too large
+pub fn fan_out_path(filename: &str) -> PathBuf {
+    let hash = Sha256::digest(filename.as_bytes());
+    let mut path = PathBuf::from(hex::encode(&hash[..1]));
+    path.push(hex::encode(&hash[1..2]));
+    path.push(filename);
+
+    path
+}
+
+fn is_safe_filename(filename: &str) -> bool {
+    filename.len() <= MAX_FILENAME_LEN
+        && !filename.starts_with(HASHED_FILENAME_PREFIX)
+        && validate_filename(filename).is_ok()
+}
+
+db_impl_base!(FileNameDb, KvcWriteable, UInt256);
+
+/// Maps arbitrary filenames to names safe to be used on all supported platforms. Over-long and
+/// invalid names are replaced by hashes; original names are kept in the sidecar database.
+#[derive(Debug)]
+pub struct SafeFileNames {
+    db: FileNameDb,
+}
+
+impl SafeFileNames {
+    pub fn with_db(db: FileNameDb) -> Self {
+        Self { db }
+    }
+
+    pub fn in_memory() -> Self {
+        Self::with_db(FileNameDb::in_memory())
+    }
+
+    pub fn with_path(path: impl AsRef<Path>) -> Self {
+        Self::with_db(FileNameDb::with_path(path))
+    }
+
+    /// Returns safe filename for the original one, registering the mapping if the name is hashed
+    pub fn safe_filename(&self, filename: &str) -> Result<String> {
+        if is_safe_filename(filename) {
+            return Ok(filename.to_string());
+        }
+
+        let hash = UInt256::from(Sha256::digest(filename.as_bytes()).as_slice());
+        if !self.db.contains(&hash)? {
+            self.db.put(&hash, filename.as_bytes())?;
+        }
+
+        Ok(hashed_filename(filename))
+    }
+
+    /// Returns original filename for the safe one
+    pub fn original_filename(&self, safe_filename: &str) -> Result<String> {
+        match Self::parse_hash(safe_filename) {
+            Some(hash) => match self.db.try_get(&hash)? {
+                Some(value) => Ok(String::from_utf8(value.to_vec())?),
+                None => fail!("Original filename is not found for {}", safe_filename),
+            },
+            None => Ok(safe_filename.to_string()),
+        }
+    }
+
+    /// Removes mapping of the safe filename (if any)
+    pub fn forget(&self, safe_filename: &str) -> Result<()> {
+        if let Some(hash) = Self::parse_hash(safe_filename) {
+            self.db.delete(&hash)?;
+        }
+
+        Ok(())
+    }
+
+    fn parse_hash(safe_filename: &str) -> Option<UInt256> {
+        let hex_hash = safe_filename.strip_prefix(HASHED_FILENAME_PREFIX)?;
+        let bytes = hex::decode(hex_hash).ok()?;
+        if bytes.len() != 32 {
+            return None;
+        }
+
+        Some(UInt256::from(bytes.as_slice()))
+    }
+}
@@ -0,0 +1,144 @@
+use std::convert::TryInto;
+use std::sync::Arc;
+use std::sync::atomic::{AtomicU32, Ordering};
+
+use ton_block::BlockIdExt;
+use ton_types::{error, Result};
+
+use crate::block_handle_db::BlockHandleDb;
+use crate::clock::{Clock, system_clock};
+use crate::key_block_db::KeyBlockDb;
+use crate::node_state_db::NodeStateDb;
+use crate::retention_profile::RetentionConfig;
+use crate::traits::Serializable;
+use crate::types::{BlockId, FLAG_PERSISTENT_STATE, McSeqNo, NodeStateKey};
+
+/// Default count of masterchain blocks retained below the last persistent state
+pub const DEFAULT_PRUNING_MARGIN: u32 = 1000;
+
+/// Safety horizon shared by GC of shard states and pruning of archives. Everything related to
+/// masterchain blocks with seq_no below the horizon may be deleted; everything at or above it
+/// must be retained. The horizon is derived from the last key block having persistent state
+/// (minus the margin), is persisted in NodeStateDb and never goes down.
+#[derive(Debug)]
+pub struct PruningCoordinator {
+    node_state_db: Arc<NodeStateDb>,
+    margin: AtomicU32,
+    horizon: AtomicU32,
+    clock: Arc<dyn Clock>,
+}
+
+impl PruningCoordinator {
+    /// Loads persisted horizon (zero, i.e. nothing may be deleted, if there is none)
+    pub fn with_node_state_db(node_state_db: Arc<NodeStateDb>, margin: u32) -> Result<Self> {
+        let horizon = match node_state_db.try_get(&NodeStateKey::PruningHorizon)? {
+            Some(value) => u32::from_le_bytes(value.as_ref().try_into()
+                .map_err(|_| error!("Bad value of {}", NodeStateKey::PruningHorizon.as_str()))?),
+            None => 0,
+        };
+        log::info!(target: "storage", "Pruning horizon is {}", horizon);
+
+        Ok(Self {
+            node_state_db,
+            margin: AtomicU32::new(margin),
+            horizon: AtomicU32::new(horizon),
+            clock: system_clock(),
+        })
+    }
+
+    /// Makes the coordinator check the blocks TTL against the clock instead of the system time
+    pub fn with_clock(mut self, clock: Arc<dyn Clock>) -> Self {
+        self.clock = clock;
+        self
+    }
+
+    /// Count of masterchain blocks retained below the last persistent state
+    pub fn margin(&self) -> u32 {
+        self.margin.load(Ordering::SeqCst)
+    }
+
+    pub fn set_margin(&self, value: u32) {
+        self.margin.store(value, Ordering::SeqCst)
+    }
+
+    /// Minimal masterchain seq_no whose blocks and states must be retained
+    pub fn min_retained_mc_seqno(&self) -> McSeqNo {
+        McSeqNo::new(self.horizon.load(Ordering::SeqCst))
+    }
+
+    /// Returns true if the data related to given masterchain seq_no may be deleted
+    pub fn allows(&self, mc_seq_no: McSeqNo) -> bool {
+        mc_seq_no < self.min_retained_mc_seqno()
+    }
+
+    /// Raises the horizon up to given masterchain seq_no (the horizon never goes down).
+    /// Returns the resulting horizon.
+    pub fn raise_horizon(&self, mc_seq_no: McSeqNo) -> Result<McSeqNo> {
+        let previous = McSeqNo::new(self.horizon.fetch_max(mc_seq_no.value(), Ordering::SeqCst));
+        if previous < mc_seq_no {
+            self.node_state_db.put(&NodeStateKey::PruningHorizon, &mc_seq_no.to_vec()?)?;
+            log::info!(target: "storage", "Pruning horizon raised to {}", mc_seq_no);
+            return Ok(mc_seq_no);
+        }
+
+        Ok(previous)
+    }
+
+    /// Recomputes the horizon from the last indexed key block whose persistent state is stored.
+    /// Returns the resulting horizon.
+    pub fn update(&self, key_block_db: &KeyBlockDb, block_handle_db: &BlockHandleDb) -> Result<McSeqNo> {
+        match Self::scan_key_blocks(key_block_db, block_handle_db, None)?.0 {
+            Some(seq_no) => self.raise_horizon(seq_no.saturating_sub(self.margin())),
+            None => Ok(self.min_retained_mc_seqno()),
+        }
+    }
+
+    /// Recomputes the horizon as `update` does, additionally keeping the blocks retained by the
+    /// configuration: the horizon doesn't go above the last key block older than the blocks TTL
+    /// and isn't raised at all if the blocks are kept forever. Returns the resulting horizon.
+    pub fn update_with_retention(
+        &self,
+        key_block_db: &KeyBlockDb,
+        block_handle_db: &BlockHandleDb,
+        retention: &RetentionConfig,
+    ) -> Result<McSeqNo> {
+        let block_ttl = match retention.block_ttl() {
+            Some(block_ttl) => block_ttl,
+            None => return Ok(self.min_retained_mc_seqno()),
+        };
+        let expired_before = self.clock.now().saturating_sub(block_ttl);
+        match Self::scan_key_blocks(key_block_db, block_handle_db, Some(expired_before))? {
+            (Some(persistent_seq_no), Some(expired_seq_no)) => self.raise_horizon(
+                std::cmp::min(persistent_seq_no.saturating_sub(self.margin()), expired_seq_no)
+            ),
+            _ => Ok(self.min_retained_mc_seqno()),
+        }
+    }
+
+    /// Returns seq_no of the last key block whose persistent state is stored and seq_no of the last
+    /// key block generated before `expired_before` (if given)
+    fn scan_key_blocks(
+        key_block_db: &KeyBlockDb,
+        block_handle_db: &BlockHandleDb,
+        expired_before: Option<u32>,
+    ) -> Result<(Option<McSeqNo>, Option<McSeqNo>)> {
+        let mut persistent = None;
+        let mut expired = None;
+        key_block_db.for_each(&mut |_key, value| {
+            let block_id = BlockIdExt::from_slice(value)?;
+            if let Some(block_meta) = block_handle_db.try_get_value(&BlockId::from(&block_id))? {
+                if block_meta.flags().load(Ordering::Relaxed) & FLAG_PERSISTENT_STATE != 0 {
+                    persistent = Some(McSeqNo::new(block_id.seq_no()));
+                }
+                if let Some(expired_before) = expired_before {
+                    if block_meta.gen_utime().load(Ordering::Relaxed) < expired_before {
+                        expired = Some(McSeqNo::new(block_id.seq_no()));
+                    }
+                }
+            }
+            Ok(true)
+        })?;
+
+        Ok((persistent, expired))
+    }
+}
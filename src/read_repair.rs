@@ -0,0 +1,49 @@
+use ton_types::{Cell, Result, UInt256};
+
+use crate::types::CellId;
+
+/// Reference of the stored cell pointing to the cell missing in the database
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub struct MissingReference {
+    /// Cell holding the reference
+    pub parent: CellId,
+    /// Index of the reference within the parent
+    pub index: usize,
+    /// Representation hash of the missing cell
+    pub hash: UInt256,
+}
+
+/// Results of the read repair pass over the tree of cells
+#[derive(Debug, Clone, Default)]
+pub struct ReadRepairReport {
+    /// Count of the stored cells visited
+    pub visited: usize,
+    /// All the dangling references found, in the order of traversal
+    pub missing: Vec<MissingReference>,
+    /// Missing cells supplied by the resolver and written (with their subtrees)
+    pub supplied: Vec<CellId>,
+    /// Parents re-persisted after all their missing children were supplied
+    pub repersisted: Vec<CellId>,
+    /// Dangling references left: all the missing ones if no resolver was given, otherwise those
+    /// the resolver couldn't supply (or supplied cells with wrong hashes for)
+    pub unresolved: Vec<MissingReference>,
+}
+
+impl ReadRepairReport {
+    /// Returns true if the tree has no dangling references left
+    pub fn is_complete(&self) -> bool {
+        self.unresolved.is_empty()
+    }
+}
+
+/// Source of the cells missing in the database (e.g. an archived state or a peer)
+pub trait MissingCellResolver {
+    /// Returns the cell (with its subtree) by the representation hash; Ok(None) if it is unknown
+    fn resolve(&self, hash: &UInt256) -> Result<Option<Cell>>;
+}
+
+impl<F: Fn(&UInt256) -> Result<Option<Cell>>> MissingCellResolver for F {
+    fn resolve(&self, hash: &UInt256) -> Result<Option<Cell>> {
+        self(hash)
+    }
+}
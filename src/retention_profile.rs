@@ -0,0 +1,137 @@
+use serde_derive::{Deserialize, Serialize};
+
+use ton_types::{fail, Result};
+
+/// Default time shard states of non-key blocks are kept for
+pub const DEFAULT_SHARD_STATE_TTL: u32 = 3600 * 24;
+
+/// Default count of days blocks are kept for by the full node
+pub const DEFAULT_FULL_NODE_DAYS: u32 = 7;
+
+const SECONDS_PER_DAY: u32 = 3600 * 24;
+
+/// Built-in profile of the block data retention
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Deserialize, Serialize)]
+#[serde(rename_all = "snake_case")]
+pub enum RetentionProfile {
+    /// Everything is kept forever
+    ArchiveNode,
+    /// Blocks of the last `days` days and all the key blocks are kept
+    FullNode { days: u32 },
+    /// Only key blocks and recent states are kept
+    Light,
+}
+
+/// Retention configuration interpreted by the archives GC, the states GC and the block handles
+/// compaction. Options left unset take the profile's defaults; options contradicting the profile
+/// are rejected by `validate`.
+#[derive(Debug, Clone, PartialEq, Deserialize, Serialize)]
+#[serde(default)]
+pub struct RetentionConfig {
+    pub profile: RetentionProfile,
+    /// Time (in seconds) shard states of non-key blocks are kept for
+    pub state_ttl: Option<u32>,
+    /// Whether whole archives below the pruning horizon are deleted
+    pub prune_archives: Option<bool>,
+    /// Whether handles of fully pruned blocks are removed
+    pub compact_handles: Option<bool>,
+}
+
+impl Default for RetentionConfig {
+    fn default() -> Self {
+        Self::new(RetentionProfile::FullNode { days: DEFAULT_FULL_NODE_DAYS })
+    }
+}
+
+impl RetentionConfig {
+    pub const fn new(profile: RetentionProfile) -> Self {
+        Self {
+            profile,
+            state_ttl: None,
+            prune_archives: None,
+            compact_handles: None,
+        }
+    }
+
+    /// Checks the options for conflicts with the profile
+    pub fn validate(&self) -> Result<()> {
+        match self.profile {
+            RetentionProfile::ArchiveNode => {
+                if self.state_ttl.is_some() {
+                    fail!("Archive node profile keeps all the states, state TTL can't be set")
+                }
+                if self.prune_archives == Some(true) {
+                    fail!("Archive node profile keeps all the archives, archives pruning can't be enabled")
+                }
+                if self.compact_handles == Some(true) {
+                    fail!("Archive node profile keeps all the block handles, handles compaction can't be enabled")
+                }
+            },
+            RetentionProfile::FullNode { days } => {
+                if days == 0 {
+                    fail!("Full node profile must keep blocks for at least one day (use light profile instead)")
+                }
+                if let Some(state_ttl) = self.state_ttl {
+                    if state_ttl > days.saturating_mul(SECONDS_PER_DAY) {
+                        fail!(
+                            "State TTL ({} s) exceeds blocks retention time of the full node profile ({} days)",
+                            state_ttl,
+                            days
+                        )
+                    }
+                }
+            },
+            RetentionProfile::Light => {
+                if self.prune_archives == Some(false) {
+                    fail!("Light profile doesn't keep the archives, archives pruning can't be disabled")
+                }
+                if self.compact_handles == Some(false) {
+                    fail!("Light profile doesn't keep the block handles, handles compaction can't be disabled")
+                }
+            },
+        }
+
+        Ok(())
+    }
+
+    /// Time (in seconds) shard states of non-key blocks are kept for; None if they are kept forever
+    pub fn state_ttl(&self) -> Option<u32> {
+        match self.profile {
+            RetentionProfile::ArchiveNode => None,
+            RetentionProfile::FullNode { .. } | RetentionProfile::Light =>
+                Some(self.state_ttl.unwrap_or(DEFAULT_SHARD_STATE_TTL)),
+        }
+    }
+
+    /// Time (in seconds) non-key blocks are kept for; None if they are kept forever
+    pub fn block_ttl(&self) -> Option<u32> {
+        match self.profile {
+            RetentionProfile::ArchiveNode => None,
+            RetentionProfile::FullNode { days } => Some(days.saturating_mul(SECONDS_PER_DAY)),
+            RetentionProfile::Light => Some(0),
+        }
+    }
+
+    pub fn prunes_archives(&self) -> bool {
+        self.prune_archives.unwrap_or(self.profile != RetentionProfile::ArchiveNode)
+    }
+
+    pub fn compacts_handles(&self) -> bool {
+        self.compact_handles.unwrap_or(self.profile != RetentionProfile::ArchiveNode)
+    }
+
+    /// Returns true if the state of the non-key block generated at `gen_utime` may be collected at `utime`
+    pub fn allows_state_gc(&self, gen_utime: u32, utime: u32) -> bool {
+        self.state_ttl()
+            .map(|ttl| gen_utime.saturating_add(ttl) < utime)
+            .unwrap_or(false)
+    }
+
+    /// Returns true if the data of the block generated at `gen_utime` may be removed at `utime`.
+    /// Key blocks are kept by all the profiles.
+    pub fn allows_block_removal(&self, gen_utime: u32, is_key_block: bool, utime: u32) -> bool {
+        !is_key_block && self.block_ttl()
+            .map(|ttl| gen_utime.saturating_add(ttl) < utime)
+            .unwrap_or(false)
+    }
+}
@@ -0,0 +1,329 @@
+use std::fmt::{Display, Formatter};
+use std::io::Cursor;
+
+use ton_types::{CellData, fail, Result};
+
+use crate::archives::package_entry::PackageEntryHeader;
+use crate::cell_format::CellRecord;
+use crate::shardstate_db::DbEntry;
+use crate::traits::Serializable;
+use crate::types::{BlockMeta, LtDbEntry, LtDesc};
+
+/// Type of the field of the on-disk record. Integers are little-endian.
+#[derive(Debug, Clone, Copy)]
+pub enum FieldType {
+    U8,
+    U16,
+    U32,
+    U64,
+    I32,
+    /// Constant bytes identifying the record
+    Magic(&'static [u8]),
+    /// Bytes of the fixed length
+    Bytes(usize),
+    /// 32-byte hash
+    Hash,
+    /// Presence byte, followed by 32-byte hash if it is not zero
+    OptionalHash,
+    /// Bit length (u16), followed by the bits padded to the whole bytes
+    BitString,
+    /// Cell data serialized by ton_types
+    CellData,
+    /// Count of the hashes (u8), followed by the hashes
+    Hashes,
+    /// Length of the section (u16), followed by the fields of the section. Bytes of the section
+    /// not covered by the fields (appended by the later versions) are skipped.
+    Section(&'static [FieldSchema]),
+    /// The rest of the record as CBOR
+    Cbor,
+    /// The rest of the record as raw bytes
+    Tail,
+}
+
+impl FieldType {
+    /// Size of the field, if it is fixed
+    pub fn width(&self) -> Option<usize> {
+        match self {
+            FieldType::U8 => Some(1),
+            FieldType::U16 => Some(2),
+            FieldType::U32 | FieldType::I32 => Some(4),
+            FieldType::U64 => Some(8),
+            FieldType::Magic(magic) => Some(magic.len()),
+            FieldType::Bytes(len) => Some(*len),
+            FieldType::Hash => Some(32),
+            _ => None,
+        }
+    }
+
+    fn name(&self) -> &'static str {
+        match self {
+            FieldType::U8 => "u8",
+            FieldType::U16 => "u16",
+            FieldType::U32 => "u32",
+            FieldType::U64 => "u64",
+            FieldType::I32 => "i32",
+            FieldType::Magic(_) => "magic",
+            FieldType::Bytes(_) => "bytes",
+            FieldType::Hash => "hash",
+            FieldType::OptionalHash => "optional hash",
+            FieldType::BitString => "bit string",
+            FieldType::CellData => "cell data",
+            FieldType::Hashes => "hashes",
+            FieldType::Section(_) => "section",
+            FieldType::Cbor => "cbor",
+            FieldType::Tail => "tail",
+        }
+    }
+}
+
+/// Field of the on-disk record
+#[derive(Debug, Clone, Copy)]
+pub struct FieldSchema {
+    pub name: &'static str,
+    pub field_type: FieldType,
+    pub description: &'static str,
+}
+
+impl FieldSchema {
+    pub const fn new(name: &'static str, field_type: FieldType, description: &'static str) -> Self {
+        Self { name, field_type, description }
+    }
+}
+
+/// Layout of one version of the on-disk record. Records of several versions may be stored at the
+/// same time; `matches` tells which layout the raw value has.
+#[derive(Debug)]
+pub struct RecordSchema {
+    pub name: &'static str,
+    pub version: u32,
+    pub description: &'static str,
+    pub fields: &'static [FieldSchema],
+    pub matches: fn(&[u8]) -> bool,
+}
+
+impl RecordSchema {
+    /// Decodes the raw value field by field
+    pub fn decode(&'static self, data: &[u8]) -> Result<DecodedRecord> {
+        let mut decoder = Decoder { data, offset: 0, fields: Vec::new() };
+        decoder.decode_fields("", self.fields)?;
+        if decoder.offset != data.len() {
+            fail!(
+                "{} v{}: {} trailing bytes after offset {}",
+                self.name, self.version, data.len() - decoder.offset, decoder.offset
+            )
+        }
+
+        Ok(DecodedRecord { schema: self, fields: decoder.fields })
+    }
+}
+
+fn write_fields(f: &mut Formatter<'_>, prefix: &str, fields: &[FieldSchema]) -> std::fmt::Result {
+    for field in fields {
+        let width = field.field_type.width()
+            .map(|width| width.to_string())
+            .unwrap_or_else(|| "var".to_string());
+        let name = format!("{}{}", prefix, field.name);
+        writeln!(f, "    {: <32} {: <16} {: >5}  {}", name, field.field_type.name(), width, field.description)?;
+        if let FieldType::Section(section) = field.field_type {
+            write_fields(f, &format!("{}.", name), section)?;
+        }
+    }
+
+    Ok(())
+}
+
+impl Display for RecordSchema {
+    fn fmt(&self, f: &mut Formatter<'_>) -> std::fmt::Result {
+        writeln!(f, "{} v{}: {}", self.name, self.version, self.description)?;
+        write_fields(f, "", self.fields)
+    }
+}
+
+/// On-disk record type describing its layouts, one for each version still readable
+pub trait RecordSchemas {
+    const SCHEMAS: &'static [RecordSchema];
+}
+
+/// Returns schemas of all the on-disk record types
+pub fn storage_schema() -> Vec<&'static RecordSchema> {
+    let mut result = Vec::new();
+    result.extend(BlockMeta::SCHEMAS);
+    result.extend(DbEntry::SCHEMAS);
+    result.extend(LtDbEntry::SCHEMAS);
+    result.extend(LtDesc::SCHEMAS);
+    result.extend(PackageEntryHeader::SCHEMAS);
+    result.extend(CellRecord::SCHEMAS);
+
+    result
+}
+
+/// Decodes the raw value of the record type with given name, choosing the layout by the value
+pub fn decode_record(name: &str, data: &[u8]) -> Result<DecodedRecord> {
+    let mut known = false;
+    for schema in storage_schema() {
+        if schema.name.eq_ignore_ascii_case(name) {
+            known = true;
+            if (schema.matches)(data) {
+                return schema.decode(data);
+            }
+        }
+    }
+
+    if known {
+        fail!("Value of {} bytes matches no layout of {}", data.len(), name)
+    }
+    fail!("Unknown record type {}", name)
+}
+
+/// Value of the field decoded from the raw record
+#[derive(Debug, Clone)]
+pub struct DecodedField {
+    pub name: String,
+    pub offset: usize,
+    pub width: usize,
+    pub value: String,
+}
+
+/// Raw record decoded by its schema
+#[derive(Debug)]
+pub struct DecodedRecord {
+    pub schema: &'static RecordSchema,
+    pub fields: Vec<DecodedField>,
+}
+
+impl Display for DecodedRecord {
+    fn fmt(&self, f: &mut Formatter<'_>) -> std::fmt::Result {
+        writeln!(f, "{} v{}", self.schema.name, self.schema.version)?;
+        for field in self.fields.iter() {
+            writeln!(f, "    {:>6} {:>5}  {: <32} {}", field.offset, field.width, field.name, field.value)?;
+        }
+
+        Ok(())
+    }
+}
+
+struct Decoder<'a> {
+    data: &'a [u8],
+    offset: usize,
+    fields: Vec<DecodedField>,
+}
+
+impl<'a> Decoder<'a> {
+    fn take(&mut self, len: usize, name: &str) -> Result<&'a [u8]> {
+        match self.data.get(self.offset..self.offset + len) {
+            Some(bytes) => {
+                self.offset += len;
+                Ok(bytes)
+            }
+            None => fail!("Field {} at offset {} needs {} bytes, {} left", name, self.offset, len, self.data.len() - self.offset),
+        }
+    }
+
+    fn take_u8(&mut self, name: &str) -> Result<u8> {
+        Ok(self.take(1, name)?[0])
+    }
+
+    fn take_u16(&mut self, name: &str) -> Result<u16> {
+        let mut buf = [0; 2];
+        buf.copy_from_slice(self.take(2, name)?);
+        Ok(u16::from_le_bytes(buf))
+    }
+
+    fn take_u32(&mut self, name: &str) -> Result<u32> {
+        let mut buf = [0; 4];
+        buf.copy_from_slice(self.take(4, name)?);
+        Ok(u32::from_le_bytes(buf))
+    }
+
+    fn take_u64(&mut self, name: &str) -> Result<u64> {
+        let mut buf = [0; 8];
+        buf.copy_from_slice(self.take(8, name)?);
+        Ok(u64::from_le_bytes(buf))
+    }
+
+    fn decode_fields(&mut self, prefix: &str, fields: &'static [FieldSchema]) -> Result<()> {
+        for field in fields {
+            let name = format!("{}{}", prefix, field.name);
+            let start = self.offset;
+            let value = self.decode_field(&name, field.field_type)?;
+            if let Some(value) = value {
+                self.fields.push(DecodedField { name, offset: start, width: self.offset - start, value });
+            }
+        }
+
+        Ok(())
+    }
+
+    /// Returns the field's value, or None if the field was expanded into the nested ones
+    fn decode_field(&mut self, name: &str, field_type: FieldType) -> Result<Option<String>> {
+        let value = match field_type {
+            FieldType::U8 => self.take_u8(name)?.to_string(),
+            FieldType::U16 => self.take_u16(name)?.to_string(),
+            FieldType::U32 => self.take_u32(name)?.to_string(),
+            FieldType::U64 => self.take_u64(name)?.to_string(),
+            FieldType::I32 => (self.take_u32(name)? as i32).to_string(),
+            FieldType::Magic(magic) => {
+                let bytes = self.take(magic.len(), name)?;
+                if bytes != magic {
+                    fail!("Field {} is {}, expected {}", name, hex::encode(bytes), hex::encode(magic))
+                }
+                hex::encode(bytes)
+            }
+            FieldType::Bytes(len) => hex::encode(self.take(len, name)?),
+            FieldType::Hash => hex::encode(self.take(32, name)?),
+            FieldType::OptionalHash => match self.take_u8(name)? {
+                0 => "none".to_string(),
+                _ => hex::encode(self.take(32, name)?),
+            },
+            FieldType::BitString => {
+                let bit_length = self.take_u16(name)? as usize;
+                format!("{} bits: {}", bit_length, hex::encode(self.take((bit_length + 7) / 8, name)?))
+            }
+            FieldType::CellData => {
+                let mut reader = Cursor::new(&self.data[self.offset..]);
+                CellData::deserialize(&mut reader)?;
+                hex::encode(self.take(reader.position() as usize, name)?)
+            }
+            FieldType::Hashes => {
+                let count = self.take_u8(name)? as usize;
+                let hashes = (0..count)
+                    .map(|_| self.take(32, name).map(hex::encode))
+                    .collect::<Result<Vec<_>>>()?;
+                format!("{}: [{}]", count, hashes.join(", "))
+            }
+            FieldType::Section(fields) => {
+                let len = self.take_u16(name)? as usize;
+                self.fields.push(DecodedField {
+                    name: name.to_string(),
+                    offset: self.offset - 2,
+                    width: 2,
+                    value: format!("{} bytes", len),
+                });
+                let end = self.offset + len;
+                if end > self.data.len() {
+                    fail!("Section {} of {} bytes exceeds the record", name, len)
+                }
+                let mut section = Decoder { data: &self.data[..end], offset: self.offset, fields: Vec::new() };
+                section.decode_fields(&format!("{}.", name), fields)?;
+                self.fields.append(&mut section.fields);
+                if section.offset < end {
+                    self.fields.push(DecodedField {
+                        name: format!("{}.<unknown>", name),
+                        offset: section.offset,
+                        width: end - section.offset,
+                        value: hex::encode(&self.data[section.offset..end]),
+                    });
+                }
+                self.offset = end;
+                return Ok(None);
+            }
+            FieldType::Cbor => {
+                let rest = self.take(self.data.len() - self.offset, name)?;
+                format!("{:?}", serde_cbor::from_slice::<serde_cbor::Value>(rest)?)
+            }
+            FieldType::Tail => hex::encode(self.take(self.data.len() - self.offset, name)?),
+        };
+
+        Ok(Some(value))
+    }
+}
@@ -0,0 +1,223 @@
+use std::marker::PhantomData;
+use std::path::Path;
+use std::sync::Arc;
+
+use ton_types::Result;
+
+use crate::db::collection_metadata::{check_collection_metadata, key_type_name};
+use crate::db::memorydb::MemoryDb;
+use crate::db::rocksdb::RocksDb;
+use crate::db::traits::{DbKey, KvcTransactional};
+use crate::traits::Serializable;
+
+/// Key of the index entry: the secondary key followed by the serialized primary id, so several
+/// primary entries may share the same secondary key
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub struct IndexEntryKey(Vec<u8>);
+
+impl IndexEntryKey {
+    pub fn new(secondary_key: &[u8], primary_id: &[u8]) -> Self {
+        let mut key = Vec::with_capacity(secondary_key.len() + primary_id.len());
+        key.extend_from_slice(secondary_key);
+        key.extend_from_slice(primary_id);
+
+        Self(key)
+    }
+}
+
+impl DbKey for IndexEntryKey {
+    fn key_name(&self) -> &'static str {
+        "IndexEntryKey"
+    }
+
+    fn key(&self) -> &[u8] {
+        &self.0
+    }
+}
+
+/// Hook of the primary collection called by its typed wrapper on every change of the entry.
+/// `on_put` is called before the entry is written and `on_delete` after it is deleted, so an
+/// interrupted change leaves at most dangling index entries, but never unindexed primary ones.
+pub trait IndexHook<PK>: Send + Sync {
+    fn name(&self) -> &str;
+
+    /// Updates the index for the primary entry being written (`old_value` is its previous value, if any)
+    fn on_put(&self, primary_id: &PK, old_value: Option<&[u8]>, new_value: &[u8]) -> Result<()>;
+
+    /// Updates the index for the deleted primary entry
+    fn on_delete(&self, primary_id: &PK, old_value: &[u8]) -> Result<()>;
+
+    /// Removes all the index entries (before rebuilding from the primary collection)
+    fn clear(&self) -> Result<()>;
+}
+
+type KeyExtractor<K, PK> = dyn Fn(&PK, &[u8]) -> Result<Option<K>> + Send + Sync;
+
+/// Secondary index over the primary collection, kept in the separate key-value collection.
+/// Secondary key of the entry is calculated by the extractor from the primary id and value (None
+/// means the entry isn't indexed). Secondary keys of the index must be of the same length, since
+/// the index entries are looked up by the key prefix. Changes of the index caused by one primary
+/// change are written in one transaction.
+pub struct SecondaryIndex<K, PK> {
+    name: &'static str,
+    db: Arc<dyn KvcTransactional<IndexEntryKey> + Send + Sync>,
+    extractor: Box<KeyExtractor<K, PK>>,
+    phantom: PhantomData<fn() -> (K, PK)>,
+}
+
+impl<K, PK> SecondaryIndex<K, PK>
+where
+    K: DbKey + Send + Sync,
+    PK: Serializable,
+{
+    pub fn with_db(
+        name: &'static str,
+        db: Arc<dyn KvcTransactional<IndexEntryKey> + Send + Sync>,
+        extractor: impl Fn(&PK, &[u8]) -> Result<Option<K>> + Send + Sync + 'static,
+    ) -> Self {
+        Self {
+            name,
+            db,
+            extractor: Box::new(extractor),
+            phantom: PhantomData,
+        }
+    }
+
+    /// Constructs new instance using in-memory key-value collection
+    pub fn in_memory(
+        name: &'static str,
+        extractor: impl Fn(&PK, &[u8]) -> Result<Option<K>> + Send + Sync + 'static,
+    ) -> Self {
+        Self::with_db(name, Arc::new(MemoryDb::new()), extractor)
+    }
+
+    /// Constructs new instance using RocksDB with given path
+    pub fn with_path(
+        name: &'static str,
+        path: impl AsRef<Path>,
+        extractor: impl Fn(&PK, &[u8]) -> Result<Option<K>> + Send + Sync + 'static,
+    ) -> Self {
+        check_collection_metadata(path.as_ref(), name, key_type_name::<IndexEntryKey>())
+            .expect("Cannot open DB");
+        Self::with_db(name, Arc::new(RocksDb::with_path(path)), extractor)
+    }
+
+    /// Constructs new instance using RocksDB with given path and bloom filters over the first
+    /// `prefix_len` bytes of the secondary keys, for the scans by such prefix
+    pub fn with_path_and_prefix_bloom(
+        name: &'static str,
+        path: impl AsRef<Path>,
+        prefix_len: usize,
+        extractor: impl Fn(&PK, &[u8]) -> Result<Option<K>> + Send + Sync + 'static,
+    ) -> Self {
+        check_collection_metadata(path.as_ref(), name, key_type_name::<IndexEntryKey>())
+            .expect("Cannot open DB");
+        Self::with_db(name, Arc::new(RocksDb::with_prefix_bloom(path, prefix_len)), extractor)
+    }
+
+    pub fn db(&self) -> &(dyn KvcTransactional<IndexEntryKey> + Send + Sync) {
+        &*self.db
+    }
+
+    /// Returns ids of the primary entries having given secondary key
+    pub fn get(&self, key: &K) -> Result<Vec<PK>> {
+        let mut result = Vec::new();
+        self.for_each_with_prefix(key.key(), |primary_id| {
+            result.push(primary_id);
+            Ok(true)
+        })?;
+
+        Ok(result)
+    }
+
+    /// Iterates over ids of the primary entries whose secondary keys start with given bytes
+    pub fn for_each_with_prefix(&self, prefix: &[u8], mut predicate: impl FnMut(PK) -> Result<bool>) -> Result<bool> {
+        self.db.for_each_with_prefix(prefix, &mut |_index_key, value| predicate(PK::from_slice(value)?))
+    }
+
+    /// Returns id of the first primary entry having given secondary key
+    pub fn get_first(&self, key: &K) -> Result<Option<PK>> {
+        Ok(self.get(key)?.into_iter().next())
+    }
+
+    /// Count of the index entries
+    pub fn len(&self) -> Result<usize> {
+        self.db.len()
+    }
+
+    pub fn is_empty(&self) -> Result<bool> {
+        self.db.is_empty()
+    }
+
+    fn entry_key(&self, primary_id: &PK, value: &[u8]) -> Result<Option<(IndexEntryKey, Vec<u8>)>> {
+        Ok(match (self.extractor)(primary_id, value)? {
+            Some(key) => {
+                let primary_id = primary_id.to_vec()?;
+                Some((IndexEntryKey::new(key.key(), &primary_id), primary_id))
+            },
+            None => None,
+        })
+    }
+}
+
+impl<K, PK> IndexHook<PK> for SecondaryIndex<K, PK>
+where
+    K: DbKey + Send + Sync,
+    PK: Serializable,
+{
+    fn name(&self) -> &str {
+        self.name
+    }
+
+    fn on_put(&self, primary_id: &PK, old_value: Option<&[u8]>, new_value: &[u8]) -> Result<()> {
+        let old_entry = match old_value {
+            Some(old_value) => self.entry_key(primary_id, old_value)?,
+            None => None,
+        };
+        let new_entry = self.entry_key(primary_id, new_value)?;
+        if old_entry == new_entry && old_value.is_some() {
+            // The entry may be missed if the previous update was interrupted after the primary write
+            if let Some((new_key, primary_id)) = new_entry {
+                if !self.db.contains(&new_key)? {
+                    self.db.put(&new_key, &primary_id)?;
+                }
+            }
+            return Ok(());
+        }
+
+        let transaction = self.db.begin_transaction()?;
+        if let Some((old_key, _)) = old_entry {
+            transaction.delete(&old_key);
+        }
+        if let Some((new_key, primary_id)) = new_entry {
+            transaction.put(&new_key, &primary_id);
+        }
+
+        transaction.commit()
+    }
+
+    fn on_delete(&self, primary_id: &PK, old_value: &[u8]) -> Result<()> {
+        if let Some((key, _)) = self.entry_key(primary_id, old_value)? {
+            self.db.delete(&key)?;
+        }
+
+        Ok(())
+    }
+
+    fn clear(&self) -> Result<()> {
+        let mut keys = Vec::new();
+        self.db.for_each_key_only(&mut |key| {
+            keys.push(IndexEntryKey(key.to_vec()));
+            Ok(true)
+        })?;
+
+        let transaction = self.db.begin_transaction()?;
+        for key in keys.iter() {
+            transaction.delete(key);
+        }
+        transaction.commit()?;
+        log::info!(target: "storage", "Index {} cleared, {} entries removed", self.name, keys.len());
+
+        Ok(())
+    }
+}
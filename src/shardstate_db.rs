@@ -1,30 +1,85 @@
 use std::io::{Cursor, Read, Write};
 use std::ops::Deref;
-use std::path::Path;
+use std::path::{Path, PathBuf};
 use std::sync::Arc;
 use std::sync::atomic::{AtomicU32, Ordering};
+use std::time::Instant;
 
 use fnv::FnvHashSet;
+use serde_derive::{Deserialize, Serialize};
 
-use ton_block::{BlockIdExt, UnixTime32};
-use ton_types::{Cell, Result};
+use ton_block::{BlockIdExt, Deserializable, UnixTime32};
+use ton_types::{BuilderData, ByteOrderRead, Cell, fail, Result, SliceData};
 
 use crate::block_handle_db::BlockHandleDb;
+use crate::cell_access_db::CellAccessStats;
 use crate::cell_db::CellDb;
+use crate::clock::{Clock, system_clock};
+use crate::cell_gc_fence::SweepGuard;
+use crate::cell_gc_horizon::{CellGcHorizon, split_cell_header, stamp_cell};
+use crate::cell_traversal::{depth_first, depth_first_unique};
+use crate::db::collection_metadata::{check_collection_metadata, key_type_name};
 use crate::db::memorydb::MemoryDb;
 use crate::db::rocksdb::RocksDb;
 use crate::db::traits::{DbKey, KvcSnapshotable};
 use crate::dynamic_boc_db::DynamicBocDb;
-use crate::dynamic_boc_diff_writer::DynamicBocDiffWriter;
+use crate::error::StorageError;
+use crate::events::{StorageEvent, StorageEventBus};
+use crate::marked_cells::{DiskMarkedCells, MarkedCells};
+use crate::pruning_coordinator::PruningCoordinator;
+use crate::read_repair::{MissingCellResolver, ReadRepairReport};
+use crate::retention_profile::{DEFAULT_SHARD_STATE_TTL, RetentionConfig};
+use crate::schema::{FieldSchema, FieldType, RecordSchema, RecordSchemas};
+use crate::slow_op_recorder::SlowOpRecorder;
+use crate::state_pins_db::StatePinsDb;
 use crate::traits::Serializable;
-use crate::types::{BlockId, CellId, Reference};
+use crate::types::{BlockId, BlockIdMismatch, CellId, FLAG_KEY_BLOCK, FLAG_PERSISTENT_STATE, McSeqNo, Reference};
 
 pub struct ShardStateDb {
     shardstate_db: Arc<dyn KvcSnapshotable<BlockId>>,
     dynamic_boc_db: Arc<DynamicBocDb>,
+    event_bus: Arc<StorageEventBus>,
+    slow_op_recorder: Option<Arc<SlowOpRecorder>>,
 }
 
-pub(crate) struct DbEntry {
+/// Result of storing shard state
+pub enum ShardStatePutResult {
+    /// The same root was already stored for the block, nothing was written
+    AlreadyStored(Cell),
+    /// The state was written; root cell and count of new cells are returned
+    Written {
+        root: Cell,
+        cells_written: usize,
+    },
+}
+
+impl ShardStatePutResult {
+    /// Root cell of the stored state, implemented as StorageCell
+    pub fn root(&self) -> &Cell {
+        match self {
+            ShardStatePutResult::AlreadyStored(root) => root,
+            ShardStatePutResult::Written { root, .. } => root,
+        }
+    }
+
+    pub fn written(&self) -> bool {
+        matches!(self, ShardStatePutResult::Written { .. })
+    }
+}
+
+const DB_ENTRY_TAG: [u8; 4] = *b"SSDE";
+const DB_ENTRY_VERSION: u8 = 1;
+/// Size of the untagged record written by older versions: root cell id and block id in the
+/// storage's own layout
+const LEGACY_DB_ENTRY_LEN: usize = 32 + 4 + 8 + 4 + 32 + 32;
+
+/// Value of the shard states database entry: id of the state's root cell and the block id.
+/// The record is tagged and versioned:
+///     tag (4 bytes) | version (1 byte) | root cell id (32 bytes) |
+///     block id bit length (u16 LE) | block id serialized by ton_block
+/// Legacy untagged records are still accepted on read.
+#[derive(Debug, Clone)]
+pub struct DbEntry {
     pub cell_id: CellId,
     pub block_id_ext: BlockIdExt,
 }
@@ -33,46 +88,190 @@ impl DbEntry {
     pub fn with_params(cell_id: CellId, block_id_ext: BlockIdExt) -> Self {
         Self { cell_id, block_id_ext }
     }
+
+    /// Returns true if the record has the legacy layout and has to be rewritten
+    pub fn is_legacy(data: &[u8]) -> bool {
+        data.len() == LEGACY_DB_ENTRY_LEN
+    }
+
+    fn deserialize_legacy<T: Read>(reader: &mut T) -> Result<Self> {
+        let mut buf = [0; 32];
+        reader.read_exact(&mut buf)?;
+        let cell_id = CellId::new(buf.into());
+        let block_id_ext = <BlockIdExt as Serializable>::deserialize(reader)?;
+
+        Ok(Self { cell_id, block_id_ext })
+    }
+}
+
+impl RecordSchemas for DbEntry {
+    const SCHEMAS: &'static [RecordSchema] = &[
+        RecordSchema {
+            name: "DbEntry",
+            version: 0,
+            description: "Legacy untagged shard states database entry",
+            fields: &[
+                FieldSchema::new("cell_id", FieldType::Hash, "Id of the state's root cell"),
+                FieldSchema::new("workchain_id", FieldType::I32, "Workchain of the block"),
+                FieldSchema::new("shard_prefix", FieldType::U64, "Tagged shard prefix of the block"),
+                FieldSchema::new("seq_no", FieldType::U32, "Seq_no of the block"),
+                FieldSchema::new("root_hash", FieldType::Hash, "Root hash of the block"),
+                FieldSchema::new("file_hash", FieldType::Hash, "File hash of the block"),
+            ],
+            matches: DbEntry::is_legacy,
+        },
+        RecordSchema {
+            name: "DbEntry",
+            version: DB_ENTRY_VERSION as u32,
+            description: "Shard states database entry",
+            fields: &[
+                FieldSchema::new("tag", FieldType::Magic(&DB_ENTRY_TAG), "Entry tag"),
+                FieldSchema::new("version", FieldType::U8, "Entry version"),
+                FieldSchema::new("cell_id", FieldType::Hash, "Id of the state's root cell"),
+                FieldSchema::new("block_id", FieldType::BitString, "Block id serialized by ton_block"),
+            ],
+            matches: |data| data.starts_with(&DB_ENTRY_TAG) && data.get(DB_ENTRY_TAG.len()) == Some(&DB_ENTRY_VERSION),
+        },
+    ];
 }
 
 impl Serializable for DbEntry {
     fn serialize<T: Write>(&self, writer: &mut T) -> Result<()> {
+        let mut builder = BuilderData::new();
+        ton_block::Serializable::write_to(&self.block_id_ext, &mut builder)?;
+
+        writer.write_all(&DB_ENTRY_TAG)?;
+        writer.write_all(&[DB_ENTRY_VERSION])?;
         writer.write_all(self.cell_id.key())?;
-        self.block_id_ext.serialize(writer)
+        writer.write_all(&(builder.length_in_bits() as u16).to_le_bytes())?;
+        writer.write_all(builder.data())?;
+
+        Ok(())
     }
 
     fn deserialize<T: Read>(reader: &mut T) -> Result<Self> {
+        let mut tag = [0; 4];
+        reader.read_exact(&mut tag)?;
+        if tag != DB_ENTRY_TAG {
+            fail!("Shard state entry has no tag")
+        }
+        let version = reader.read_byte()?;
+        if version != DB_ENTRY_VERSION {
+            fail!("Unsupported shard state entry version {}", version)
+        }
+
         let mut buf = [0; 32];
         reader.read_exact(&mut buf)?;
         let cell_id = CellId::new(buf.into());
-        let block_id_ext = BlockIdExt::deserialize(reader)?;
+
+        let bit_length = reader.read_le_u16()? as usize;
+        let mut data = vec![0; (bit_length + 7) / 8];
+        reader.read_exact(&mut data)?;
+        let block_id_ext = BlockIdExt::construct_from(&mut SliceData::from_raw(data, bit_length))?;
 
         Ok(Self { cell_id, block_id_ext })
     }
+
+    fn from_slice(data: &[u8]) -> Result<Self> {
+        if Self::is_legacy(data) {
+            return Self::deserialize_legacy(&mut Cursor::new(data));
+        }
+
+        Self::deserialize(&mut Cursor::new(data))
+    }
 }
 
 impl ShardStateDb {
     /// Constructs new instance using in-memory key-value collections
     pub fn in_memory() -> Self {
-        Self::with_dbs(Arc::new(MemoryDb::new()), CellDb::in_memory())
+        Self::with_dbs(Arc::new(MemoryDb::new()), CellDb::in_memory(), None, None)
+    }
+
+    /// Constructs new instance using in-memory key-value collections, with the horizon-based cell
+    /// GC (see `with_paths_and_gc_horizon`); the cells below the horizon are deleted by
+    /// `compact_range` of the cells database
+    pub fn in_memory_with_gc_horizon(gc_horizon: Arc<CellGcHorizon>, access_stats: Option<Arc<CellAccessStats>>) -> Self {
+        Self::with_dbs(
+            Arc::new(MemoryDb::new()),
+            CellDb::in_memory_with_gc_horizon(&gc_horizon),
+            access_stats,
+            Some(gc_horizon),
+        )
     }
 
     /// Constructs new instance using RocksDB with given paths
     pub fn with_paths<P1: AsRef<Path>, P2: AsRef<Path>>(shardstate_db_path: P1, cell_db_path: P2) -> Self {
         Self::with_dbs(
-            Arc::new(RocksDb::with_path(shardstate_db_path)),
+            Self::open_shardstate_db(shardstate_db_path),
+            CellDb::with_path(cell_db_path),
+            None,
+            None,
+        )
+    }
+
+    /// Constructs new instance using RocksDB with given paths, collecting cells access statistics
+    pub fn with_paths_and_access_stats<P1: AsRef<Path>, P2: AsRef<Path>>(
+        shardstate_db_path: P1,
+        cell_db_path: P2,
+        access_stats: Arc<CellAccessStats>,
+    ) -> Self {
+        Self::with_dbs(
+            Self::open_shardstate_db(shardstate_db_path),
             CellDb::with_path(cell_db_path),
+            Some(access_stats),
+            None,
+        )
+    }
+
+    /// Constructs new instance using RocksDB with given paths, with compaction filter-based cell GC:
+    /// GC only restamps live cells and raises the horizon, and the cells below the horizon are
+    /// deleted by RocksDB during regular compactions. Cells access statistics are collected if
+    /// the collector is given.
+    pub fn with_paths_and_gc_horizon<P1: AsRef<Path>, P2: AsRef<Path>>(
+        shardstate_db_path: P1,
+        cell_db_path: P2,
+        gc_horizon: Arc<CellGcHorizon>,
+        access_stats: Option<Arc<CellAccessStats>>,
+    ) -> Self {
+        Self::with_dbs(
+            Self::open_shardstate_db(shardstate_db_path),
+            CellDb::with_path_and_gc_horizon(cell_db_path, &gc_horizon),
+            access_stats,
+            Some(gc_horizon),
         )
     }
 
+    fn open_shardstate_db(path: impl AsRef<Path>) -> Arc<RocksDb> {
+        check_collection_metadata(path.as_ref(), "ShardStateDb", key_type_name::<BlockId>())
+            .expect("Cannot open DB");
+        Arc::new(RocksDb::with_path(path))
+    }
+
     /// Constructs new instance using given key-value collection implementations
-    fn with_dbs(shardstate_db: Arc<dyn KvcSnapshotable<BlockId>>, cell_db: CellDb) -> Self {
+    fn with_dbs(
+        shardstate_db: Arc<dyn KvcSnapshotable<BlockId>>,
+        cell_db: CellDb,
+        access_stats: Option<Arc<CellAccessStats>>,
+        gc_horizon: Option<Arc<CellGcHorizon>>,
+    ) -> Self {
         Self {
             shardstate_db,
-            dynamic_boc_db: Arc::new(DynamicBocDb::with_db(cell_db)),
+            dynamic_boc_db: Arc::new(DynamicBocDb::with_params(cell_db, access_stats, gc_horizon)),
+            event_bus: Arc::new(StorageEventBus::new()),
+            slow_op_recorder: None,
         }
     }
 
+    /// Sets the bus StateSaved events are emitted to
+    pub fn set_event_bus(&mut self, event_bus: Arc<StorageEventBus>) {
+        self.event_bus = event_bus;
+    }
+
+    /// Sets the recorder slow saving and loading of the states are reported to
+    pub fn set_slow_op_recorder(&mut self, slow_op_recorder: Arc<SlowOpRecorder>) {
+        self.slow_op_recorder = Some(slow_op_recorder);
+    }
+
     /// Returns reference to shardstates database
     pub fn shardstate_db(&self) -> Arc<dyn KvcSnapshotable<BlockId>> {
         Arc::clone(&self.shardstate_db)
@@ -91,49 +290,196 @@ impl ShardStateDb {
     /// Stores cells from given tree which don't exist in the storage.
     /// Returns root cell which is implemented as StorageCell.
     /// So after store() origin shard state's cells might be dropped.
-    pub fn put(&self, id: &BlockId, state_root: Cell) -> Result<()> {
+    /// If the same root is already registered for the block, the tree is not traversed.
+    #[cfg_attr(feature = "tracing", tracing::instrument(
+        target = "storage",
+        skip(self, id, state_root),
+        fields(block_id = %id.block_id_ext())
+    ))]
+    pub fn put(&self, id: &BlockId, state_root: Cell) -> Result<ShardStatePutResult> {
+        let started = Instant::now();
         let cell_id = CellId::from(state_root.repr_hash());
-        self.dynamic_boc_db.save_as_dynamic_boc(state_root)?;
+        if let Some(db_entry) = self.try_load_entry(id)? {
+            if db_entry.cell_id == cell_id && self.cell_db().contains(&cell_id)? {
+                log::trace!(target: "storage", "Shard state {} is already stored", id.block_id_ext());
+                let root = self.dynamic_boc_db.load_dynamic_boc(&cell_id)?;
+                self.event_bus.emit(StorageEvent::StateSaved {
+                    block_id: id.block_id_ext().clone(),
+                    cells_written: 0,
+                });
+                self.check_slow_op("state_put", id, started);
+                return Ok(ShardStatePutResult::AlreadyStored(root));
+            }
+        }
 
-        let block_id_ext = id.block_id_ext().clone();
-        let db_entry = DbEntry::with_params(cell_id, block_id_ext);
+        let result = self.force_put(id, state_root)?;
+        self.check_slow_op("state_put", id, started);
 
+        Ok(result)
+    }
+
+    /// Stores cells from given tree which don't exist in the storage and registers the root for the
+    /// block unconditionally. Intended for repair.
+    pub fn force_put(&self, id: &BlockId, state_root: Cell) -> Result<ShardStatePutResult> {
+        let cell_id = CellId::from(state_root.repr_hash());
+        let db_entry = DbEntry::with_params(cell_id.clone(), id.block_id_ext().clone());
         let mut buf = Vec::new();
         db_entry.serialize(&mut Cursor::new(&mut buf))?;
+        id.validate_write(&buf, |value| Ok(DbEntry::from_slice(value)?.block_id_ext))?;
+
+        // The writer stays registered in the GC fence until the root is registered
+        let registration = Arc::new(self.dynamic_boc_db.gc_fence().register());
+        let cells_written = self.dynamic_boc_db
+            .save_as_dynamic_boc_registered(state_root, Arc::clone(&registration))?;
 
+        // The cells may be committed by several sub-batches (see `set_sub_batch_limits`), so the
+        // entry registering the root is written last: readers see the state once it is complete
         self.shardstate_db.put(id, buf.as_slice())?;
+        drop(registration);
+        self.event_bus.emit(StorageEvent::StateSaved {
+            block_id: id.block_id_ext().clone(),
+            cells_written,
+        });
 
-        Ok(())
+        Ok(ShardStatePutResult::Written {
+            root: self.dynamic_boc_db.load_dynamic_boc(&cell_id)?,
+            cells_written,
+        })
+    }
+
+    /// Finds entries whose key doesn't match the block id stored in the entry. States of such
+    /// entries can't be found by their block id.
+    pub fn find_block_id_mismatches(&self) -> Result<Vec<BlockIdMismatch>> {
+        let mut mismatches = Vec::new();
+        self.shardstate_db.for_each(&mut |key, value| {
+            let block_id_ext = DbEntry::from_slice(value)?.block_id_ext;
+            if !BlockId::key_matches(key, &block_id_ext) {
+                mismatches.push(BlockIdMismatch { collection: "ShardStateDb", key: key.to_vec(), block_id_ext });
+            }
+            Ok(true)
+        })?;
+
+        Ok(mismatches)
+    }
+
+    /// Reads the entry of the block (legacy records are read as they are, see `migrate_legacy_entries`)
+    fn try_load_entry(&self, id: &BlockId) -> Result<Option<DbEntry>> {
+        match self.shardstate_db.try_get(id)? {
+            Some(db_slice) => Ok(Some(DbEntry::from_slice(db_slice.as_ref())?)),
+            None => Ok(None),
+        }
+    }
+
+    /// Rewrites legacy entries in the current format. Must be run before GC is started: the entry
+    /// deleted by GC concurrently with the rewrite would be resurrected. Returns count of the
+    /// entries rewritten. Entries whose key doesn't match the block id (see
+    /// `find_block_id_mismatches`) are left as they are.
+    pub fn migrate_legacy_entries(&self) -> Result<usize> {
+        let mut legacy = Vec::new();
+        self.shardstate_db.for_each(&mut |key, value| {
+            if DbEntry::is_legacy(value) {
+                let db_entry = DbEntry::from_slice(value)?;
+                if BlockId::key_matches(key, &db_entry.block_id_ext) {
+                    legacy.push(db_entry);
+                }
+            }
+            Ok(true)
+        })?;
+
+        for db_entry in legacy.iter() {
+            self.shardstate_db.put(&BlockId::from(&db_entry.block_id_ext), &db_entry.to_vec()?)?;
+        }
+        if !legacy.is_empty() {
+            log::info!(target: "storage", "{} shard state entries are rewritten in the current format", legacy.len());
+        }
+
+        Ok(legacy.len())
+    }
+
+    fn load_entry(&self, id: &BlockId) -> Result<DbEntry> {
+        match self.try_load_entry(id)? {
+            Some(db_entry) => Ok(db_entry),
+            None => Err(StorageError::KeyNotFound(id.key_name(), id.as_string()))?,
+        }
     }
 
     /// Loads previously stored root cell
     pub fn get(&self, id: &BlockId) -> Result<Cell> {
-        let db_entry = DbEntry::from_slice(self.shardstate_db.get(id)?.as_ref())?;
+        let started = Instant::now();
+        let db_entry = self.load_entry(id)?;
         let root_cell = self.dynamic_boc_db.load_dynamic_boc(&db_entry.cell_id)?;
+        self.check_slow_op("state_get", id, started);
 
         Ok(root_cell)
     }
+
+    /// Collects references of the stored state to the cells missing in the database. If the
+    /// resolver is given, the missing cells it supplies are stored and their parents re-persisted.
+    pub fn read_repair(&self, id: &BlockId, resolver: Option<&dyn MissingCellResolver>) -> Result<ReadRepairReport> {
+        let db_entry = self.load_entry(id)?;
+        match resolver {
+            Some(resolver) => self.dynamic_boc_db.repair_missing_references(&db_entry.cell_id, resolver),
+            None => self.dynamic_boc_db.find_missing_references(&db_entry.cell_id),
+        }
+    }
+
+    fn check_slow_op(&self, op: &str, id: &BlockId, started: Instant) {
+        if let Some(ref slow_op_recorder) = self.slow_op_recorder {
+            slow_op_recorder.check(op, id.block_id_ext(), started);
+        }
+    }
+}
+
+/// The way GC deletes the cells of the collected states
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Deserialize, Serialize)]
+#[serde(rename_all = "snake_case")]
+pub enum StatesGcMode {
+    /// Live cells are marked, the rest are swept by GC itself
+    Marking,
+    /// Live cells are restamped and the horizon is raised; the cells below the horizon are
+    /// deleted by the compaction filter (see `ShardStateDb::with_paths_and_gc_horizon`)
+    Horizon,
+}
+
+impl Default for StatesGcMode {
+    fn default() -> Self {
+        StatesGcMode::Marking
+    }
 }
 
 pub(crate) trait AllowStateGcResolver: Send + Sync {
-    fn allow_state_gc(&self, block_id_ext: &BlockIdExt, gc_utime: UnixTime32) -> Result<bool>;
+    fn allow_state_gc(&self, block_id_ext: &BlockIdExt, root_cell_id: &CellId, gc_utime: UnixTime32) -> Result<bool>;
 }
 
 struct AllowStateGcResolverImpl {
     // dynamic_boc_db: Arc<DynamicBocDb>,
     block_handle_db: Arc<BlockHandleDb>,
+    cell_access_stats: Option<Arc<CellAccessStats>>,
+    pruning_coordinator: Option<Arc<PruningCoordinator>>,
     shard_state_ttl: AtomicU32,
+    cell_idle_time: AtomicU32,
 }
 
 impl AllowStateGcResolverImpl {
-    pub fn with_data(/*dynamic_boc_db: Arc<DynamicBocDb>,*/ block_handle_db: Arc<BlockHandleDb>) -> Self {
+    pub fn with_data(
+        /*dynamic_boc_db: Arc<DynamicBocDb>,*/
+        block_handle_db: Arc<BlockHandleDb>,
+        cell_access_stats: Option<Arc<CellAccessStats>>,
+        pruning_coordinator: Option<Arc<PruningCoordinator>>,
+        shard_state_ttl: u32,
+        cell_idle_time: u32,
+    ) -> Self {
         Self {
             // dynamic_boc_db,
             block_handle_db,
-            shard_state_ttl: AtomicU32::new(3600 * 24),
+            cell_access_stats,
+            pruning_coordinator,
+            shard_state_ttl: AtomicU32::new(shard_state_ttl),
+            cell_idle_time: AtomicU32::new(cell_idle_time),
         }
     }
 
+    /// Time the states are kept for; u32::MAX means the states are kept forever
     #[allow(dead_code)]
     pub fn shard_state_ttl(&self) -> u32 {
         self.shard_state_ttl.load(Ordering::SeqCst)
@@ -143,16 +489,61 @@ impl AllowStateGcResolverImpl {
     pub fn set_shard_state_ttl(&self, value: u32) {
         self.shard_state_ttl.store(value, Ordering::SeqCst)
     }
+
+    /// Minimal time the state's root cell must not be read in order to allow collecting the state;
+    /// zero disables this criterion
+    #[allow(dead_code)]
+    pub fn cell_idle_time(&self) -> u32 {
+        self.cell_idle_time.load(Ordering::SeqCst)
+    }
+
+    #[allow(dead_code)]
+    pub fn set_cell_idle_time(&self, value: u32) {
+        self.cell_idle_time.store(value, Ordering::SeqCst)
+    }
 }
 
 impl AllowStateGcResolver for AllowStateGcResolverImpl {
-    fn allow_state_gc(&self, block_id_ext: &BlockIdExt, gc_utime: UnixTime32) -> Result<bool> {
+    fn allow_state_gc(&self, block_id_ext: &BlockIdExt, root_cell_id: &CellId, gc_utime: UnixTime32) -> Result<bool> {
+        // Zerostates are kept forever
+        if block_id_ext.seq_no() == 0 {
+            return Ok(false);
+        }
+
         let block_id = BlockId::from(block_id_ext);
         let block_meta = self.block_handle_db.get_value(&block_id)?;
 
+        // States of persistent states' blocks and key blocks are kept forever
+        if block_meta.flags().load(Ordering::SeqCst) & (FLAG_PERSISTENT_STATE | FLAG_KEY_BLOCK) != 0 {
+            return Ok(false);
+        }
+
         // TODO: Implement more sophisticated logic of decision shard state garbage collecting
 
-        Ok(block_meta.gen_utime().load(Ordering::SeqCst) + self.shard_state_ttl() < gc_utime.0)
+        if let Some(ref pruning_coordinator) = self.pruning_coordinator {
+            let mc_seq_no = if block_id_ext.shard().is_masterchain() {
+                block_id_ext.seq_no()
+            } else {
+                block_meta.masterchain_ref_seq_no().load(Ordering::SeqCst)
+            };
+            // Zero masterchain reference of a shard block is unknown one: the state is kept
+            if mc_seq_no == 0 || !pruning_coordinator.allows(McSeqNo::new(mc_seq_no)) {
+                return Ok(false);
+            }
+        }
+
+        if block_meta.gen_utime().load(Ordering::SeqCst).saturating_add(self.shard_state_ttl()) >= gc_utime.0 {
+            return Ok(false);
+        }
+
+        let cell_idle_time = self.cell_idle_time();
+        if let Some(ref cell_access_stats) = self.cell_access_stats {
+            if cell_idle_time > 0 {
+                return cell_access_stats.idle_since(root_cell_id, gc_utime.0.saturating_sub(cell_idle_time));
+            }
+        }
+
+        Ok(true)
     }
 }
 
@@ -160,17 +551,69 @@ pub struct GC {
     shardstate_db: Arc<dyn KvcSnapshotable<BlockId>>,
     dynamic_boc_db: Arc<DynamicBocDb>,
     allow_state_gc_resolver: Arc<dyn AllowStateGcResolver>,
+    marked_cells_path: Option<PathBuf>,
+    event_bus: Option<Arc<StorageEventBus>>,
+    state_pins: Option<Arc<StatePinsDb>>,
+    clock: Arc<dyn Clock>,
 }
 
 impl GC {
     pub fn new(db: &ShardStateDb, block_handle_db: Arc<BlockHandleDb>) -> Self {
+        Self::with_cell_idle_time(db, block_handle_db, 0)
+    }
+
+    /// Constructs GC which additionally doesn't collect states whose root cells were read during
+    /// last `cell_idle_time` seconds (requires cells access statistics to be enabled in ShardStateDb)
+    pub fn with_cell_idle_time(db: &ShardStateDb, block_handle_db: Arc<BlockHandleDb>, cell_idle_time: u32) -> Self {
+        Self::with_params(db, block_handle_db, cell_idle_time, None, DEFAULT_SHARD_STATE_TTL)
+    }
+
+    /// Constructs GC which additionally doesn't collect states of the blocks at or above the
+    /// horizon of the pruning coordinator
+    pub fn with_pruning_coordinator(
+        db: &ShardStateDb,
+        block_handle_db: Arc<BlockHandleDb>,
+        cell_idle_time: u32,
+        pruning_coordinator: Arc<PruningCoordinator>,
+    ) -> Self {
+        Self::with_params(db, block_handle_db, cell_idle_time, Some(pruning_coordinator), DEFAULT_SHARD_STATE_TTL)
+    }
+
+    /// Constructs GC keeping the states according to the retention configuration (which is
+    /// validated first) and the horizon of the pruning coordinator
+    pub fn with_retention(
+        db: &ShardStateDb,
+        block_handle_db: Arc<BlockHandleDb>,
+        cell_idle_time: u32,
+        retention: &RetentionConfig,
+        pruning_coordinator: Arc<PruningCoordinator>,
+    ) -> Result<Self> {
+        retention.validate()?;
+        let shard_state_ttl = retention.state_ttl().unwrap_or(u32::MAX);
+
+        Ok(Self::with_params(db, block_handle_db, cell_idle_time, Some(pruning_coordinator), shard_state_ttl))
+    }
+
+    fn with_params(
+        db: &ShardStateDb,
+        block_handle_db: Arc<BlockHandleDb>,
+        cell_idle_time: u32,
+        pruning_coordinator: Option<Arc<PruningCoordinator>>,
+        shard_state_ttl: u32,
+    ) -> Self {
+        let dynamic_boc_db = db.dynamic_boc_db();
+        let cell_access_stats = dynamic_boc_db.cell_access_stats().cloned();
         Self::with_data(
             db.shardstate_db(),
-            db.dynamic_boc_db(),
+            dynamic_boc_db,
             Arc::new(
                 AllowStateGcResolverImpl::with_data(
                     // db.dynamic_boc_db(),
-                    block_handle_db
+                    block_handle_db,
+                    cell_access_stats,
+                    pruning_coordinator,
+                    shard_state_ttl,
+                    cell_idle_time,
                 )
             )
         )
@@ -185,17 +628,168 @@ impl GC {
             shardstate_db,
             dynamic_boc_db,
             allow_state_gc_resolver,
+            marked_cells_path: None,
+            event_bus: None,
+            state_pins: None,
+            clock: system_clock(),
         }
     }
 
+    /// Makes GC emit GcFinished events to the bus
+    pub fn with_event_bus(mut self, event_bus: Arc<StorageEventBus>) -> Self {
+        self.event_bus = Some(event_bus);
+        self
+    }
+
+    /// Makes GC take the current time (which TTLs of the states and pins are checked against) from
+    /// the clock instead of the system one
+    pub fn with_clock(mut self, clock: Arc<dyn Clock>) -> Self {
+        self.clock = clock;
+        self
+    }
+
+    /// Makes GC keep the states pinned in the database (expired pins are ignored)
+    pub fn with_state_pins(mut self, state_pins: Arc<StatePinsDb>) -> Self {
+        self.state_pins = Some(state_pins);
+        self
+    }
+
+    /// Makes marking keep the set of marked cells in the temporary database at given path instead
+    /// of memory, so states much larger than available memory can be processed
+    pub fn with_disk_marking(mut self, marked_cells_path: impl Into<PathBuf>) -> Self {
+        self.marked_cells_path = Some(marked_cells_path.into());
+        self
+    }
+
+    /// Collects garbage. Returns count of deleted cells; if the cells database uses compaction
+    /// filter-based GC, the cells are deleted later by compactions and zero is returned.
+    #[cfg_attr(feature = "tracing", tracing::instrument(target = "storage", name = "gc", skip(self)))]
     pub fn collect(&self) -> Result<usize> {
-        let (marked, to_sweep) = self.mark(UnixTime32::now())?;
-        let result = self.sweep(to_sweep, marked);
+        if let Some(gc_horizon) = self.dynamic_boc_db.gc_horizon() {
+            return self.collect_by_horizon(gc_horizon);
+        }
+
+        let mut marked: Box<dyn MarkedCells> = match self.marked_cells_path {
+            Some(ref path) => Box::new(DiskMarkedCells::with_path(path)?),
+            None => Box::new(FnvHashSet::default()),
+        };
+        // The snapshot is taken before the states are selected: the states registered afterwards
+        // aren't marked, so their cells are fenced by the writers
+        let sweep_guard = self.dynamic_boc_db.gc_fence().begin_sweep();
+        let to_sweep = self.mark(UnixTime32(self.clock.now()), marked.as_mut())?;
+        let states_deleted = to_sweep.len();
+        let cells_deleted = self.sweep(to_sweep, marked.as_ref(), &sweep_guard)?;
+        drop(sweep_guard);
+
+        if let Some(ref event_bus) = self.event_bus {
+            event_bus.emit(StorageEvent::GcFinished { states_deleted, cells_deleted });
+        }
+
+        Ok(cells_deleted)
+    }
+
+    /// Restamps the live cells with the new generation, deletes entries of collected states and
+    /// raises the horizon up to the generation started by the previous run. Thus unreferenced cells
+    /// are dropped only after two runs. Cells reached by the writers whose roots aren't registered
+    /// yet are restamped as well, so the states being saved concurrently with the runs are safe.
+    /// New generation is started only if there are states to collect, or the cells of the states
+    /// collected by the previous run are still above the horizon; otherwise the run does nothing.
+    fn collect_by_horizon(&self, gc_horizon: &CellGcHorizon) -> Result<usize> {
+        // Writers finished after the states are selected are fenced until the horizon is raised
+        let sweep_guard = self.dynamic_boc_db.gc_fence().begin_sweep();
+        let (to_mark, to_sweep) = self.select(UnixTime32(self.clock.now()))?;
+        if to_sweep.is_empty() && !gc_horizon.collection_pending() {
+            log::debug!(target: "storage", "GC generation {}: nothing to collect", gc_horizon.generation());
+            if let Some(ref event_bus) = self.event_bus {
+                event_bus.emit(StorageEvent::GcFinished { states_deleted: 0, cells_deleted: 0 });
+            }
+            return Ok(0);
+        }
+        let previous_generation = gc_horizon.generation();
+        let generation = gc_horizon.advance()?;
+
+        let mut restamped = 0;
+        for cell_id in to_mark {
+            restamped += self.restamp_subtree(cell_id, generation)?;
+        }
+        let states_deleted = to_sweep.len();
+        for (block_id, _cell_id) in to_sweep {
+            self.shardstate_db.delete(&block_id)?;
+        }
+        {
+            // Writers' checks are blocked, so no cell is reached between the restamp and the raise
+            let _commit_locked = sweep_guard.lock_commit();
+            for cell_id in sweep_guard.reached_cells() {
+                // Cells not written yet are going to be stamped with the current generation
+                if self.dynamic_boc_db.cell_db().contains(&cell_id)? {
+                    restamped += self.restamp_subtree(cell_id, generation)?;
+                }
+            }
+            gc_horizon.raise_horizon(previous_generation)?;
+        }
+        drop(sweep_guard);
+        gc_horizon.set_collection_pending(states_deleted > 0);
+
+        log::info!(
+            target: "storage",
+            "GC generation {}: {} cells restamped, {} states deleted",
+            generation,
+            restamped,
+            states_deleted
+        );
+        if let Some(ref event_bus) = self.event_bus {
+            event_bus.emit(StorageEvent::GcFinished { states_deleted, cells_deleted: 0 });
+        }
+
+        Ok(0)
+    }
+
+    /// Stamps all the cells of the subtree with the generation. Already stamped cells are not
+    /// traversed, so the stamp itself serves as the mark. Returns count of restamped cells.
+    #[cfg_attr(feature = "tracing", tracing::instrument(
+        target = "storage",
+        name = "gc_restamp",
+        skip(self, root_cell_id),
+        fields(root = %root_cell_id)
+    ))]
+    fn restamp_subtree(&self, root_cell_id: CellId, generation: u32) -> Result<usize> {
+        const BATCH_SIZE: usize = 10_000;
+
+        let cell_db = self.dynamic_boc_db.cell_db();
+        let mut restamped = 0;
+        let mut transaction = cell_db.begin_transaction()?;
+        // Cells restamped in the uncommitted batch aren't visible in the database yet
+        let mut in_batch = FnvHashSet::default();
+        depth_first(Some(root_cell_id), |cell_id| {
+            if in_batch.contains(&cell_id) {
+                return Ok(Vec::new());
+            }
+            let value = cell_db.get(&cell_id)?;
+            let (cell_generation, payload) = split_cell_header(value.as_ref());
+            if cell_generation >= Some(generation) {
+                return Ok(Vec::new());
+            }
+
+            transaction.put(&cell_id, &stamp_cell(generation, payload));
+            restamped += 1;
+            let children = cell_db.deserialize_cell(payload)?.1.iter()
+                .map(|reference| reference.hash().into())
+                .collect();
+            in_batch.insert(cell_id);
+            if transaction.len() >= BATCH_SIZE {
+                std::mem::replace(&mut transaction, cell_db.begin_transaction()?).commit()?;
+                in_batch.clear();
+            }
+            Ok(children)
+        })?;
+        transaction.commit()?;
 
-        result
+        Ok(restamped)
     }
 
-    fn mark(&self, gc_utime: UnixTime32) -> Result<(FnvHashSet<CellId>, Vec<(BlockId, CellId)>)> {
+    /// Splits registered states into the ones to be kept and the ones to be collected
+    #[cfg_attr(feature = "tracing", tracing::instrument(target = "storage", name = "gc_select", skip(self, gc_utime)))]
+    fn select(&self, gc_utime: UnixTime32) -> Result<(Vec<CellId>, Vec<(BlockId, CellId)>)> {
         let mut to_mark = Vec::new();
         let mut to_sweep = Vec::new();
         let shardstates = self.shardstate_db.snapshot()?;
@@ -203,10 +797,9 @@ impl GC {
             let db_entry = DbEntry::from_slice(value)?;
             let cell_id = db_entry.cell_id;
             let block_id_ext = db_entry.block_id_ext;
-            if (!self.dynamic_boc_db.cells_map().read()
-                .expect("Poisoned RwLock")
-                .contains_key(&cell_id))
-                && self.allow_state_gc_resolver.allow_state_gc(&block_id_ext, gc_utime)?
+            if (!self.dynamic_boc_db.cells_map().read().contains_key(&cell_id))
+                && !self.is_pinned(&block_id_ext, gc_utime)?
+                && self.allow_state_gc_resolver.allow_state_gc(&block_id_ext, &cell_id, gc_utime)?
             {
                 let block_id = BlockId::from(block_id_ext);
                 to_sweep.push((block_id, cell_id));
@@ -217,72 +810,360 @@ impl GC {
             Ok(true)
         })?;
 
-        let mut marked = FnvHashSet::default();
+        Ok((to_mark, to_sweep))
+    }
+
+    fn is_pinned(&self, block_id_ext: &BlockIdExt, gc_utime: UnixTime32) -> Result<bool> {
+        match self.state_pins {
+            Some(ref state_pins) => state_pins.is_pinned(block_id_ext, gc_utime.0),
+            None => Ok(false),
+        }
+    }
+
+    #[cfg_attr(feature = "tracing", tracing::instrument(target = "storage", name = "gc_mark", skip(self, gc_utime, marked)))]
+    fn mark(&self, gc_utime: UnixTime32, marked: &mut dyn MarkedCells) -> Result<Vec<(BlockId, CellId)>> {
+        let (to_mark, to_sweep) = self.select(gc_utime)?;
         if to_sweep.len() > 0 {
             for cell_id in to_mark {
-                self.mark_subtree_recursive(cell_id, &mut marked)?;
+                self.mark_subtree(cell_id, marked)?;
             }
         }
 
-        Ok((marked, to_sweep))
+        Ok(to_sweep)
     }
 
-    fn mark_subtree_recursive(&self, cell_id: CellId, marked: &mut FnvHashSet<CellId>) -> Result<()> {
-        if marked.contains(&cell_id) {
-            return Ok(());
-        }
-
-        let references = self.load_cell_references(&cell_id)?;
-        marked.insert(cell_id);
+    /// Marks all the cells of the subtree. Cells are streamed from the database (not loaded as
+    /// StorageCells) and traversed iteratively, so deep trees don't exhaust the thread stack.
+    fn mark_subtree(&self, root_cell_id: CellId, marked: &mut dyn MarkedCells) -> Result<()> {
+        depth_first(Some(root_cell_id), |cell_id| {
+            if marked.contains(&cell_id)? {
+                return Ok(Vec::new());
+            }
 
-        for reference in references {
-            self.mark_subtree_recursive(reference.hash().into(), marked)?;
-        }
+            let references = self.load_cell_references(&cell_id)?;
+            marked.insert(&cell_id)?;
 
-        Ok(())
+            Ok(references.iter().map(|reference| reference.hash().into()).collect())
+        })
     }
 
-    fn sweep(&self, to_sweep: Vec<(BlockId, CellId)>, marked: FnvHashSet<CellId>) -> Result<usize> {
+    #[cfg_attr(feature = "tracing", tracing::instrument(
+        target = "storage",
+        name = "gc_sweep",
+        skip(self, to_sweep, marked, sweep_guard),
+        fields(states = to_sweep.len())
+    ))]
+    fn sweep(
+        &self,
+        to_sweep: Vec<(BlockId, CellId)>,
+        marked: &dyn MarkedCells,
+        sweep_guard: &SweepGuard,
+    ) -> Result<usize> {
         if to_sweep.len() < 1 {
             return Ok(0);
         }
 
-        let diff_writer = self.dynamic_boc_db.diff_factory().construct();
-        let mut deleted_count = 0;
+        // Cells shared by the swept states are deleted once
+        let mut swept = FnvHashSet::default();
+        let mut to_delete = FnvHashSet::default();
         for (block_id, cell_id) in to_sweep {
-            deleted_count += self.sweep_cells_recursive(&diff_writer, cell_id, &marked)?;
+            self.sweep_subtree(cell_id, marked, &mut swept, &mut to_delete)?;
             self.shardstate_db.delete(&block_id)?;
         }
+
+        let diff_writer = self.dynamic_boc_db.diff_factory().construct();
+        let _commit_locked = sweep_guard.lock_commit();
+        // Cells reached by the writers since the snapshot are kept together with their subtrees
+        let kept = self.fenced_subtrees(&to_delete, sweep_guard)?;
+        if !kept.is_empty() {
+            log::info!(
+                target: "storage",
+                "GC sweep (snapshot generation {}) keeps {} cells reached by the writers",
+                sweep_guard.snapshot(),
+                kept.len()
+            );
+        }
+        let mut deleted_count = 0;
+        for cell_id in to_delete.iter().filter(|cell_id| !kept.contains(*cell_id)) {
+            diff_writer.delete_cell(cell_id);
+            deleted_count += 1;
+        }
         diff_writer.apply()?;
 
         Ok(deleted_count)
     }
 
-    fn sweep_cells_recursive(
+    /// Collects the unmarked cells of the subtree, traversing it iteratively
+    fn sweep_subtree(
         &self,
-        diff_writer: &DynamicBocDiffWriter,
-        cell_id: CellId,
-        marked: &FnvHashSet<CellId>,
-    ) -> Result<usize> {
-        if marked.contains(&cell_id) {
-            return Ok(0);
-        }
+        root_cell_id: CellId,
+        marked: &dyn MarkedCells,
+        swept: &mut FnvHashSet<CellId>,
+        to_delete: &mut FnvHashSet<CellId>,
+    ) -> Result<()> {
+        depth_first_unique(Some(root_cell_id), swept, |cell_id| {
+            if marked.contains(&cell_id)? {
+                return Ok(Vec::new());
+            }
 
-        let mut deleted_count = 0;
-        let references = self.load_cell_references(&cell_id)?;
-        for reference in references {
-            deleted_count += self.sweep_cells_recursive(diff_writer, reference.hash().into(), marked)?;
-        }
+            let references = self.load_cell_references(&cell_id)?;
+            to_delete.insert(cell_id);
 
-        diff_writer.delete_cell(&cell_id);
-        deleted_count += 1;
+            Ok(references.iter().map(|reference| reference.hash().into()).collect())
+        })
+    }
 
-        Ok(deleted_count)
+    /// Returns the cells to delete reached by the writers, together with their subtrees within the
+    /// cells to delete
+    fn fenced_subtrees(&self, to_delete: &FnvHashSet<CellId>, sweep_guard: &SweepGuard) -> Result<FnvHashSet<CellId>> {
+        let mut kept = FnvHashSet::default();
+        depth_first(sweep_guard.fenced_cells(to_delete), |cell_id| {
+            if !to_delete.contains(&cell_id) || !kept.insert(cell_id.clone()) {
+                return Ok(Vec::new());
+            }
+
+            Ok(self.load_cell_references(&cell_id)?.iter().map(|reference| reference.hash().into()).collect())
+        })?;
+
+        Ok(kept)
     }
 
     fn load_cell_references(&self, cell_id: &CellId) -> Result<Vec<Reference>> {
         let slice = self.dynamic_boc_db.cell_db().get(cell_id)?;
 
-        Ok(CellDb::deserialize_cell(slice.as_ref())?.1)
+        Ok(self.dynamic_boc_db.cell_db().deserialize_cell(slice.as_ref())?.1)
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use ton_block::ShardIdent;
+    use ton_types::UInt256;
+    use crate::clock::ManualClock;
+    use crate::node_state_db::NodeStateDb;
+    use crate::types::BlockMeta;
+
+    /// Allows GC of the given block's state only
+    struct Collect(BlockIdExt);
+
+    impl AllowStateGcResolver for Collect {
+        fn allow_state_gc(&self, block_id_ext: &BlockIdExt, _root_cell_id: &CellId, _gc_utime: UnixTime32) -> Result<bool> {
+            Ok(block_id_ext == &self.0)
+        }
+    }
+
+    fn build_tree(depth: u32, counter: &mut u64) -> Cell {
+        let mut builder = BuilderData::new();
+        builder.append_u64(*counter).unwrap();
+        *counter += 1;
+        if depth > 1 {
+            for _ in 0..2 {
+                builder.append_reference_cell(build_tree(depth - 1, counter));
+            }
+        }
+
+        builder.into()
+    }
+
+    fn assert_same_tree(expected: &Cell, loaded: &Cell) {
+        assert_eq!(expected.repr_hash(), loaded.repr_hash());
+        assert_eq!(expected.data(), loaded.data());
+        assert_eq!(expected.references_count(), loaded.references_count());
+        for i in 0..expected.references_count() {
+            assert_same_tree(&expected.reference(i).unwrap(), &loaded.reference(i).unwrap());
+        }
+    }
+
+    #[test]
+    fn state_survives_horizon_gc() {
+        let path = std::env::temp_dir().join(format!("shardstate_db_horizon_{}", std::process::id()));
+        let _ = std::fs::remove_dir_all(&path);
+        let node_state_db = Arc::new(NodeStateDb::in_memory());
+        let open = || ShardStateDb::with_paths_and_gc_horizon(
+            path.join("states"),
+            path.join("cells"),
+            Arc::new(CellGcHorizon::with_node_state_db(Arc::clone(&node_state_db)).unwrap()),
+            None,
+        );
+
+        let mut counter = 0;
+        let root = build_tree(6, &mut counter);
+        let block_id = BlockId::from(BlockIdExt::default());
+        let collected_id = BlockIdExt { seq_no: 1, ..BlockIdExt::default() };
+        {
+            let db = open();
+            db.put(&block_id, root.clone()).unwrap();
+            db.put(&BlockId::from(&collected_id), build_tree(6, &mut counter)).unwrap();
+
+            // The first run collects the other state, the second one raises the horizon above it
+            let gc = GC::with_data(db.shardstate_db(), db.dynamic_boc_db(), Arc::new(Collect(collected_id)));
+            gc.collect().unwrap();
+            gc.collect().unwrap();
+            assert_eq!(db.dynamic_boc_db().gc_horizon().unwrap().horizon(), 1);
+            db.cell_db().flush().unwrap();
+            db.cell_db().compact_range(None, None).unwrap();
+        }
+
+        // Reopened database has no cached cells, so the whole tree is read from the records
+        let db = open();
+        assert_same_tree(&root, &db.get(&block_id).unwrap());
+        drop(db);
+        let _ = std::fs::remove_dir_all(&path);
+    }
+
+    #[test]
+    fn in_memory_horizon_gc_drops_collected_state() {
+        let gc_horizon = Arc::new(CellGcHorizon::with_node_state_db(Arc::new(NodeStateDb::in_memory())).unwrap());
+        let db = ShardStateDb::in_memory_with_gc_horizon(gc_horizon, None);
+
+        let mut counter = 0;
+        let kept_root = build_tree(4, &mut counter);
+        let collected_root = build_tree(4, &mut counter);
+        let kept_id = BlockId::from(BlockIdExt::default());
+        let collected_id = BlockIdExt { seq_no: 1, ..BlockIdExt::default() };
+        db.put(&kept_id, kept_root.clone()).unwrap();
+        db.put(&BlockId::from(&collected_id), collected_root.clone()).unwrap();
+
+        let gc = GC::with_data(db.shardstate_db(), db.dynamic_boc_db(), Arc::new(Collect(collected_id.clone())));
+        gc.collect().unwrap();
+        gc.collect().unwrap();
+        assert_eq!(db.dynamic_boc_db().gc_horizon().unwrap().horizon(), 1);
+        db.cell_db().compact_range(None, None).unwrap();
+
+        assert!(!db.shardstate_db().contains(&BlockId::from(&collected_id)).unwrap());
+        assert!(!db.cell_db().contains(&collected_root.repr_hash().into()).unwrap());
+        assert!(db.cell_db().contains(&kept_root.repr_hash().into()).unwrap());
+        assert_same_tree(&kept_root, &db.get(&kept_id).unwrap());
+    }
+
+    const GC_UTIME: u32 = 1_000_000;
+    const STATE_TTL: u32 = 100;
+
+    fn mc_block_id(seq_no: u32) -> BlockIdExt {
+        BlockIdExt { shard_id: ShardIdent::masterchain(), seq_no, ..BlockIdExt::default() }
+    }
+
+    fn shard_block_id(seq_no: u32) -> BlockIdExt {
+        BlockIdExt { shard_id: ShardIdent::full(0), seq_no, ..BlockIdExt::default() }
+    }
+
+    fn root_cell_id() -> CellId {
+        CellId::new(UInt256::from([1; 32]))
+    }
+
+    /// Resolver over the handles with given flags, generation time and masterchain reference
+    fn resolver(
+        handles: &[(&BlockIdExt, u32, u32, u32)],
+        pruning_coordinator: Option<Arc<PruningCoordinator>>,
+        cell_access_stats: Option<Arc<CellAccessStats>>,
+        cell_idle_time: u32,
+    ) -> AllowStateGcResolverImpl {
+        let block_handle_db = Arc::new(BlockHandleDb::in_memory());
+        for (block_id, flags, gen_utime, mc_ref_seq_no) in handles.iter() {
+            let block_meta = BlockMeta::with_data(*flags, *gen_utime, 0, *mc_ref_seq_no, true);
+            block_handle_db.put_value(&BlockId::from(*block_id), block_meta).unwrap();
+        }
+
+        AllowStateGcResolverImpl::with_data(
+            block_handle_db,
+            cell_access_stats,
+            pruning_coordinator,
+            STATE_TTL,
+            cell_idle_time,
+        )
+    }
+
+    fn allows(resolver: &AllowStateGcResolverImpl, block_id: &BlockIdExt) -> bool {
+        resolver.allow_state_gc(block_id, &root_cell_id(), UnixTime32(GC_UTIME)).unwrap()
+    }
+
+    #[test]
+    fn expired_state_is_collected() {
+        let block_id = mc_block_id(10);
+        let resolver = resolver(&[(&block_id, 0, GC_UTIME - STATE_TTL - 1, 0)], None, None, 0);
+
+        assert!(allows(&resolver, &block_id));
+    }
+
+    #[test]
+    fn state_within_ttl_is_kept() {
+        let block_id = mc_block_id(10);
+        let resolver = resolver(&[(&block_id, 0, GC_UTIME - STATE_TTL, 0)], None, None, 0);
+
+        assert!(!allows(&resolver, &block_id));
+    }
+
+    #[test]
+    fn zerostate_is_kept() {
+        let block_id = mc_block_id(0);
+        let resolver = resolver(&[(&block_id, 0, 0, 0)], None, None, 0);
+
+        assert!(!allows(&resolver, &block_id));
+    }
+
+    #[test]
+    fn key_block_state_is_kept() {
+        let block_id = mc_block_id(10);
+        let resolver = resolver(&[(&block_id, FLAG_KEY_BLOCK, 0, 0)], None, None, 0);
+
+        assert!(!allows(&resolver, &block_id));
+    }
+
+    #[test]
+    fn persistent_state_is_kept() {
+        let block_id = shard_block_id(10);
+        let resolver = resolver(&[(&block_id, FLAG_PERSISTENT_STATE, 0, 5)], None, None, 0);
+
+        assert!(!allows(&resolver, &block_id));
+    }
+
+    #[test]
+    fn states_at_or_above_pruning_horizon_are_kept() {
+        let pruning_coordinator = Arc::new(
+            PruningCoordinator::with_node_state_db(Arc::new(NodeStateDb::in_memory()), 0).unwrap()
+        );
+        pruning_coordinator.raise_horizon(McSeqNo::new(10)).unwrap();
+        let (below, at, shard_below, shard_above) = (mc_block_id(9), mc_block_id(10), shard_block_id(20), shard_block_id(21));
+        let resolver = resolver(
+            &[(&below, 0, 0, 0), (&at, 0, 0, 0), (&shard_below, 0, 0, 9), (&shard_above, 0, 0, 11)],
+            Some(pruning_coordinator),
+            None,
+            0,
+        );
+
+        assert!(allows(&resolver, &below));
+        assert!(!allows(&resolver, &at));
+        assert!(allows(&resolver, &shard_below));
+        assert!(!allows(&resolver, &shard_above));
+    }
+
+    #[test]
+    fn shard_state_with_unknown_masterchain_ref_is_kept() {
+        let pruning_coordinator = Arc::new(
+            PruningCoordinator::with_node_state_db(Arc::new(NodeStateDb::in_memory()), 0).unwrap()
+        );
+        pruning_coordinator.raise_horizon(McSeqNo::new(10)).unwrap();
+        let block_id = shard_block_id(20);
+        let resolver = resolver(&[(&block_id, 0, 0, 0)], Some(pruning_coordinator), None, 0);
+
+        assert!(!allows(&resolver, &block_id));
+    }
+
+    #[test]
+    fn recently_read_state_is_kept() {
+        let block_id = mc_block_id(10);
+        let clock = Arc::new(ManualClock::new(GC_UTIME - 3601));
+        let cell_access_stats = Arc::new(CellAccessStats::in_memory(1).with_clock(clock.clone()));
+        let resolver = resolver(&[(&block_id, 0, 0, 0)], None, Some(Arc::clone(&cell_access_stats)), 3600);
+        assert!(allows(&resolver, &block_id));
+
+        // Read more than `cell_idle_time` before the GC
+        cell_access_stats.on_cell_accessed(&root_cell_id()).unwrap();
+        assert!(allows(&resolver, &block_id));
+
+        clock.advance(1);
+        cell_access_stats.on_cell_accessed(&root_cell_id()).unwrap();
+        assert!(!allows(&resolver, &block_id));
     }
 }
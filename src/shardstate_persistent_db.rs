@@ -1,29 +1,209 @@
+use std::collections::HashMap;
 use std::ops::{Deref, DerefMut};
-use std::path::Path;
+use std::path::{Path, PathBuf};
+use std::sync::atomic::{AtomicU64, Ordering};
+use std::sync::RwLock;
+use std::time::{Duration, Instant};
+
+use ton_types::Result;
 
 use crate::db::filedb::FileDb;
 use crate::db::traits::KvcWriteableAsync;
+use crate::error::StorageError;
 use crate::types::BlockId;
 use crate::db::async_adapter::KvcWriteableAsyncAdapter;
 
+/// Default maximal size of the persistent state slice returned by `read_slice`
+pub const DEFAULT_MAX_SLICE_SIZE: u64 = 2 << 20;
+
+/// Default size of the chunk the persistent state is written by
+pub const DEFAULT_WRITE_CHUNK_SIZE: u64 = 16 << 20;
+
+/// Progress of the running persistent state saving
+#[derive(Debug, Clone)]
+pub struct PersistenceProgress {
+    pub block_id: BlockId,
+    pub total_bytes: u64,
+    pub bytes_written: u64,
+    pub chunks_done: u64,
+    pub total_chunks: u64,
+    pub started_at: Instant,
+    /// Time spent at the moment of the last progress update
+    pub elapsed: Duration,
+}
+
+impl PersistenceProgress {
+    fn new(block_id: BlockId, total_bytes: u64, chunk_size: u64) -> Self {
+        Self {
+            block_id,
+            total_bytes,
+            bytes_written: 0,
+            chunks_done: 0,
+            total_chunks: (total_bytes + chunk_size - 1) / chunk_size,
+            started_at: Instant::now(),
+            elapsed: Duration::default(),
+        }
+    }
+
+    pub fn is_finished(&self) -> bool {
+        self.bytes_written >= self.total_bytes
+    }
+
+    /// Estimated time remaining, extrapolated from the average write speed so far
+    pub fn eta(&self) -> Option<Duration> {
+        if self.bytes_written == 0 {
+            return None;
+        }
+        let remaining = self.total_bytes.saturating_sub(self.bytes_written);
+
+        Some(Duration::from_secs_f64(
+            self.elapsed.as_secs_f64() * remaining as f64 / self.bytes_written as f64
+        ))
+    }
+}
+
 #[derive(Debug)]
 pub struct ShardStatePersistentDb {
     db: Box<dyn KvcWriteableAsync<BlockId>>,
+    /// Path of the underlying FileDb, if any: states are written into the files by chunks
+    path: Option<PathBuf>,
+    max_slice_size: AtomicU64,
+    write_chunk_size: AtomicU64,
+    jobs: RwLock<HashMap<u64, PersistenceProgress>>,
+    next_job_id: AtomicU64,
 }
 
 impl ShardStatePersistentDb {
     /// Constructs new instance using in-memory key-value collection
     pub fn in_memory() -> Self {
-        Self {
-            db: Box::new(KvcWriteableAsyncAdapter::new(crate::db::memorydb::MemoryDb::new()))
-        }
+        Self::with_db(Box::new(KvcWriteableAsyncAdapter::new(crate::db::memorydb::MemoryDb::new())), None)
     }
 
     /// Constructs new instance using FileDb with given path
     pub fn with_path<P: AsRef<Path>>(path: P) -> Self {
+        Self::with_db(Box::new(FileDb::with_path(path.as_ref())), Some(path.as_ref().to_path_buf()))
+    }
+
+    fn with_db(db: Box<dyn KvcWriteableAsync<BlockId>>, path: Option<PathBuf>) -> Self {
         Self {
-            db: Box::new(FileDb::with_path(path))
+            db,
+            path,
+            max_slice_size: AtomicU64::new(DEFAULT_MAX_SLICE_SIZE),
+            write_chunk_size: AtomicU64::new(DEFAULT_WRITE_CHUNK_SIZE),
+            jobs: RwLock::new(HashMap::new()),
+            next_job_id: AtomicU64::new(0),
+        }
+    }
+
+    pub fn max_slice_size(&self) -> u64 {
+        self.max_slice_size.load(Ordering::Relaxed)
+    }
+
+    pub fn set_max_slice_size(&self, value: u64) {
+        self.max_slice_size.store(value, Ordering::Relaxed)
+    }
+
+    pub fn write_chunk_size(&self) -> u64 {
+        self.write_chunk_size.load(Ordering::Relaxed)
+    }
+
+    pub fn set_write_chunk_size(&self, value: u64) {
+        self.write_chunk_size.store(std::cmp::max(value, 1), Ordering::Relaxed)
+    }
+
+    /// Stores the state, tracking the progress in the list of running jobs
+    pub async fn put(&self, block_id: &BlockId, data: &[u8]) -> Result<()> {
+        self.put_with_progress(block_id, data, |_| {}).await
+    }
+
+    /// Stores the state by chunks, calling `on_progress` after each chunk written. While the
+    /// state is being written, its progress is also available through `running_jobs`.
+    pub async fn put_with_progress(
+        &self,
+        block_id: &BlockId,
+        data: &[u8],
+        mut on_progress: impl FnMut(&PersistenceProgress),
+    ) -> Result<()> {
+        let chunk_size = self.write_chunk_size();
+        let job_id = self.next_job_id.fetch_add(1, Ordering::Relaxed);
+        let mut progress = PersistenceProgress::new(block_id.clone(), data.len() as u64, chunk_size);
+        self.jobs.write().expect("Poisoned RwLock").insert(job_id, progress.clone());
+
+        let mut update = |bytes_written: u64| {
+            progress.bytes_written = bytes_written;
+            progress.chunks_done += 1;
+            progress.elapsed = progress.started_at.elapsed();
+            if let Some(job) = self.jobs.write().expect("Poisoned RwLock").get_mut(&job_id) {
+                *job = progress.clone();
+            }
+            on_progress(&progress);
+        };
+
+        let result = match self.path {
+            Some(ref path) => FileDb::with_path(path)
+                .put_chunked(block_id, data, chunk_size as usize, &mut update).await,
+            None => match self.db.put(block_id, data).await {
+                Ok(()) => {
+                    update(data.len() as u64);
+                    Ok(())
+                },
+                Err(err) => Err(err),
+            },
+        };
+        self.jobs.write().expect("Poisoned RwLock").remove(&job_id);
+
+        match result {
+            Ok(()) => log::debug!(
+                target: "storage",
+                "Persistent state {} saved: {} bytes in {:?}",
+                block_id,
+                data.len(),
+                progress.started_at.elapsed()
+            ),
+            Err(ref err) => log::warn!(
+                target: "storage",
+                "Persistent state {} saving failed: {}",
+                block_id,
+                err
+            ),
         }
+
+        result
+    }
+
+    /// Progress of the persistent states being saved at the moment, in order of start
+    pub fn running_jobs(&self) -> Vec<PersistenceProgress> {
+        let mut jobs = self.jobs.read().expect("Poisoned RwLock").iter()
+            .map(|(job_id, progress)| (*job_id, progress.clone()))
+            .collect::<Vec<_>>();
+        jobs.sort_by_key(|(job_id, _)| *job_id);
+
+        jobs.into_iter().map(|(_, progress)| progress).collect()
+    }
+
+    /// Reads slice of the stored state (e.g. for serving `downloadPersistentStateSlice` queries).
+    /// The slice is clamped to the end of the state and to the maximal slice size, so it might be
+    /// shorter than `max_size`; an empty slice is returned at the end of the state.
+    /// Fails with StorageError::OutOfRange if the offset is beyond the end of the state.
+    pub async fn read_slice(&self, block_id: &BlockId, offset: u64, max_size: u64) -> Result<Vec<u8>> {
+        let size = self.db.get_size(block_id).await?;
+        if offset > size {
+            log::debug!(
+                target: "storage",
+                "Persistent state {} slice requested at offset {} beyond its size {}",
+                block_id,
+                offset,
+                size
+            );
+            return Err(StorageError::OutOfRange.into());
+        }
+
+        let limit = std::cmp::min(std::cmp::min(max_size, self.max_slice_size()), size - offset);
+        if limit == 0 {
+            return Ok(Vec::new());
+        }
+
+        Ok(self.db.get_slice(block_id, offset, limit).await?.to_vec())
     }
 }
 
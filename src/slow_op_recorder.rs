@@ -0,0 +1,143 @@
+use std::collections::HashMap;
+use std::fmt::Display;
+use std::sync::RwLock;
+use std::sync::atomic::{AtomicU32, AtomicU64, Ordering};
+use std::time::{Duration, Instant};
+
+use ton_block::UnixTime32;
+use ton_types::{fail, Result};
+
+use crate::diagnostics_db::DiagnosticsDb;
+use crate::types::SlowOpRecord;
+
+/// Default duration threshold of the operations being recorded
+pub const DEFAULT_SLOW_OP_THRESHOLD_MS: u32 = 100;
+
+/// Default count of the most recent slow operations kept
+pub const DEFAULT_SLOW_OP_CAPACITY: u32 = 1024;
+
+/// Recorder of the operations exceeding their duration thresholds. The records are kept in the
+/// bounded ring buffer persisted in DiagnosticsDb: when the buffer is full, the oldest record is
+/// overwritten. Failures to persist the record are logged and never affect the operation itself.
+#[derive(Debug)]
+pub struct SlowOpRecorder {
+    db: DiagnosticsDb,
+    capacity: u32,
+    next_seq_no: AtomicU64,
+    default_threshold_ms: AtomicU32,
+    thresholds_ms: RwLock<HashMap<String, u32>>,
+}
+
+impl SlowOpRecorder {
+    /// Opens the recorder over the database, continuing the sequence of the stored records
+    pub fn with_db(db: DiagnosticsDb, capacity: u32) -> Result<Self> {
+        if capacity == 0 {
+            fail!("Capacity of slow operations buffer must be positive")
+        }
+
+        let mut next_seq_no = 0;
+        db.for_each_record(|_slot, record| {
+            next_seq_no = std::cmp::max(next_seq_no, record.seq_no() + 1);
+            Ok(true)
+        })?;
+
+        Ok(Self {
+            db,
+            capacity,
+            next_seq_no: AtomicU64::new(next_seq_no),
+            default_threshold_ms: AtomicU32::new(DEFAULT_SLOW_OP_THRESHOLD_MS),
+            thresholds_ms: RwLock::new(HashMap::new()),
+        })
+    }
+
+    pub const fn db(&self) -> &DiagnosticsDb {
+        &self.db
+    }
+
+    /// Maximal count of the records kept
+    pub const fn capacity(&self) -> u32 {
+        self.capacity
+    }
+
+    /// Threshold of the operations having no own one; zero disables recording of them
+    pub fn default_threshold_ms(&self) -> u32 {
+        self.default_threshold_ms.load(Ordering::Relaxed)
+    }
+
+    pub fn set_default_threshold_ms(&self, value: u32) {
+        self.default_threshold_ms.store(value, Ordering::Relaxed)
+    }
+
+    /// Threshold of the operation type; zero means recording of the operation is disabled
+    pub fn threshold_ms(&self, op: &str) -> u32 {
+        self.thresholds_ms.read().expect("Poisoned RwLock")
+            .get(op)
+            .copied()
+            .unwrap_or_else(|| self.default_threshold_ms())
+    }
+
+    /// Sets own threshold of the operation type (None resets it to the default one)
+    pub fn set_threshold_ms(&self, op: &str, value: Option<u32>) {
+        let mut thresholds_ms = self.thresholds_ms.write().expect("Poisoned RwLock");
+        match value {
+            Some(value) => thresholds_ms.insert(op.to_string(), value),
+            None => thresholds_ms.remove(op),
+        };
+    }
+
+    /// Records the operation started at `started`, if its duration exceeds the threshold.
+    /// Returns true if the operation is recorded.
+    pub fn check(&self, op: &str, key: impl Display, started: Instant) -> bool {
+        let duration = started.elapsed();
+        let threshold_ms = self.threshold_ms(op);
+        if threshold_ms == 0 || duration < Duration::from_millis(threshold_ms as u64) {
+            return false;
+        }
+
+        log::warn!(target: "storage", "Slow {} of {}: {} ms", op, key, duration.as_millis());
+        match self.record(op, key.to_string(), duration) {
+            Ok(_) => true,
+            Err(err) => {
+                log::warn!(target: "storage", "Can't record slow {} of {}: {}", op, key, err);
+                false
+            }
+        }
+    }
+
+    /// Unconditionally appends the record into the buffer, overwriting the oldest one if the buffer is full
+    pub fn record(&self, op: &str, key: String, duration: Duration) -> Result<SlowOpRecord> {
+        let seq_no = self.next_seq_no.fetch_add(1, Ordering::SeqCst);
+        let record = SlowOpRecord::with_values(
+            seq_no,
+            op.to_string(),
+            key,
+            duration.as_micros() as u64,
+            UnixTime32::now().0,
+        );
+        self.db.put_value(&((seq_no % self.capacity as u64) as u32).into(), &record)?;
+
+        Ok(record)
+    }
+
+    /// Returns up to `limit` most recent records, the most recent first
+    pub fn dump(&self, limit: usize) -> Result<Vec<SlowOpRecord>> {
+        let mut records = Vec::new();
+        self.db.for_each_record(|_slot, record| {
+            records.push(record);
+            Ok(true)
+        })?;
+        records.sort_by(|a, b| b.seq_no().cmp(&a.seq_no()));
+        records.truncate(limit);
+
+        Ok(records)
+    }
+
+    /// Removes all the records
+    pub fn clear(&self) -> Result<()> {
+        for slot in 0..self.capacity {
+            self.db.delete(&slot.into())?;
+        }
+
+        Ok(())
+    }
+}
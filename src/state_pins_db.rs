@@ -0,0 +1,119 @@
+use ton_block::BlockIdExt;
+use ton_types::{fail, Result};
+
+use crate::db::traits::KvcWriteable;
+use crate::db_impl_serializable;
+use crate::traits::Serializable;
+use crate::types::{StatePin, StatePinKey};
+
+const MAX_OWNER_LEN: usize = 256;
+
+db_impl_serializable!(StatePinsDb, KvcWriteable, StatePinKey, StatePin);
+
+impl StatePinsDb {
+    /// Pins the state of the block on behalf of the owner at time `now` for `ttl` seconds (forever,
+    /// if None). Pinning the state again by the same owner replaces the previous pin.
+    pub fn pin(&self, block_id_ext: &BlockIdExt, owner: &str, ttl: Option<u32>, now: u32) -> Result<StatePin> {
+        if owner.is_empty() || owner.len() > MAX_OWNER_LEN {
+            fail!("State pin owner must be from 1 to {} bytes long", MAX_OWNER_LEN)
+        }
+
+        let pin = StatePin::with_values(
+            block_id_ext.clone(),
+            owner.to_string(),
+            now,
+            ttl.map(|ttl| now.saturating_add(std::cmp::max(ttl, 1))),
+        );
+        self.put_value(&pin.key(), &pin)?;
+        log::debug!(target: "storage", "State {} pinned by {} (ttl = {:?})", block_id_ext, owner, ttl);
+
+        Ok(pin)
+    }
+
+    /// Removes the pin of the owner. Returns true if the pin existed.
+    pub fn unpin(&self, block_id_ext: &BlockIdExt, owner: &str) -> Result<bool> {
+        let key = StatePinKey::new(block_id_ext, owner);
+        if !self.contains(&key)? {
+            return Ok(false);
+        }
+        self.delete(&key)?;
+        log::debug!(target: "storage", "State {} unpinned by {}", block_id_ext, owner);
+
+        Ok(true)
+    }
+
+    /// Returns all the pins of the block's state, including expired ones
+    pub fn get_pins(&self, block_id_ext: &BlockIdExt) -> Result<Vec<StatePin>> {
+        let prefix = StatePinKey::prefix(block_id_ext);
+        let mut result = Vec::new();
+        self.for_each_from(&prefix, &mut |key, value| {
+            if !key.starts_with(&prefix) {
+                return Ok(false);
+            }
+            result.push(StatePin::from_slice(value)?);
+            Ok(true)
+        })?;
+
+        Ok(result)
+    }
+
+    /// Returns true if the block's state has any pin not expired at given time
+    pub fn is_pinned(&self, block_id_ext: &BlockIdExt, utime: u32) -> Result<bool> {
+        Ok(self.get_pins(block_id_ext)?
+            .iter()
+            .any(|pin| !pin.is_expired(utime)))
+    }
+
+    /// Lists all the pins
+    pub fn list(&self) -> Result<Vec<StatePin>> {
+        let mut result = Vec::new();
+        self.for_each(&mut |_key, value| {
+            result.push(StatePin::from_slice(value)?);
+            Ok(true)
+        })?;
+
+        Ok(result)
+    }
+
+    /// Removes all the pins expired at given time. Returns the removed pins.
+    pub fn release_expired(&self, utime: u32) -> Result<Vec<StatePin>> {
+        self.release_where(|pin| pin.is_expired(utime))
+    }
+
+    /// Removes all the pins of the block's state regardless of their owners and TTLs.
+    /// Returns the removed pins.
+    pub fn force_release(&self, block_id_ext: &BlockIdExt) -> Result<Vec<StatePin>> {
+        let pins = self.get_pins(block_id_ext)?;
+        for pin in pins.iter() {
+            self.delete(&pin.key())?;
+        }
+        if !pins.is_empty() {
+            log::warn!(target: "storage", "{} pins of state {} are force released", pins.len(), block_id_ext);
+        }
+
+        Ok(pins)
+    }
+
+    /// Removes all the pins of the owner. Returns the removed pins.
+    pub fn force_release_owner(&self, owner: &str) -> Result<Vec<StatePin>> {
+        self.release_where(|pin| pin.owner() == owner)
+    }
+
+    fn release_where(&self, predicate: impl Fn(&StatePin) -> bool) -> Result<Vec<StatePin>> {
+        let released = self.list()?
+            .into_iter()
+            .filter(|pin| predicate(pin))
+            .collect::<Vec<_>>();
+        for pin in released.iter() {
+            self.delete(&pin.key())?;
+            log::info!(
+                target: "storage",
+                "Pin of state {} by {} released",
+                pin.block_id_ext(),
+                pin.owner()
+            );
+        }
+
+        Ok(released)
+    }
+}
@@ -1,17 +1,67 @@
+use std::any::Any;
 use std::borrow::Borrow;
+use std::collections::HashMap;
+use std::path::Path;
+use std::sync::Mutex;
 
-use ton_types::Result;
+use tokio::sync::watch;
+use ton_types::{fail, Result};
 
-use crate::db_impl_base;
-use crate::db::traits::KvcWriteable;
+use crate::db::collection_metadata::{check_collection_metadata, key_type_name};
+use crate::db::instrumented::InstrumentedDb;
+use crate::db::memorydb::MemoryDb;
+use crate::db::rocksdb::RocksDb;
+use crate::db::traits::{KvcReadable, KvcWriteable};
+use crate::metrics;
 use crate::traits::Serializable;
 use crate::types::StatusKey;
 
-db_impl_base!(StatusDb, KvcWriteable, StatusKey);
+type StatusChannel<T> = (watch::Sender<Option<T>>, watch::Receiver<Option<T>>);
+
+struct StatusWatcher {
+    /// Serialized value last broadcast, so unchanged values are not broadcast again
+    last: Option<Vec<u8>>,
+    /// StatusChannel<T> of the type the key was subscribed with
+    channel: Box<dyn Any + Send>,
+}
+
+pub struct StatusDb {
+    db: Box<dyn KvcWriteable<StatusKey> + Send + Sync>,
+    watchers: Mutex<HashMap<StatusKey, StatusWatcher>>,
+}
+
+impl std::fmt::Debug for StatusDb {
+    fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+        f.debug_struct("StatusDb")
+            .field("db", &self.db)
+            .finish()
+    }
+}
 
 impl StatusDb {
+    /// Constructs new instance using in-memory key-value collection
+    pub fn in_memory() -> Self {
+        Self::with_db(Box::new(MemoryDb::new()))
+    }
+
+    /// Constructs new instance using RocksDB with given path
+    pub fn with_path<P: AsRef<Path>>(path: P) -> Self {
+        check_collection_metadata(path.as_ref(), "StatusDb", key_type_name::<StatusKey>())
+            .expect("Cannot open DB");
+        let db = RocksDb::with_path(path);
+        if metrics::is_enabled() {
+            Self::with_db(Box::new(InstrumentedDb::new(db, metrics::collection_metrics("StatusDb"))))
+        } else {
+            Self::with_db(Box::new(db))
+        }
+    }
+
+    fn with_db(db: Box<dyn KvcWriteable<StatusKey> + Send + Sync>) -> Self {
+        Self { db, watchers: Mutex::new(HashMap::new()) }
+    }
+
     pub fn try_get_value<T: Serializable>(&self, key: &StatusKey) -> Result<Option<T>> {
-        Ok(if let Some(db_slice) = self.try_get(key)? {
+        Ok(if let Some(db_slice) = self.db.try_get(key)? {
             Some(T::from_slice(db_slice.as_ref())?)
         } else {
             None
@@ -19,10 +69,82 @@ impl StatusDb {
     }
 
     pub fn get_value<T: Serializable>(&self, key: &StatusKey) -> Result<T> {
-        T::from_slice(self.get(key)?.as_ref())
+        T::from_slice(self.db.get(key)?.as_ref())
+    }
+
+    /// Stores the value; if it differs from the stored one, subscribers of the key are notified.
+    /// The values are written only by `put_value` and `delete_value`, so no change is missed.
+    pub fn put_value<T>(&self, key: &StatusKey, value: impl Borrow<T>) -> Result<()>
+    where
+        T: Serializable + Clone + Send + Sync + 'static
+    {
+        let data = value.borrow().to_vec()?;
+        let mut watchers = self.watchers.lock().expect("Poisoned Mutex");
+        self.db.put(key, data.as_slice())?;
+
+        let watcher = match watchers.get_mut(key) {
+            Some(watcher) if watcher.last.as_ref() != Some(&data) => watcher,
+            _ => return Ok(()),
+        };
+        match watcher.channel.downcast_ref::<StatusChannel<T>>() {
+            Some((sender, _receiver)) => {
+                // The channel keeps its own receiver, so broadcasting never fails
+                sender.broadcast(Some(value.borrow().clone())).ok();
+                watcher.last = Some(data);
+            },
+            None => log::warn!(target: "storage", "Status {:?} is watched as a value of another type", key),
+        }
+
+        Ok(())
     }
 
-    pub fn put_value<T: Serializable>(&self, key: &StatusKey, value: impl Borrow<T>) -> Result<()> {
-        self.put(key, value.borrow().to_vec()?.as_slice())
+    /// Deletes the value; subscribers of the key are notified with None
+    pub fn delete_value<T>(&self, key: &StatusKey) -> Result<()>
+    where
+        T: Serializable + Clone + Send + Sync + 'static
+    {
+        let mut watchers = self.watchers.lock().expect("Poisoned Mutex");
+        self.db.delete(key)?;
+
+        let watcher = match watchers.get_mut(key) {
+            Some(watcher) if watcher.last.is_some() => watcher,
+            _ => return Ok(()),
+        };
+        match watcher.channel.downcast_ref::<StatusChannel<T>>() {
+            Some((sender, _receiver)) => {
+                sender.broadcast(None).ok();
+                watcher.last = None;
+            },
+            None => log::warn!(target: "storage", "Status {:?} is watched as a value of another type", key),
+        }
+
+        Ok(())
+    }
+
+    /// Subscribes to the changes of the value made by `put_value` and `delete_value`. The receiver
+    /// starts with the current value (None if it is not stored). All the subscribers of the key must
+    /// use the same value type.
+    pub fn subscribe<T>(&self, key: &StatusKey) -> Result<watch::Receiver<Option<T>>>
+    where
+        T: Serializable + Clone + Send + Sync + 'static
+    {
+        let mut watchers = self.watchers.lock().expect("Poisoned Mutex");
+        if let Some(watcher) = watchers.get(key) {
+            match watcher.channel.downcast_ref::<StatusChannel<T>>() {
+                Some((_sender, receiver)) => return Ok(receiver.clone()),
+                None => fail!("Status {:?} is already watched as a value of another type", key),
+            }
+        }
+
+        let last = self.db.try_get(key)?.map(|db_slice| db_slice.to_vec());
+        let value = match last {
+            Some(ref data) => Some(T::from_slice(data)?),
+            None => None,
+        };
+        let (sender, receiver) = watch::channel(value);
+        let channel: StatusChannel<T> = (sender, receiver.clone());
+        watchers.insert(key.clone(), StatusWatcher { last, channel: Box::new(channel) });
+
+        Ok(receiver)
     }
 }
@@ -0,0 +1,268 @@
+use serde_derive::{Deserialize, Serialize};
+
+use ton_types::{fail, Result};
+
+use crate::archives::package::DEFAULT_PACKAGE_FILE_BUDGET;
+use crate::archives::read_ahead_cache::ReadAheadConfig;
+use crate::archives::storage_pools::StoragePoolsConfig;
+use crate::archives::unapplied_gc::UnappliedGcConfig;
+use crate::cell_access_db::CellAccessStatsConfig;
+use crate::cell_db_scrubber::CellDbScrubberConfig;
+use crate::cell_prefetcher::PrefetchConfig;
+use crate::db::rocksdb::FsyncPolicy;
+use crate::dynamic_boc_db::LoadVerification;
+use crate::dynamic_boc_diff_writer::{DiffCoalescingConfig, SubBatchLimits};
+use crate::pruning_coordinator::DEFAULT_PRUNING_MARGIN;
+use crate::retention_profile::RetentionConfig;
+use crate::shardstate_db::StatesGcMode;
+use crate::shardstate_persistent_db::{DEFAULT_MAX_SLICE_SIZE, DEFAULT_WRITE_CHUNK_SIZE};
+use crate::slow_op_recorder::{DEFAULT_SLOW_OP_CAPACITY, DEFAULT_SLOW_OP_THRESHOLD_MS};
+use crate::warm_up::WarmUpConfig;
+
+/// All the tunables of the storage, intended to be loaded from the node's configuration file.
+/// Every option may be omitted, taking its default; `validate` checks the options for consistency.
+#[derive(Debug, Clone, Default, Deserialize, Serialize)]
+#[serde(default, deny_unknown_fields)]
+pub struct StorageConfig {
+    pub retention: RetentionConfig,
+    pub cells: CellsConfig,
+    pub archives: ArchivesConfig,
+    pub block_index: BlockIndexConfig,
+    pub persistent_states: PersistentStatesConfig,
+    pub diagnostics: DiagnosticsConfig,
+    pub pruning: PruningConfig,
+    /// Block infos of at least the size are deduplicated through the blob store (None disables)
+    pub block_info_dedup_min_size: Option<usize>,
+    pub warm_up: WarmUpConfig,
+    pub scrubber: CellDbScrubberConfig,
+    /// TTL of the shard states is set by `retention.state_ttl`
+    pub states_gc: StatesGcConfig,
+    pub fsync: FsyncPolicy,
+}
+
+/// Tunables of the cells storage (see `DynamicBocDb`)
+#[derive(Debug, Clone, Deserialize, Serialize)]
+#[serde(default, deny_unknown_fields)]
+pub struct CellsConfig {
+    pub lazy_parsing: bool,
+    pub load_verification: LoadVerification,
+    /// None disables committing of the saved trees by sub-batches
+    pub sub_batches: Option<SubBatchLimits>,
+    /// None disables merging of the coalesced diffs
+    pub diff_coalescing: Option<DiffCoalescingConfig>,
+    /// None disables prefetching of the child cells
+    pub prefetch: Option<PrefetchConfig>,
+}
+
+impl Default for CellsConfig {
+    fn default() -> Self {
+        Self {
+            lazy_parsing: false,
+            load_verification: LoadVerification::Disabled,
+            sub_batches: None,
+            diff_coalescing: None,
+            prefetch: None,
+        }
+    }
+}
+
+/// Tunables of the shard states GC (see `GC`)
+#[derive(Debug, Clone, Default, PartialEq, Eq, Deserialize, Serialize)]
+#[serde(default, deny_unknown_fields)]
+pub struct StatesGcConfig {
+    pub mode: StatesGcMode,
+    /// None disables collecting of the cells access statistics, so the recently read states are
+    /// not kept
+    pub access_stats: Option<CellAccessStatsConfig>,
+}
+
+/// Tunables of the block archives (see `ArchiveManager`)
+#[derive(Debug, Clone, Deserialize, Serialize)]
+#[serde(default, deny_unknown_fields)]
+pub struct ArchivesConfig {
+    /// Maximal count of the package reader handles kept open
+    pub max_open_files: usize,
+    pub read_ahead: ReadAheadConfig,
+    /// Whether adding a file already stored with different content fails instead of overwriting it
+    pub write_once: bool,
+    pub storage_pools: StoragePoolsConfig,
+    pub unapplied_gc: UnappliedGcConfig,
+}
+
+impl Default for ArchivesConfig {
+    fn default() -> Self {
+        Self {
+            max_open_files: DEFAULT_PACKAGE_FILE_BUDGET,
+            read_ahead: ReadAheadConfig::default(),
+            write_once: false,
+            storage_pools: StoragePoolsConfig::default(),
+            unapplied_gc: UnappliedGcConfig::default(),
+        }
+    }
+}
+
+/// Tunables of the block lookups (see `BlockIndexDb`)
+#[derive(Debug, Clone, PartialEq, Eq, Deserialize, Serialize)]
+#[serde(default, deny_unknown_fields)]
+pub struct BlockIndexConfig {
+    pub fork_tolerant: bool,
+}
+
+impl Default for BlockIndexConfig {
+    fn default() -> Self {
+        Self { fork_tolerant: false }
+    }
+}
+
+/// Tunables of the persistent states storage (see `ShardStatePersistentDb`)
+#[derive(Debug, Clone, PartialEq, Eq, Deserialize, Serialize)]
+#[serde(default, deny_unknown_fields)]
+pub struct PersistentStatesConfig {
+    pub max_slice_size: u64,
+    pub write_chunk_size: u64,
+}
+
+impl Default for PersistentStatesConfig {
+    fn default() -> Self {
+        Self { max_slice_size: DEFAULT_MAX_SLICE_SIZE, write_chunk_size: DEFAULT_WRITE_CHUNK_SIZE }
+    }
+}
+
+/// Tunables of the slow operations recording (see `SlowOpRecorder`)
+#[derive(Debug, Clone, PartialEq, Eq, Deserialize, Serialize)]
+#[serde(default, deny_unknown_fields)]
+pub struct DiagnosticsConfig {
+    pub slow_op_capacity: u32,
+    pub slow_op_threshold_ms: u32,
+    /// Whether the collections are instrumented (see `metrics`)
+    pub metrics: bool,
+}
+
+impl Default for DiagnosticsConfig {
+    fn default() -> Self {
+        Self {
+            slow_op_capacity: DEFAULT_SLOW_OP_CAPACITY,
+            slow_op_threshold_ms: DEFAULT_SLOW_OP_THRESHOLD_MS,
+            metrics: false,
+        }
+    }
+}
+
+/// Tunables of the pruning horizon (see `PruningCoordinator`)
+#[derive(Debug, Clone, PartialEq, Eq, Deserialize, Serialize)]
+#[serde(default, deny_unknown_fields)]
+pub struct PruningConfig {
+    /// Count of masterchain blocks retained below the last persistent state
+    pub margin: u32,
+}
+
+impl Default for PruningConfig {
+    fn default() -> Self {
+        Self { margin: DEFAULT_PRUNING_MARGIN }
+    }
+}
+
+impl StorageConfig {
+    /// Parses the configuration from JSON; omitted options take their defaults
+    pub fn from_json(json: &str) -> Result<Self> {
+        let config: Self = serde_json::from_str(json)?;
+        config.validate()?;
+
+        Ok(config)
+    }
+
+    /// Checks the options for consistency
+    pub fn validate(&self) -> Result<()> {
+        self.retention.validate()?;
+        self.cells.validate()?;
+        self.archives.validate()?;
+        self.states_gc.validate()?;
+        if self.persistent_states.max_slice_size == 0 || self.persistent_states.write_chunk_size == 0 {
+            fail!("Persistent state slice and write chunk sizes must be positive")
+        }
+        if self.diagnostics.slow_op_capacity == 0 {
+            fail!("Slow operations capacity must be positive")
+        }
+        if self.block_info_dedup_min_size == Some(0) {
+            fail!("Block info deduplication threshold must be positive (omit it to disable deduplication)")
+        }
+        if self.scrubber.cells_per_second == 0 || self.scrubber.batch_size == 0 {
+            fail!("Scrubber rate and batch size must be positive")
+        }
+
+        Ok(())
+    }
+}
+
+impl CellsConfig {
+    pub fn validate(&self) -> Result<()> {
+        if let Some(ref limits) = self.sub_batches {
+            if limits.max_bytes == 0 || limits.max_cells == 0 {
+                fail!("Sub-batch limits must be positive")
+            }
+        }
+        if let Some(ref coalescing) = self.diff_coalescing {
+            if coalescing.max_batch_bytes == 0 {
+                fail!("Diff coalescing batch budget must be positive")
+            }
+        }
+        if let Some(ref prefetch) = self.prefetch {
+            if prefetch.queue_capacity == 0 || prefetch.batch_size == 0 {
+                fail!("Prefetch queue capacity and batch size must be positive")
+            }
+        }
+
+        Ok(())
+    }
+}
+
+impl StatesGcConfig {
+    pub fn validate(&self) -> Result<()> {
+        if let Some(ref access_stats) = self.access_stats {
+            if access_stats.sample_rate == 0 {
+                fail!("Cells access sample rate must be positive")
+            }
+            if access_stats.cell_idle_time == 0 {
+                fail!("Cell idle time must be positive (omit access stats to disable them)")
+            }
+        }
+
+        Ok(())
+    }
+
+    /// Time (in seconds) the states whose root cells were read are kept for by GC
+    pub fn cell_idle_time(&self) -> u32 {
+        self.access_stats.as_ref().map(|access_stats| access_stats.cell_idle_time).unwrap_or(0)
+    }
+}
+
+impl ArchivesConfig {
+    pub fn validate(&self) -> Result<()> {
+        if self.max_open_files == 0 {
+            fail!("Maximal count of open package files must be positive")
+        }
+        if self.read_ahead.max_files > 0 && self.read_ahead.buffer_size == 0 {
+            fail!("Read-ahead buffer size must be positive unless read-ahead is disabled")
+        }
+        if self.unapplied_gc.ttl == 0 {
+            fail!("Unapplied files TTL must be positive")
+        }
+
+        Ok(())
+    }
+}
+
+/// Serde representation of `Duration` as a count of milliseconds
+pub(crate) mod duration_ms {
+    use std::time::Duration;
+
+    use serde::{Deserialize, Deserializer, Serializer};
+
+    pub fn serialize<S: Serializer>(value: &Duration, serializer: S) -> Result<S::Ok, S::Error> {
+        serializer.serialize_u64(value.as_millis() as u64)
+    }
+
+    pub fn deserialize<'de, D: Deserializer<'de>>(deserializer: D) -> Result<Duration, D::Error> {
+        u64::deserialize(deserializer).map(Duration::from_millis)
+    }
+}
@@ -0,0 +1,76 @@
+use std::path::{Path, PathBuf};
+
+use ton_types::Result;
+
+/// Disk usage of the storage component (top-level entry of the storage root directory)
+#[derive(Debug, Clone)]
+pub struct ComponentUsage {
+    pub name: String,
+    pub size_before: u64,
+    pub size_after: u64,
+}
+
+/// Result of the storage shrink
+#[derive(Debug, Clone)]
+pub struct ShrinkReport {
+    pub components: Vec<ComponentUsage>,
+    /// Total sizes of the archive packages before and after their compaction
+    pub packages_size_before: u64,
+    pub packages_size_after: u64,
+    /// Empty package directories removed
+    pub removed_dirs: Vec<PathBuf>,
+}
+
+impl ShrinkReport {
+    pub fn total_before(&self) -> u64 {
+        self.components.iter().map(|component| component.size_before).sum()
+    }
+
+    pub fn total_after(&self) -> u64 {
+        self.components.iter().map(|component| component.size_after).sum()
+    }
+}
+
+/// Total size of the files under the path (or of the file itself)
+pub fn disk_usage(path: impl AsRef<Path>) -> Result<u64> {
+    let metadata = std::fs::symlink_metadata(path.as_ref())?;
+    if !metadata.is_dir() {
+        return Ok(metadata.len());
+    }
+
+    let mut total = 0;
+    for dir_entry in std::fs::read_dir(path.as_ref())? {
+        total += disk_usage(dir_entry?.path())?;
+    }
+
+    Ok(total)
+}
+
+/// Disk usage of every top-level entry of the storage root directory, sorted by name
+pub(crate) fn components_usage(db_root_path: &Path) -> Result<Vec<(String, u64)>> {
+    let mut result = Vec::new();
+    for dir_entry in std::fs::read_dir(db_root_path)? {
+        let dir_entry = dir_entry?;
+        result.push((dir_entry.file_name().to_string_lossy().to_string(), disk_usage(dir_entry.path())?));
+    }
+    result.sort();
+
+    Ok(result)
+}
+
+/// Merges usage measured before and after the shrink; components appeared or disappeared in
+/// between are reported with zero size on the other side
+pub(crate) fn merge_usage(before: Vec<(String, u64)>, after: Vec<(String, u64)>) -> Vec<ComponentUsage> {
+    let mut components = before.into_iter()
+        .map(|(name, size_before)| ComponentUsage { name, size_before, size_after: 0 })
+        .collect::<Vec<_>>();
+    for (name, size_after) in after {
+        match components.iter_mut().find(|component| component.name == name) {
+            Some(component) => component.size_after = size_after,
+            None => components.push(ComponentUsage { name, size_before: 0, size_after }),
+        }
+    }
+    components.sort_by(|a, b| a.name.cmp(&b.name));
+
+    components
+}
@@ -1,8 +1,21 @@
+use std::fmt::Debug;
 use std::io::{Cursor, Read, Write};
+use std::sync::Arc;
 
 use ton_block::{BlockIdExt, ShardIdent};
 use ton_types::{ByteOrderRead, Result, UInt256};
 
+use crate::types::{CellId, StorageCell};
+
+/// Source of storage cells. StorageCell uses it in order to lazily load its references.
+pub trait CellLoader: Debug + Send + Sync {
+    /// Loads cell by the cell id
+    fn load_cell(self: Arc<Self>, cell_id: &CellId) -> Result<Arc<StorageCell>>;
+
+    /// Notifies the loader that the cell loaded by it is being dropped
+    fn on_cell_dropped(&self, _cell_id: &CellId) {}
+}
+
 pub trait Serializable {
     fn serialize<W: Write>(&self, writer: &mut W) -> Result<()>;
 
@@ -0,0 +1,52 @@
+use std::io::{Read, Write};
+
+use ton_block::BlockIdExt;
+use ton_types::{ByteOrderRead, Result};
+
+use crate::traits::Serializable;
+
+/// Progress of block application in the shard: the last block whose state is fully stored
+#[derive(Debug, Clone, PartialEq)]
+pub struct ApplyCheckpoint {
+    block_id_ext: BlockIdExt,
+    apply_seq_no: u64,
+    updated_at: u32,
+}
+
+impl ApplyCheckpoint {
+    pub const fn with_values(block_id_ext: BlockIdExt, apply_seq_no: u64, updated_at: u32) -> Self {
+        Self { block_id_ext, apply_seq_no, updated_at }
+    }
+
+    /// The last fully applied block
+    pub const fn block_id_ext(&self) -> &BlockIdExt {
+        &self.block_id_ext
+    }
+
+    /// Sequential number of the checkpoint update in the shard, increased by every update (including resets)
+    pub const fn apply_seq_no(&self) -> u64 {
+        self.apply_seq_no
+    }
+
+    pub const fn updated_at(&self) -> u32 {
+        self.updated_at
+    }
+}
+
+impl Serializable for ApplyCheckpoint {
+    fn serialize<W: Write>(&self, writer: &mut W) -> Result<()> {
+        self.block_id_ext.serialize(writer)?;
+        writer.write_all(&self.apply_seq_no.to_le_bytes())?;
+        writer.write_all(&self.updated_at.to_le_bytes())?;
+
+        Ok(())
+    }
+
+    fn deserialize<R: Read>(reader: &mut R) -> Result<Self> {
+        let block_id_ext = BlockIdExt::deserialize(reader)?;
+        let apply_seq_no = reader.read_le_u64()?;
+        let updated_at = reader.read_le_u32()?;
+
+        Ok(Self { block_id_ext, apply_seq_no, updated_at })
+    }
+}
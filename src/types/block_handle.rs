@@ -1,28 +1,93 @@
 use std::io::Write;
+use std::ops::BitOr;
+use std::sync::Arc;
 use std::sync::atomic::{AtomicBool, Ordering};
 
 use tokio::sync::RwLock;
 use ton_block::{BlockIdExt, BlockInfo, ShardStateUnsplit, Block};
-use ton_types::{fail, Result};
+use ton_types::{fail, Result, UInt256};
 
-use crate::block_handle_db::BlockHandleCache;
+use crate::block_handle_db::{BlockHandleCache, BlockHandleWriter};
 use crate::traits::Serializable;
 use crate::types::BlockMeta;
 
-const FLAG_DATA: u32 = 1;
-const FLAG_PROOF: u32 = 1 << 1;
-const FLAG_PROOF_LINK: u32 = 1 << 2;
-const FLAG_EXT_DB: u32 = 1 << 3;
-const FLAG_STATE: u32 = 1 << 4;
-const FLAG_PERSISTENT_STATE: u32 = 1 << 5;
-const FLAG_NEXT_1: u32 = 1 << 6;
-const FLAG_NEXT_2: u32 = 1 << 7;
-const FLAG_PREV_1: u32 = 1 << 8;
-const FLAG_PREV_2: u32 = 1 << 9;
-const FLAG_APPLIED: u32 = 1 << 10;
-const FLAG_KEY_BLOCK: u32 = 1 << 11;
-const FLAG_MOVED_TO_ARCHIVE: u32 = 1 << 13;
-const FLAG_INDEXED: u32 = 1 << 14;
+pub(crate) const FLAG_DATA: u32 = 1;
+pub(crate) const FLAG_PROOF: u32 = 1 << 1;
+pub(crate) const FLAG_PROOF_LINK: u32 = 1 << 2;
+pub(crate) const FLAG_EXT_DB: u32 = 1 << 3;
+pub(crate) const FLAG_STATE: u32 = 1 << 4;
+pub(crate) const FLAG_PERSISTENT_STATE: u32 = 1 << 5;
+pub(crate) const FLAG_NEXT_1: u32 = 1 << 6;
+pub(crate) const FLAG_NEXT_2: u32 = 1 << 7;
+pub(crate) const FLAG_PREV_1: u32 = 1 << 8;
+pub(crate) const FLAG_PREV_2: u32 = 1 << 9;
+pub(crate) const FLAG_APPLIED: u32 = 1 << 10;
+pub(crate) const FLAG_KEY_BLOCK: u32 = 1 << 11;
+pub(crate) const FLAG_MOVED_TO_ARCHIVE: u32 = 1 << 13;
+pub(crate) const FLAG_INDEXED: u32 = 1 << 14;
+pub(crate) const FLAG_PRUNED: u32 = 1 << 15;
+
+const FLAG_NAMES: [(u32, &str); 15] = [
+    (FLAG_DATA, "data"),
+    (FLAG_PROOF, "proof"),
+    (FLAG_PROOF_LINK, "proof_link"),
+    (FLAG_EXT_DB, "ext_db"),
+    (FLAG_STATE, "state"),
+    (FLAG_PERSISTENT_STATE, "persistent_state"),
+    (FLAG_NEXT_1, "next1"),
+    (FLAG_NEXT_2, "next2"),
+    (FLAG_PREV_1, "prev1"),
+    (FLAG_PREV_2, "prev2"),
+    (FLAG_APPLIED, "applied"),
+    (FLAG_KEY_BLOCK, "key_block"),
+    (FLAG_MOVED_TO_ARCHIVE, "moved_to_archive"),
+    (FLAG_INDEXED, "indexed"),
+    (FLAG_PRUNED, "pruned"),
+];
+
+/// Returns names of the flags set in the block meta flags value; unknown bits are ignored
+pub fn describe_block_flags(flags: u32) -> Vec<&'static str> {
+    FLAG_NAMES.iter()
+        .filter(|(flag, _name)| flags & flag != 0)
+        .map(|(_flag, name)| *name)
+        .collect()
+}
+
+/// Set of the handle flags set together by `BlockHandle::apply_transition`
+#[derive(Debug, Clone, Copy, Default, PartialEq, Eq)]
+pub struct BlockFlags(u32);
+
+impl BlockFlags {
+    pub const DATA: Self = Self(FLAG_DATA);
+    pub const PROOF: Self = Self(FLAG_PROOF);
+    pub const PROOF_LINK: Self = Self(FLAG_PROOF_LINK);
+    pub const EXT_DB: Self = Self(FLAG_EXT_DB);
+    pub const STATE: Self = Self(FLAG_STATE);
+    pub const PERSISTENT_STATE: Self = Self(FLAG_PERSISTENT_STATE);
+    pub const NEXT_1: Self = Self(FLAG_NEXT_1);
+    pub const NEXT_2: Self = Self(FLAG_NEXT_2);
+    pub const PREV_1: Self = Self(FLAG_PREV_1);
+    pub const PREV_2: Self = Self(FLAG_PREV_2);
+    pub const APPLIED: Self = Self(FLAG_APPLIED);
+    pub const MOVED_TO_ARCHIVE: Self = Self(FLAG_MOVED_TO_ARCHIVE);
+    pub const INDEXED: Self = Self(FLAG_INDEXED);
+
+    pub const fn bits(&self) -> u32 {
+        self.0
+    }
+
+    pub const fn is_empty(&self) -> bool {
+        self.0 == 0
+    }
+}
+
+impl BitOr for BlockFlags {
+    type Output = Self;
+
+    fn bitor(self, other: Self) -> Self {
+        Self(self.0 | other.0)
+    }
+}
 
 /// Meta information related to block
 #[derive(Debug)]
@@ -32,6 +97,8 @@ pub struct BlockHandle {
     moving_to_archive_started: AtomicBool,
     temp_lock: RwLock<()>,
     block_handle_cache: BlockHandleCache,
+    // Set for the handles created by BlockHandleStorage
+    writer: Option<Arc<BlockHandleWriter>>,
 }
 
 impl BlockHandle {
@@ -45,10 +112,17 @@ impl BlockHandle {
             meta,
             moving_to_archive_started: AtomicBool::new(false),
             temp_lock: RwLock::new(()),
-            block_handle_cache
+            block_handle_cache,
+            writer: None,
         }
     }
 
+    /// Makes the handle store its meta on transitions
+    pub(crate) fn with_writer(mut self, writer: Arc<BlockHandleWriter>) -> Self {
+        self.writer = Some(writer);
+        self
+    }
+
     pub fn serialize<W: Write>(&self, writer: &mut W) -> Result<()> {
         self.meta.serialize(writer)
     }
@@ -250,6 +324,18 @@ impl BlockHandle {
         self.set_flags(FLAG_MOVED_TO_ARCHIVE)
     }
 
+    /// True if the block's archive is deleted by pruning
+    pub fn pruned(&self) -> bool {
+        self.flags_all(FLAG_PRUNED)
+    }
+
+    /// Marks the block's data, proof and prooflink as deleted along with the block's archive.
+    /// Returns true if the block was already marked pruned.
+    pub fn set_pruned(&self) -> bool {
+        self.meta.flags().fetch_and(!(FLAG_DATA | FLAG_PROOF | FLAG_PROOF_LINK | FLAG_MOVED_TO_ARCHIVE), Ordering::SeqCst);
+        self.set_flags(FLAG_PRUNED)
+    }
+
     pub fn fetched(&self) -> bool {
         self.meta().fetched()
     }
@@ -262,10 +348,55 @@ impl BlockHandle {
         }
     }
 
+    /// Remembers when and from where the block was received. Only the first arrival is recorded;
+    /// returns true if the values were already set.
+    pub fn set_received(&self, received_at: u32, source: Option<UInt256>) -> bool {
+        if self.meta.received_at()
+            .compare_exchange(0, received_at, Ordering::SeqCst, Ordering::SeqCst)
+            .is_err()
+        {
+            return true;
+        }
+        self.meta.set_source(source);
+
+        false
+    }
+
+    /// Unix time the block was received at, if known
+    pub fn received_at(&self) -> Option<u32> {
+        match self.meta.received_at().load(Ordering::SeqCst) {
+            0 => None,
+            received_at => Some(received_at),
+        }
+    }
+
+    /// Id of the node the block was received from, if known
+    pub fn source(&self) -> Option<UInt256> {
+        self.meta.source()
+    }
+
+    /// Sets the flags at once and, if any of them wasn't set before, stores the meta (unless the
+    /// handle wasn't created by BlockHandleStorage). Returns true if anything changed.
+    pub fn apply_transition(&self, flags: BlockFlags) -> Result<bool> {
+        if self.set_flags(flags.bits()) {
+            return Ok(false);
+        }
+        if let Some(ref writer) = self.writer {
+            writer.store(self)?;
+        }
+
+        Ok(true)
+    }
+
     pub fn start_moving_to_archive(&self) -> bool {
         self.moving_to_archive_started.swap(true, Ordering::SeqCst)
     }
 
+    /// Clears the started move after it failed, so the move may be retried
+    pub(crate) fn reset_moving_to_archive(&self) {
+        self.moving_to_archive_started.store(false, Ordering::SeqCst);
+    }
+
     pub(crate) fn temp_lock(&self) -> &RwLock<()>  {
         &self.temp_lock
     }
@@ -1,10 +1,46 @@
 use std::fmt::{Display, Formatter};
+use std::sync::atomic::{AtomicBool, Ordering};
 
+use lazy_static::lazy_static;
 use sha2::{Digest, Sha256};
 
 use ton_block::BlockIdExt;
+use ton_types::Result;
 
 use crate::db::traits::DbKey;
+use crate::error::StorageError;
+
+lazy_static! {
+    static ref VALIDATE_WRITES: AtomicBool = AtomicBool::new(cfg!(debug_assertions));
+}
+
+/// Enables (or disables) validation of the BlockId keys at the write paths. It is enabled by
+/// default in debug builds only.
+pub fn set_write_validation(value: bool) {
+    VALIDATE_WRITES.store(value, Ordering::Relaxed)
+}
+
+pub fn write_validation_enabled() -> bool {
+    VALIDATE_WRITES.load(Ordering::Relaxed)
+}
+
+/// Stored record whose BlockId key doesn't match the block id embedded into the record
+#[derive(Debug, Clone)]
+pub struct BlockIdMismatch {
+    pub collection: &'static str,
+    pub key: Vec<u8>,
+    pub block_id_ext: BlockIdExt,
+}
+
+fn block_id_key(block_id_ext: &BlockIdExt) -> Vec<u8> {
+    let mut hasher = Sha256::new();
+    hasher.input(block_id_ext.shard_id.workchain_id().to_le_bytes());
+    hasher.input(block_id_ext.shard_id.shard_prefix_with_tag().to_le_bytes());
+    hasher.input(block_id_ext.seq_no.to_le_bytes());
+    hasher.input(block_id_ext.root_hash.as_slice());
+    hasher.input(block_id_ext.file_hash.as_slice());
+    hasher.result().to_vec()
+}
 
 #[derive(Debug, Clone, PartialEq, Eq, PartialOrd, Ord)]
 pub struct BlockId {
@@ -16,17 +52,44 @@ impl BlockId {
     pub const fn block_id_ext(&self) -> &BlockIdExt {
         &self.block_id_ext
     }
+
+    /// Checks whether the raw key is the key of given block id
+    pub fn key_matches(key: &[u8], block_id_ext: &BlockIdExt) -> bool {
+        key == block_id_key(block_id_ext).as_slice()
+    }
+
+    /// Checks that the key is recomputed from the id embedded into the written record, and that
+    /// the embedded id is the id of the key
+    pub fn validate(&self, embedded: &BlockIdExt) -> Result<()> {
+        if !Self::key_matches(&self.key, embedded) || &self.block_id_ext != embedded {
+            log::error!(
+                target: "storage",
+                "BlockId key {} doesn't match the embedded block id {}", self, embedded
+            );
+            Err(StorageError::CorruptedData(format!("key {} doesn't match block id {}", self, embedded)))?
+        }
+
+        Ok(())
+    }
+
+    /// Validates the key against the block id read back from the serialized record before the
+    /// record is written, if the write validation is enabled
+    pub(crate) fn validate_write(
+        &self,
+        value: &[u8],
+        read_embedded: impl FnOnce(&[u8]) -> Result<BlockIdExt>,
+    ) -> Result<()> {
+        if write_validation_enabled() {
+            self.validate(&read_embedded(value)?)?;
+        }
+
+        Ok(())
+    }
 }
 
 impl From<BlockIdExt> for BlockId {
     fn from(block_id_ext: BlockIdExt) -> Self {
-        let mut hasher = Sha256::new();
-        hasher.input(block_id_ext.shard_id.workchain_id().to_le_bytes());
-        hasher.input(block_id_ext.shard_id.shard_prefix_with_tag().to_le_bytes());
-        hasher.input(block_id_ext.seq_no.to_le_bytes());
-        hasher.input(block_id_ext.root_hash.as_slice());
-        hasher.input(block_id_ext.file_hash.as_slice());
-        let key = hasher.result().to_vec();
+        let key = block_id_key(&block_id_ext);
 
         Self { key, block_id_ext }
     }
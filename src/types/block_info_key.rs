@@ -0,0 +1,90 @@
+use std::fmt::{Display, Formatter};
+
+use ton_block::BlockIdExt;
+
+use crate::db::traits::DbKey;
+use crate::types::BlockId;
+
+/// Kind of block proof stored in BlockInfoDb
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Hash)]
+pub enum ProofKind {
+    Proof,
+    ProofLink,
+}
+
+impl ProofKind {
+    /// Proof kind applicable for the block: proofs for masterchain blocks, prooflinks otherwise
+    pub fn for_block(block_id: &BlockIdExt) -> Self {
+        if block_id.shard().is_masterchain() {
+            ProofKind::Proof
+        } else {
+            ProofKind::ProofLink
+        }
+    }
+
+    const fn key_suffix(&self) -> u8 {
+        match self {
+            ProofKind::Proof => 1,
+            ProofKind::ProofLink => 2,
+        }
+    }
+}
+
+impl Display for ProofKind {
+    fn fmt(&self, f: &mut Formatter<'_>) -> std::fmt::Result {
+        match self {
+            ProofKind::Proof => f.write_str("proof"),
+            ProofKind::ProofLink => f.write_str("prooflink"),
+        }
+    }
+}
+
+/// Key of BlockInfoDb: raw block info is stored by block id key, proofs have key suffix by kind
+pub struct BlockInfoKey {
+    key: Vec<u8>,
+    block_id_ext: BlockIdExt,
+    proof_kind: Option<ProofKind>,
+}
+
+impl BlockInfoKey {
+    pub fn info(block_id: BlockId) -> Self {
+        Self {
+            key: block_id.key().to_vec(),
+            block_id_ext: block_id.block_id_ext().clone(),
+            proof_kind: None,
+        }
+    }
+
+    pub fn proof(block_id: BlockId, proof_kind: ProofKind) -> Self {
+        let mut key = block_id.key().to_vec();
+        key.push(proof_kind.key_suffix());
+        Self {
+            key,
+            block_id_ext: block_id.block_id_ext().clone(),
+            proof_kind: Some(proof_kind),
+        }
+    }
+}
+
+impl From<BlockId> for BlockInfoKey {
+    fn from(block_id: BlockId) -> Self {
+        Self::info(block_id)
+    }
+}
+
+impl DbKey for BlockInfoKey {
+    fn key_name(&self) -> &'static str {
+        "BlockInfoKey"
+    }
+
+    fn as_string(&self) -> String {
+        match self.proof_kind {
+            Some(proof_kind) => format!("{} {}", proof_kind, self.block_id_ext),
+            None => format!("{}", self.block_id_ext),
+        }
+    }
+
+    fn key(&self) -> &[u8] {
+        &self.key
+    }
+}
@@ -1,12 +1,28 @@
-use std::io::{Read, Write};
+use std::io::{Cursor, Read, Write};
 use std::sync::atomic::{AtomicBool, AtomicU32, AtomicU64, Ordering};
 
 use tokio::sync::RwLock;
 
-use ton_types::{ByteOrderRead, Result};
+use ton_types::{ByteOrderRead, error, fail, Result, UInt256};
 
+use crate::schema::{FieldSchema, FieldType, RecordSchema, RecordSchemas};
 use crate::traits::Serializable;
 
+/// Current version of the serialized block meta, written by `serialize`. Version 0 records have
+/// no extension, version 1 records have the extension fields of the fixed layout, and records of
+/// version 2 and later have the length-prefixed extension section. Later versions only append
+/// fields to the section, so the fields unknown to the reader are skipped.
+pub const BLOCK_META_VERSION: u8 = 2;
+
+// Bits of the byte following masterchain_ref_seq_no. Records of version 0 contain only the
+// "fetched" bit, extended records are followed by the version and the extension.
+const META_FETCHED: u8 = 1;
+const META_EXTENDED: u8 = 1 << 1;
+const META_EXT_VERSION_FIXED: u8 = 1;
+
+// Size of the fields preceding the meta bits
+const META_FIXED_SIZE: usize = 4 + 4 + 8 + 4;
+
 #[derive(Debug, Default)]
 pub struct BlockMeta {
     flags: AtomicU32,
@@ -16,6 +32,8 @@ pub struct BlockMeta {
     fetched: AtomicBool,
     moving_to_archive_started: AtomicBool,
     temp_lock: RwLock<()>,
+    received_at: AtomicU32,
+    source: std::sync::RwLock<Option<UInt256>>,
 }
 
 impl BlockMeta {
@@ -28,6 +46,8 @@ impl BlockMeta {
             fetched: AtomicBool::new(fetched),
             moving_to_archive_started: AtomicBool::new(false),
             temp_lock: RwLock::new(()),
+            received_at: AtomicU32::new(0),
+            source: std::sync::RwLock::new(None),
         }
     }
 
@@ -54,6 +74,113 @@ impl BlockMeta {
     pub fn set_fetched(&self) -> bool {
         self.fetched.swap(true, Ordering::SeqCst)
     }
+
+    /// Unix time the block was received at; zero if unknown
+    pub const fn received_at(&self) -> &AtomicU32 {
+        &self.received_at
+    }
+
+    /// Id of the node (overlay peer) the block was received from, if known
+    pub fn source(&self) -> Option<UInt256> {
+        self.source.read().expect("Poisoned RwLock").clone()
+    }
+
+    pub fn set_source(&self, source: Option<UInt256>) {
+        *self.source.write().expect("Poisoned RwLock") = source;
+    }
+
+    /// Returns version of the serialized block meta without deserializing it (e.g. for migration
+    /// of the records older than BLOCK_META_VERSION)
+    pub fn serialized_version(data: &[u8]) -> Result<u8> {
+        match data.get(META_FIXED_SIZE) {
+            None => fail!("Block meta is too short: {} bytes", data.len()),
+            Some(meta_bits) if meta_bits & META_EXTENDED == 0 => Ok(0),
+            Some(_) => data.get(META_FIXED_SIZE + 1)
+                .copied()
+                .ok_or_else(|| error!("Block meta extension version is missing")),
+        }
+    }
+
+    fn serialize_extension<W: Write>(&self, writer: &mut W) -> Result<()> {
+        writer.write_all(&self.received_at.load(Ordering::SeqCst).to_le_bytes())?;
+        match self.source() {
+            Some(source) => {
+                writer.write_all(&[1])?;
+                writer.write_all(source.as_slice())?;
+            }
+            None => writer.write_all(&[0])?,
+        }
+
+        Ok(())
+    }
+
+    fn deserialize_extension<R: Read>(&self, reader: &mut R) -> Result<()> {
+        self.received_at.store(reader.read_le_u32()?, Ordering::SeqCst);
+        if reader.read_byte()? != 0 {
+            self.set_source(Some(UInt256::from(reader.read_u256()?)));
+        }
+
+        Ok(())
+    }
+}
+
+const META_HEAD_FIELDS: [FieldSchema; 5] = [
+    FieldSchema::new("flags", FieldType::U32, "FLAG_* bits"),
+    FieldSchema::new("gen_utime", FieldType::U32, "Block generation time"),
+    FieldSchema::new("gen_lt", FieldType::U64, "Block generation lt"),
+    FieldSchema::new("masterchain_ref_seq_no", FieldType::U32, "Seq_no of the referred masterchain block"),
+    FieldSchema::new("meta_bits", FieldType::U8, "Bit 0: fetched, bit 1: extended"),
+];
+const META_EXTENSION_FIELDS: [FieldSchema; 2] = [
+    FieldSchema::new("received_at", FieldType::U32, "Unix time the block was received at, 0 if unknown"),
+    FieldSchema::new("source", FieldType::OptionalHash, "Id of the node the block was received from"),
+];
+const HANDLE_BLOCK_ID_FIELD: FieldSchema = FieldSchema::new(
+    "block_id", FieldType::Tail, "Block id appended by BlockHandleStorage, absent in the oldest records"
+);
+
+fn is_meta_version(data: &[u8], version: u8) -> bool {
+    BlockMeta::serialized_version(data).map(|actual| actual == version).unwrap_or(false)
+}
+
+impl RecordSchemas for BlockMeta {
+    const SCHEMAS: &'static [RecordSchema] = &[
+        RecordSchema {
+            name: "BlockMeta",
+            version: 0,
+            description: "Block handle meta without extension",
+            fields: &[
+                META_HEAD_FIELDS[0], META_HEAD_FIELDS[1], META_HEAD_FIELDS[2], META_HEAD_FIELDS[3], META_HEAD_FIELDS[4],
+                HANDLE_BLOCK_ID_FIELD,
+            ],
+            matches: |data| is_meta_version(data, 0),
+        },
+        RecordSchema {
+            name: "BlockMeta",
+            version: 1,
+            description: "Block handle meta with the extension of the fixed layout",
+            fields: &[
+                META_HEAD_FIELDS[0], META_HEAD_FIELDS[1], META_HEAD_FIELDS[2], META_HEAD_FIELDS[3], META_HEAD_FIELDS[4],
+                FieldSchema::new("extension_version", FieldType::U8, "Version of the extension"),
+                META_EXTENSION_FIELDS[0], META_EXTENSION_FIELDS[1],
+                HANDLE_BLOCK_ID_FIELD,
+            ],
+            matches: |data| is_meta_version(data, 1),
+        },
+        RecordSchema {
+            name: "BlockMeta",
+            version: BLOCK_META_VERSION as u32,
+            description: "Block handle meta with the length-prefixed extension",
+            fields: &[
+                META_HEAD_FIELDS[0], META_HEAD_FIELDS[1], META_HEAD_FIELDS[2], META_HEAD_FIELDS[3], META_HEAD_FIELDS[4],
+                FieldSchema::new("extension_version", FieldType::U8, "Version of the extension"),
+                FieldSchema::new("extension", FieldType::Section(&META_EXTENSION_FIELDS), "Fields appended by later versions"),
+                HANDLE_BLOCK_ID_FIELD,
+            ],
+            // Later versions only append fields to the extension
+            matches: |data| BlockMeta::serialized_version(data).map(|version| version >= BLOCK_META_VERSION).unwrap_or(false),
+        },
+    ];
 }
 
 impl Serializable for BlockMeta {
@@ -62,8 +189,12 @@ impl Serializable for BlockMeta {
         writer.write_all(&self.gen_utime.load(Ordering::SeqCst).to_le_bytes())?;
         writer.write_all(&self.gen_lt.load(Ordering::SeqCst).to_le_bytes())?;
         writer.write_all(&self.masterchain_ref_seq_no.load(Ordering::SeqCst).to_le_bytes())?;
-        writer.write_all(&[self.fetched() as u8])?;
-
+        let meta_bits = if self.fetched() { META_FETCHED | META_EXTENDED } else { META_EXTENDED };
+        writer.write_all(&[meta_bits, BLOCK_META_VERSION])?;
+        let mut extension = Vec::new();
+        self.serialize_extension(&mut extension)?;
+        writer.write_all(&(extension.len() as u16).to_le_bytes())?;
+        writer.write_all(&extension)?;
 
         Ok(())
     }
@@ -73,9 +204,23 @@ impl Serializable for BlockMeta {
         let gen_utime = reader.read_le_u32()?;
         let gen_lt = reader.read_le_u64()?;
         let masterchain_ref_seq_no = reader.read_le_u32()?;
-        let fetched = reader.read_byte()? != 0;
+        let meta_bits = reader.read_byte()?;
+        let fetched = meta_bits & META_FETCHED != 0;
         let bm = Self::with_data(flags, gen_utime, gen_lt, masterchain_ref_seq_no, fetched);
-
+        if meta_bits & META_EXTENDED != 0 {
+            match reader.read_byte()? {
+                0 => fail!("Incorrect block meta extension version: 0"),
+                META_EXT_VERSION_FIXED => bm.deserialize_extension(reader)?,
+                _ => {
+                    // The whole section is read, so the fields appended by later versions are skipped
+                    let mut len = [0; 2];
+                    reader.read_exact(&mut len)?;
+                    let mut extension = vec![0; u16::from_le_bytes(len) as usize];
+                    reader.read_exact(&mut extension)?;
+                    bm.deserialize_extension(&mut Cursor::new(extension))?;
+                }
+            }
+        }
 
         Ok(bm)
     }
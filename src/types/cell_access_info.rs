@@ -0,0 +1,54 @@
+use std::io::{Read, Write};
+
+use ton_types::{ByteOrderRead, Result};
+
+use crate::traits::Serializable;
+
+/// Approximate (sampled) access statistics of a cell
+#[derive(Debug, Clone, Default, PartialEq)]
+pub struct CellAccessInfo {
+    access_count: u64,
+    last_access_utime: u32,
+}
+
+impl CellAccessInfo {
+    pub const fn with_values(access_count: u64, last_access_utime: u32) -> Self {
+        Self { access_count, last_access_utime }
+    }
+
+    pub const fn access_count(&self) -> u64 {
+        self.access_count
+    }
+
+    pub const fn last_access_utime(&self) -> u32 {
+        self.last_access_utime
+    }
+
+    pub fn register_access(&mut self, weight: u64, utime: u32) {
+        self.access_count += weight;
+        if self.last_access_utime < utime {
+            self.last_access_utime = utime;
+        }
+    }
+
+    /// Adds the accesses registered in the other record
+    pub fn merge(&mut self, other: &CellAccessInfo) {
+        self.register_access(other.access_count, other.last_access_utime);
+    }
+}
+
+impl Serializable for CellAccessInfo {
+    fn serialize<W: Write>(&self, writer: &mut W) -> Result<()> {
+        writer.write_all(&self.access_count.to_le_bytes())?;
+        writer.write_all(&self.last_access_utime.to_le_bytes())?;
+
+        Ok(())
+    }
+
+    fn deserialize<R: Read>(reader: &mut R) -> Result<Self> {
+        let access_count = reader.read_le_u64()?;
+        let last_access_utime = reader.read_le_u32()?;
+
+        Ok(Self::with_values(access_count, last_access_utime))
+    }
+}
@@ -1,4 +1,6 @@
+use std::convert::TryInto;
 use ton_types::types::UInt256;
+use ton_types::Result;
 use std::fmt::{Display, Formatter, Debug};
 use crate::db::traits::DbKey;
 
@@ -11,6 +13,12 @@ impl CellId {
     pub const fn new(hash: UInt256) -> Self {
         Self { hash }
     }
+
+    /// Constructs cell id from the raw key stored in a database
+    pub fn from_key(key: &[u8]) -> Result<Self> {
+        let hash: [u8; 32] = key.try_into()?;
+        Ok(Self::new(hash.into()))
+    }
 }
 
 impl Display for CellId {
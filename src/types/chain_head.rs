@@ -0,0 +1,31 @@
+use std::convert::TryInto;
+
+use serde_derive::{Deserialize, Serialize};
+use ton_types::Result;
+
+/// Last applied block of the shard
+#[derive(Debug, Clone, PartialEq, Serialize, Deserialize)]
+pub struct ChainHead {
+    block_id_ext: ton_api::ton::ton_node::blockidext::BlockIdExt,
+    seq_no: u32,
+    gen_utime: u32,
+}
+
+impl ChainHead {
+    pub fn with_values(block_id_ext: &ton_block::BlockIdExt, gen_utime: u32) -> Self {
+        Self { block_id_ext: block_id_ext.into(), seq_no: block_id_ext.seq_no(), gen_utime }
+    }
+
+    pub fn block_id_ext(&self) -> Result<ton_block::BlockIdExt> {
+        Ok((&self.block_id_ext).try_into()?)
+    }
+
+    pub const fn seq_no(&self) -> u32 {
+        self.seq_no
+    }
+
+    /// Generation time of the block
+    pub const fn gen_utime(&self) -> u32 {
+        self.gen_utime
+    }
+}
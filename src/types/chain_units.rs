@@ -0,0 +1,153 @@
+use std::convert::TryFrom;
+use std::fmt::{Display, Formatter};
+use std::io::{Read, Write};
+
+use serde_derive::{Deserialize, Serialize};
+
+use ton_block::{BlockIdExt, UnixTime32};
+use ton_types::{ByteOrderRead, error, Result};
+
+use crate::traits::Serializable;
+
+macro_rules! impl_chain_unit {
+    ($type: ident, $raw: ty, $read: ident) => {
+        impl $type {
+            pub const fn new(value: $raw) -> Self {
+                Self(value)
+            }
+
+            pub const fn value(self) -> $raw {
+                self.0
+            }
+
+            pub fn checked_add(self, rhs: $raw) -> Option<Self> {
+                self.0.checked_add(rhs).map(Self)
+            }
+
+            pub fn checked_sub(self, rhs: $raw) -> Option<Self> {
+                self.0.checked_sub(rhs).map(Self)
+            }
+
+            pub fn saturating_sub(self, rhs: $raw) -> Self {
+                Self(self.0.saturating_sub(rhs))
+            }
+
+            /// Distance to the earlier value; None if `earlier` is actually later
+            pub fn since(self, earlier: Self) -> Option<$raw> {
+                self.0.checked_sub(earlier.0)
+            }
+        }
+
+        impl From<$raw> for $type {
+            fn from(value: $raw) -> Self {
+                Self(value)
+            }
+        }
+
+        impl From<$type> for $raw {
+            fn from(value: $type) -> Self {
+                value.0
+            }
+        }
+
+        impl Display for $type {
+            fn fmt(&self, f: &mut Formatter<'_>) -> std::fmt::Result {
+                Display::fmt(&self.0, f)
+            }
+        }
+
+        impl Serializable for $type {
+            fn serialize<W: Write>(&self, writer: &mut W) -> Result<()> {
+                Ok(writer.write_all(&self.0.to_le_bytes())?)
+            }
+
+            fn deserialize<R: Read>(reader: &mut R) -> Result<Self> where Self: Sized {
+                Ok(Self(reader.$read()?))
+            }
+        }
+    };
+}
+
+/// Sequence number of the masterchain block
+#[derive(Debug, Clone, Copy, Default, PartialEq, Eq, PartialOrd, Ord, Hash, Serialize, Deserialize)]
+#[serde(transparent)]
+pub struct McSeqNo(u32);
+
+impl_chain_unit!(McSeqNo, u32, read_le_u32);
+
+impl McSeqNo {
+    /// Seq_no of the masterchain block; fails for the blocks of other shards
+    pub fn of_block(block_id: &BlockIdExt) -> Result<Self> {
+        if !block_id.shard().is_masterchain() {
+            return Err(error!("Block {} is not a masterchain one", block_id));
+        }
+
+        Ok(Self(block_id.seq_no()))
+    }
+}
+
+impl TryFrom<i32> for McSeqNo {
+    type Error = failure::Error;
+
+    fn try_from(value: i32) -> Result<Self> {
+        u32::try_from(value)
+            .map(Self)
+            .map_err(|_| error!("Masterchain seq_no {} is negative", value))
+    }
+}
+
+impl TryFrom<u64> for McSeqNo {
+    type Error = failure::Error;
+
+    fn try_from(value: u64) -> Result<Self> {
+        u32::try_from(value)
+            .map(Self)
+            .map_err(|_| error!("Masterchain seq_no {} is out of range", value))
+    }
+}
+
+/// Logical time
+#[derive(Debug, Clone, Copy, Default, PartialEq, Eq, PartialOrd, Ord, Hash, Serialize, Deserialize)]
+#[serde(transparent)]
+pub struct Lt(u64);
+
+impl_chain_unit!(Lt, u64, read_le_u64);
+
+/// Unix time in seconds, as stored in the block headers
+#[derive(Debug, Clone, Copy, Default, PartialEq, Eq, PartialOrd, Ord, Hash, Serialize, Deserialize)]
+#[serde(transparent)]
+pub struct UnixTime(u32);
+
+impl_chain_unit!(UnixTime, u32, read_le_u32);
+
+impl From<UnixTime32> for UnixTime {
+    fn from(value: UnixTime32) -> Self {
+        Self(value.0)
+    }
+}
+
+impl From<UnixTime> for UnixTime32 {
+    fn from(value: UnixTime) -> Self {
+        UnixTime32(value.0)
+    }
+}
+
+impl TryFrom<u64> for UnixTime {
+    type Error = failure::Error;
+
+    fn try_from(value: u64) -> Result<Self> {
+        u32::try_from(value)
+            .map(Self)
+            .map_err(|_| error!("Unix time {} is out of range", value))
+    }
+}
+
+impl TryFrom<i64> for UnixTime {
+    type Error = failure::Error;
+
+    fn try_from(value: i64) -> Result<Self> {
+        u32::try_from(value)
+            .map(Self)
+            .map_err(|_| error!("Unix time {} is out of range", value))
+    }
+}
@@ -0,0 +1,54 @@
+use crate::types::DbSlice;
+
+/// Value assembled from several database slices (e.g. chunks of a large value), which are not
+/// copied into one buffer until it is actually required
+pub struct DbRope<'a> {
+    chunks: Vec<DbSlice<'a>>,
+    len: usize,
+}
+
+impl<'a> DbRope<'a> {
+    pub fn with_chunks(chunks: Vec<DbSlice<'a>>) -> Self {
+        let len = chunks.iter().map(|chunk| chunk.len()).sum();
+        Self { chunks, len }
+    }
+
+    /// Total size of the value
+    pub const fn len(&self) -> usize {
+        self.len
+    }
+
+    pub const fn is_empty(&self) -> bool {
+        self.len == 0
+    }
+
+    /// Iterates over the parts of the value in order
+    pub fn chunks(&self) -> impl Iterator<Item = &[u8]> {
+        self.chunks.iter().map(|chunk| chunk.as_ref())
+    }
+
+    /// Copies the value into the contiguous buffer
+    pub fn to_vec(&self) -> Vec<u8> {
+        let mut result = Vec::with_capacity(self.len);
+        for chunk in self.chunks() {
+            result.extend_from_slice(chunk);
+        }
+
+        result
+    }
+
+    /// Converts the rope into the slice; the single chunk is returned without copying
+    pub fn into_slice(mut self) -> DbSlice<'a> {
+        if self.chunks.len() == 1 {
+            return self.chunks.pop().expect("Chunk is checked to exist");
+        }
+
+        self.to_vec().into()
+    }
+}
+
+impl<'a> From<DbSlice<'a>> for DbRope<'a> {
+    fn from(slice: DbSlice<'a>) -> Self {
+        Self::with_chunks(vec![slice])
+    }
+}
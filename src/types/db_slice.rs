@@ -7,6 +7,22 @@ pub enum DbSlice<'a> {
     Vector(Vec<u8>)
 }
 
+impl DbSlice<'_> {
+    /// Converts the slice into the one owning its bytes, so no database resources stay pinned
+    /// while it is held; the vector is not copied
+    pub fn into_owned(self) -> DbSlice<'static> {
+        match self {
+            DbSlice::RocksDb(slice) => DbSlice::Vector(slice.as_ref().to_vec()),
+            DbSlice::Vector(vector) => DbSlice::Vector(vector),
+        }
+    }
+
+    /// Copies the bytes of the slice
+    pub fn to_bytes(&self) -> Vec<u8> {
+        self.as_ref().to_vec()
+    }
+}
+
 impl AsRef<[u8]> for DbSlice<'_> {
     fn as_ref(&self) -> &[u8] {
         match self {
@@ -24,6 +40,15 @@ impl Deref for DbSlice<'_> {
     }
 }
 
+impl From<DbSlice<'_>> for Vec<u8> {
+    fn from(slice: DbSlice<'_>) -> Self {
+        match slice {
+            DbSlice::RocksDb(slice) => slice.as_ref().to_vec(),
+            DbSlice::Vector(vector) => vector,
+        }
+    }
+}
+
 impl<'a> From<DBPinnableSlice<'a>> for DbSlice<'a> {
     fn from(slice: DBPinnableSlice<'a>) -> Self {
         DbSlice::RocksDb(slice)
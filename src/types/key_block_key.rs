@@ -0,0 +1,34 @@
+use crate::db::traits::DbKey;
+
+/// Key of KeyBlockDb: masterchain seq_no in big-endian order, so keys are iterated in ascending seq_no order
+pub struct KeyBlockKey([u8; 4]);
+
+impl KeyBlockKey {
+    pub fn new(seq_no: u32) -> Self {
+        Self(seq_no.to_be_bytes())
+    }
+
+    pub fn seq_no(&self) -> u32 {
+        u32::from_be_bytes(self.0)
+    }
+}
+
+impl From<u32> for KeyBlockKey {
+    fn from(seq_no: u32) -> Self {
+        Self::new(seq_no)
+    }
+}
+
+impl DbKey for KeyBlockKey {
+    fn key_name(&self) -> &'static str {
+        "KeyBlockKey"
+    }
+
+    fn as_string(&self) -> String {
+        self.seq_no().to_string()
+    }
+
+    fn key(&self) -> &[u8] {
+        &self.0
+    }
+}
@@ -1,17 +1,61 @@
+use std::convert::TryInto;
+use std::io::{Cursor, Read, Write};
+
 use serde_derive::{Deserialize, Serialize};
 
 use ton_api::ton::ton_node::blockidext::BlockIdExt;
+use ton_types::{ByteOrderRead, fail, Result};
+
+use crate::schema::{FieldSchema, FieldType, RecordSchema, RecordSchemas};
+use crate::traits::Serializable;
+
+/// First byte of the fixed layout records; CBOR-encoded records (maps) never start with it
+pub const LT_DB_ENTRY_TAG: u8 = 0xF7;
+
+const ENTRY_FLAG_APPLIED: u8 = 1;
+const ENTRY_FLAG_PRUNED: u8 = 2;
+
+/// Alternative (forked) block at the same position of the shard's history
+#[derive(Debug, Clone, PartialEq, Serialize, Deserialize)]
+pub struct LtDbCandidate {
+    block_id_ext: BlockIdExt,
+    lt: u64,
+    unix_time: u32,
+}
+
+impl LtDbCandidate {
+    pub const fn block_id_ext(&self) -> &BlockIdExt {
+        &self.block_id_ext
+    }
+
+    pub const fn lt(&self) -> u64 {
+        self.lt
+    }
+
+    pub const fn unix_time(&self) -> u32 {
+        self.unix_time
+    }
+}
 
+/// Entry of LtDb. The primary block is the one used by lookups; in fork-tolerant mode the entry
+/// may additionally hold forked candidates. If one of the blocks is applied, it is always the
+/// primary one. Entries of the blocks whose data are gone are tombstoned with the pruned flag.
 #[derive(Debug, PartialEq, Serialize, Deserialize)]
 pub struct LtDbEntry {
     block_id_ext: BlockIdExt,
     lt: u64,
     unix_time: u32,
+    #[serde(default)]
+    applied: bool,
+    #[serde(default, skip_serializing_if = "Vec::is_empty")]
+    forks: Vec<LtDbCandidate>,
+    #[serde(default, skip_serializing_if = "std::ops::Not::not")]
+    pruned: bool,
 }
 
 impl LtDbEntry {
     pub const fn with_values(block_id_ext: BlockIdExt, lt: u64, unix_time: u32) -> Self {
-        Self { block_id_ext, lt, unix_time }
+        Self { block_id_ext, lt, unix_time, applied: false, forks: Vec::new(), pruned: false }
     }
 
     pub const fn block_id_ext(&self) -> &BlockIdExt {
@@ -25,4 +69,177 @@ impl LtDbEntry {
     pub const fn unix_time(&self) -> u32 {
         self.unix_time
     }
+
+    pub const fn applied(&self) -> bool {
+        self.applied
+    }
+
+    pub fn set_applied(&mut self, value: bool) {
+        self.applied = value;
+    }
+
+    /// Checks whether the data of the primary block are known to be pruned
+    pub const fn pruned(&self) -> bool {
+        self.pruned
+    }
+
+    pub fn set_pruned(&mut self, value: bool) {
+        self.pruned = value;
+    }
+
+    pub fn forks(&self) -> &[LtDbCandidate] {
+        &self.forks
+    }
+
+    /// Checks whether the block is the primary one or one of the candidates
+    pub fn contains(&self, block_id_ext: &BlockIdExt) -> bool {
+        &self.block_id_ext == block_id_ext
+            || self.forks.iter().any(|candidate| &candidate.block_id_ext == block_id_ext)
+    }
+
+    /// Adds forked candidate. Returns false if the block is already in the entry.
+    pub fn add_fork(&mut self, block_id_ext: BlockIdExt, lt: u64, unix_time: u32) -> bool {
+        if self.contains(&block_id_ext) {
+            return false;
+        }
+        self.forks.push(LtDbCandidate { block_id_ext, lt, unix_time });
+
+        true
+    }
+
+    /// Makes the block the applied primary one; the former primary block becomes a candidate.
+    /// Returns false if the block is not in the entry.
+    pub fn promote(&mut self, block_id_ext: &BlockIdExt) -> bool {
+        if &self.block_id_ext != block_id_ext {
+            let position = match self.forks.iter().position(|candidate| &candidate.block_id_ext == block_id_ext) {
+                Some(position) => position,
+                None => return false,
+            };
+            let candidate = &mut self.forks[position];
+            std::mem::swap(&mut self.block_id_ext, &mut candidate.block_id_ext);
+            std::mem::swap(&mut self.lt, &mut candidate.lt);
+            std::mem::swap(&mut self.unix_time, &mut candidate.unix_time);
+            self.pruned = false;
+        }
+        self.applied = true;
+
+        true
+    }
+
+    /// Drops forked candidates, if the primary block is applied. Returns count of dropped candidates.
+    pub fn drop_forks(&mut self) -> usize {
+        if !self.applied {
+            return 0;
+        }
+
+        std::mem::take(&mut self.forks).len()
+    }
+}
+
+/// Entries are written in the fixed layout, read without intermediate allocations (except for the
+/// forks); entries written in CBOR by the older versions are still read and get rewritten in the
+/// fixed layout on the next update
+impl Serializable for LtDbEntry {
+    fn serialize<W: Write>(&self, writer: &mut W) -> Result<()> {
+        if self.forks.len() > u16::MAX as usize {
+            fail!("Too many forked candidates in the block index entry: {}", self.forks.len())
+        }
+        let mut flags = 0;
+        if self.applied {
+            flags |= ENTRY_FLAG_APPLIED;
+        }
+        if self.pruned {
+            flags |= ENTRY_FLAG_PRUNED;
+        }
+        writer.write_all(&[LT_DB_ENTRY_TAG, flags])?;
+        serialize_block_id(&self.block_id_ext, writer)?;
+        writer.write_all(&self.lt.to_le_bytes())?;
+        writer.write_all(&self.unix_time.to_le_bytes())?;
+        writer.write_all(&(self.forks.len() as u16).to_le_bytes())?;
+        for candidate in self.forks.iter() {
+            serialize_block_id(&candidate.block_id_ext, writer)?;
+            writer.write_all(&candidate.lt.to_le_bytes())?;
+            writer.write_all(&candidate.unix_time.to_le_bytes())?;
+        }
+
+        Ok(())
+    }
+
+    fn deserialize<R: Read>(reader: &mut R) -> Result<Self> {
+        let tag = reader.read_byte()?;
+        if tag != LT_DB_ENTRY_TAG {
+            let mut data = vec![tag];
+            reader.read_to_end(&mut data)?;
+            return Ok(serde_cbor::from_slice(&data)?);
+        }
+
+        let flags = reader.read_byte()?;
+        let block_id_ext = deserialize_block_id(reader)?;
+        let lt = reader.read_le_u64()?;
+        let unix_time = reader.read_le_u32()?;
+        let forks_count = reader.read_le_u16()? as usize;
+        let mut forks = Vec::with_capacity(forks_count);
+        for _ in 0..forks_count {
+            let block_id_ext = deserialize_block_id(reader)?;
+            let lt = reader.read_le_u64()?;
+            let unix_time = reader.read_le_u32()?;
+            forks.push(LtDbCandidate { block_id_ext, lt, unix_time });
+        }
+
+        Ok(Self {
+            block_id_ext,
+            lt,
+            unix_time,
+            applied: flags & ENTRY_FLAG_APPLIED != 0,
+            forks,
+            pruned: flags & ENTRY_FLAG_PRUNED != 0,
+        })
+    }
+
+    fn from_slice(data: &[u8]) -> Result<Self> {
+        if data.first() != Some(&LT_DB_ENTRY_TAG) {
+            return Ok(serde_cbor::from_slice(data)?);
+        }
+
+        Self::deserialize(&mut Cursor::new(data))
+    }
+}
+
+/// Block id is stored in the layout of the key block ids (80 bytes)
+fn serialize_block_id<W: Write>(block_id_ext: &BlockIdExt, writer: &mut W) -> Result<()> {
+    let block_id_ext: ton_block::BlockIdExt = block_id_ext.try_into()?;
+    block_id_ext.serialize(writer)
+}
+
+fn deserialize_block_id<R: Read>(reader: &mut R) -> Result<BlockIdExt> {
+    Ok((&ton_block::BlockIdExt::deserialize(reader)?).into())
+}
+
+impl RecordSchemas for LtDbEntry {
+    const SCHEMAS: &'static [RecordSchema] = &[
+        RecordSchema {
+            name: "LtDbEntry",
+            version: 0,
+            description: "Block index entry; optional fields are appended as the CBOR map grows",
+            fields: &[
+                FieldSchema::new("entry", FieldType::Cbor, "block_id_ext, lt, unix_time, applied, forks, pruned"),
+            ],
+            matches: |data| data.first() != Some(&LT_DB_ENTRY_TAG),
+        },
+        RecordSchema {
+            name: "LtDbEntry",
+            version: 1,
+            description: "Block index entry of the fixed layout",
+            fields: &[
+                FieldSchema::new("tag", FieldType::Magic(&[LT_DB_ENTRY_TAG]), "Marks the fixed layout"),
+                FieldSchema::new("flags", FieldType::U8, "Bit 0: applied, bit 1: pruned"),
+                FieldSchema::new("block_id", FieldType::Bytes(80), "Primary block id"),
+                FieldSchema::new("lt", FieldType::U64, "Block generation lt"),
+                FieldSchema::new("unix_time", FieldType::U32, "Block generation time"),
+                FieldSchema::new("forks_count", FieldType::U16, "Count of the forked candidates"),
+                FieldSchema::new("forks", FieldType::Tail, "Block id, lt (u64) and unix time (u32) of every candidate"),
+            ],
+            matches: |data| data.first() == Some(&LT_DB_ENTRY_TAG),
+        },
+    ];
 }
@@ -15,6 +15,16 @@ impl LtDbKey {
 
         Ok(Self(key))
     }
+
+    /// Common prefix of the keys of all the entries of the shard
+    pub fn shard_prefix(shard_id: &ShardIdent) -> Result<Vec<u8>> {
+        shard_id.to_vec()
+    }
+
+    /// Wraps raw key read from the database
+    pub(crate) fn from_key(key: &[u8]) -> Self {
+        Self(key.to_vec())
+    }
 }
 
 impl DbKey for LtDbKey {
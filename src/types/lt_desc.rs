@@ -1,17 +1,33 @@
+use std::io::{Cursor, Read, Write};
+
 use serde_derive::{Deserialize, Serialize};
 
-#[derive(Debug, PartialEq, Serialize, Deserialize)]
+use ton_types::{ByteOrderRead, Result};
+
+use crate::schema::{FieldSchema, FieldType, RecordSchema, RecordSchemas};
+use crate::traits::Serializable;
+
+/// First byte of the fixed layout records; CBOR-encoded records (maps) never start with it
+pub const LT_DESC_TAG: u8 = 0xF7;
+
+const DESC_FLAG_RETIRED: u8 = 1;
+
+/// Descriptor of the shard's index. The descriptor of the shard gone by a reconfiguration is retired:
+/// it is kept for historical lookups, which newer lookups skip without reading it.
+#[derive(Debug, Clone, PartialEq, Serialize, Deserialize)]
 pub struct LtDesc {
     first_index: u32,
     last_index: u32,
     last_seq_no: u32,
     last_lt: u64,
     last_unix_time: u32,
+    #[serde(default, skip_serializing_if = "std::ops::Not::not")]
+    retired: bool,
 }
 
 impl LtDesc {
     pub const fn with_values(first_index: u32, last_index: u32, last_seq_no: u32, last_lt: u64, last_unix_time: u32) -> Self {
-        Self { first_index, last_index, last_seq_no, last_lt, last_unix_time }
+        Self { first_index, last_index, last_seq_no, last_lt, last_unix_time, retired: false }
     }
 
     pub const fn first_index(&self) -> u32 {
@@ -53,4 +69,95 @@ impl LtDesc {
     pub fn set_last_unix_time(&mut self, value: u32) {
         self.last_unix_time = value;
     }
+
+    /// Checks whether the shard is known to have no new blocks (see `BlockIndexDb::retire_inactive_shards`)
+    pub const fn retired(&self) -> bool {
+        self.retired
+    }
+
+    pub fn set_retired(&mut self, value: bool) {
+        self.retired = value;
+    }
+}
+
+/// Descriptors are written in the fixed layout; descriptors written in CBOR by the older versions
+/// are still read and get rewritten in the fixed layout on the next update
+impl Serializable for LtDesc {
+    fn serialize<W: Write>(&self, writer: &mut W) -> Result<()> {
+        let flags = if self.retired { DESC_FLAG_RETIRED } else { 0 };
+        writer.write_all(&[LT_DESC_TAG, flags])?;
+        writer.write_all(&self.first_index.to_le_bytes())?;
+        writer.write_all(&self.last_index.to_le_bytes())?;
+        writer.write_all(&self.last_seq_no.to_le_bytes())?;
+        writer.write_all(&self.last_lt.to_le_bytes())?;
+        writer.write_all(&self.last_unix_time.to_le_bytes())?;
+
+        Ok(())
+    }
+
+    fn deserialize<R: Read>(reader: &mut R) -> Result<Self> {
+        let tag = reader.read_byte()?;
+        if tag != LT_DESC_TAG {
+            let mut data = vec![tag];
+            reader.read_to_end(&mut data)?;
+            return Ok(serde_cbor::from_slice(&data)?);
+        }
+
+        let flags = reader.read_byte()?;
+        let first_index = reader.read_le_u32()?;
+        let last_index = reader.read_le_u32()?;
+        let last_seq_no = reader.read_le_u32()?;
+        let last_lt = reader.read_le_u64()?;
+        let last_unix_time = reader.read_le_u32()?;
+
+        Ok(Self {
+            first_index,
+            last_index,
+            last_seq_no,
+            last_lt,
+            last_unix_time,
+            retired: flags & DESC_FLAG_RETIRED != 0,
+        })
+    }
+
+    fn from_slice(data: &[u8]) -> Result<Self> {
+        if data.first() != Some(&LT_DESC_TAG) {
+            return Ok(serde_cbor::from_slice(data)?);
+        }
+
+        Self::deserialize(&mut Cursor::new(data))
+    }
+}
+
+impl RecordSchemas for LtDesc {
+    const SCHEMAS: &'static [RecordSchema] = &[
+        RecordSchema {
+            name: "LtDesc",
+            version: 0,
+            description: "Descriptor of the shard's index encoded in CBOR",
+            fields: &[
+                FieldSchema::new(
+                    "desc",
+                    FieldType::Cbor,
+                    "first_index, last_index, last_seq_no, last_lt, last_unix_time, retired",
+                ),
+            ],
+            matches: |data| data.first() != Some(&LT_DESC_TAG),
+        },
+        RecordSchema {
+            name: "LtDesc",
+            version: 1,
+            description: "Descriptor of the shard's index of the fixed layout",
+            fields: &[
+                FieldSchema::new("tag", FieldType::Magic(&[LT_DESC_TAG]), "Marks the fixed layout"),
+                FieldSchema::new("flags", FieldType::U8, "Bit 0: retired"),
+                FieldSchema::new("first_index", FieldType::U32, "Index of the first entry"),
+                FieldSchema::new("last_index", FieldType::U32, "Index of the last entry"),
+                FieldSchema::new("last_seq_no", FieldType::U32, "Seq_no of the last indexed block"),
+                FieldSchema::new("last_lt", FieldType::U64, "Lt of the last indexed block"),
+                FieldSchema::new("last_unix_time", FieldType::U32, "Generation time of the last indexed block"),
+            ],
+            matches: |data| data.first() == Some(&LT_DESC_TAG),
+        },
+    ];
 }
@@ -1,34 +1,59 @@
 
+mod apply_checkpoint;
 mod block_handle;
 mod block_id;
+mod block_info_key;
 mod block_meta;
+mod cell_access_info;
 mod cell_id;
+mod chain_head;
+mod chain_units;
 mod complex_id;
+mod db_rope;
 mod db_slice;
+mod key_block_key;
 mod lt_db_entry;
 mod lt_db_key;
 mod lt_desc;
+mod node_state_key;
 mod reference;
 mod shard_ident_key;
+mod slow_op_record;
+mod state_pin;
 mod status_key;
 mod storage_cell;
+mod write_mode;
+mod zerostate_key;
 
+pub use apply_checkpoint::*;
 pub use block_handle::*;
 pub use block_id::*;
+pub use block_info_key::*;
 pub use block_meta::*;
+pub use cell_access_info::*;
 pub use cell_id::*;
+pub use chain_head::*;
+pub use chain_units::*;
 pub use complex_id::*;
+pub use db_rope::*;
 pub use db_slice::*;
+pub use key_block_key::*;
 pub use lt_db_entry::*;
 pub use lt_db_key::*;
 pub use lt_desc::*;
+pub use node_state_key::*;
 pub use reference::*;
 pub use shard_ident_key::*;
+pub use slow_op_record::*;
+pub use state_pin::*;
 pub use status_key::*;
 pub use storage_cell::*;
+pub use write_mode::*;
+pub use zerostate_key::*;
 
-/// Usually >= 1; 0 used to indicate the initial state, i.e. "zerostate"
-pub type BlockSeqNo = i32;
+/// Usually >= 1; 0 used to indicate the initial state, i.e. "zerostate". Unsigned, as in ton_block;
+/// seq_no of the masterchain blocks travel through the API as McSeqNo.
+pub type BlockSeqNo = u32;
 pub type BlockVertSeqNo = u32;
 pub type WorkchainId = i32;
 pub type ShardId = i64;
@@ -0,0 +1,70 @@
+use crate::db::traits::DbKey;
+
+/// Keys of the node state database
+#[derive(Debug, Clone, PartialEq, Eq, Hash)]
+pub enum NodeStateKey {
+    LastAppliedMcBlock,
+    ShardsClientMcBlock,
+    PssKeeperMcBlock,
+    InitMcBlock,
+    CellDbScrubPosition,
+    CellGcGeneration,
+    CellGcHorizon,
+    PruningHorizon,
+    /// Escape hatch for the keys which have no dedicated variant
+    Other(String),
+}
+
+/// Pairs of legacy string keys and the corresponding typed keys
+pub(crate) const LEGACY_NODE_STATE_KEYS: [(&str, NodeStateKey); 4] = [
+    ("LastMcBlockId", NodeStateKey::LastAppliedMcBlock),
+    ("ShardsClientMcBlockId", NodeStateKey::ShardsClientMcBlock),
+    ("PssKeeperBlockId", NodeStateKey::PssKeeperMcBlock),
+    ("InitMcBlockId", NodeStateKey::InitMcBlock),
+];
+
+impl NodeStateKey {
+    pub fn as_str(&self) -> &str {
+        match self {
+            NodeStateKey::LastAppliedMcBlock => "LastAppliedMcBlock",
+            NodeStateKey::ShardsClientMcBlock => "ShardsClientMcBlock",
+            NodeStateKey::PssKeeperMcBlock => "PssKeeperMcBlock",
+            NodeStateKey::InitMcBlock => "InitMcBlock",
+            NodeStateKey::CellDbScrubPosition => "CellDbScrubPosition",
+            NodeStateKey::CellGcGeneration => "CellGcGeneration",
+            NodeStateKey::CellGcHorizon => "CellGcHorizon",
+            NodeStateKey::PruningHorizon => "PruningHorizon",
+            NodeStateKey::Other(key) => key.as_str(),
+        }
+    }
+}
+
+impl From<&str> for NodeStateKey {
+    fn from(key: &str) -> Self {
+        match key {
+            "LastAppliedMcBlock" => NodeStateKey::LastAppliedMcBlock,
+            "ShardsClientMcBlock" => NodeStateKey::ShardsClientMcBlock,
+            "PssKeeperMcBlock" => NodeStateKey::PssKeeperMcBlock,
+            "InitMcBlock" => NodeStateKey::InitMcBlock,
+            "CellDbScrubPosition" => NodeStateKey::CellDbScrubPosition,
+            "CellGcGeneration" => NodeStateKey::CellGcGeneration,
+            "CellGcHorizon" => NodeStateKey::CellGcHorizon,
+            "PruningHorizon" => NodeStateKey::PruningHorizon,
+            _ => NodeStateKey::Other(key.to_string()),
+        }
+    }
+}
+
+impl DbKey for NodeStateKey {
+    fn key_name(&self) -> &'static str {
+        "NodeStateKey"
+    }
+
+    fn as_string(&self) -> String {
+        self.as_str().to_string()
+    }
+
+    fn key(&self) -> &[u8] {
+        self.as_str().as_bytes()
+    }
+}
@@ -4,11 +4,14 @@ use ton_types::Result;
 use crate::db::traits::DbKey;
 use crate::traits::Serializable;
 
+/// Length of the serialized shard ident: workchain id followed by the tagged shard prefix
+pub const SHARD_IDENT_KEY_LEN: usize = 4 + 8;
+
 pub struct ShardIdentKey(Vec<u8>);
 
 impl ShardIdentKey {
     pub fn new(shard_ident: &ShardIdent) -> Result<Self> {
-        let mut key = Vec::with_capacity(std::mem::size_of::<ShardIdent>());
+        let mut key = Vec::with_capacity(SHARD_IDENT_KEY_LEN);
         shard_ident.serialize(&mut key)?;
 
         Ok(Self(key))
@@ -0,0 +1,41 @@
+use serde_derive::{Deserialize, Serialize};
+
+/// Operation which exceeded its duration threshold
+#[derive(Debug, Clone, PartialEq, Serialize, Deserialize)]
+pub struct SlowOpRecord {
+    seq_no: u64,
+    op: String,
+    key: String,
+    duration_micros: u64,
+    timestamp: u32,
+}
+
+impl SlowOpRecord {
+    pub const fn with_values(seq_no: u64, op: String, key: String, duration_micros: u64, timestamp: u32) -> Self {
+        Self { seq_no, op, key, duration_micros, timestamp }
+    }
+
+    /// Sequential number of the record; the greater, the more recent
+    pub const fn seq_no(&self) -> u64 {
+        self.seq_no
+    }
+
+    /// Type of the operation
+    pub fn op(&self) -> &str {
+        &self.op
+    }
+
+    /// Key (e.g. block or cell id) the operation was performed on
+    pub fn key(&self) -> &str {
+        &self.key
+    }
+
+    pub const fn duration_micros(&self) -> u64 {
+        self.duration_micros
+    }
+
+    /// Unix time the operation finished at
+    pub const fn timestamp(&self) -> u32 {
+        self.timestamp
+    }
+}
@@ -0,0 +1,108 @@
+use std::io::{Read, Write};
+
+use ton_block::BlockIdExt;
+use ton_types::{ByteOrderRead, Result};
+
+use crate::db::traits::DbKey;
+use crate::traits::Serializable;
+use crate::types::BlockId;
+
+/// Pin of the shard state, which protects the state from GC while an external reader needs it
+#[derive(Debug, Clone, PartialEq)]
+pub struct StatePin {
+    block_id_ext: BlockIdExt,
+    owner: String,
+    created_at: u32,
+    expires_at: Option<u32>,
+}
+
+impl StatePin {
+    pub fn with_values(block_id_ext: BlockIdExt, owner: String, created_at: u32, expires_at: Option<u32>) -> Self {
+        Self { block_id_ext, owner, created_at, expires_at }
+    }
+
+    pub const fn block_id_ext(&self) -> &BlockIdExt {
+        &self.block_id_ext
+    }
+
+    /// Tag of the service the pin belongs to
+    pub fn owner(&self) -> &str {
+        &self.owner
+    }
+
+    pub const fn created_at(&self) -> u32 {
+        self.created_at
+    }
+
+    /// Time the pin stops protecting the state at; None for pins with no TTL
+    pub const fn expires_at(&self) -> Option<u32> {
+        self.expires_at
+    }
+
+    pub fn is_expired(&self, utime: u32) -> bool {
+        self.expires_at.map(|expires_at| expires_at <= utime).unwrap_or(false)
+    }
+
+    pub fn key(&self) -> StatePinKey {
+        StatePinKey::new(&self.block_id_ext, &self.owner)
+    }
+}
+
+impl Serializable for StatePin {
+    fn serialize<W: Write>(&self, writer: &mut W) -> Result<()> {
+        self.block_id_ext.serialize(writer)?;
+        writer.write_all(&(self.owner.len() as u16).to_le_bytes())?;
+        writer.write_all(self.owner.as_bytes())?;
+        writer.write_all(&self.created_at.to_le_bytes())?;
+        writer.write_all(&self.expires_at.unwrap_or(0).to_le_bytes())?;
+
+        Ok(())
+    }
+
+    fn deserialize<R: Read>(reader: &mut R) -> Result<Self> {
+        let block_id_ext = BlockIdExt::deserialize(reader)?;
+        let mut owner = vec![0; reader.read_le_u16()? as usize];
+        reader.read_exact(&mut owner)?;
+        let created_at = reader.read_le_u32()?;
+        let expires_at = reader.read_le_u32()?;
+
+        Ok(Self {
+            block_id_ext,
+            owner: String::from_utf8(owner)?,
+            created_at,
+            expires_at: if expires_at == 0 { None } else { Some(expires_at) },
+        })
+    }
+}
+
+/// Key of StatePinsDb: key of the block id followed by the owner tag, so all the pins of the
+/// block are stored contiguously
+pub struct StatePinKey(Vec<u8>);
+
+impl StatePinKey {
+    pub fn new(block_id_ext: &BlockIdExt, owner: &str) -> Self {
+        let mut key = Self::prefix(block_id_ext);
+        key.extend_from_slice(owner.as_bytes());
+
+        Self(key)
+    }
+
+    /// Common prefix of the keys of all the pins of the block
+    pub fn prefix(block_id_ext: &BlockIdExt) -> Vec<u8> {
+        BlockId::from(block_id_ext).key().to_vec()
+    }
+}
+
+impl DbKey for StatePinKey {
+    fn key_name(&self) -> &'static str {
+        "StatePinKey"
+    }
+
+    fn as_string(&self) -> String {
+        hex::encode(&self.0)
+    }
+
+    fn key(&self) -> &[u8] {
+        &self.0
+    }
+}
@@ -2,9 +2,12 @@ use strum_macros::AsRefStr;
 
 use crate::db::traits::DbKey;
 
-#[derive(Debug, AsRefStr)]
+#[derive(Debug, Clone, PartialEq, Eq, Hash, AsRefStr)]
 pub enum StatusKey {
-    // TODO: Reserved for DynamicBocDb
+    /// Seq_no (McSeqNo) of the last masterchain block marked applied
+    LastAppliedMcSeqNo,
+    /// Pruning horizon (McSeqNo) last computed by the pruning coordinator
+    PruningHorizon,
 }
 
 impl DbKey for StatusKey {
@@ -1,17 +1,49 @@
-use std::sync::{Arc, RwLock};
+use std::sync::{Arc, Mutex, RwLock};
 
-use ton_types::{Cell, CellData, CellImpl, CellType, LevelMask, MAX_LEVEL, Result};
+use std::io::Cursor;
+
+use once_cell::sync::{Lazy, OnceCell};
+use ton_types::{BuilderData, Cell, CellData, CellImpl, CellType, error, LevelMask, MAX_LEVEL, Result};
 use ton_types::types::UInt256;
 
 use crate::{
-    dynamic_boc_db::DynamicBocDb, types::{CellId, Reference}
+    cell_format, cell_traversal::depth_first, traits::CellLoader, types::{CellId, Reference}
 };
 
+/// Parsed content of the stored cell record
 #[derive(Debug)]
-pub struct StorageCell {
+struct CellContent {
     cell_data: CellData,
     references: RwLock<Vec<Reference>>,
-    boc_db: Arc<DynamicBocDb>,
+}
+
+/// Content served by the accessors unable to return an error for the record which can't be parsed:
+/// the content of the empty cell
+static UNPARSED_CONTENT: Lazy<CellContent> = Lazy::new(|| {
+    let cell: Cell = BuilderData::new().into();
+    let mut data = Vec::new();
+    cell.cell_data().serialize(&mut data).expect("Empty cell is serializable");
+    CellContent {
+        cell_data: CellData::deserialize(&mut Cursor::new(data)).expect("Empty cell is deserializable"),
+        references: RwLock::new(Vec::new()),
+    }
+});
+
+/// Raw V1 record of the lazily parsed cell
+#[derive(Debug)]
+struct LazyRecord {
+    // Released once the record is parsed
+    raw_data: Mutex<Vec<u8>>,
+    // Known without parsing
+    repr_hash: UInt256,
+}
+
+#[derive(Debug)]
+pub struct StorageCell {
+    content: OnceCell<CellContent>,
+    // Present for the lazily parsed cell only
+    lazy: Option<Box<LazyRecord>>,
+    loader: Arc<dyn CellLoader>,
 }
 
 /// Represents Cell for storing in persistent storage
@@ -20,12 +52,22 @@ impl StorageCell {
     pub fn with_params(
         cell_data: CellData,
         references: Vec<Reference>,
-        boc_db: Arc<DynamicBocDb>,
+        loader: Arc<dyn CellLoader>,
     ) -> Self {
         Self {
-            cell_data,
-            references: RwLock::new(references),
-            boc_db,
+            content: OnceCell::from(CellContent { cell_data, references: RwLock::new(references) }),
+            lazy: None,
+            loader,
+        }
+    }
+
+    /// Constructs StorageCell from raw V1 record, which is parsed on the first access to the cell's
+    /// data or references. The representation hash (i.e. the id) is available without parsing.
+    pub fn with_raw_data(cell_id: &CellId, raw_data: Vec<u8>, loader: Arc<dyn CellLoader>) -> Self {
+        Self {
+            content: OnceCell::new(),
+            lazy: Some(Box::new(LazyRecord { raw_data: Mutex::new(raw_data), repr_hash: cell_id.clone().into() })),
+            loader,
         }
     }
 
@@ -39,16 +81,67 @@ impl StorageCell {
         self.hash(MAX_LEVEL as usize)
     }
 
+    /// Returns true if the cell's record is parsed
+    pub fn is_parsed(&self) -> bool {
+        self.content.get().is_some()
+    }
+
+    /// Returns ids of the references not loaded yet; a lazily parsed cell isn't parsed for that, so
+    /// nothing is returned until it is
+    pub(crate) fn unloaded_reference_ids(&self) -> Vec<CellId> {
+        match self.content.get() {
+            Some(content) => content.references.read().expect("Poisoned RwLock")
+                .iter()
+                .filter_map(|reference| match reference {
+                    Reference::NeedToLoad(hash) => Some(CellId::from(hash.clone())),
+                    Reference::Loaded(_) => None,
+                })
+                .collect(),
+            None => Vec::new(),
+        }
+    }
+
+    /// Parses the record of the lazily parsed cell, if not parsed yet
+    pub fn try_parse(&self) -> Result<()> {
+        self.try_content().map(|_| ())
+    }
+
+    fn try_content(&self) -> Result<&CellContent> {
+        self.content.get_or_try_init(|| {
+            let lazy = self.lazy.as_ref()
+                .ok_or_else(|| error!("Cell has neither content nor record"))?;
+            let mut raw_data = lazy.raw_data.lock().expect("Poisoned Mutex");
+            let (cell_data, references) = cell_format::decode_record(&raw_data)
+                .map_err(|err| error!("Can't parse record of cell {:x}: {}", lazy.repr_hash, err))?;
+            *raw_data = Vec::new();
+
+            Ok(CellContent { cell_data, references: RwLock::new(references) })
+        })
+    }
+
+    /// Content for the accessors unable to return an error: the record which can't be parsed is
+    /// reported and served as the empty cell (the error is returned by the fallible accessors)
+    fn content(&self) -> &CellContent {
+        match self.try_content() {
+            Ok(content) => content,
+            Err(err) => {
+                log::error!(target: "storage", "{}", err);
+                &UNPARSED_CONTENT
+            }
+        }
+    }
+
     pub(crate) fn reference(&self, index: usize) -> Result<Arc<StorageCell>> {
-        let hash = match &self.references.read().expect("Poisoned RwLock")[index]
+        let references = &self.try_content()?.references;
+        let hash = match &references.read().expect("Poisoned RwLock")[index]
         {
             Reference::Loaded(cell) => return Ok(Arc::clone(cell)),
             Reference::NeedToLoad(hash) => hash.clone()
         };
 
         let cell_id = CellId::from(hash.clone());
-        let storage_cell = self.boc_db.load_cell(&cell_id)?;
-        self.references.write().expect("Poisoned RwLock")[index] = Reference::Loaded(Arc::clone(&storage_cell));
+        let storage_cell = Arc::clone(&self.loader).load_cell(&cell_id)?;
+        references.write().expect("Poisoned RwLock")[index] = Reference::Loaded(Arc::clone(&storage_cell));
 
         Ok(storage_cell)
     }
@@ -56,19 +149,19 @@ impl StorageCell {
 
 impl CellImpl for StorageCell {
     fn data(&self) -> &[u8] {
-        self.cell_data.data()
+        self.content().cell_data.data()
     }
 
     fn cell_data(&self) -> &CellData {
-        &self.cell_data
+        &self.content().cell_data
     }
 
     fn bit_length(&self) -> usize {
-        self.cell_data.bit_length() as usize
+        self.content().cell_data.bit_length() as usize
     }
 
     fn references_count(&self) -> usize {
-        self.references.read().expect("Poisoned RwLock").len()
+        self.content().references.read().expect("Poisoned RwLock").len()
     }
 
     fn reference(&self, index: usize) -> Result<Cell> {
@@ -76,23 +169,26 @@ impl CellImpl for StorageCell {
     }
 
     fn cell_type(&self) -> CellType {
-        self.cell_data.cell_type()
+        self.content().cell_data.cell_type()
     }
 
     fn level_mask(&self) -> LevelMask {
-        self.cell_data.level_mask()
+        self.content().cell_data.level_mask()
     }
 
     fn hash(&self, index: usize) -> UInt256 {
-        self.cell_data.hash(index)
+        match &self.lazy {
+            Some(lazy) if index == MAX_LEVEL as usize => lazy.repr_hash.clone(),
+            _ => self.content().cell_data.hash(index),
+        }
     }
 
     fn depth(&self, index: usize) -> u16 {
-        self.cell_data.depth(index)
+        self.content().cell_data.depth(index)
     }
 
     fn store_hashes(&self) -> bool {
-        self.cell_data.store_hashes()
+        self.content().cell_data.store_hashes()
     }
 }
 
@@ -107,20 +203,48 @@ fn references_hashes_equal(left: &Vec<Reference>, right: &Vec<Reference>) -> boo
 
 impl Drop for StorageCell {
     fn drop(&mut self) {
-        self.boc_db.cells_map().write()
-            .expect("Poisoned RwLock")
-            .remove(&self.id());
+        self.loader.on_cell_dropped(&self.id());
+
+        // Loaded references are released iteratively: dropping the last owner of a deep chain of
+        // cells recursively would exhaust the thread stack
+        let mut released = Vec::new();
+        take_loaded_references(self, &mut released);
+        depth_first(released, |cell| {
+            let mut children = Vec::new();
+            if let Ok(mut cell) = Arc::try_unwrap(cell) {
+                take_loaded_references(&mut cell, &mut children);
+            }
+            Ok(children)
+        }).expect("Releasing of references doesn't fail");
+    }
+}
+
+/// Replaces loaded references of the cell with their hashes, moving the loaded cells out
+fn take_loaded_references(cell: &mut StorageCell, loaded: &mut Vec<Arc<StorageCell>>) {
+    if let Some(content) = cell.content.get_mut() {
+        for reference in content.references.get_mut().expect("Poisoned RwLock").iter_mut() {
+            if let Reference::Loaded(_) = reference {
+                let hash = reference.hash();
+                if let Reference::Loaded(child) = std::mem::replace(reference, Reference::NeedToLoad(hash)) {
+                    loaded.push(child);
+                }
+            }
+        }
     }
 }
 
 impl PartialEq for StorageCell {
     fn eq(&self, other: &Self) -> bool {
-        if self.cell_data != other.cell_data {
+        let (self_content, other_content) = match (self.try_content(), other.try_content()) {
+            (Ok(self_content), Ok(other_content)) => (self_content, other_content),
+            _ => return false,
+        };
+        if self_content.cell_data != other_content.cell_data {
             return false;
         }
 
-        let self_guard = self.references.read().expect("Poisoned RwLock");
-        let other_guard = other.references.read().expect("Poisoned RwLock");
+        let self_guard = self_content.references.read().expect("Poisoned RwLock");
+        let other_guard = other_content.references.read().expect("Poisoned RwLock");
         self_guard.len() == other_guard.len()
             && references_hashes_equal(&self_guard, &other_guard)
     }
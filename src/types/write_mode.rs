@@ -0,0 +1,27 @@
+use std::fmt::Display;
+
+use sha2::{Digest, Sha256};
+use ton_types::Result;
+
+use crate::error::StorageError;
+
+/// Behavior of writing an entry whose key already exists
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum WriteMode {
+    /// Existing entry is kept, new content is ignored
+    KeepExisting,
+    /// Existing entry must have the same content, otherwise StorageError::ContentMismatch is raised
+    WriteOnce,
+    /// Existing entry is replaced. Intended for repair tools only.
+    Overwrite,
+}
+
+/// Checks that existing and new contents of the entry are equal (by hashes)
+pub(crate) fn check_same_content(key: impl Display, existing: &[u8], data: &[u8]) -> Result<()> {
+    if Sha256::digest(existing) != Sha256::digest(data) {
+        log::error!(target: "storage", "Content mismatch for existing entry {}", key);
+        return Err(StorageError::ContentMismatch(key.to_string()).into());
+    }
+
+    Ok(())
+}
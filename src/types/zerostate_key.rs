@@ -0,0 +1,34 @@
+use crate::db::traits::DbKey;
+
+/// Key of ZerostateDb: workchain id
+pub struct ZerostateKey([u8; 4]);
+
+impl ZerostateKey {
+    pub fn new(workchain_id: i32) -> Self {
+        Self(workchain_id.to_be_bytes())
+    }
+
+    pub fn workchain_id(&self) -> i32 {
+        i32::from_be_bytes(self.0)
+    }
+}
+
+impl From<i32> for ZerostateKey {
+    fn from(workchain_id: i32) -> Self {
+        Self::new(workchain_id)
+    }
+}
+
+impl DbKey for ZerostateKey {
+    fn key_name(&self) -> &'static str {
+        "ZerostateKey"
+    }
+
+    fn as_string(&self) -> String {
+        self.workchain_id().to_string()
+    }
+
+    fn key(&self) -> &[u8] {
+        &self.0
+    }
+}
@@ -0,0 +1,108 @@
+use std::sync::Arc;
+use std::time::{Duration, Instant};
+
+use fnv::FnvHashSet;
+use serde_derive::{Deserialize, Serialize};
+
+use ton_types::{Cell, error, Result};
+
+use crate::types::BlockHandle;
+
+/// Configuration of the storage warm-up on node start
+#[derive(Debug, Clone, Deserialize, Serialize)]
+#[serde(default)]
+pub struct WarmUpConfig {
+    /// Count of the top levels of the masterchain state's tree of cells preloaded
+    pub state_depth: usize,
+    /// Maximal count of the state's cells preloaded
+    pub max_cells: usize,
+    /// Count of the last masterchain blocks whose handles are preloaded
+    pub recent_mc_blocks: u32,
+    /// Wall-clock time after which warm-up returns, even if it isn't finished (in milliseconds
+    /// in the configuration)
+    #[serde(with = "crate::storage_config::duration_ms")]
+    pub budget: Duration,
+}
+
+impl Default for WarmUpConfig {
+    fn default() -> Self {
+        Self {
+            state_depth: 8,
+            max_cells: 100_000,
+            recent_mc_blocks: 1000,
+            budget: Duration::from_secs(30),
+        }
+    }
+}
+
+/// Part of the data being preloaded, reported to the progress callback
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum WarmUpStage {
+    StateCells,
+    BlockHandles,
+    LtDescs,
+}
+
+/// Data preloaded by the warm-up. Loaded cells and handles stay cached only while they are alive,
+/// so the result should be kept until the node gets going.
+pub struct WarmUpResult {
+    pub cells: Vec<Cell>,
+    pub handles: Vec<Arc<BlockHandle>>,
+    pub lt_descs: usize,
+    /// False if the warm-up was interrupted by the budget or the limits
+    pub completed: bool,
+}
+
+/// Loads the top levels of the tree of cells in breadth-first order until the deadline.
+/// Returns the loaded cells and whether all the requested levels were loaded.
+pub(crate) fn preload_cells(
+    root: Cell,
+    depth: usize,
+    max_cells: usize,
+    deadline: Instant,
+    progress: &(dyn Fn(WarmUpStage, usize) + Send + Sync),
+) -> Result<(Vec<Cell>, bool)> {
+    let mut visited = FnvHashSet::default();
+    visited.insert(root.repr_hash());
+    let mut cells = vec![root];
+    let mut level_start = 0;
+    for _ in 0..depth {
+        let level_end = cells.len();
+        for index in level_start..level_end {
+            for i in 0..cells[index].references_count() {
+                if cells.len() >= max_cells || Instant::now() >= deadline {
+                    progress(WarmUpStage::StateCells, cells.len());
+                    return Ok((cells, false));
+                }
+                let child = cells[index].reference(i)?;
+                if visited.insert(child.repr_hash()) {
+                    cells.push(child);
+                }
+            }
+        }
+        progress(WarmUpStage::StateCells, cells.len());
+        if level_end == cells.len() {
+            break;
+        }
+        level_start = level_end;
+    }
+
+    Ok((cells, true))
+}
+
+/// Runs the blocking part of the warm-up on the thread of its own (the crate owns no runtime to
+/// run it with `spawn_blocking`), resolving with its result
+pub(crate) async fn run_blocking<T: Send + 'static>(
+    name: &'static str,
+    task: impl FnOnce() -> Result<T> + Send + 'static,
+) -> Result<T> {
+    let (sender, receiver) = futures::channel::oneshot::channel();
+    std::thread::Builder::new()
+        .name(format!("warm-up: {}", name))
+        .spawn(move || {
+            sender.send(task()).ok();
+        })?;
+
+    receiver.await
+        .map_err(|_| error!("Warm-up thread preloading {} has terminated unexpectedly", name))?
+}
@@ -0,0 +1,50 @@
+use std::io::Cursor;
+
+use sha2::{Digest, Sha256};
+
+use ton_block::{BlockIdExt, ShardIdent};
+use ton_types::{Cell, deserialize_tree_of_cells, fail, Result, UInt256};
+
+use crate::db::traits::KvcWriteable;
+use crate::db_impl_serializable;
+use crate::traits::Serializable;
+use crate::types::{CellId, ZerostateKey};
+
+db_impl_serializable!(ZerostateDb, KvcWriteable, ZerostateKey, BlockIdExt);
+
+impl ZerostateDb {
+    /// Builds zerostate id of the workchain: the root hash is the representation hash of the root
+    /// cell and the file hash is the hash of the canonical BOC. Fails if the BOC doesn't contain
+    /// the given tree of cells.
+    pub fn zerostate_id(workchain_id: i32, root_cell: &Cell, raw_boc: &[u8]) -> Result<BlockIdExt> {
+        let boc_root = deserialize_tree_of_cells(&mut Cursor::new(raw_boc))?;
+        if boc_root.repr_hash() != root_cell.repr_hash() {
+            fail!(
+                "Zerostate BOC root {} doesn't match root cell {}",
+                CellId::from(boc_root.repr_hash()),
+                CellId::from(root_cell.repr_hash())
+            )
+        }
+
+        Ok(BlockIdExt {
+            shard_id: ShardIdent::full(workchain_id),
+            seq_no: 0,
+            root_hash: root_cell.repr_hash(),
+            file_hash: UInt256::from(Sha256::digest(raw_boc).as_slice()),
+        })
+    }
+
+    /// Gets id of the stored zerostate of the workchain
+    pub fn get_zerostate_id(&self, workchain_id: i32) -> Result<Option<BlockIdExt>> {
+        self.try_get_value(&workchain_id.into())
+    }
+
+    /// Checks whether the block is a zerostate registered in the database
+    pub fn is_zerostate(&self, block_id: &BlockIdExt) -> Result<bool> {
+        if block_id.seq_no() != 0 {
+            return Ok(false);
+        }
+
+        Ok(self.get_zerostate_id(block_id.shard().workchain_id())?.as_ref() == Some(block_id))
+    }
+}